@@ -0,0 +1,5 @@
+fn main() {
+    // the Node.js addon leaves node's symbols to be found once loaded, which macOS has to be told
+    #[cfg(feature = "node")]
+    napi_build::setup();
+}
@@ -0,0 +1,62 @@
+/*
+ * Node.js bindings of usb_picoboot_rs, for flashing RP2040 and RP2350 devices in BOOTSEL mode
+ * from JavaScript or TypeScript, e.g. an Electron app. Build the addon with
+ *   cargo rustc --release --lib --features node --crate-type cdylib
+ * and copy libusb_picoboot_rs.so (usb_picoboot_rs.dll, libusb_picoboot_rs.dylib) to
+ * picoboot.node, next to this file.
+ *
+ * Flashing, reading and rebooting run off the main thread and return promises. Failures throw,
+ * or reject, with an Error whose message says what went wrong.
+ */
+
+/* A device in BOOTSEL mode found by listDevices, which isn't opened or claimed. */
+export interface DeviceInfo {
+  bus: number;
+  address: number;
+  /* ports from the root hub down to the device, empty if the OS doesn't say */
+  ports: number[];
+  /* undefined if the string couldn't be read, e.g. for lack of permissions */
+  serial?: string;
+  manufacturer?: string;
+  product?: string;
+  chip: "rp2040" | "rp2350";
+  /* whether another program has the PICOBOOT interface claimed, undefined if it can't be told */
+  inUse?: boolean;
+}
+
+/* Progress of a flashFile or read, in bytes. */
+export interface Progress {
+  op: "erase" | "write" | "verify" | "read";
+  done: number;
+  total: number;
+}
+
+/* Every connected device in BOOTSEL mode. */
+export function listDevices(): DeviceInfo[];
+
+export class Device {
+  /* Opens the device with the given serial number, or the first found. */
+  static open(serial?: string): Device;
+
+  /* "rp2040" or "rp2350", or null if the chip isn't known. */
+  get chip(): "rp2040" | "rp2350" | null;
+
+  /*
+   * Flashes a UF2, ELF, Intel HEX or binary file (binaries go at the start of flash), verifying
+   * what's written by reading it back, and loads any SRAM segments. Resolves to the bytes of
+   * flash programmed. The device isn't rebooted, see reboot.
+   */
+  flashFile(path: string, onProgress?: (progress: Progress) => void): Promise<number>;
+
+  /* Reads len bytes of flash (or other memory) at addr. */
+  read(addr: number, len: number, onProgress?: (progress: Progress) => void): Promise<Buffer>;
+
+  /*
+   * Reboots into the flashed firmware after delayMs milliseconds, or back into BOOTSEL if
+   * bootsel is true. The device should be closed afterwards, as it leaves the bus.
+   */
+  reboot(delayMs?: number, bootsel?: boolean): Promise<void>;
+
+  /* Releases the device once anything still running on it is done. */
+  close(): void;
+}
@@ -0,0 +1,57 @@
+/*
+ * C API of usb_picoboot_rs, for flashing RP2040 and RP2350 devices in BOOTSEL mode from C or C++.
+ * Build the shared library with
+ *   cargo rustc --release --lib --features ffi --crate-type cdylib
+ * and link against libusb_picoboot_rs.so (usb_picoboot_rs.dll, libusb_picoboot_rs.dylib).
+ *
+ * Every function returning int returns 0 on success and -1 on failure, when
+ * picoboot_last_error() says what went wrong. A device may be used from any thread, but only
+ * from one at a time.
+ */
+#ifndef PICOBOOT_H
+#define PICOBOOT_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct PicobootDevice PicobootDevice;
+
+/*
+ * Opens the device with the given serial number, or the first found if serial is NULL, storing
+ * it in *out. serial must be NULL or a NUL terminated UTF-8 string, and out must not be NULL.
+ */
+int picoboot_open(const char *serial, PicobootDevice **out);
+
+/* Closes a device from picoboot_open, which mustn't be used again. NULL is ignored. */
+void picoboot_close(PicobootDevice *dev);
+
+/*
+ * Flashes a UF2, ELF, Intel HEX or binary file (binaries go at the start of flash), verifying it
+ * by reading it back, and loads any SRAM segments. The device isn't rebooted. path must be a
+ * NUL terminated UTF-8 string.
+ */
+int picoboot_flash_file(PicobootDevice *dev, const char *path);
+
+/* Reads len bytes at addr into buf, which must have room for them. */
+int picoboot_read(PicobootDevice *dev, uint32_t addr, uint8_t *buf, uint32_t len);
+
+/*
+ * Reboots into the flashed firmware after delay_ms milliseconds, or back into BOOTSEL if bootsel
+ * is non-zero. Close the device afterwards, as it leaves the bus.
+ */
+int picoboot_reboot(PicobootDevice *dev, uint32_t delay_ms, int bootsel);
+
+/*
+ * What the last failing call on this thread went wrong with, or an empty string. The string
+ * stays valid until the next failure on the same thread.
+ */
+const char *picoboot_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,580 @@
+// The PICOBOOT wire format: constants, command IDs, status codes and the packing of commands,
+// their arguments and the command status. It's a crate of its own so no_std code, such as
+// firmware answering PICOBOOT or another transport, can depend on it and share the exact same
+// definitions as the host side in `usb_picoboot_rs`, which re-exports it as `protocol`
+
+#![no_std]
+
+// see https://datasheets.raspberrypi.com/rp2040/rp2040-datasheet.pdf
+// section 2.8.5 for details on PICOBOOT interface
+
+pub const PICO_PAGE_SIZE: usize = 256;
+pub const PICO_SECTOR_SIZE: u32 = 4096;
+pub const PICO_FLASH_START: u32 = 0x10000000;
+pub const PICO_STACK_POINTER: u32 = 0x20042000;
+pub const PICO2_STACK_POINTER: u32 = 0x20082000;
+pub const PICOBOOT_VID: u16 = 0x2E8A;
+pub const PICOBOOT_PID_RP2040: u16 = 0x0003;
+pub const PICOBOOT_PID_RP2350: u16 = 0x000f;
+pub const PICOBOOT_MAGIC: u32 = 0x431FD10B;
+
+// Sizes of a command on the bulk out endpoint, of the arguments it carries and of the command
+// status answered to the GET_COMMAND_STATUS control request
+pub const CMD_SIZE: usize = 32;
+pub const ARGS_SIZE: usize = 16;
+pub const STATUS_SIZE: usize = 16;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PicobootCmdId {
+    Unknown = 0x0,
+    ExclusiveAccess = 0x1,
+    Reboot = 0x2,
+    FlashErase = 0x3,
+    Read = 0x84, // either RAM or FLASH
+    Write = 0x5, // either RAM or FLASH (does no erase)
+    ExitXip = 0x6,
+    EnterCmdXip = 0x7,
+    Exec = 0x8,
+    VectorizeFlash = 0x9,
+    // RP2350 only below here
+    Reboot2 = 0xA,
+    GetInfo = 0x8B,
+    OtpRead = 0x8C,
+    OtpWrite = 0xD,
+    //Exec2 = 0xE, // currently unused
+}
+impl TryFrom<u8> for PicobootCmdId {
+    type Error = ();
+
+    fn try_from(x: u8) -> core::result::Result<Self, Self::Error> {
+        match x {
+            x if x == Self::Unknown as u8 => Ok(Self::Unknown),
+            x if x == Self::ExclusiveAccess as u8 => Ok(Self::ExclusiveAccess),
+            x if x == Self::Reboot as u8 => Ok(Self::Reboot),
+            x if x == Self::FlashErase as u8 => Ok(Self::FlashErase),
+            x if x == Self::Read as u8 => Ok(Self::Read),
+            x if x == Self::Write as u8 => Ok(Self::Write),
+            x if x == Self::ExitXip as u8 => Ok(Self::ExitXip),
+            x if x == Self::EnterCmdXip as u8 => Ok(Self::EnterCmdXip),
+            x if x == Self::Exec as u8 => Ok(Self::Exec),
+            x if x == Self::VectorizeFlash as u8 => Ok(Self::VectorizeFlash),
+            x if x == Self::Reboot2 as u8 => Ok(Self::Reboot2),
+            x if x == Self::GetInfo as u8 => Ok(Self::GetInfo),
+            x if x == Self::OtpRead as u8 => Ok(Self::OtpRead),
+            x if x == Self::OtpWrite as u8 => Ok(Self::OtpWrite),
+            // x if x == Self::Exec2 as u8 => Ok(Self::Exec2),
+            _ => Err(()),
+        }
+    }
+}
+
+// Status codes the bootrom reports for a command, see `PicobootError::Command`. Codes this
+// doesn't know come back as `UnknownError`
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PicobootStatus {
+    Ok = 0,
+    UnknownCmd = 1,
+    InvalidCmdLength = 2,
+    InvalidTransferLength = 3,
+    InvalidAddress = 4,
+    BadAlignment = 5,
+    InterleavedWrite = 6,
+    Rebooting = 7,
+    UnknownError = 8,
+    InvalidState = 9,
+    NotPermitted = 10,
+    InvalidArg = 11,
+    BufferTooSmall = 12,
+    PreconditionNotMet = 13,
+    ModifiedData = 14,
+    InvalidData = 15,
+    NotFound = 16,
+    UnsupportedModification = 17,
+}
+impl TryFrom<u32> for PicobootStatus {
+    type Error = ();
+
+    fn try_from(x: u32) -> core::result::Result<Self, Self::Error> {
+        match x {
+            x if x == Self::Ok as u32 => Ok(Self::Ok),
+            x if x == Self::UnknownCmd as u32 => Ok(Self::UnknownCmd),
+            x if x == Self::InvalidCmdLength as u32 => Ok(Self::InvalidCmdLength),
+            x if x == Self::InvalidTransferLength as u32 => Ok(Self::InvalidTransferLength),
+            x if x == Self::InvalidAddress as u32 => Ok(Self::InvalidAddress),
+            x if x == Self::BadAlignment as u32 => Ok(Self::BadAlignment),
+            x if x == Self::InterleavedWrite as u32 => Ok(Self::InterleavedWrite),
+            x if x == Self::Rebooting as u32 => Ok(Self::Rebooting),
+            x if x == Self::UnknownError as u32 => Ok(Self::UnknownError),
+            x if x == Self::InvalidState as u32 => Ok(Self::InvalidState),
+            x if x == Self::NotPermitted as u32 => Ok(Self::NotPermitted),
+            x if x == Self::InvalidArg as u32 => Ok(Self::InvalidArg),
+            x if x == Self::BufferTooSmall as u32 => Ok(Self::BufferTooSmall),
+            x if x == Self::PreconditionNotMet as u32 => Ok(Self::PreconditionNotMet),
+            x if x == Self::ModifiedData as u32 => Ok(Self::ModifiedData),
+            x if x == Self::InvalidData as u32 => Ok(Self::InvalidData),
+            x if x == Self::NotFound as u32 => Ok(Self::NotFound),
+            x if x == Self::UnsupportedModification as u32 => Ok(Self::UnsupportedModification),
+            _ => Err(()),
+        }
+    }
+}
+
+// What to ask for with `PicobootConnection::get_info`, RP2350 only
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InfoType {
+    Sys = 1,
+    PartitionTable = 2,
+    Uf2TargetPartition = 3,
+    Uf2Status = 4,
+}
+
+// The structs below are packed by hand into the little-endian layout the bootrom expects,
+// rather than relying on how the compiler or a serializer lays them out. Reserved bytes are
+// sent as zero and ignored when parsing
+
+// Copies `bytes` into `buf` at `*at` and moves `at` past them
+fn put(buf: &mut [u8], at: &mut usize, bytes: &[u8]) {
+    buf[*at..*at + bytes.len()].copy_from_slice(bytes);
+    *at += bytes.len();
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+// Arguments of FLASH_ERASE, READ, WRITE, EXEC and VECTORIZE_FLASH, the latter two only using
+// `addr`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PicobootRangeCmd {
+    pub addr: u32,
+    pub size: u32,
+}
+impl PicobootRangeCmd {
+    pub fn ser(addr: u32, size: u32) -> [u8; ARGS_SIZE] {
+        PicobootRangeCmd { addr, size }.pack()
+    }
+
+    pub fn pack(&self) -> [u8; ARGS_SIZE] {
+        let mut buf = [0u8; ARGS_SIZE];
+        let mut at = 0;
+        put(&mut buf, &mut at, &self.addr.to_le_bytes());
+        put(&mut buf, &mut at, &self.size.to_le_bytes());
+        buf
+    }
+
+    pub fn parse(args: &[u8; ARGS_SIZE]) -> Self {
+        PicobootRangeCmd {
+            addr: u32_at(args, 0),
+            size: u32_at(args, 4),
+        }
+    }
+}
+
+// Arguments of REBOOT, RP2040 only
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PicobootRebootCmd {
+    pub pc: u32,
+    pub sp: u32,
+    pub delay: u32,
+}
+impl PicobootRebootCmd {
+    pub fn ser(pc: u32, sp: u32, delay: u32) -> [u8; ARGS_SIZE] {
+        PicobootRebootCmd { pc, sp, delay }.pack()
+    }
+
+    pub fn pack(&self) -> [u8; ARGS_SIZE] {
+        let mut buf = [0u8; ARGS_SIZE];
+        let mut at = 0;
+        put(&mut buf, &mut at, &self.pc.to_le_bytes());
+        put(&mut buf, &mut at, &self.sp.to_le_bytes());
+        put(&mut buf, &mut at, &self.delay.to_le_bytes());
+        buf
+    }
+
+    pub fn parse(args: &[u8; ARGS_SIZE]) -> Self {
+        PicobootRebootCmd {
+            pc: u32_at(args, 0),
+            sp: u32_at(args, 4),
+            delay: u32_at(args, 8),
+        }
+    }
+}
+
+// Arguments of REBOOT2, RP2350 only
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PicobootReboot2Cmd {
+    pub flags: u32,
+    pub delay: u32,
+    pub p0: u32,
+    pub p1: u32,
+}
+impl PicobootReboot2Cmd {
+    pub fn ser(flags: u32, delay: u32, p0: u32, p1: u32) -> [u8; ARGS_SIZE] {
+        PicobootReboot2Cmd {
+            flags,
+            delay,
+            p0,
+            p1,
+        }
+        .pack()
+    }
+
+    pub fn pack(&self) -> [u8; ARGS_SIZE] {
+        let mut buf = [0u8; ARGS_SIZE];
+        let mut at = 0;
+        put(&mut buf, &mut at, &self.flags.to_le_bytes());
+        put(&mut buf, &mut at, &self.delay.to_le_bytes());
+        put(&mut buf, &mut at, &self.p0.to_le_bytes());
+        put(&mut buf, &mut at, &self.p1.to_le_bytes());
+        buf
+    }
+
+    pub fn parse(args: &[u8; ARGS_SIZE]) -> Self {
+        PicobootReboot2Cmd {
+            flags: u32_at(args, 0),
+            delay: u32_at(args, 4),
+            p0: u32_at(args, 8),
+            p1: u32_at(args, 12),
+        }
+    }
+}
+
+// Arguments of OTP_READ and OTP_WRITE, RP2350 only
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PicobootOtpCmd {
+    pub row: u16,
+    pub row_count: u16,
+    pub ecc: bool,
+}
+impl PicobootOtpCmd {
+    pub fn ser(row: u16, row_count: u16, ecc: bool) -> [u8; ARGS_SIZE] {
+        PicobootOtpCmd {
+            row,
+            row_count,
+            ecc,
+        }
+        .pack()
+    }
+
+    pub fn pack(&self) -> [u8; ARGS_SIZE] {
+        let mut buf = [0u8; ARGS_SIZE];
+        let mut at = 0;
+        put(&mut buf, &mut at, &self.row.to_le_bytes());
+        put(&mut buf, &mut at, &self.row_count.to_le_bytes());
+        put(&mut buf, &mut at, &[self.ecc as u8]);
+        buf
+    }
+
+    pub fn parse(args: &[u8; ARGS_SIZE]) -> Self {
+        PicobootOtpCmd {
+            row: u16_at(args, 0),
+            row_count: u16_at(args, 2),
+            ecc: args[4] != 0,
+        }
+    }
+}
+
+// Arguments of GET_INFO, RP2350 only. `info_type` is kept raw, as parsed commands may carry types
+// `InfoType` doesn't know
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PicobootGetInfoCmd {
+    pub info_type: u8,
+    pub param: u8,
+    pub word_param: u16,
+    pub params: [u32; 3],
+}
+impl PicobootGetInfoCmd {
+    pub fn ser(info_type: InfoType, params: [u32; 3]) -> [u8; ARGS_SIZE] {
+        PicobootGetInfoCmd {
+            info_type: info_type as u8,
+            param: 0,
+            word_param: 0,
+            params,
+        }
+        .pack()
+    }
+
+    pub fn pack(&self) -> [u8; ARGS_SIZE] {
+        let mut buf = [0u8; ARGS_SIZE];
+        let mut at = 0;
+        put(&mut buf, &mut at, &[self.info_type, self.param]);
+        put(&mut buf, &mut at, &self.word_param.to_le_bytes());
+        for p in self.params {
+            put(&mut buf, &mut at, &p.to_le_bytes());
+        }
+        buf
+    }
+
+    pub fn parse(args: &[u8; ARGS_SIZE]) -> Self {
+        PicobootGetInfoCmd {
+            info_type: args[0],
+            param: args[1],
+            word_param: u16_at(args, 2),
+            params: [u32_at(args, 4), u32_at(args, 8), u32_at(args, 12)],
+        }
+    }
+}
+
+// The answer to the GET_COMMAND_STATUS control request, about the last command sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PicobootStatusCmd {
+    pub token: u32,
+    pub status_code: u32,
+    pub cmd_id: u8,
+    // whether the command is still running
+    pub in_progress: bool,
+}
+impl PicobootStatusCmd {
+    pub fn pack(&self) -> [u8; STATUS_SIZE] {
+        let mut buf = [0u8; STATUS_SIZE];
+        let mut at = 0;
+        put(&mut buf, &mut at, &self.token.to_le_bytes());
+        put(&mut buf, &mut at, &self.status_code.to_le_bytes());
+        put(&mut buf, &mut at, &[self.cmd_id, self.in_progress as u8]);
+        buf
+    }
+
+    pub fn parse(buf: &[u8; STATUS_SIZE]) -> Self {
+        PicobootStatusCmd {
+            token: u32_at(buf, 0),
+            status_code: u32_at(buf, 4),
+            cmd_id: buf[8],
+            in_progress: buf[9] != 0,
+        }
+    }
+}
+
+// A command as sent on the bulk out endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PicobootCmd {
+    pub magic: u32,
+    pub token: u32,
+    pub cmd_id: u8,
+    // how many bytes of `args` the command uses
+    pub cmd_size: u8,
+    // bytes of data following the command, in the direction `is_data_in` says
+    pub transfer_len: u32,
+    pub args: [u8; ARGS_SIZE],
+}
+impl PicobootCmd {
+    pub fn new(
+        cmd_id: PicobootCmdId,
+        cmd_size: u8,
+        transfer_len: u32,
+        args: [u8; ARGS_SIZE],
+    ) -> Self {
+        PicobootCmd {
+            magic: PICOBOOT_MAGIC,
+            token: 0,
+            cmd_id: cmd_id as u8,
+            cmd_size,
+            transfer_len,
+            args,
+        }
+    }
+
+    // Whether data goes from the device to the host, which the top bit of the ID says. The ack
+    // then goes the other way
+    pub fn is_data_in(&self) -> bool {
+        self.cmd_id & 0x80 != 0
+    }
+
+    pub fn pack(&self) -> [u8; CMD_SIZE] {
+        let mut buf = [0u8; CMD_SIZE];
+        let mut at = 0;
+        put(&mut buf, &mut at, &self.magic.to_le_bytes());
+        put(&mut buf, &mut at, &self.token.to_le_bytes());
+        put(&mut buf, &mut at, &[self.cmd_id, self.cmd_size]);
+        // two reserved bytes
+        at += 2;
+        put(&mut buf, &mut at, &self.transfer_len.to_le_bytes());
+        put(&mut buf, &mut at, &self.args);
+        buf
+    }
+
+    // None if `buf` isn't a command, i.e. the wrong length or without the magic
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() != CMD_SIZE || u32_at(buf, 0) != PICOBOOT_MAGIC {
+            return None;
+        }
+        let mut args = [0u8; ARGS_SIZE];
+        args.copy_from_slice(&buf[16..]);
+        Some(PicobootCmd {
+            magic: PICOBOOT_MAGIC,
+            token: u32_at(buf, 4),
+            cmd_id: buf[8],
+            cmd_size: buf[9],
+            transfer_len: u32_at(buf, 12),
+            args,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Packs a command with a fixed token. The golden bytes it's compared with are written out
+    // from the layout in the RP2040 datasheet: magic, token, ID, args size, 2 reserved bytes,
+    // transfer length, then the args
+    fn cmd(
+        cmd_id: PicobootCmdId,
+        cmd_size: u8,
+        transfer_len: u32,
+        args: [u8; ARGS_SIZE],
+    ) -> [u8; CMD_SIZE] {
+        PicobootCmd {
+            token: 0x12345678,
+            ..PicobootCmd::new(cmd_id, cmd_size, transfer_len, args)
+        }
+        .pack()
+    }
+
+    fn round_trip(buf: [u8; CMD_SIZE]) {
+        assert_eq!(PicobootCmd::parse(&buf).unwrap().pack(), buf);
+    }
+
+    #[test]
+    fn range_commands_match_the_wire_format() {
+        let args = PicobootRangeCmd::ser(0x10000100, 0x200);
+        #[rustfmt::skip]
+        let golden = [
+            0x0B, 0xD1, 0x1F, 0x43, 0x78, 0x56, 0x34, 0x12,
+            0x84, 0x08, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00,
+            0x00, 0x01, 0x00, 0x10, 0x00, 0x02, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(cmd(PicobootCmdId::Read, 8, 0x200, args), golden);
+        assert_eq!(
+            PicobootRangeCmd::parse(&args),
+            PicobootRangeCmd {
+                addr: 0x10000100,
+                size: 0x200
+            }
+        );
+        round_trip(golden);
+        let cmd = PicobootCmd::parse(&golden).unwrap();
+        assert!(cmd.is_data_in());
+        assert_eq!(
+            (cmd.token, cmd.cmd_id, cmd.cmd_size, cmd.transfer_len),
+            (0x12345678, 0x84, 8, 0x200)
+        );
+    }
+
+    #[test]
+    fn reboot_commands_match_the_wire_format() {
+        let args = PicobootRebootCmd::ser(0x20000001, PICO_STACK_POINTER, 500);
+        #[rustfmt::skip]
+        let golden = [
+            0x0B, 0xD1, 0x1F, 0x43, 0x78, 0x56, 0x34, 0x12,
+            0x02, 0x0C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x20, 0x00, 0x20, 0x04, 0x20,
+            0xF4, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(cmd(PicobootCmdId::Reboot, 12, 0, args), golden);
+        assert_eq!(PicobootRebootCmd::parse(&args).sp, PICO_STACK_POINTER);
+        round_trip(golden);
+
+        let args = PicobootReboot2Cmd::ser(0x102, 100, 0x10001000, 0x4000);
+        #[rustfmt::skip]
+        let golden = [
+            0x0B, 0xD1, 0x1F, 0x43, 0x78, 0x56, 0x34, 0x12,
+            0x0A, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x02, 0x01, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00,
+            0x00, 0x10, 0x00, 0x10, 0x00, 0x40, 0x00, 0x00,
+        ];
+        assert_eq!(cmd(PicobootCmdId::Reboot2, 0x10, 0, args), golden);
+        assert_eq!(PicobootReboot2Cmd::parse(&args).p1, 0x4000);
+        round_trip(golden);
+    }
+
+    #[test]
+    fn otp_commands_match_the_wire_format() {
+        let args = PicobootOtpCmd::ser(0x0F80, 0x40, true);
+        #[rustfmt::skip]
+        let golden = [
+            0x0B, 0xD1, 0x1F, 0x43, 0x78, 0x56, 0x34, 0x12,
+            0x8C, 0x05, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00,
+            0x80, 0x0F, 0x40, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(cmd(PicobootCmdId::OtpRead, 5, 0x80, args), golden);
+        round_trip(golden);
+        let parsed = PicobootOtpCmd::parse(&args);
+        assert_eq!(
+            (parsed.row, parsed.row_count, parsed.ecc),
+            (0x0F80, 0x40, true)
+        );
+
+        // OTP_WRITE sends its data out, so the top bit of the ID is clear
+        let write = PicobootCmd::parse(&cmd(
+            PicobootCmdId::OtpWrite,
+            5,
+            4,
+            PicobootOtpCmd::ser(1, 1, false),
+        ))
+        .unwrap();
+        assert_eq!(write.cmd_id, 0x0D);
+        assert!(!write.is_data_in());
+        assert_eq!(write.args[4], 0);
+    }
+
+    #[test]
+    fn get_info_commands_match_the_wire_format() {
+        let args = PicobootGetInfoCmd::ser(InfoType::PartitionTable, [0x8003, 0, 0xAABBCCDD]);
+        #[rustfmt::skip]
+        let golden = [
+            0x0B, 0xD1, 0x1F, 0x43, 0x78, 0x56, 0x34, 0x12,
+            0x8B, 0x10, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+            0x02, 0x00, 0x00, 0x00, 0x03, 0x80, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0xDD, 0xCC, 0xBB, 0xAA,
+        ];
+        assert_eq!(cmd(PicobootCmdId::GetInfo, 0x10, 0x100, args), golden);
+        round_trip(golden);
+        let parsed = PicobootGetInfoCmd::parse(&args);
+        assert_eq!(parsed.info_type, InfoType::PartitionTable as u8);
+        assert_eq!(parsed.params, [0x8003, 0, 0xAABBCCDD]);
+    }
+
+    #[test]
+    fn command_status_matches_the_wire_format() {
+        #[rustfmt::skip]
+        let golden = [
+            0x78, 0x56, 0x34, 0x12, 0x0A, 0x00, 0x00, 0x00,
+            0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let status = PicobootStatusCmd::parse(&golden);
+        assert_eq!(
+            status,
+            PicobootStatusCmd {
+                token: 0x12345678,
+                status_code: PicobootStatus::NotPermitted as u32,
+                cmd_id: 0x05,
+                in_progress: true,
+            }
+        );
+        assert_eq!(status.pack(), golden);
+        assert_eq!(
+            PicobootStatus::try_from(status.status_code),
+            Ok(PicobootStatus::NotPermitted)
+        );
+        assert_eq!(PicobootStatus::try_from(18), Err(()));
+    }
+
+    #[test]
+    fn commands_without_the_magic_are_refused() {
+        let mut buf = cmd(PicobootCmdId::ExitXip, 0, 0, [0; ARGS_SIZE]);
+        assert!(PicobootCmd::parse(&buf[..31]).is_none());
+        buf[3] = 0x44;
+        assert!(PicobootCmd::parse(&buf).is_none());
+        assert_eq!(PicobootCmdId::try_from(0x8C), Ok(PicobootCmdId::OtpRead));
+        assert_eq!(PicobootCmdId::try_from(0xE), Err(()));
+    }
+}
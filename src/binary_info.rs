@@ -0,0 +1,348 @@
+// Binary info, the table of tagged entries pico-sdk builds embed so tools can learn about an
+// image without running it, see https://github.com/raspberrypi/pico-sdk/tree/master/src/common/pico_binary_info
+//
+// Near the start of the image is a header of 5 words: a start marker, the start and end of an
+// array of pointers to the entries, a pointer to the table mapping data copied into SRAM back to
+// where it came from in flash, and an end marker
+
+use crate::image::{read_range, Segment};
+use crate::memmap::FlashAddr;
+use crate::picousb::TargetID;
+
+pub const MARKER_START: u32 = 0x7188EBF2;
+pub const MARKER_END: u32 = 0xE71AA390;
+pub const TAG_RASPBERRY_PI: u16 = 0x5052;
+
+pub const TYPE_ID_AND_INT: u16 = 5;
+pub const TYPE_ID_AND_STRING: u16 = 6;
+pub const TYPE_PINS_WITH_FUNC: u16 = 8;
+pub const TYPE_PINS_WITH_NAME: u16 = 9;
+pub const TYPE_PINS64_WITH_NAME: u16 = 14;
+
+pub const ID_RP_PROGRAM_NAME: u32 = 0x02031C86;
+pub const ID_RP_PROGRAM_VERSION_STRING: u32 = 0x11A9BC3A;
+pub const ID_RP_PROGRAM_BUILD_DATE_STRING: u32 = 0x9DA22254;
+pub const ID_RP_BINARY_END: u32 = 0x68F465DE;
+pub const ID_RP_PROGRAM_URL: u32 = 0x1856239A;
+pub const ID_RP_PROGRAM_DESCRIPTION: u32 = 0xB6A07C19;
+pub const ID_RP_PROGRAM_FEATURE: u32 = 0xA1F4B453;
+pub const ID_RP_PROGRAM_BUILD_ATTRIBUTE: u32 = 0x4275F0D3;
+pub const ID_RP_SDK_VERSION: u32 = 0x5360B3AB;
+pub const ID_RP_PICO_BOARD: u32 = 0xB63CFFBB;
+pub const ID_RP_BOOT2_NAME: u32 = 0x7F8882E1;
+
+const PINS_ENCODING_RANGE: u32 = 1;
+const PINS_ENCODING_MULTI: u32 = 2;
+// how far into an image the header is looked for, it follows the vector table
+pub const HEADER_SEARCH_SIZE: u32 = 1024;
+// bounds on what's read, so garbage that happens to look like a header isn't followed far
+const MAX_ENTRIES: u32 = 256;
+const MAX_MAPPINGS: u32 = 16;
+const MAX_STRING: u32 = 128;
+// the largest entry decoded, a 64 bit pin mask with a name
+const MAX_ENTRY_SIZE: u32 = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub entries_start: u32,
+    pub entries_end: u32,
+    pub mapping_table: u32,
+}
+
+// Data at `dest..dest_end` in SRAM at runtime, copied from `source` in flash
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub source: u32,
+    pub dest: u32,
+    pub dest_end: u32,
+}
+
+fn words(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    bytes
+        .chunks_exact(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+}
+
+// Looks for the header in the first bytes of an image
+pub fn find_header(bytes: &[u8]) -> Option<Header> {
+    let w: Vec<u32> = words(bytes).collect();
+    w.windows(5)
+        .find(|h| h[0] == MARKER_START && h[4] == MARKER_END)
+        .map(|h| Header {
+            entries_start: h[1],
+            entries_end: h[2],
+            mapping_table: h[3],
+        })
+}
+
+// Parses the mapping table, which ends with a zero source address
+pub fn parse_mappings(bytes: &[u8]) -> Vec<Mapping> {
+    let w: Vec<u32> = words(bytes).collect();
+    w.chunks_exact(3)
+        .take_while(|m| m[0] != 0)
+        .map(|m| Mapping {
+            source: m[0],
+            dest: m[1],
+            dest_end: m[2],
+        })
+        .collect()
+}
+
+// Where a runtime address can be read from in flash
+pub fn to_flash(mappings: &[Mapping], addr: u32) -> u32 {
+    mappings
+        .iter()
+        .find(|m| (m.dest..m.dest_end).contains(&addr))
+        .map_or(addr, |m| m.source + (addr - m.dest))
+}
+
+// An entry of the binary info, with strings already read. Entries of other types are skipped
+#[derive(Debug, Clone)]
+pub enum Entry {
+    IdAndInt { tag: u16, id: u32, value: u32 },
+    IdAndString { tag: u16, id: u32, value: String },
+    // the raw pin encoding, whose layout depends on the SDK version, see `decode_pins`
+    PinsWithFunc { tag: u16, encoding: u32 },
+    PinsWithName { tag: u16, mask: u64, label: String },
+}
+
+// Reads a NUL terminated string, going through the mapping table as for entries
+fn read_string<E>(
+    read: &mut impl FnMut(u32, u32) -> Result<Vec<u8>, E>,
+    mappings: &[Mapping],
+    ptr: u32,
+) -> Result<String, E> {
+    let addr = to_flash(mappings, ptr);
+    if FlashAddr::new(addr).is_none() {
+        return Ok(String::new());
+    }
+    let bytes = read(addr, MAX_STRING)?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+// Reads the entries of the image at `image_start`. `read` reads bytes from the image by address,
+// e.g. from a device or a loaded file. None if there's no binary info
+pub fn read_entries<E>(
+    mut read: impl FnMut(u32, u32) -> Result<Vec<u8>, E>,
+    image_start: u32,
+) -> Result<Option<Vec<Entry>>, E> {
+    let Some(header) = find_header(&read(image_start, HEADER_SEARCH_SIZE)?) else {
+        return Ok(None);
+    };
+    let size = header.entries_end.wrapping_sub(header.entries_start);
+    if FlashAddr::new(header.entries_start).is_none() || size > MAX_ENTRIES * 4 {
+        return Ok(None);
+    }
+    let mappings = if FlashAddr::new(header.mapping_table).is_some() {
+        parse_mappings(&read(header.mapping_table, MAX_MAPPINGS * 12)?)
+    } else {
+        vec![]
+    };
+
+    let mut entries = vec![];
+    for ptr in words(&read(header.entries_start, size)?) {
+        let addr = to_flash(&mappings, ptr);
+        if FlashAddr::new(addr).is_none() {
+            continue;
+        }
+        let w: Vec<u32> = words(&read(addr, MAX_ENTRY_SIZE)?).collect();
+        if w.len() < 4 {
+            continue;
+        }
+        let (kind, tag) = (w[0] as u16, (w[0] >> 16) as u16);
+        entries.push(match kind {
+            TYPE_ID_AND_INT => Entry::IdAndInt {
+                tag,
+                id: w[1],
+                value: w[2],
+            },
+            TYPE_ID_AND_STRING => Entry::IdAndString {
+                tag,
+                id: w[1],
+                value: read_string(&mut read, &mappings, w[2])?,
+            },
+            TYPE_PINS_WITH_FUNC => Entry::PinsWithFunc {
+                tag,
+                encoding: w[1],
+            },
+            TYPE_PINS_WITH_NAME => Entry::PinsWithName {
+                tag,
+                mask: w[1].into(),
+                label: read_string(&mut read, &mappings, w[2])?,
+            },
+            TYPE_PINS64_WITH_NAME => Entry::PinsWithName {
+                tag,
+                mask: w[1] as u64 | (w[2] as u64) << 32,
+                label: read_string(&mut read, &mappings, w[3])?,
+            },
+            _ => continue,
+        });
+    }
+    Ok(Some(entries))
+}
+
+// Finds the end of the program in flash from its BINARY_END entry, like `picotool save` does.
+// None if there's no binary info or it has no such entry
+pub fn binary_end<E>(
+    read: impl FnMut(u32, u32) -> Result<Vec<u8>, E>,
+    image_start: u32,
+) -> Result<Option<u32>, E> {
+    let Some(entries) = read_entries(read, image_start)? else {
+        return Ok(None);
+    };
+    Ok(entries.iter().find_map(|e| match *e {
+        Entry::IdAndInt {
+            tag: TAG_RASPBERRY_PI,
+            id: ID_RP_BINARY_END,
+            value,
+        } => Some(value).filter(|&end| end > image_start && FlashAddr::new(end - 1).is_some()),
+        _ => None,
+    }))
+}
+
+// Splits a pin encoding into the GPIO function and the pins using it. SDK 2 widened the fields
+// for the RP2350's extra pins and functions: 5 bits of function and 6 bit pins from bit 8,
+// where SDK 1 had 4 and 5 from bit 7. A list of pins ends early where a pin repeats
+pub fn decode_pins(encoding: u32, sdk1: bool) -> (u8, Vec<u8>) {
+    let (func_bits, pin_bits, first) = if sdk1 { (4, 5, 7) } else { (5, 6, 8) };
+    let func = (encoding >> 3) & ((1 << func_bits) - 1);
+    let pin = |i: u32| ((encoding >> (first + i * pin_bits)) & ((1 << pin_bits) - 1)) as u8;
+    let pins = match encoding & 7 {
+        PINS_ENCODING_RANGE => (pin(0)..=pin(1)).collect(),
+        PINS_ENCODING_MULTI => {
+            let mut pins: Vec<u8> = vec![];
+            for i in 0..(32 - first) / pin_bits {
+                if pins.last() == Some(&pin(i)) {
+                    break;
+                }
+                pins.push(pin(i));
+            }
+            pins
+        }
+        _ => vec![],
+    };
+    (func as u8, pins)
+}
+
+// Name of a GPIO function, as numbered in each chip's IO_BANK0 FUNCSEL
+pub fn function_name(target: TargetID, func: u8) -> &'static str {
+    let names: &[&str] = match target {
+        TargetID::Rp2040 => &[
+            "XIP", "SPI", "UART", "I2C", "PWM", "SIO", "PIO0", "PIO1", "GPCK", "USB",
+        ],
+        TargetID::Rp2350 => &[
+            "HSTX", "SPI", "UART", "I2C", "PWM", "SIO", "PIO0", "PIO1", "PIO2", "GPCK", "USB",
+            "UART_AUX",
+        ],
+    };
+    names.get(func as usize).copied().unwrap_or("unknown")
+}
+
+// What the binary info of a program says about it, like `picotool info` shows
+#[derive(Debug, Clone, Default)]
+pub struct ProgramInfo {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub build_date: Option<String>,
+    pub url: Option<String>,
+    pub description: Option<String>,
+    pub features: Vec<String>,
+    pub build_attributes: Vec<String>,
+    pub sdk_version: Option<String>,
+    pub board: Option<String>,
+    pub boot2: Option<String>,
+    pub binary_end: Option<u32>,
+    // what each pin is used for, by function or name, in pin order
+    pub pins: Vec<(u8, Vec<String>)>,
+}
+impl ProgramInfo {
+    // Reads the binary info of a firmware file instead of a device, from the image starting at
+    // its lowest flash address. None if it has none
+    pub fn from_segments(segments: &[Segment], target: TargetID) -> Option<Self> {
+        let start = segments
+            .iter()
+            .map(|s| s.addr)
+            .filter(|&a| FlashAddr::new(a).is_some())
+            .min()?;
+        let read = |addr, size| Ok::<_, std::convert::Infallible>(read_range(segments, addr, size));
+        let entries = read_entries(read, start).unwrap_or_else(|e| match e {})?;
+        Some(Self::from_entries(&entries, target))
+    }
+
+    pub fn from_entries(entries: &[Entry], target: TargetID) -> Self {
+        let mut info = ProgramInfo::default();
+        for e in entries {
+            match e {
+                Entry::IdAndString {
+                    tag: TAG_RASPBERRY_PI,
+                    id,
+                    value,
+                } => {
+                    let value = Some(value.clone());
+                    match *id {
+                        ID_RP_PROGRAM_NAME => info.name = value,
+                        ID_RP_PROGRAM_VERSION_STRING => info.version = value,
+                        ID_RP_PROGRAM_BUILD_DATE_STRING => info.build_date = value,
+                        ID_RP_PROGRAM_URL => info.url = value,
+                        ID_RP_PROGRAM_DESCRIPTION => info.description = value,
+                        ID_RP_PROGRAM_FEATURE => info.features.extend(value),
+                        ID_RP_PROGRAM_BUILD_ATTRIBUTE => info.build_attributes.extend(value),
+                        ID_RP_SDK_VERSION => info.sdk_version = value,
+                        ID_RP_PICO_BOARD => info.board = value,
+                        ID_RP_BOOT2_NAME => info.boot2 = value,
+                        _ => {}
+                    }
+                }
+                Entry::IdAndInt {
+                    tag: TAG_RASPBERRY_PI,
+                    id: ID_RP_BINARY_END,
+                    value,
+                } => info.binary_end = Some(*value),
+                _ => {}
+            }
+        }
+
+        // the pin encoding depends on the SDK, which is only known once all entries are seen
+        let sdk1 = info
+            .sdk_version
+            .as_ref()
+            .is_some_and(|v| v.starts_with("1."));
+        let mut pins: std::collections::BTreeMap<u8, Vec<String>> = Default::default();
+        for e in entries {
+            match e {
+                Entry::PinsWithFunc {
+                    tag: TAG_RASPBERRY_PI,
+                    encoding,
+                } => {
+                    let (func, list) = decode_pins(*encoding, sdk1);
+                    for pin in list {
+                        pins.entry(pin)
+                            .or_default()
+                            .push(function_name(target, func).to_string());
+                    }
+                }
+                // a label for several pins names each in turn, separated by '|'
+                Entry::PinsWithName {
+                    tag: TAG_RASPBERRY_PI,
+                    mask,
+                    label,
+                } => {
+                    let names: Vec<&str> = label.split('|').collect();
+                    let set = (0..64u8).filter(|p| mask & (1 << p) != 0);
+                    for (i, pin) in set.enumerate() {
+                        let name = if names.len() == 1 {
+                            names[0]
+                        } else {
+                            names.get(i).copied().unwrap_or("")
+                        };
+                        pins.entry(pin).or_default().push(name.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        info.pins = pins.into_iter().collect();
+        info
+    }
+}
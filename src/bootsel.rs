@@ -0,0 +1,154 @@
+// Getting a board that's running application firmware back into BOOTSEL without holding the
+// button, like `picotool -f`. pico-sdk's stdio_usb adds a vendor reset interface taking a
+// "reboot to BOOTSEL" control request, and can also reboot into BOOTSEL when its CDC port is set
+// to 1200 baud. See https://github.com/raspberrypi/pico-sdk/blob/master/src/rp2_common/pico_stdio_usb/reset_interface.c
+
+use rusb::{Device, UsbContext};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::picousb::{
+    target_for_pid, DeviceLocation, PicobootConnectionBuilder, PicobootError, Result, PICOBOOT_VID,
+    WAIT_POLL_INTERVAL,
+};
+
+const RESET_INTERFACE_CLASS: u8 = 0xFF;
+const RESET_INTERFACE_SUBCLASS: u8 = 0x00;
+const RESET_INTERFACE_PROTOCOL: u8 = 0x01;
+const RESET_REQUEST_BOOTSEL: u8 = 0x01;
+
+const CDC_COMM_CLASS: u8 = 0x02;
+const CDC_ACM_SUBCLASS: u8 = 0x02;
+const CDC_SET_LINE_CODING: u8 = 0x20;
+// 1200 baud, 1 stop bit, no parity, 8 data bits
+const LINE_CODING_1200: [u8; 7] = [0xB0, 0x04, 0x00, 0x00, 0x00, 0x00, 0x08];
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How a running board can be asked to reboot into BOOTSEL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResetMethod {
+    ResetInterface(u8),
+    BaudTouch(u8),
+}
+
+// The first interface on the device that can get it into BOOTSEL, preferring the reset
+// interface. Only Raspberry Pi devices get the 1200 baud touch, as other CDC devices take it as
+// a plain baud rate change
+fn reset_method<C: UsbContext>(device: &Device<C>) -> Option<ResetMethod> {
+    let config = device.active_config_descriptor().ok()?;
+    let vid = device.device_descriptor().ok()?.vendor_id();
+    let mut touch = None;
+    for iface in config.interfaces() {
+        for setting in iface.descriptors() {
+            let class = (
+                setting.class_code(),
+                setting.sub_class_code(),
+                setting.protocol_code(),
+            );
+            if class
+                == (
+                    RESET_INTERFACE_CLASS,
+                    RESET_INTERFACE_SUBCLASS,
+                    RESET_INTERFACE_PROTOCOL,
+                )
+            {
+                return Some(ResetMethod::ResetInterface(iface.number()));
+            }
+            if vid == PICOBOOT_VID
+                && (class.0, class.1) == (CDC_COMM_CLASS, CDC_ACM_SUBCLASS)
+                && touch.is_none()
+            {
+                touch = Some(ResetMethod::BaudTouch(iface.number()));
+            }
+        }
+    }
+    touch
+}
+
+// Finds a board running application firmware, matching the builder's serial number and location
+// if given, and asks it to reboot into BOOTSEL. Returns whether one was found. Only a port path
+// location can match, as the bus address changes when the board reboots
+pub fn reboot_to_bootsel<C: UsbContext>(ctx: &C, opts: &PicobootConnectionBuilder) -> Result<bool> {
+    for device in ctx.devices()?.iter() {
+        if opts.location.as_ref().is_some_and(|l| !l.matches(&device)) {
+            continue;
+        }
+        let Some(method) = reset_method(&device) else {
+            continue;
+        };
+        let Ok(handle) = device.open() else {
+            continue;
+        };
+        if let Some(serial) = &opts.serial {
+            let desc = device.device_descriptor()?;
+            if handle.read_serial_number_string_ascii(&desc).ok().as_ref() != Some(serial) {
+                continue;
+            }
+        }
+
+        // the board reboots as soon as it gets the request, so the request itself may well
+        // appear to fail
+        match method {
+            ResetMethod::ResetInterface(iface) => {
+                log::info!("asking the running board to reboot into BOOTSEL");
+                let _ = handle.claim_interface(iface);
+                let _ = handle.write_control(
+                    0b01000001,
+                    RESET_REQUEST_BOOTSEL,
+                    0,
+                    iface.into(),
+                    &[],
+                    CONTROL_TIMEOUT,
+                );
+            }
+            ResetMethod::BaudTouch(iface) => {
+                log::info!("setting the running board's serial port to 1200 baud to reboot it");
+                if handle.kernel_driver_active(iface).unwrap_or(false) {
+                    let _ = handle.detach_kernel_driver(iface);
+                }
+                let _ = handle.claim_interface(iface);
+                let _ = handle.write_control(
+                    0b00100001,
+                    CDC_SET_LINE_CODING,
+                    0,
+                    iface.into(),
+                    &LINE_CODING_1200,
+                    CONTROL_TIMEOUT,
+                );
+            }
+        }
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+// Waits for whatever the board rebooted into to show up at `location`, i.e. for a device that
+// isn't in BOOTSEL to enumerate there, e.g. after flashing so a serial console can be opened
+// right away. Returns whether one did within `timeout`, giving up early once `cancel` is set.
+// Firmware that doesn't use USB never shows up at all
+pub fn wait_for_application<C: UsbContext>(
+    ctx: &C,
+    location: &DeviceLocation,
+    timeout: Duration,
+    cancel: Option<&AtomicBool>,
+) -> Result<bool> {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        for device in ctx.devices()?.iter() {
+            let Ok(desc) = device.device_descriptor() else {
+                continue;
+            };
+            let bootsel =
+                desc.vendor_id() == PICOBOOT_VID && target_for_pid(desc.product_id()).is_some();
+            if !bootsel && location.matches(&device) {
+                return Ok(true);
+            }
+        }
+        if cancel.is_some_and(|c| c.load(Ordering::SeqCst)) {
+            return Err(PicobootError::Interrupted);
+        }
+        std::thread::sleep(WAIT_POLL_INTERVAL);
+    }
+    Ok(false)
+}
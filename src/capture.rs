@@ -0,0 +1,120 @@
+// Writing USB transfers as a pcapng of usbmon events, for `Trace::to_pcapng`, which Wireshark
+// and `picoboot decode` both read. The decoding itself is in the CLI, see `cli::capture`
+
+// the pcap link type of usbmon captures
+pub(crate) const LINKTYPE_USB_LINUX: u32 = 189;
+
+pub(crate) const PCAPNG_SHB: u32 = 0x0A0D0D0A;
+pub(crate) const PCAPNG_BYTE_ORDER: u32 = 0x1A2B3C4D;
+pub(crate) const PCAPNG_IDB: u32 = 1;
+pub(crate) const PCAPNG_EPB: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    Control {
+        setup: [u8; 8],
+    },
+    Bulk,
+    // interrupt and isochronous, which PICOBOOT doesn't use, so only read from captures
+    #[cfg(feature = "cli")]
+    Other,
+}
+
+// One completed USB transfer, put together from usbmon's submit and callback events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    // when it was submitted and when it completed, in microseconds since whatever the capture
+    // counts from
+    pub submit_us: u64,
+    pub time_us: u64,
+    pub bus: u16,
+    pub device: u8,
+    // the endpoint address, with 0x80 set for IN
+    pub endpoint: u8,
+    pub kind: TransferKind,
+    // bytes actually transferred, of which `data` may hold fewer if the capture cut them short
+    pub len: usize,
+    pub data: Vec<u8>,
+    // 0 on success, otherwise a negative errno, e.g. -32 (EPIPE) for a stall
+    pub status: i32,
+}
+impl Transfer {
+    pub fn is_in(&self) -> bool {
+        self.endpoint & 0x80 != 0
+    }
+}
+
+// A usbmon pcap packet for one event of `t`, the submit if `submit` is set or else the callback
+pub(crate) fn usbmon_packet(t: &Transfer, id: u64, submit: bool) -> Vec<u8> {
+    let (xfer_type, setup) = match t.kind {
+        TransferKind::Control { setup } => (2u8, Some(setup)),
+        TransferKind::Bulk => (3, None),
+        #[cfg(feature = "cli")]
+        TransferKind::Other => (1, None),
+    };
+    // OUT data goes with the submit and IN data with the callback
+    let data: &[u8] = if submit != t.is_in() { &t.data } else { &[] };
+    let (kind, time_us, status) = match submit {
+        true => (b'S', t.submit_us, -115),
+        false => (b'C', t.time_us, t.status),
+    };
+    let mut p = Vec::with_capacity(48 + data.len());
+    p.extend_from_slice(&id.to_le_bytes());
+    p.extend_from_slice(&[kind, xfer_type, t.endpoint, t.device]);
+    p.extend_from_slice(&t.bus.to_le_bytes());
+    let setup_flag = match (submit, setup) {
+        (true, Some(_)) => 0,
+        _ => b'-',
+    };
+    let data_flag = if data.is_empty() { b'<' } else { 0 };
+    p.extend_from_slice(&[setup_flag, data_flag]);
+    p.extend_from_slice(&(time_us / 1_000_000).to_le_bytes());
+    p.extend_from_slice(&((time_us % 1_000_000) as u32).to_le_bytes());
+    p.extend_from_slice(&status.to_le_bytes());
+    p.extend_from_slice(&(t.len as u32).to_le_bytes());
+    p.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    p.extend_from_slice(&setup.filter(|_| submit).unwrap_or_default());
+    p.extend_from_slice(data);
+    p
+}
+
+fn pcapng_block(out: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    let padded = body.len().next_multiple_of(4);
+    let len = (padded + 12) as u32;
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(body);
+    out.resize(out.len() + padded - body.len(), 0);
+    out.extend_from_slice(&len.to_le_bytes());
+}
+
+// Writes transfers as a pcapng of usbmon events, which Wireshark and `picoboot decode` both read
+pub fn to_pcapng(transfers: &[Transfer]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut shb = vec![];
+    shb.extend_from_slice(&PCAPNG_BYTE_ORDER.to_le_bytes());
+    // version 1.0, and an unknown section length
+    shb.extend_from_slice(&[1, 0, 0, 0]);
+    shb.extend_from_slice(&(-1i64).to_le_bytes());
+    pcapng_block(&mut out, PCAPNG_SHB, &shb);
+    let mut idb = vec![];
+    idb.extend_from_slice(&(LINKTYPE_USB_LINUX as u16).to_le_bytes());
+    idb.extend_from_slice(&[0, 0]);
+    idb.extend_from_slice(&0u32.to_le_bytes());
+    pcapng_block(&mut out, PCAPNG_IDB, &idb);
+    for (i, t) in transfers.iter().enumerate() {
+        for submit in [true, false] {
+            let packet = usbmon_packet(t, i as u64 + 1, submit);
+            let time_us = if submit { t.submit_us } else { t.time_us };
+            let mut epb = vec![];
+            epb.extend_from_slice(&0u32.to_le_bytes());
+            epb.extend_from_slice(&((time_us >> 32) as u32).to_le_bytes());
+            epb.extend_from_slice(&(time_us as u32).to_le_bytes());
+            epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            epb.extend_from_slice(&packet);
+            pcapng_block(&mut out, PCAPNG_EPB, &epb);
+        }
+    }
+    out
+}
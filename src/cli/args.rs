@@ -0,0 +1,105 @@
+// Parsers for option values clap can't read by itself, and addresses checked against the memory
+// map. A bad value is a usage error, reported by clap with the option it was given for
+
+use super::output::CliResult;
+use crate::picousb::{self, PICO_SECTOR_SIZE};
+use crate::{image, otp, uf2, FlashAddr, MemAddr, SramAddr};
+
+pub(super) const PAST_FLASH: &str = "runs past end of flash";
+
+// An address known to be in flash, e.g. from the flash half of the inputs
+pub(super) fn flash_addr(addr: u32) -> CliResult<FlashAddr> {
+    FlashAddr::new(addr).ok_or_else(|| format!("{:#X} is not a flash address", addr))
+}
+
+// An address anywhere READ reaches, e.g. from --region
+pub(super) fn mem_addr(addr: u32) -> CliResult<MemAddr> {
+    MemAddr::new(addr).ok_or_else(|| format!("{:#X} is not in ROM, flash or SRAM", addr))
+}
+
+// A number or address, in decimal or with 0x in hex
+pub(super) fn addr(s: &str) -> Result<u32, String> {
+    image::parse_addr(s).ok_or_else(|| format!("expected a number or 0x address: {}", s))
+}
+
+pub(super) fn sram_addr(s: &str) -> Result<SramAddr, String> {
+    let addr = addr(s)?;
+    SramAddr::new(addr).ok_or_else(|| format!("{:#X} is not an SRAM address", addr))
+}
+
+// A region given as ADDR+LEN
+pub(super) fn range(s: &str) -> Result<(u32, u32), String> {
+    s.split_once('+')
+        .and_then(|(a, l)| Some((image::parse_addr(a)?, image::parse_addr(l)?)))
+        .ok_or_else(|| format!("expected ADDR+LEN: {}", s))
+}
+
+// A range of whole sectors
+pub(super) fn region(s: &str) -> Result<(u32, u32), String> {
+    let (addr, len) = range(s)?;
+    if !addr.is_multiple_of(PICO_SECTOR_SIZE) || !len.is_multiple_of(PICO_SECTOR_SIZE) || len == 0 {
+        return Err(format!(
+            "region must be a non-empty, whole number of sectors: {:#X}+{:#X}",
+            addr, len
+        ));
+    }
+    Ok((addr, len))
+}
+
+// A size in bytes with an optional K or M suffix, e.g. 64K
+pub(super) fn size(s: &str) -> Result<u32, String> {
+    let (num, mult) = match s.strip_suffix(['K', 'k']) {
+        Some(n) => (n, 1024),
+        None => match s.strip_suffix(['M', 'm']) {
+            Some(n) => (n, 1024 * 1024),
+            None => (s, 1),
+        },
+    };
+    image::parse_addr(num)
+        .and_then(|n| n.checked_mul(mult))
+        .ok_or_else(|| format!("expected a size like 4096, 0x1000 or 4K: {}", s))
+}
+
+// A USB VID or PID
+pub(super) fn usb_id(s: &str) -> Result<u16, String> {
+    image::parse_addr(s)
+        .and_then(|n| u16::try_from(n).ok())
+        .ok_or_else(|| format!("expected a 16 bit ID like 0x2E8A: {}", s))
+}
+
+// An OTP row number
+pub(super) fn row(s: &str) -> Result<u16, String> {
+    image::parse_addr(s)
+        .and_then(|n| u16::try_from(n).ok())
+        .ok_or_else(|| format!("expected a row number like 0x100: {}", s))
+}
+
+// OTP rows by name or number, see `otp::OtpSelector`
+pub(super) fn otp_rows(s: &str) -> Result<otp::OtpSelector, String> {
+    s.parse().map_err(|e: otp::OtpError| e.to_string())
+}
+
+pub(super) fn family(s: &str) -> Result<u32, String> {
+    uf2::family_id_from_str(s).ok_or_else(|| format!("unknown family: {}", s))
+}
+
+pub(super) fn arch(s: &str) -> Result<picousb::RebootArch, String> {
+    match s {
+        "arm" => Ok(picousb::RebootArch::Arm),
+        "riscv" => Ok(picousb::RebootArch::RiscV),
+        _ => Err(format!("unknown architecture: {}, use arm or riscv", s)),
+    }
+}
+
+pub(super) fn chip(s: &str) -> Result<picousb::TargetID, String> {
+    match s {
+        "rp2040" => Ok(picousb::TargetID::Rp2040),
+        "rp2350" => Ok(picousb::TargetID::Rp2350),
+        _ => Err(format!("unknown chip: {}, use rp2040 or rp2350", s)),
+    }
+}
+
+pub(super) fn location(s: &str) -> Result<picousb::DeviceLocation, String> {
+    s.parse()
+        .map_err(|_| format!("expected BUS:ADDRESS or a port path like 1-4.2: {}", s))
+}
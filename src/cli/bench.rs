@@ -0,0 +1,324 @@
+// Subcommands exercising flash over a scratch region, to measure and qualify devices
+
+use super::args::{self, flash_addr, PAST_FLASH};
+use super::globals::Globals;
+use super::output::{failure, report, CliResult, ErrorContext, ExitCode};
+use super::signal;
+use crate::picousb::{self, PicobootConnection, PICO_PAGE_SIZE, PICO_SECTOR_SIZE};
+use crate::transport::Transport;
+use crate::{json, FlashAddr};
+use std::time::Duration;
+
+// Throughput in MB/s, of 10^6 bytes
+fn mb_per_sec(bytes: u32, time: Duration) -> f64 {
+    bytes as f64 / 1e6 / time.as_secs_f64().max(1e-9)
+}
+
+#[derive(clap::Args)]
+pub(super) struct BenchArgs {
+    #[arg(
+        long,
+        value_name = "ADDR+LEN",
+        value_parser = args::region,
+        required_unless_present = "read_only",
+        help = "the scratch region to use"
+    )]
+    region: Option<(u32, u32)>,
+    // a page, a bulk chunk and a read command of `flash_read_all`
+    #[arg(
+        long,
+        value_name = "LIST",
+        value_parser = page_multiple,
+        value_delimiter = ',',
+        default_value = "256,4K,64K",
+        help = "bytes per transfer to try, in whole pages"
+    )]
+    sizes: Vec<u32>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "runs of each to average"
+    )]
+    repeat: u32,
+    #[arg(
+        long,
+        help = "only read, leaving flash alone (the first 256K unless --region)"
+    )]
+    read_only: bool,
+}
+
+fn page_multiple(s: &str) -> Result<u32, String> {
+    match args::size(s)? {
+        n if n != 0 && n % 256 == 0 => Ok(n),
+        _ => Err(format!("expected whole pages: {}", s)),
+    }
+}
+
+// Measures how fast the device erases, writes and reads flash, for tuning transfer sizes and for
+// finding slow USB paths (hubs, cables, VMs). The region is erased whole, then written and read
+// back whole with each transfer size in turn, each timed over `--repeat` runs and averaged
+pub(super) fn bench(globals: &Globals, args: &BenchArgs) -> CliResult {
+    signal::install_handler();
+
+    let (repeat, read_only) = (args.repeat, args.read_only);
+    let (addr, len) = args
+        .region
+        .unwrap_or((picousb::PICO_FLASH_START, 256 * 1024));
+    let flash_start = flash_addr(addr)?;
+
+    let mut conn = globals.open()?;
+    let mut conn = conn
+        .exclusive_access_guard(true)
+        .context("failed to claim access")?
+        .reset_on_drop(true);
+    conn.exit_xip().context("failed to exit from xip mode")?;
+    conn.check_flash_range(flash_start, len)
+        .context("the region doesn't fit in flash")?;
+    say!("{} bytes at {:#X}, average of {} runs", len, addr, repeat);
+
+    // the same data every run, so differences are down to the transfer size
+    let mut data = vec![0; len as usize];
+    stress_pattern(1, &mut data);
+    let mut erase_time = Duration::ZERO;
+    if !read_only {
+        for _ in 0..repeat {
+            if signal::interrupted() {
+                return Err("interrupted".into());
+            }
+            let start = std::time::Instant::now();
+            conn.flash_erase(flash_start, len)
+                .context("failed to erase flash")?;
+            erase_time += start.elapsed();
+        }
+        let erase_time = erase_time / repeat;
+        let sectors = len / PICO_SECTOR_SIZE;
+        say!(
+            "erase: {:.1} ms, {:.1} ms per sector, {:.3} MB/s",
+            erase_time.as_secs_f64() * 1000.0,
+            erase_time.as_secs_f64() * 1000.0 / sectors as f64,
+            mb_per_sec(len, erase_time)
+        );
+        report("erase_ms", erase_time.as_millis() as u64);
+        report(
+            "erase_mb_per_sec",
+            json::Value::Number(format!("{:.3}", mb_per_sec(len, erase_time))),
+        );
+    }
+
+    say!(
+        "{:>10} {:>12} {:>12}",
+        "transfer",
+        "write MB/s",
+        "read MB/s"
+    );
+    let mut results = vec![];
+    for &size in &args.sizes {
+        let mut write_time = Duration::ZERO;
+        let mut read_time = Duration::ZERO;
+        for _ in 0..repeat {
+            if signal::interrupted() {
+                return Err("interrupted".into());
+            }
+            if !read_only {
+                // written flash has to be erased again first, which isn't part of the write
+                conn.flash_erase(flash_start, len)
+                    .context("failed to erase flash")?;
+                let start = std::time::Instant::now();
+                for (i, chunk) in data.chunks(size as usize).enumerate() {
+                    let at = flash_start.checked_add(i as u32 * size).ok_or(PAST_FLASH)?;
+                    conn.flash_write(at, chunk.to_vec())
+                        .context("failed to write flash")?;
+                }
+                write_time += start.elapsed();
+            }
+            let start = std::time::Instant::now();
+            for offset in (0..len).step_by(size as usize) {
+                let at = flash_start.checked_add(offset).ok_or(PAST_FLASH)?;
+                conn.flash_read(at, size.min(len - offset))
+                    .context("failed to read flash")?;
+            }
+            read_time += start.elapsed();
+        }
+        let write = (!read_only).then(|| mb_per_sec(len, write_time / repeat));
+        let read = mb_per_sec(len, read_time / repeat);
+        let write_text = write.map_or("-".to_string(), |w| format!("{:.3}", w));
+        say!("{:>10} {:>12} {:>12.3}", size, write_text, read);
+        let number = |n: f64| json::Value::Number(format!("{:.3}", n));
+        results.push(json::Value::Object(vec![
+            ("size".to_string(), size.into()),
+            ("write_mb_per_sec".to_string(), write.map(number).into()),
+            ("read_mb_per_sec".to_string(), number(read)),
+        ]));
+    }
+    report("transfers", results);
+    report("usb_retries", conn.diagnostics().usb_retries);
+    Ok(())
+}
+
+// Fills `buf` with the test pattern for one stress cycle: alternating bit patterns on even
+// cycles and pseudo random data (seeded by the cycle number) on odd ones
+fn stress_pattern(cycle: u32, buf: &mut [u8]) {
+    let mut state = cycle.wrapping_mul(0x9E3779B9) | 1;
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = if cycle.is_multiple_of(2) {
+            if (i + cycle as usize / 2).is_multiple_of(2) {
+                0x55
+            } else {
+                0xAA
+            }
+        } else {
+            // xorshift32
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        };
+    }
+}
+
+#[derive(clap::Args)]
+pub(super) struct StressArgs {
+    #[arg(
+        long,
+        value_name = "ADDR+LEN",
+        value_parser = args::region,
+        help = "the scratch region to use"
+    )]
+    region: (u32, u32),
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 100,
+        help = "number of cycles to run, 0 for no limit"
+    )]
+    cycles: u32,
+    #[arg(long, value_name = "SECS", help = "stop after this long")]
+    duration: Option<u64>,
+    #[arg(long, help = "count USB errors as failed cycles instead of stopping")]
+    keep_going: bool,
+}
+
+// Repeatedly erases, writes and verifies a scratch region, to qualify flash parts, cables and
+// fixtures. Prints statistics for the whole run at the end, or when interrupted
+pub(super) fn stress(globals: &Globals, args: &StressArgs) -> CliResult {
+    signal::install_handler();
+
+    let (cycles, keep_going) = (args.cycles, args.keep_going);
+    let duration = args.duration.map(Duration::from_secs);
+    let (addr, len) = args.region;
+    let flash_start = flash_addr(addr)?;
+
+    let mut conn = globals.open()?;
+    let mut conn = conn
+        .exclusive_access_guard(true)
+        .context("failed to claim access")?
+        .reset_on_drop(true);
+    conn.exit_xip().context("failed to exit from xip mode")?;
+
+    let run_start = std::time::Instant::now();
+    let mut data = vec![0; len as usize];
+    let mut errors = 0;
+    let mut usb_errors = 0;
+    let mut times = vec![];
+    let mut cycle = 0;
+    // with --cycles 0 it runs until --duration is up or Ctrl-C is pressed, for soak tests
+    while cycles == 0 || cycle < cycles {
+        if duration.is_some_and(|d| run_start.elapsed() >= d) {
+            break;
+        }
+        if signal::interrupted() {
+            say!("interrupted, stopping");
+            break;
+        }
+        let start = std::time::Instant::now();
+        stress_pattern(cycle, &mut data);
+        match stress_cycle(&mut conn, flash_start, &data) {
+            Ok(0) => {}
+            Ok(bad) => {
+                errors += 1;
+                say!("cycle {}: {} bytes mismatched", cycle, bad);
+            }
+            Err(picousb::PicobootError::Interrupted) => {
+                say!("interrupted, stopping");
+                break;
+            }
+            // the failed command has reset the interface, so the next cycle can go ahead
+            Err(e) if keep_going => {
+                usb_errors += 1;
+                say!("cycle {}: {}", cycle, e);
+                conn.exit_xip().context("failed to exit from xip mode")?;
+            }
+            Err(e) => return Err(e).context(&format!("cycle {} failed", cycle)),
+        }
+        times.push(start.elapsed());
+        cycle += 1;
+    }
+
+    if times.is_empty() {
+        return Ok(());
+    }
+    let n = times.len() as u32;
+    let total: Duration = times.iter().sum();
+    // compare the first and last tenth of the run, to spot flash slowing with wear
+    let tenth = times.len().div_ceil(10);
+    let first: Duration = times[..tenth].iter().sum::<Duration>() / tenth as u32;
+    let last: Duration = times[times.len() - tenth..].iter().sum::<Duration>() / tenth as u32;
+    let diagnostics = conn.diagnostics();
+    let rate = |count: u32| count as f64 * 100.0 / n as f64;
+    report("cycles", n);
+    report("bad_cycles", errors);
+    report("failed_cycles", usb_errors);
+    report("read_retries", diagnostics.retries);
+    report("usb_retries", diagnostics.usb_retries);
+    report("timeouts", diagnostics.timeouts);
+    report("stalls", diagnostics.stalls);
+    say!("cycles run: {} in {:?}", n, run_start.elapsed());
+    say!("cycles with errors: {} ({:.2}%)", errors, rate(errors));
+    if keep_going {
+        say!("cycles failed: {} ({:.2}%)", usb_errors, rate(usb_errors));
+    }
+    say!("read retries: {}", diagnostics.retries);
+    say!(
+        "USB retries: {}, timeouts: {}, stalls: {}",
+        diagnostics.usb_retries,
+        diagnostics.timeouts,
+        diagnostics.stalls
+    );
+    say!(
+        "cycle time: min {:?}, avg {:?}, max {:?}",
+        times.iter().min().unwrap(),
+        total / n,
+        times.iter().max().unwrap()
+    );
+    say!("cycle time drift: {:?} at start, {:?} at end", first, last);
+    if errors != 0 || usb_errors != 0 {
+        return Err(failure(
+            ExitCode::VerifyFailed,
+            format!(
+                "stress test found {} bad and {} failed cycles",
+                errors, usb_errors
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// One stress cycle: erases the region `data` goes in, writes it and reads it back, returning how
+// many bytes came back wrong
+fn stress_cycle<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    addr: FlashAddr,
+    data: &[u8],
+) -> picousb::Result<usize> {
+    conn.flash_erase(addr, data.len() as u32)?;
+    for (i, page) in data.chunks(PICO_PAGE_SIZE).enumerate() {
+        let at = addr
+            .checked_add((i * PICO_PAGE_SIZE) as u32)
+            .ok_or(picousb::PicobootError::InvalidArgument(PAST_FLASH))?;
+        conn.flash_write(at, page.to_vec())?;
+    }
+    let read = conn.flash_read_all(addr, data.len() as u32, 3)?;
+    Ok(data.iter().zip(&read).filter(|(a, b)| a != b).count())
+}
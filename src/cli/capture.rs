@@ -0,0 +1,771 @@
+// Decoding captured USB traffic back into PICOBOOT commands, data, acks and command status, to
+// debug interoperability problems with picotool and other hosts. Captures come from the Linux
+// usbmon interface, either as its text output (cat /sys/kernel/debug/usb/usbmon/1u) or as pcap
+// or pcapng files saved by Wireshark or tcpdump. USBPcap captures from Windows aren't supported.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::capture::{
+    Transfer, TransferKind, LINKTYPE_USB_LINUX, PCAPNG_BYTE_ORDER, PCAPNG_EPB, PCAPNG_IDB,
+    PCAPNG_SHB,
+};
+use crate::protocol::{
+    PicobootCmd, PicobootCmdId, PicobootGetInfoCmd, PicobootOtpCmd, PicobootRangeCmd,
+    PicobootReboot2Cmd, PicobootRebootCmd, PicobootStatus, PicobootStatusCmd, ARGS_SIZE,
+    STATUS_SIZE,
+};
+
+// the usbmon link type with the longer header of the memory mapped interface
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+
+const PCAP_MAGIC_US: u32 = 0xA1B2C3D4;
+const PCAP_MAGIC_NS: u32 = 0xA1B23C4D;
+const PCAPNG_SPB: u32 = 3;
+
+// the PICOBOOT class requests, see `RusbTransport`
+const REQUEST_TYPE_STATUS: u8 = 0xC1;
+const REQUEST_STATUS: u8 = 0x42;
+const REQUEST_TYPE_RESET: u8 = 0x41;
+const REQUEST_RESET: u8 = 0x41;
+// CLEAR_FEATURE(ENDPOINT_HALT), as sent to clear a stall
+const REQUEST_TYPE_CLEAR_HALT: u8 = 0x02;
+const REQUEST_CLEAR_FEATURE: u8 = 0x01;
+
+#[derive(Debug)]
+pub enum CaptureError {
+    // the file ends in the middle of a record
+    Truncated,
+    // a pcap of something other than usbmon, e.g. USBPcap or Ethernet
+    LinkType(u32),
+    // a line of usbmon text that doesn't parse, numbered from 1
+    BadLine(usize),
+    // a packet timestamp too large to count in microseconds
+    BadTimestamp,
+    // not a pcap, pcapng or usbmon text capture
+    UnknownFormat,
+}
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaptureError::Truncated => write!(f, "capture is truncated"),
+            CaptureError::LinkType(t) => {
+                write!(f, "capture has link type {}, only usbmon is supported", t)
+            }
+            CaptureError::BadLine(n) => write!(f, "line {} isn't usbmon text", n),
+            CaptureError::BadTimestamp => write!(f, "capture has a packet with a bad timestamp"),
+            CaptureError::UnknownFormat => {
+                write!(f, "not a pcap, pcapng or usbmon text capture")
+            }
+        }
+    }
+}
+impl std::error::Error for CaptureError {}
+
+// A usbmon event, of which a submit ('S') and callback ('C') or error ('E') make a transfer
+struct UsbmonEvent {
+    id: u64,
+    kind: u8,
+    time_us: u64,
+    bus: u16,
+    device: u8,
+    endpoint: u8,
+    transfer: TransferKind,
+    status: i32,
+    len: usize,
+    data: Vec<u8>,
+}
+
+// Pairs each callback with its submit, which holds the setup packet and any OUT data
+fn pair_events(events: Vec<UsbmonEvent>) -> Vec<Transfer> {
+    let mut submitted: HashMap<u64, UsbmonEvent> = HashMap::new();
+    let mut transfers = vec![];
+    for e in events {
+        if e.kind == b'S' {
+            submitted.insert(e.id, e);
+            continue;
+        }
+        let submit = submitted.remove(&e.id);
+        let is_in = e.endpoint & 0x80 != 0;
+        let submit_us = submit.as_ref().map_or(e.time_us, |s| s.time_us);
+        let (kind, data) = match submit {
+            Some(s) if is_in => (s.transfer, e.data),
+            Some(s) => (s.transfer, s.data),
+            // submitted before the capture started
+            None if is_in => (e.transfer, e.data),
+            None => (e.transfer, vec![]),
+        };
+        transfers.push(Transfer {
+            submit_us,
+            time_us: e.time_us,
+            bus: e.bus,
+            device: e.device,
+            endpoint: e.endpoint,
+            kind,
+            len: e.len,
+            data,
+            status: e.status,
+        });
+    }
+    transfers
+}
+
+// Reads the transfers in a capture, telling its format from its first bytes
+pub fn parse(bytes: &[u8]) -> Result<Vec<Transfer>, CaptureError> {
+    let magic = |le: bool| {
+        let b = bytes.get(0..4).map(|b| [b[0], b[1], b[2], b[3]]);
+        b.map(|b| match le {
+            true => u32::from_le_bytes(b),
+            false => u32::from_be_bytes(b),
+        })
+    };
+    let events = match (magic(true), magic(false)) {
+        (Some(PCAPNG_SHB), _) => parse_pcapng(bytes)?,
+        (Some(PCAP_MAGIC_US | PCAP_MAGIC_NS), _) => parse_pcap(bytes, true)?,
+        (_, Some(PCAP_MAGIC_US | PCAP_MAGIC_NS)) => parse_pcap(bytes, false)?,
+        _ => {
+            let text = std::str::from_utf8(bytes).map_err(|_| CaptureError::UnknownFormat)?;
+            parse_text(text)?
+        }
+    };
+    Ok(pair_events(events))
+}
+
+// Reads integers in the byte order of the capture
+struct Reader<'a> {
+    bytes: &'a [u8],
+    le: bool,
+}
+impl Reader<'_> {
+    fn get<const N: usize>(&self, at: usize) -> Result<[u8; N], CaptureError> {
+        let b = self.bytes.get(at..at + N).ok_or(CaptureError::Truncated)?;
+        let mut out = [0u8; N];
+        out.copy_from_slice(b);
+        if !self.le {
+            out.reverse();
+        }
+        Ok(out)
+    }
+
+    fn u16(&self, at: usize) -> Result<u16, CaptureError> {
+        Ok(u16::from_le_bytes(self.get(at)?))
+    }
+
+    fn u32(&self, at: usize) -> Result<u32, CaptureError> {
+        Ok(u32::from_le_bytes(self.get(at)?))
+    }
+
+    fn u64(&self, at: usize) -> Result<u64, CaptureError> {
+        Ok(u64::from_le_bytes(self.get(at)?))
+    }
+}
+
+// The usbmon header in front of each packet of a pcap, in the byte order of the capturing
+// machine, which is that of the file
+fn parse_usbmon_packet(
+    packet: &[u8],
+    le: bool,
+    link_type: u32,
+) -> Result<UsbmonEvent, CaptureError> {
+    let header_len = match link_type {
+        LINKTYPE_USB_LINUX => 48,
+        LINKTYPE_USB_LINUX_MMAPPED => 64,
+        t => return Err(CaptureError::LinkType(t)),
+    };
+    if packet.len() < header_len {
+        return Err(CaptureError::Truncated);
+    }
+    let r = Reader { bytes: packet, le };
+    let id = r.u64(0)?;
+    let (kind, xfer_type, endpoint, device) = (packet[8], packet[9], packet[10], packet[11]);
+    let bus = r.u16(12)?;
+    let has_setup = packet[14] == 0;
+    let secs = r.u64(16)?;
+    let usecs = r.u32(24)?;
+    let status = r.u32(28)? as i32;
+    let len = r.u32(32)? as usize;
+    let mut setup = [0u8; 8];
+    setup.copy_from_slice(&packet[40..48]);
+    let data = packet[header_len..].to_vec();
+    let time_us = secs
+        .checked_mul(1_000_000)
+        .and_then(|us| us.checked_add(usecs as u64))
+        .ok_or(CaptureError::BadTimestamp)?;
+    let transfer = match xfer_type {
+        2 if has_setup => TransferKind::Control { setup },
+        2 => TransferKind::Control { setup: [0; 8] },
+        3 => TransferKind::Bulk,
+        _ => TransferKind::Other,
+    };
+    Ok(UsbmonEvent {
+        id,
+        kind,
+        time_us,
+        bus,
+        device,
+        endpoint,
+        transfer,
+        status,
+        len,
+        data,
+    })
+}
+
+fn parse_pcap(bytes: &[u8], le: bool) -> Result<Vec<UsbmonEvent>, CaptureError> {
+    let r = Reader { bytes, le };
+    let link_type = r.u32(20)?;
+    let mut events = vec![];
+    let mut at = 24;
+    while at < bytes.len() {
+        let caplen = r.u32(at + 8)? as usize;
+        let end = (at + 16)
+            .checked_add(caplen)
+            .ok_or(CaptureError::Truncated)?;
+        let packet = bytes.get(at + 16..end).ok_or(CaptureError::Truncated)?;
+        events.push(parse_usbmon_packet(packet, le, link_type)?);
+        at += 16 + caplen;
+    }
+    Ok(events)
+}
+
+fn parse_pcapng(bytes: &[u8]) -> Result<Vec<UsbmonEvent>, CaptureError> {
+    let mut le = true;
+    let mut link_types = vec![];
+    let mut events = vec![];
+    let mut at = 0;
+    while at < bytes.len() {
+        let mut r = Reader { bytes, le };
+        if r.u32(at)? == PCAPNG_SHB {
+            // each section gives its own byte order, and its own interfaces
+            le = r.u32(at + 8)? == PCAPNG_BYTE_ORDER;
+            r.le = le;
+            link_types.clear();
+        }
+        let block_type = r.u32(at)?;
+        let block_len = r.u32(at + 4)? as usize;
+        // the smallest block of each type: its header, fixed fields and trailing length
+        let min_len = match block_type {
+            PCAPNG_EPB => 32,
+            PCAPNG_SPB => 16,
+            _ => 12,
+        };
+        let end = at.checked_add(block_len).ok_or(CaptureError::Truncated)?;
+        if block_len < min_len || end > bytes.len() {
+            return Err(CaptureError::Truncated);
+        }
+        let packet = match block_type {
+            PCAPNG_IDB => {
+                link_types.push(r.u16(at + 8)? as u32);
+                None
+            }
+            PCAPNG_EPB => {
+                let iface = r.u32(at + 8)? as usize;
+                let caplen = r.u32(at + 20)? as usize;
+                Some((iface, at + 28, caplen))
+            }
+            // simple packet blocks always belong to the first interface
+            PCAPNG_SPB => Some((0, at + 12, r.u32(at + 8)? as usize)),
+            _ => None,
+        };
+        if let Some((iface, start, caplen)) = packet {
+            let link_type = *link_types.get(iface).ok_or(CaptureError::Truncated)?;
+            // the packet data ends before the trailing block length
+            let caplen = caplen.min(end - 4 - start);
+            let packet = bytes
+                .get(start..start + caplen)
+                .ok_or(CaptureError::Truncated)?;
+            events.push(parse_usbmon_packet(packet, le, link_type)?);
+        }
+        at = end;
+    }
+    Ok(events)
+}
+
+// usbmon text, one event per line, e.g. for a command going out:
+// ffff8c3a0c5d2cc0 3575914555 S Bo:1:005:2 -115 32 = 0bd11f43 01000000 ...
+// See Documentation/usb/usbmon.rst in the kernel for the whole format
+fn parse_text(text: &str) -> Result<Vec<UsbmonEvent>, CaptureError> {
+    let mut events = vec![];
+    for (n, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = parse_text_line(line).ok_or(CaptureError::BadLine(n + 1))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+fn parse_text_line(line: &str) -> Option<UsbmonEvent> {
+    let mut words = line.split_whitespace();
+    let id = u64::from_str_radix(words.next()?, 16).ok()?;
+    let time_us = words.next()?.parse().ok()?;
+    let kind = match words.next()? {
+        "S" => b'S',
+        "C" => b'C',
+        "E" => b'E',
+        _ => return None,
+    };
+    // type and direction, bus, device and endpoint, e.g. Bo:1:005:2
+    let mut address = words.next()?.split(':');
+    let pipe = address.next()?.as_bytes();
+    let bus = address.next()?.parse().ok()?;
+    let device = address.next()?.parse().ok()?;
+    let mut endpoint: u8 = address.next()?.parse().ok()?;
+    if pipe.get(1) == Some(&b'i') {
+        endpoint |= 0x80;
+    }
+    let mut transfer = match pipe.first()? {
+        b'C' => TransferKind::Control { setup: [0; 8] },
+        b'B' => TransferKind::Bulk,
+        _ => TransferKind::Other,
+    };
+    // either the setup packet of a control submit, or the status (and for interrupt and
+    // isochronous transfers, more numbers after colons)
+    let status_word = words.next()?;
+    let status = if status_word == "s" {
+        let mut setup = [0u8; 8];
+        setup[0] = u8::from_str_radix(words.next()?, 16).ok()?;
+        setup[1] = u8::from_str_radix(words.next()?, 16).ok()?;
+        for i in 0..3 {
+            let v = u16::from_str_radix(words.next()?, 16).ok()?;
+            setup[2 + i * 2..4 + i * 2].copy_from_slice(&v.to_le_bytes());
+        }
+        transfer = TransferKind::Control { setup };
+        -115
+    } else {
+        status_word.split(':').next()?.parse().ok()?
+    };
+    let len = words.next().map_or(Some(0), |w| w.parse().ok())?;
+    let mut data = vec![];
+    if words.next() == Some("=") {
+        // the bytes in order, in groups of four
+        for w in words {
+            for i in (0..w.len()).step_by(2) {
+                data.push(u8::from_str_radix(w.get(i..i + 2)?, 16).ok()?);
+            }
+        }
+    }
+    Some(UsbmonEvent {
+        id,
+        kind,
+        time_us,
+        bus,
+        device,
+        endpoint,
+        transfer,
+        status,
+        len,
+        data,
+    })
+}
+
+// What a transfer was to PICOBOOT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Command(PicobootCmd),
+    // part of a command's data phase, `len` bytes of which `data` holds what the capture kept
+    Data {
+        is_in: bool,
+        len: usize,
+        data: Vec<u8>,
+    },
+    // the zero length transfer ending a command, in the opposite direction of its data
+    Ack {
+        is_in: bool,
+    },
+    Status(PicobootStatusCmd),
+    // the host resetting the PICOBOOT interface, as after a failed command
+    Reset,
+    // the host clearing a stall on an endpoint
+    ClearHalt {
+        endpoint: u8,
+    },
+    // a transfer that failed, e.g. with -32 (EPIPE) as the device stalled the endpoint
+    Failed {
+        endpoint: u8,
+        status: i32,
+    },
+    // a bulk transfer where none was expected, or not the one expected
+    Unexpected(Transfer),
+}
+
+// The command args `cmd_size` says are used, named by what they are to the command
+fn fmt_args(f: &mut fmt::Formatter, cmd: &PicobootCmd) -> fmt::Result {
+    let args: &[u8; ARGS_SIZE] = &cmd.args;
+    let Ok(id) = PicobootCmdId::try_from(cmd.cmd_id) else {
+        return Ok(());
+    };
+    match id {
+        PicobootCmdId::ExclusiveAccess => write!(f, " exclusive={}", args[0]),
+        PicobootCmdId::Reboot => {
+            let a = PicobootRebootCmd::parse(args);
+            write!(f, " pc={:#X} sp={:#X} delay={}", a.pc, a.sp, a.delay)
+        }
+        PicobootCmdId::FlashErase | PicobootCmdId::Read | PicobootCmdId::Write => {
+            let a = PicobootRangeCmd::parse(args);
+            write!(f, " addr={:#X} size={:#X}", a.addr, a.size)
+        }
+        PicobootCmdId::Exec | PicobootCmdId::VectorizeFlash => {
+            write!(f, " addr={:#X}", PicobootRangeCmd::parse(args).addr)
+        }
+        PicobootCmdId::Reboot2 => {
+            let a = PicobootReboot2Cmd::parse(args);
+            write!(
+                f,
+                " flags={:#X} delay={} p0={:#X} p1={:#X}",
+                a.flags, a.delay, a.p0, a.p1
+            )
+        }
+        PicobootCmdId::GetInfo => {
+            let a = PicobootGetInfoCmd::parse(args);
+            write!(
+                f,
+                " type={} params={:#X},{:#X},{:#X}",
+                a.info_type, a.params[0], a.params[1], a.params[2]
+            )
+        }
+        PicobootCmdId::OtpRead | PicobootCmdId::OtpWrite => {
+            let a = PicobootOtpCmd::parse(args);
+            write!(f, " row={:#X} count={} ecc={}", a.row, a.row_count, a.ecc)
+        }
+        PicobootCmdId::Unknown | PicobootCmdId::ExitXip | PicobootCmdId::EnterCmdXip => Ok(()),
+    }
+}
+
+fn fmt_cmd_id(f: &mut fmt::Formatter, cmd_id: u8) -> fmt::Result {
+    match PicobootCmdId::try_from(cmd_id) {
+        Ok(id) => write!(f, "{:?}", id),
+        Err(_) => write!(f, "{:#04X}", cmd_id),
+    }
+}
+
+// At most this many bytes of a data phase are shown
+const SHOWN_DATA: usize = 16;
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let dir = |is_in: bool| if is_in { "in" } else { "out" };
+        match self {
+            Event::Command(cmd) => {
+                write!(f, "command ")?;
+                fmt_cmd_id(f, cmd.cmd_id)?;
+                write!(f, " (token {})", cmd.token)?;
+                fmt_args(f, cmd)?;
+                if cmd.transfer_len != 0 {
+                    let d = dir(cmd.is_data_in());
+                    write!(f, ", {} bytes {}", cmd.transfer_len, d)?;
+                }
+                Ok(())
+            }
+            Event::Data { is_in, len, data } => {
+                write!(f, "data {} {} bytes", dir(*is_in), len)?;
+                if !data.is_empty() {
+                    write!(f, ":")?;
+                    for b in data.iter().take(SHOWN_DATA) {
+                        write!(f, " {:02x}", b)?;
+                    }
+                    if *len > SHOWN_DATA {
+                        write!(f, " ...")?;
+                    }
+                }
+                Ok(())
+            }
+            Event::Ack { is_in } => write!(f, "ack {}", dir(*is_in)),
+            Event::Status(s) => {
+                write!(f, "status of ")?;
+                fmt_cmd_id(f, s.cmd_id)?;
+                write!(f, " (token {}): ", s.token)?;
+                match PicobootStatus::try_from(s.status_code) {
+                    Ok(status) => write!(f, "{:?}", status)?,
+                    Err(_) => write!(f, "{}", s.status_code)?,
+                }
+                if s.in_progress {
+                    write!(f, ", in progress")?;
+                }
+                Ok(())
+            }
+            Event::Reset => write!(f, "interface reset"),
+            Event::ClearHalt { endpoint } => write!(f, "clear halt on endpoint {:#04X}", endpoint),
+            Event::Failed { endpoint, status } => {
+                write!(f, "transfer on endpoint {:#04X} failed", endpoint)?;
+                match status {
+                    -32 => write!(f, ", stalled"),
+                    s => write!(f, " with status {}", s),
+                }
+            }
+            Event::Unexpected(t) => write!(
+                f,
+                "unexpected {} bytes {} on endpoint {:#04X}",
+                t.len,
+                dir(t.is_in()),
+                t.endpoint
+            ),
+        }
+    }
+}
+
+// What the next bulk transfer of the command sequence should be
+#[derive(Debug, Clone, Copy)]
+enum Expect {
+    Command,
+    Data { is_in: bool, remaining: usize },
+    Ack { is_in: bool },
+}
+
+// The first device sending a PICOBOOT command, as (bus, device)
+pub fn find_device(transfers: &[Transfer]) -> Option<(u16, u8)> {
+    transfers
+        .iter()
+        .find(|t| {
+            t.kind == TransferKind::Bulk && !t.is_in() && PicobootCmd::parse(&t.data).is_some()
+        })
+        .map(|t| (t.bus, t.device))
+}
+
+// Turns the transfers to and from a device, given as (bus, device), into PICOBOOT events with
+// the time each happened. Transfers with other devices are left out, and control transfers
+// other than the PICOBOOT requests and clearing stalls
+pub fn decode(transfers: &[Transfer], device: (u16, u8)) -> Vec<(u64, Event)> {
+    let mut events = vec![];
+    let mut expect = Expect::Command;
+    for t in transfers.iter().filter(|t| (t.bus, t.device) == device) {
+        let event = match t.kind {
+            TransferKind::Other => continue,
+            TransferKind::Control { setup } => match decode_control(t, setup) {
+                Some(e) => e,
+                None => continue,
+            },
+            TransferKind::Bulk if t.status != 0 => {
+                // the host resets the interface after a failure, so a new command comes next
+                expect = Expect::Command;
+                Event::Failed {
+                    endpoint: t.endpoint,
+                    status: t.status,
+                }
+            }
+            TransferKind::Bulk => decode_bulk(t, &mut expect),
+        };
+        if matches!(event, Event::Reset) {
+            expect = Expect::Command;
+        }
+        events.push((t.time_us, event));
+    }
+    events
+}
+
+fn decode_control(t: &Transfer, setup: [u8; 8]) -> Option<Event> {
+    if t.status != 0 {
+        return Some(Event::Failed {
+            endpoint: t.endpoint,
+            status: t.status,
+        });
+    }
+    match (setup[0], setup[1]) {
+        (REQUEST_TYPE_STATUS, REQUEST_STATUS) => {
+            let buf: &[u8; STATUS_SIZE] = t.data.get(..STATUS_SIZE)?.try_into().unwrap();
+            Some(Event::Status(PicobootStatusCmd::parse(buf)))
+        }
+        (REQUEST_TYPE_RESET, REQUEST_RESET) => Some(Event::Reset),
+        (REQUEST_TYPE_CLEAR_HALT, REQUEST_CLEAR_FEATURE) => {
+            Some(Event::ClearHalt { endpoint: setup[4] })
+        }
+        _ => None,
+    }
+}
+
+fn decode_bulk(t: &Transfer, expect: &mut Expect) -> Event {
+    match *expect {
+        Expect::Data { is_in, remaining } if is_in == t.is_in() => {
+            let remaining = remaining.saturating_sub(t.len);
+            *expect = match remaining {
+                0 => Expect::Ack { is_in: !is_in },
+                _ => Expect::Data { is_in, remaining },
+            };
+            return Event::Data {
+                is_in,
+                len: t.len,
+                data: t.data.clone(),
+            };
+        }
+        Expect::Ack { is_in } if is_in == t.is_in() && t.len <= 1 => {
+            *expect = Expect::Command;
+            return Event::Ack { is_in };
+        }
+        _ => {}
+    }
+    // a command is taken wherever it turns up, so decoding picks up again after anything the
+    // capture missed
+    match PicobootCmd::parse(&t.data) {
+        Some(cmd) if !t.is_in() => {
+            let is_in = cmd.is_data_in();
+            *expect = match cmd.transfer_len as usize {
+                0 => Expect::Ack { is_in: !is_in },
+                remaining => Expect::Data { is_in, remaining },
+            };
+            Event::Command(cmd)
+        }
+        _ => Event::Unexpected(t.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{to_pcapng, usbmon_packet};
+
+    // a bulk OUT PICOBOOT command, EXCLUSIVE_ACCESS with token 1
+    fn command() -> Vec<u8> {
+        let mut cmd = vec![
+            0x0B, 0xD1, 0x1F, 0x43, 1, 0, 0, 0, 0x01, 1, 0, 0, 0, 0, 0, 0,
+        ];
+        cmd.resize(32, 0);
+        cmd
+    }
+
+    fn transfers() -> Vec<Transfer> {
+        vec![
+            Transfer {
+                submit_us: 1_000_000,
+                time_us: 1_000_250,
+                bus: 1,
+                device: 5,
+                endpoint: 0x02,
+                kind: TransferKind::Bulk,
+                len: 32,
+                data: command(),
+                status: 0,
+            },
+            Transfer {
+                submit_us: 1_000_300,
+                time_us: 1_000_400,
+                bus: 1,
+                device: 5,
+                endpoint: 0x81,
+                kind: TransferKind::Bulk,
+                len: 0,
+                data: vec![],
+                status: 0,
+            },
+        ]
+    }
+
+    fn pcap(le: bool) -> Vec<u8> {
+        let u32b = |n: u32| if le { n.to_le_bytes() } else { n.to_be_bytes() };
+        let mut out = vec![];
+        out.extend_from_slice(&u32b(PCAP_MAGIC_US));
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&u32b(LINKTYPE_USB_LINUX));
+        for (i, t) in transfers().iter().enumerate() {
+            for submit in [true, false] {
+                let mut packet = usbmon_packet(t, i as u64 + 1, submit);
+                if !le {
+                    swap_usbmon_header(&mut packet);
+                }
+                out.extend_from_slice(&[0; 8]);
+                out.extend_from_slice(&u32b(packet.len() as u32));
+                out.extend_from_slice(&u32b(packet.len() as u32));
+                out.extend_from_slice(&packet);
+            }
+        }
+        out
+    }
+
+    // the usbmon header as a big endian machine would have written it
+    fn swap_usbmon_header(p: &mut [u8]) {
+        for (at, len) in [(0, 8), (12, 2), (16, 8), (24, 4), (28, 4), (32, 4), (36, 4)] {
+            p[at..at + len].reverse();
+        }
+    }
+
+    #[test]
+    fn reads_pcap_in_either_byte_order() {
+        assert_eq!(parse(&pcap(true)).unwrap(), transfers());
+        assert_eq!(parse(&pcap(false)).unwrap(), transfers());
+    }
+
+    #[test]
+    fn reads_back_its_own_pcapng() {
+        assert_eq!(parse(&to_pcapng(&transfers())).unwrap(), transfers());
+    }
+
+    #[test]
+    fn decodes_command_and_ack() {
+        let t = parse(&to_pcapng(&transfers())).unwrap();
+        assert_eq!(find_device(&t), Some((1, 5)));
+        let events = decode(&t, (1, 5));
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0].1, Event::Command(c) if c.token == 1));
+        assert_eq!(events[1], (1_000_400, Event::Ack { is_in: true }));
+    }
+
+    #[test]
+    fn reads_usbmon_text() {
+        let text = "ffff8c3a0c5d2cc0 3575914555 S Bo:1:005:2 -115 32 = 0bd11f43 01000000 \
+                    01010000 00000000 00000000 00000000 00000000 00000000\n\
+                    ffff8c3a0c5d2cc0 3575914805 C Bo:1:005:2 0 32 >\n\
+                    ffff8c3a0c5d2a80 3575914900 S Ci:1:005:0 s c1 42 0000 0001 0010 16 <\n\
+                    ffff8c3a0c5d2a80 3575915000 C Ci:1:005:0 0 16 = 01000000 00000000 \
+                    01000000 00000000\n";
+        let t = parse(text.as_bytes()).unwrap();
+        assert_eq!(t.len(), 2);
+        assert_eq!(t[0].data, command());
+        assert_eq!(t[0].submit_us, 3575914555);
+        assert_eq!(t[0].time_us, 3575914805);
+        assert_eq!(t[1].endpoint, 0x80);
+        let events = decode(&t, (1, 5));
+        assert!(matches!(events[1].1, Event::Status(s) if s.token == 1 && s.cmd_id == 1));
+    }
+
+    #[test]
+    fn rejects_bad_usbmon_text() {
+        let e = parse(b"ffff8c3a0c5d2cc0 3575914555 X Bo:1:005:2 -115 0\n");
+        assert!(matches!(e, Err(CaptureError::BadLine(1))));
+        assert!(matches!(
+            parse(&[0xFF, 0xFE]),
+            Err(CaptureError::UnknownFormat)
+        ));
+    }
+
+    #[test]
+    fn rejects_undersized_pcapng_blocks() {
+        for (block_type, len) in [(PCAPNG_EPB, 16u32), (PCAPNG_EPB, 28), (PCAPNG_SPB, 12)] {
+            // the section header and interface description, then the block
+            let mut bytes = to_pcapng(&[]);
+            bytes.extend_from_slice(&block_type.to_le_bytes());
+            bytes.extend_from_slice(&len.to_le_bytes());
+            bytes.resize(bytes.len() + len as usize - 12, 0xFF);
+            bytes.extend_from_slice(&len.to_le_bytes());
+            assert!(matches!(parse(&bytes), Err(CaptureError::Truncated)));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_captures() {
+        let mut bytes = to_pcapng(&transfers());
+        bytes.truncate(bytes.len() - 3);
+        assert!(matches!(parse(&bytes), Err(CaptureError::Truncated)));
+        let mut bytes = pcap(true);
+        bytes.truncate(bytes.len() - 1);
+        assert!(matches!(parse(&bytes), Err(CaptureError::Truncated)));
+        // a caplen running past the end of the file
+        let mut bytes = pcap(true);
+        bytes[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(parse(&bytes), Err(CaptureError::Truncated)));
+    }
+
+    #[test]
+    fn rejects_overflowing_timestamps() {
+        let mut bytes = pcap(true);
+        // the seconds of the first packet's usbmon header
+        bytes[40 + 16..40 + 24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(parse(&bytes), Err(CaptureError::BadTimestamp)));
+    }
+
+    #[test]
+    fn rejects_other_link_types() {
+        let mut bytes = pcap(true);
+        bytes[20..24].copy_from_slice(&1u32.to_le_bytes());
+        assert!(matches!(parse(&bytes), Err(CaptureError::LinkType(1))));
+    }
+}
@@ -0,0 +1,486 @@
+// Subcommands running a single PICOBOOT operation against the device
+
+use super::args::{self, flash_addr, mem_addr, PAST_FLASH};
+use super::globals::Globals;
+use super::load::{set_reboot_arch, target_segments};
+use super::output::{
+    failure, hex, report, set_exit_code, CliResult, ErrorContext, ExitCode, ProgressBar,
+};
+use super::signal;
+use crate::{exec, image, json, picousb, uf2, FlashAddr, SramAddr};
+
+#[derive(clap::Args)]
+pub(super) struct ExecArgs {
+    #[arg(value_name = "STUB.BIN")]
+    stub: String,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = args::sram_addr,
+        default_value_t = exec::DEFAULT_LOAD_ADDR,
+        help = "where to load the stub"
+    )]
+    load_addr: SramAddr,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = args::sram_addr,
+        default_value_t = exec::DEFAULT_MAILBOX_ADDR,
+        help = "where to pass arguments and results"
+    )]
+    mailbox: SramAddr,
+    #[arg(long = "args", value_name = "HEX", help = "arguments for the stub")]
+    stub_args: Option<String>,
+}
+
+// Runs a stub in SRAM with arguments from the command line, then prints what it returns
+pub(super) fn exec(globals: &Globals, args: &ExecArgs) -> CliResult {
+    let stub_args = match &args.stub_args {
+        Some(hex) => exec::parse_hex(hex)
+            .ok_or_else(|| failure(ExitCode::Usage, format!("bad hex arguments: {}", hex)))?,
+        None => vec![],
+    };
+    let stub = std::fs::read(&args.stub).context("failed to read stub")?;
+
+    let mut conn = globals.open()?;
+    if let Some(picousb::TargetID::Rp2350) = conn.get_device_type() {
+        return Err("the RP2350 bootrom can't execute code over PICOBOOT".into());
+    }
+
+    let result = exec::run_stub(&mut conn, &stub, args.load_addr, args.mailbox, &stub_args)
+        .context("failed to run stub")?;
+    let hex: String = result.iter().map(|b| format!("{:02x}", b)).collect();
+    say!("stub returned {} bytes: {}", result.len(), hex);
+    report("result", hex);
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub(super) struct RebootArgs {
+    #[arg(
+        long,
+        conflicts_with_all = ["pc", "ram_image", "flash_update"],
+        help = "reboot back into BOOTSEL"
+    )]
+    bootsel: bool,
+    #[arg(
+        long,
+        requires = "bootsel",
+        help = "with --bootsel, leave out the BOOTSEL drive"
+    )]
+    disable_msc: bool,
+    #[arg(
+        long,
+        requires = "bootsel",
+        help = "with --bootsel, leave out PICOBOOT"
+    )]
+    disable_picoboot: bool,
+    #[arg(
+        long = "led",
+        value_name = "GPIO",
+        requires = "bootsel",
+        help = "with --bootsel, show activity on an LED"
+    )]
+    led_gpio: Option<u8>,
+    #[arg(
+        long,
+        requires = "bootsel",
+        help = "the LED is on when the GPIO is low (RP2350 only)"
+    )]
+    led_active_low: bool,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = args::addr,
+        conflicts_with_all = ["ram_image", "flash_update"],
+        help = "start running at ADDR"
+    )]
+    pc: Option<u32>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = args::addr,
+        requires = "pc",
+        help = "with --pc, the stack pointer (top of SRAM)"
+    )]
+    sp: Option<u32>,
+    #[arg(
+        long,
+        value_name = "ADDR+LEN",
+        value_parser = args::range,
+        conflicts_with = "flash_update",
+        help = "boot an image already in SRAM (RP2350 only)"
+    )]
+    ram_image: Option<(u32, u32)>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = args::addr,
+        help = "boot as after updating flash at ADDR (RP2350 only)"
+    )]
+    flash_update: Option<u32>,
+    #[arg(
+        long,
+        value_parser = args::arch,
+        help = "switch the cores to arm or riscv (RP2350 only)"
+    )]
+    arch: Option<picousb::RebootArch>,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 500,
+        help = "delay before rebooting"
+    )]
+    delay: u32,
+}
+
+// Reboots the device into its firmware, back into BOOTSEL, or any of the other REBOOT2 modes
+pub(super) fn reboot(globals: &Globals, args: &RebootArgs) -> CliResult {
+    if args.disable_msc && args.disable_picoboot {
+        return Err(failure(
+            ExitCode::Usage,
+            "BOOTSEL needs at least one of its USB interfaces",
+        ));
+    }
+
+    let mut conn = globals.open()?;
+    let target = conn.get_device_type().ok_or("no known RP chip found")?;
+    set_reboot_arch(&mut conn, args.arch)?;
+    let kind = if args.bootsel {
+        picousb::Reboot2Kind::Bootsel {
+            disable_msc: args.disable_msc,
+            disable_picoboot: args.disable_picoboot,
+            led_gpio: args.led_gpio,
+            led_active_low: args.led_active_low,
+        }
+    } else if let Some(pc) = args.pc {
+        let sp = args.sp.unwrap_or(match target {
+            picousb::TargetID::Rp2040 => picousb::PICO_STACK_POINTER,
+            picousb::TargetID::Rp2350 => picousb::PICO2_STACK_POINTER,
+        });
+        picousb::Reboot2Kind::PcSp { pc, sp }
+    } else if let Some((start, size)) = args.ram_image {
+        picousb::Reboot2Kind::RamImage { start, size }
+    } else if let Some(start) = args.flash_update {
+        picousb::Reboot2Kind::FlashUpdate { start }
+    } else {
+        picousb::Reboot2Kind::Normal
+    };
+    match conn.reboot_as(kind, args.delay) {
+        Err(picousb::PicobootError::NotSupported) => {
+            return Err(format!("the {:?} can't reboot like that", target))
+        }
+        res => res.context("failed to reboot device")?,
+    }
+    report("mode", format!("{:?}", kind));
+    say!("reboot success");
+    Ok(())
+}
+
+#[derive(clap::Args)]
+#[command(group = clap::ArgGroup::new("what").required(true).args(["range", "all"]))]
+pub(super) struct EraseArgs {
+    #[arg(
+        long,
+        alias = "region",
+        value_name = "ADDR+LEN",
+        value_parser = args::range,
+        help = "the range of flash to erase"
+    )]
+    range: Option<(u32, u32)>,
+    #[arg(long, help = "erase all of flash")]
+    all: bool,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = args::addr,
+        requires = "all",
+        help = "size of the flash for --all (read from the chip)"
+    )]
+    flash_size: Option<u32>,
+    #[arg(
+        long,
+        help = "widen a range that isn't sector aligned to whole sectors"
+    )]
+    round_out: bool,
+}
+
+// Erases whole sectors of flash
+pub(super) fn erase(globals: &Globals, args: &EraseArgs) -> CliResult {
+    signal::install_handler();
+
+    let mut region = args.range;
+    // only whole sectors can be erased, so a range that isn't aligned takes out more than asked
+    // for. That's only done when asked to, saying how much more
+    if let Some((addr, len)) = region {
+        if len == 0 {
+            return Err("the range to erase is empty".into());
+        }
+        if FlashAddr::new(addr)
+            .and_then(|a| a.checked_add(len - 1))
+            .is_none()
+        {
+            return Err(format!(
+                "{:#X}+{:#X} is not a range of flash, which starts at {:#X}",
+                addr,
+                len,
+                picousb::PICO_FLASH_START
+            ));
+        }
+        let (start, size) = picousb::sector_span(addr, len);
+        if (start, size) != (addr, len) {
+            if !args.round_out {
+                return Err(format!(
+                    "{:#X}+{:#X} is not sector aligned, the sectors covering it are {:#X}+{:#X} \
+                     (pass --round-out to erase those)",
+                    addr, len, start, size
+                ));
+            }
+            say!(
+                "erasing {:#X}+{:#X}, the whole sectors covering {:#X}+{:#X}: {:#X} bytes before \
+                 and {:#X} after the range are erased too",
+                start,
+                size,
+                addr,
+                len,
+                addr - start,
+                (start + size) - (addr + len)
+            );
+            region = Some((start, size));
+        }
+    }
+
+    let mut conn = globals.open()?;
+    let mut bar = ProgressBar::new("erased");
+    conn.set_progress_handler(move |p| bar.update(p));
+    let mut conn = conn
+        .exclusive_access_guard(true)
+        .context("failed to claim access")?
+        .reset_on_drop(true);
+    conn.exit_xip().context("failed to exit from xip mode")?;
+    match region {
+        Some((addr, len)) => {
+            let start = flash_addr(addr)?;
+            conn.check_flash_range(start, len)
+                .context("can't erase that range")?;
+            conn.flash_erase_range(start, len)
+                .context("failed to erase flash")?;
+            say!("erased {:#X} bytes at {:#X}", len, addr);
+            report("addr", hex(addr));
+            report("size", len);
+        }
+        None => {
+            conn.flash_erase_all(args.flash_size)
+                .context("failed to erase flash")?;
+            say!("erased all of flash");
+            report("all", true);
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub(super) struct SaveArgs {
+    #[arg(short, long, value_name = "OUT.BIN|OUT.UF2")]
+    output: String,
+    #[arg(long, help = "save all of flash, not just the program")]
+    all: bool,
+    #[arg(
+        long,
+        value_name = "ADDR+LEN",
+        value_parser = args::region,
+        conflicts_with = "all",
+        help = "save the given range instead"
+    )]
+    region: Option<(u32, u32)>,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = args::addr,
+        requires = "all",
+        help = "size of the flash for --all (read from the chip)"
+    )]
+    flash_size: Option<u32>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        help = "re-read failed or short reads N times"
+    )]
+    read_retries: u32,
+}
+
+// Reads the program in flash, all of flash or a given range back into a file
+pub(super) fn save(globals: &Globals, args: &SaveArgs) -> CliResult {
+    signal::install_handler();
+
+    let output = &args.output;
+    let mut conn = globals.open()?;
+    let target = conn.get_device_type().ok_or("no known RP chip found")?;
+    let mut bar = ProgressBar::new("read");
+    conn.set_progress_handler(move |p| bar.update(p));
+    let mut conn = conn
+        .exclusive_access_guard(false)
+        .context("failed to claim access")?
+        .reset_on_drop(true);
+    conn.exit_xip().context("failed to exit from xip mode")?;
+
+    let (addr, size) = match args.region {
+        Some(region) => region,
+        None if args.all => {
+            let size = match args.flash_size {
+                Some(size) => size,
+                None => conn.flash_size().context("failed to get the flash size")?,
+            };
+            (picousb::PICO_FLASH_START, size)
+        }
+        None => {
+            let end = conn
+                .program_end()
+                .context("failed to read binary info")?
+                .ok_or("no program with binary info found in flash, use --all or --region")?;
+            (picousb::PICO_FLASH_START, end - picousb::PICO_FLASH_START)
+        }
+    };
+    say!("saving {:#X} bytes at {:#X}", size, addr);
+    report("addr", hex(addr));
+    report("size", size);
+    let data = match conn.flash_read_all(mem_addr(addr)?, size, args.read_retries) {
+        Ok(data) => data,
+        Err(picousb::PicobootError::Interrupted) => return Err("interrupted".into()),
+        Err(e) => return Err(e).context("failed to read flash"),
+    };
+
+    let bytes = if output.to_lowercase().ends_with(".uf2") {
+        let family = match target {
+            picousb::TargetID::Rp2040 => uf2::FAMILY_ID_RP2040,
+            picousb::TargetID::Rp2350 => uf2::FAMILY_ID_ABSOLUTE,
+        };
+        let seg = image::Segment {
+            addr,
+            data,
+            family: None,
+        };
+        uf2::encode(&[seg], family).map_err(|e| e.to_string())?
+    } else {
+        data
+    };
+    std::fs::write(output, bytes).context("failed to write output")?;
+    say!("saved to {}", output);
+    report("output", output.as_str());
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub(super) struct VerifyArgs {
+    #[arg(value_name = "FILE[@ADDR]")]
+    inputs: Vec<String>,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        help = "re-read failed or mismatching pages N times"
+    )]
+    read_retries: u32,
+    #[arg(long, help = "compare CRCs computed on the device (RP2040 only)")]
+    device_crc: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "list up to N differing bytes on failure"
+    )]
+    max_mismatches: u32,
+    #[arg(
+        long,
+        help = "check UF2 blocks even if their family is for another chip"
+    )]
+    ignore_family: bool,
+}
+
+// Compares flash against firmware without writing anything, listing the first differing bytes.
+// Fails on a mismatch, so CI can gate on it
+pub(super) fn verify(globals: &Globals, args: &VerifyArgs) -> CliResult {
+    signal::install_handler();
+
+    let inputs = globals.firmware(&args.inputs, "verify")?;
+    let (read_retries, max_mismatches) = (args.read_retries, args.max_mismatches as usize);
+
+    let mut conn = globals.open()?;
+    let target = conn.get_device_type().ok_or("no known RP chip found")?;
+    if args.device_crc && matches!(target, picousb::TargetID::Rp2350) {
+        return Err(failure(
+            ExitCode::Incompatible,
+            "--device-crc needs an RP2040",
+        ));
+    }
+    let segments = target_segments(&inputs, target, args.ignore_family)?;
+    // SRAM doesn't survive a reboot, so only what's in flash can be checked
+    let (flash_segments, ram_segments) = image::split_flash_ram(segments);
+    if !ram_segments.is_empty() {
+        say!("skipping {} segments in SRAM", ram_segments.len());
+    }
+
+    let mut conn = conn
+        .exclusive_access_guard(false)
+        .context("failed to claim access")?
+        .reset_on_drop(true);
+    conn.exit_xip().context("failed to exit from xip mode")?;
+    let mut checked = 0;
+    let mut mismatches = vec![];
+    for seg in &flash_segments {
+        let left = max_mismatches - mismatches.len();
+        let start = flash_addr(seg.addr)?;
+        // the device CRC only tells which 64K chunk differs, so that's read back to find the bytes
+        let res = if args.device_crc {
+            match conn.verify_crc32(start, &seg.data) {
+                Err(picousb::PicobootError::CrcMismatch { addr, .. }) => {
+                    let skip = (addr - seg.addr) as usize;
+                    let at = start.checked_add(skip as u32).ok_or(PAST_FLASH)?;
+                    conn.find_mismatches(at, &seg.data[skip..], read_retries, left)
+                }
+                res => res.map(|()| vec![]),
+            }
+        } else {
+            conn.find_mismatches(start, &seg.data, read_retries, left)
+        };
+        match res {
+            Ok(found) => mismatches.extend(found),
+            Err(picousb::PicobootError::Interrupted) => return Err("interrupted".into()),
+            Err(e) => return Err(e).context("failed to check flash"),
+        }
+        checked += seg.data.len();
+        if mismatches.len() >= max_mismatches {
+            break;
+        }
+    }
+
+    let mut listed = vec![];
+    for m in &mismatches {
+        say!(
+            "mismatch at {:#010X}: expected {:#04X}, found {:#04X}",
+            m.addr,
+            m.expected,
+            m.actual
+        );
+        listed.push(json::Value::Object(vec![
+            ("addr".into(), hex(m.addr)),
+            ("expected".into(), (m.expected as u32).into()),
+            ("actual".into(), (m.actual as u32).into()),
+        ]));
+    }
+    report("matches", mismatches.is_empty());
+    report("mismatches", listed);
+    report("bytes_checked", checked);
+    if let Some(first) = mismatches.first() {
+        say!("FAIL: flash does not match");
+        set_exit_code(ExitCode::VerifyFailed);
+        return Err(format!(
+            "flash does not match, first at {:#010X}",
+            first.addr
+        ));
+    }
+    say!("PASS: flash matches, {} bytes checked", checked);
+    Ok(())
+}
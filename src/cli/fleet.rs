@@ -0,0 +1,270 @@
+// `fleet`, flashing many devices at once, and preparing images the way `provision` does too
+
+use super::globals::{device_name, Globals};
+use super::load::{
+    check_image, entry_for, flash_device, sequencing, target_segments, FlashOptions, PreparedImage,
+};
+use super::output::{failure, report, CliResult, ErrorContext, ExitCode};
+use super::session::Session;
+use super::signal;
+use super::WATCH_POLL_INTERVAL;
+use crate::picousb;
+use crate::{image, json};
+use std::time::Duration;
+
+#[derive(clap::Args)]
+pub(super) struct FleetArgs {
+    #[arg(
+        long,
+        value_name = "N",
+        help = "fail unless N devices are found (with -w, wait for N)"
+    )]
+    expect: Option<usize>,
+    #[arg(long, help = "only erase and write sectors that differ from the image")]
+    skip_unchanged: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        help = "re-read failed or mismatching pages N times"
+    )]
+    read_retries: u32,
+    #[arg(long, help = "verify by CRC computed on the device (RP2040 only)")]
+    device_crc: bool,
+    #[arg(long, help = "leave the devices in BOOTSEL after flashing")]
+    no_reboot: bool,
+    #[arg(long, conflicts_with = "no_reboot", help = "reboot back into BOOTSEL")]
+    reboot_bootsel: bool,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 500,
+        help = "delay before rebooting"
+    )]
+    reboot_delay: u32,
+    #[arg(long, help = "sequence commands exactly like picotool")]
+    picotool_compat: bool,
+    #[arg(
+        long,
+        help = "write UF2 blocks even if their family is for another chip"
+    )]
+    ignore_family: bool,
+    #[arg(long, help = "flash RP2350 images the bootrom would refuse to boot")]
+    no_image_check: bool,
+    #[arg(value_name = "FILE[@ADDR]")]
+    inputs: Vec<String>,
+}
+
+// Flashes every connected device in BOOTSEL at once, one thread each, e.g. a hub full of boards
+// on a production line. Each device's progress is shown prefixed with where it's plugged in, then
+// a summary of which ones succeeded. Fails if any device did
+pub(super) fn fleet(globals: &Globals, args: &FleetArgs) -> CliResult {
+    signal::install_handler();
+
+    let opts = FlashOptions {
+        skip_if_same: false,
+        skip_unchanged: args.skip_unchanged,
+        read_retries: args.read_retries,
+        device_crc: args.device_crc,
+        sequencing: sequencing(args.picotool_compat),
+        reboot: match (args.no_reboot, args.reboot_bootsel) {
+            (true, _) => None,
+            (_, true) => Some(picousb::RebootMode::Bootsel),
+            _ => Some(picousb::RebootMode::Normal),
+        },
+        reboot_delay: args.reboot_delay,
+        quiet: true,
+    };
+    let (ignore_family, image_check) = (args.ignore_family, !args.no_image_check);
+    let inputs = globals.firmware(&args.inputs, "fleet")?;
+    if globals.serial.is_some() || globals.location.is_some() {
+        return Err("fleet flashes every device, --serial and --device don't apply".into());
+    }
+    if globals.trace.is_some() {
+        return Err("--trace records a single device, it can't be used with fleet".into());
+    }
+
+    let ctx = globals.context()?;
+    let devices = fleet_devices(globals, &ctx, args.expect)?;
+    let mut images = vec![];
+    for target in [picousb::TargetID::Rp2040, picousb::TargetID::Rp2350] {
+        if devices.iter().any(|d| d.target == target) {
+            let image = prepare_image(&inputs, target, ignore_family, image_check, &opts)?;
+            images.push((target, image));
+        }
+    }
+
+    let name = |d: &picousb::DeviceInfo| device_name(d.bus, d.address, &d.ports);
+    say!("flashing {} devices", devices.len());
+    let started = std::time::SystemTime::now();
+    let results: Vec<(CliResult<usize>, Duration)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = devices
+            .iter()
+            .map(|d| {
+                let (_, image) = images.iter().find(|(t, _)| *t == d.target).unwrap();
+                let (ctx, name, opts) = (ctx.clone(), name(d), &opts);
+                scope.spawn(move || {
+                    let start = std::time::Instant::now();
+                    let res = flash_fleet_device(globals, ctx, d, &name, image, opts);
+                    (res, start.elapsed())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| {
+                w.join()
+                    .unwrap_or_else(|_| (Err("worker panicked".into()), Duration::ZERO))
+            })
+            .collect()
+    });
+
+    let mut found = vec![];
+    let mut failed = 0;
+    let mut recorded = Ok(());
+    say!();
+    for (d, (res, duration)) in devices.iter().zip(&results) {
+        let session = Session {
+            command: "fleet",
+            started,
+            location: name(d),
+            serial: d.serial.clone(),
+            chip: d.target,
+            inputs: &inputs,
+            verify: opts.verify(),
+        };
+        recorded = recorded.and(globals.record_session(&session, res, *duration));
+        let serial = d.serial.as_deref().unwrap_or("unknown");
+        let mut fields = vec![
+            ("location".to_string(), name(d).into()),
+            ("chip".to_string(), format!("{:?}", d.target).into()),
+            ("serial".to_string(), d.serial.clone().into()),
+            ("ok".to_string(), res.is_ok().into()),
+        ];
+        match res {
+            Ok(bytes) => {
+                say!("{} ({:?}, {}): ok", name(d), d.target, serial);
+                fields.push(("bytes_written".to_string(), (*bytes).into()));
+            }
+            Err(e) => {
+                say!("{} ({:?}, {}): FAILED, {}", name(d), d.target, serial, e);
+                fields.push(("error".to_string(), e.as_str().into()));
+                failed += 1;
+            }
+        }
+        found.push(json::Value::Object(fields));
+    }
+    report("devices", found);
+    report("succeeded", devices.len() - failed);
+    report("failed", failed);
+    say!(
+        "{} of {} devices flashed",
+        devices.len() - failed,
+        devices.len()
+    );
+    if failed != 0 {
+        return Err(format!("{} of {} devices failed", failed, devices.len()));
+    }
+    recorded
+}
+
+// The devices in BOOTSEL to flash. With `--wait` keeps looking until there are `expect` of them,
+// or at least one
+fn fleet_devices(
+    globals: &Globals,
+    ctx: &rusb::Context,
+    expect: Option<usize>,
+) -> CliResult<Vec<picousb::DeviceInfo>> {
+    let wait = globals.wait;
+    if wait {
+        say!("waiting for devices in BOOTSEL mode, press Ctrl-C to give up");
+    }
+    loop {
+        let devices = globals
+            .builder()
+            .list_devices(ctx)
+            .context("failed to list devices")?;
+        if wait && devices.len() < expect.unwrap_or(1) {
+            if signal::interrupted() {
+                return Err("interrupted".into());
+            }
+            std::thread::sleep(WATCH_POLL_INTERVAL);
+            continue;
+        }
+        if devices.is_empty() {
+            return Err(failure(
+                ExitCode::NoDevice,
+                "no devices in BOOTSEL mode found",
+            ));
+        }
+        if let Some(n) = expect.filter(|&n| n != devices.len()) {
+            let msg = format!("found {} devices, expected {}", devices.len(), n);
+            return Err(failure(ExitCode::NoDevice, msg));
+        }
+        return Ok(devices);
+    }
+}
+
+// Loads and checks the image for `target`, before any device is touched. `fleet` does this once
+// per kind of chip rather than in every worker
+pub(super) fn prepare_image(
+    inputs: &[String],
+    target: picousb::TargetID,
+    ignore_family: bool,
+    image_check: bool,
+    opts: &FlashOptions,
+) -> CliResult<PreparedImage> {
+    if opts.device_crc && target != picousb::TargetID::Rp2040 {
+        return Err(failure(
+            ExitCode::Incompatible,
+            "--device-crc needs an RP2040, but an RP2350 is connected",
+        ));
+    }
+    let segments = target_segments(inputs, target, ignore_family)?;
+    if image_check {
+        check_image(&segments, target)?;
+    }
+    let (flash_segments, ram_segments) = image::split_flash_ram(segments);
+    Ok(PreparedImage {
+        reboot: entry_for(inputs, &flash_segments, target, opts.reboot)?,
+        fw_pages: image::pages(&flash_segments).map_err(|e| e.to_string())?,
+        ram_segments,
+    })
+}
+
+// One `fleet` worker, flashing the device `d` and returning the bytes written. Output is
+// prefixed with `name` to tell the devices apart, and nothing is reported as that's per command
+fn flash_fleet_device(
+    globals: &Globals,
+    ctx: rusb::Context,
+    d: &picousb::DeviceInfo,
+    name: &str,
+    image: &PreparedImage,
+    opts: &FlashOptions,
+) -> CliResult<usize> {
+    let fail =
+        |what: &str, e: picousb::PicobootError| failure((&e).into(), format!("{}: {}", what, e));
+    let location = picousb::DeviceLocation::BusAddress {
+        bus: d.bus,
+        address: d.address,
+    };
+    let mut conn = globals
+        .builder()
+        .location(location)
+        .build(ctx)
+        .map_err(|e| fail("could not open device", e))?;
+    conn.set_sequencing(opts.sequencing);
+    conn.set_skip_unchanged(opts.skip_unchanged);
+    let prefix = name.to_string();
+    let mut shown = None;
+    conn.set_progress_handler(move |p| {
+        let tenths = p.done * 10 / p.total.max(1);
+        if p.op == picousb::ProgressOp::Write && shown != Some(tenths) {
+            say!("{}: {}% written", prefix, tenths * 10);
+            shown = Some(tenths);
+        }
+    });
+    let written = flash_device(&mut conn, image, opts)?;
+    say!("{}: done", name);
+    Ok(written)
+}
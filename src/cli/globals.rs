@@ -0,0 +1,352 @@
+// The options every subcommand takes, which pick the device and how to talk to it, and opening
+// the device with them
+
+use super::args;
+use super::output::{failure, CliResult, ErrorContext, ExitCode};
+use super::signal;
+use crate::picousb::{self, PicobootConnection, UsbConnection};
+use crate::transport::RusbTransport;
+use crate::{config, trace};
+use rusb::UsbContext;
+use std::time::Duration;
+
+#[derive(clap::Args)]
+#[command(next_help_heading = "Global options")]
+pub(super) struct GlobalArgs {
+    #[arg(
+        long,
+        global = true,
+        help = "pick which device to use when several are connected"
+    )]
+    serial: Option<String>,
+    #[arg(
+        long = "device",
+        global = true,
+        value_name = "LOCATION",
+        value_parser = args::location,
+        help = "pick it by where it's plugged in, as BUS:ADDRESS or a port path like 1-4.2"
+    )]
+    location: Option<picousb::DeviceLocation>,
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "wait for a device to be connected instead of failing"
+    )]
+    wait: bool,
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "reboot a board running its application into BOOTSEL if none is in it"
+    )]
+    force: bool,
+    #[arg(
+        short,
+        long,
+        global = true,
+        action = clap::ArgAction::Count,
+        conflicts_with = "quiet",
+        help = "also log each command sent, and with -vv every command status"
+    )]
+    verbose: u8,
+    #[arg(short, long, global = true, help = "log only warnings and errors")]
+    quiet: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "print the outcome as a JSON object on stdout, and everything else on stderr"
+    )]
+    pub(super) json: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        value_parser = trace_path,
+        help = "record every USB transfer into FILE, as .json or .pcapng"
+    )]
+    trace: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "try a command up to N times on a USB timeout, stall or I/O error [default: 3]"
+    )]
+    usb_attempts: Option<u32>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        help = "append a record of each device flashed to FILE, as .csv or JSON lines"
+    )]
+    session_report: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        requires = "pid",
+        value_parser = args::usb_id,
+        help = "with --pid, the VID of a device to look for too [default: 0x2E8A]"
+    )]
+    vid: Option<u16>,
+    #[arg(
+        long,
+        global = true,
+        value_parser = args::usb_id,
+        help = "also look for a device with this PID, e.g. a white-labelled RP2350"
+    )]
+    pid: Option<u16>,
+    #[arg(
+        long,
+        global = true,
+        help = "ignore ./picoboot.toml and ~/.config/picoboot/config.toml"
+    )]
+    no_config: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "use libusb's UsbDk backend on Windows, which needs no WinUSB driver"
+    )]
+    usbdk: bool,
+}
+
+fn trace_path(path: &str) -> Result<String, String> {
+    if !path.ends_with(".json") && !path.ends_with(".pcapng") {
+        return Err(format!(
+            "can't write a trace to {}, use .json or .pcapng",
+            path
+        ));
+    }
+    Ok(path.to_string())
+}
+
+impl GlobalArgs {
+    // The level of the library's log records to print: -v shows each command sent, -vv also
+    // every status, -q only warnings and errors
+    pub(super) fn log_level(&self) -> log::LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => log::LevelFilter::Warn,
+            (false, 0) => log::LevelFilter::Info,
+            (false, 1) => log::LevelFilter::Debug,
+            (false, _) => log::LevelFilter::Trace,
+        }
+    }
+
+    // Reads the config file, unless `--no-config`, for the options to fall back on
+    pub(super) fn resolve(self) -> CliResult<Globals> {
+        let config = if self.no_config {
+            config::Config::default()
+        } else {
+            config::Config::load().map_err(|(path, e)| {
+                failure(
+                    ExitCode::Usage,
+                    format!("bad config file {}: {}", path.display(), e),
+                )
+            })?
+        };
+        if self.usbdk && !cfg!(windows) {
+            return Err(failure(
+                ExitCode::Usage,
+                "--usbdk is only available on Windows",
+            ));
+        }
+        Ok(Globals {
+            wait: self.wait || config.wait == Some(true),
+            serial: self.serial,
+            location: self.location,
+            force: self.force,
+            trace: self.trace.map(|path| Tracing {
+                path,
+                trace: trace::Trace::new(),
+                device: Default::default(),
+            }),
+            usb_attempts: self.usb_attempts,
+            session_report: self.session_report,
+            extra_id: self
+                .pid
+                .map(|pid| (self.vid.unwrap_or(crate::protocol::PICOBOOT_VID), pid)),
+            usbdk: self.usbdk,
+            config,
+        })
+    }
+}
+
+// The trace asked for with `--trace`, and the bus and address of the last device traced for the
+// pcapng export. Connections opened by `Globals::connect` record into it, and `main` writes it
+// out at the end
+#[derive(Clone)]
+pub(super) struct Tracing {
+    path: String,
+    trace: trace::Trace,
+    device: std::sync::Arc<std::sync::Mutex<(u16, u8)>>,
+}
+
+// The global options, with the config file's defaults filled in, passed to every subcommand
+#[derive(Clone)]
+pub(super) struct Globals {
+    pub(super) serial: Option<String>,
+    pub(super) location: Option<picousb::DeviceLocation>,
+    pub(super) wait: bool,
+    pub(super) force: bool,
+    pub(super) trace: Option<Tracing>,
+    usb_attempts: Option<u32>,
+    pub(super) session_report: Option<String>,
+    // a white-labelled RP2350's VID/PID to look for too
+    extra_id: Option<(u16, u16)>,
+    #[cfg_attr(not(windows), allow(dead_code))]
+    usbdk: bool,
+    config: config::Config,
+}
+impl Globals {
+    // These options with `--wait`, for commands going from one board to the next
+    pub(super) fn waiting(&self) -> Globals {
+        Globals {
+            wait: true,
+            ..self.clone()
+        }
+    }
+
+    // These options with `--force`, for commands replacing whatever the board is running
+    pub(super) fn forcing(&self) -> Globals {
+        Globals {
+            force: true,
+            ..self.clone()
+        }
+    }
+
+    // Opens the device given by `--serial` and `--device`, or the first one found. With `--wait`
+    // it keeps looking until one is connected, or Ctrl-C is pressed. With `--force` a board
+    // running its application is rebooted into BOOTSEL if none is in it already
+    pub(super) fn connect<T: UsbContext>(
+        &self,
+        ctx: T,
+    ) -> picousb::Result<PicobootConnection<RusbTransport<T>>> {
+        let mut builder = self.builder();
+        // the config file's device is only a default, so either option replaces both of its
+        let (serial, location) = if self.serial.is_some() || self.location.is_some() {
+            (self.serial.as_ref(), self.location.as_ref())
+        } else {
+            (self.config.serial.as_ref(), self.config.device.as_ref())
+        };
+        if let Some(serial) = serial {
+            builder = builder.serial(serial);
+        }
+        if let Some(location) = location {
+            builder = builder.location(location.clone());
+        }
+        if self.wait {
+            signal::install_handler();
+            say!("waiting for a device in BOOTSEL mode, press Ctrl-C to give up");
+            builder = builder.wait(Duration::MAX);
+        }
+        if self.force {
+            builder = builder.force(true);
+        }
+        let mut conn = builder.build(ctx)?;
+        if let Some(tracing) = &self.trace {
+            let device = conn.transport().device();
+            *tracing.device.lock().unwrap() = (device.bus_number() as u16, device.address());
+            conn.set_trace(Some(tracing.trace.clone()));
+        }
+        Ok(conn)
+    }
+
+    // A connection builder with the config file's USB settings and the retry policy asked for
+    // with `--usb-attempts`, before picking a device. Its connections stop on Ctrl-C once the
+    // handler is installed
+    pub(super) fn builder(&self) -> picousb::PicobootConnectionBuilder {
+        let mut builder = self
+            .config
+            .apply(picousb::PicobootConnectionBuilder::new())
+            .cancel_token(signal::token());
+        if let Some((vid, pid)) = self.extra_id {
+            builder = builder.extra_id(vid, pid, picousb::TargetID::Rp2350);
+        }
+        if let Some(max_attempts) = self.usb_attempts {
+            builder = builder.retry_policy(picousb::RetryPolicy {
+                max_attempts,
+                ..Default::default()
+            });
+        }
+        builder
+    }
+
+    pub(super) fn context(&self) -> CliResult<rusb::Context> {
+        #[cfg(windows)]
+        if self.usbdk {
+            return rusb::Context::with_options(&[rusb::UsbOption::use_usbdk()])
+                .context("could not initialize libusb with UsbDk, is it installed?");
+        }
+        rusb::Context::new().context("could not initialize libusb")
+    }
+
+    // Opens the PICOBOOT device and resets its interface, ready for commands
+    pub(super) fn open(&self) -> CliResult<UsbConnection> {
+        let mut conn = self
+            .connect(self.context()?)
+            .context("could not open device")?;
+        conn.reset_interface()
+            .context("failed to reset interface")?;
+        Ok(conn)
+    }
+
+    // The firmware to flash, the inputs given or else the config file's `firmware`
+    pub(super) fn firmware(&self, inputs: &[String], command: &str) -> CliResult<Vec<String>> {
+        if !inputs.is_empty() {
+            return Ok(inputs.to_vec());
+        }
+        match &self.config.firmware {
+            Some(firmware) => Ok(vec![firmware.clone()]),
+            None => Err(failure(
+                ExitCode::Usage,
+                format!(
+                    "no firmware given, e.g. `picoboot {} fw_blink.uf2`",
+                    command
+                ),
+            )),
+        }
+    }
+
+    // Writes out the trace asked for with `--trace`, as JSON or pcapng by the file's extension
+    pub(super) fn write_trace(&self) -> CliResult {
+        let Some(tracing) = &self.trace else {
+            return Ok(());
+        };
+        let out = if tracing.path.ends_with(".pcapng") {
+            let (bus, device) = *tracing.device.lock().unwrap();
+            tracing.trace.to_pcapng(bus, device)
+        } else {
+            format!("{}\n", tracing.trace.to_json()).into_bytes()
+        };
+        std::fs::write(&tracing.path, out).context("failed to write trace")?;
+        say!(
+            "wrote {} transfers to {}",
+            tracing.trace.entries().len(),
+            tracing.path
+        );
+        Ok(())
+    }
+}
+
+// Where a device is plugged in as the operator knows it: the port path, which stays put as the
+// board reboots, or else its bus and address
+pub(super) fn device_name(bus: u8, address: u8, ports: &[u8]) -> String {
+    if ports.is_empty() {
+        return format!("{:03}:{:03}", bus, address);
+    }
+    let path = picousb::DeviceLocation::PortPath {
+        bus,
+        ports: ports.to_vec(),
+    };
+    path.to_string()
+}
+
+// Where the connected device is plugged in and its serial number, for the session report
+pub(super) fn connected_device<T: UsbContext>(
+    conn: &PicobootConnection<RusbTransport<T>>,
+) -> (String, Option<String>) {
+    let device = conn.transport().device();
+    let ports = device.port_numbers().unwrap_or_default();
+    let name = device_name(device.bus_number(), device.address(), &ports);
+    (name, conn.transport().strings().serial)
+}
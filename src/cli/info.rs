@@ -0,0 +1,310 @@
+// Subcommands describing the connected devices, or the programs in firmware files
+
+use super::globals::Globals;
+use super::output::{hex, report, CliResult, ErrorContext};
+use crate::{binary_info, image, json, otp, picousb, uf2};
+
+// Flash size of a listed device, which only the RP2350 bootrom reports. The device is opened
+// just long enough to ask, and left alone if something else is using it
+fn listed_flash_size(ctx: &rusb::Context, d: &picousb::DeviceInfo) -> Option<u32> {
+    if d.in_use != Some(false) || !matches!(d.target, picousb::TargetID::Rp2350) {
+        return None;
+    }
+    let location = picousb::DeviceLocation::BusAddress {
+        bus: d.bus,
+        address: d.address,
+    };
+    let mut conn = picousb::PicobootConnectionBuilder::new()
+        .location(location)
+        .build(ctx.clone())
+        .ok()?;
+    conn.reset_interface().ok()?;
+    conn.flash_size().ok()
+}
+
+// Lists every connected device in BOOTSEL mode as a table, to pick one for `--serial` or
+// `--device` from. Devices in use by another program are only listed, never claimed
+pub(super) fn list(globals: &Globals) -> CliResult {
+    let ctx = globals.context()?;
+    let devices = globals
+        .builder()
+        .list_devices(&ctx)
+        .context("failed to list devices")?;
+    if devices.is_empty() {
+        say!("no devices in BOOTSEL mode found");
+        report("devices", Vec::<json::Value>::new());
+        return Ok(());
+    }
+    let mut rows = vec![];
+    let mut found = vec![];
+    for d in devices {
+        let flash_size = listed_flash_size(&ctx, &d);
+        let path = (!d.ports.is_empty()).then(|| {
+            picousb::DeviceLocation::PortPath {
+                bus: d.bus,
+                ports: d.ports.clone(),
+            }
+            .to_string()
+        });
+        let state = match d.in_use {
+            Some(true) => "in use",
+            Some(false) => "free",
+            None => "unknown",
+        };
+        rows.push([
+            format!("{:03}:{:03}", d.bus, d.address),
+            path.clone().unwrap_or_else(|| "-".into()),
+            format!("{:?}", d.target),
+            d.serial.clone().unwrap_or_else(|| "unknown".into()),
+            d.product.clone().unwrap_or_else(|| "-".into()),
+            flash_size.map_or("-".into(), |s| format!("{} KiB", s / 1024)),
+            state.to_string(),
+        ]);
+        found.push(json::Value::Object(vec![
+            ("bus".into(), (d.bus as u32).into()),
+            ("address".into(), (d.address as u32).into()),
+            ("port".into(), path.into()),
+            ("chip".into(), format!("{:?}", d.target).into()),
+            ("serial".into(), d.serial.into()),
+            ("manufacturer".into(), d.manufacturer.into()),
+            ("product".into(), d.product.into()),
+            ("flash_size".into(), flash_size.into()),
+            ("in_use".into(), d.in_use.into()),
+        ]));
+    }
+
+    let header = [
+        "DEVICE", "PORT", "CHIP", "SERIAL", "PRODUCT", "FLASH", "STATE",
+    ]
+    .map(String::from);
+    let mut widths = [0; 7];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, w)| format!("{:<w$}", cell, w = w))
+            .collect();
+        say!("{}", cells.join("  ").trim_end());
+    }
+    report("devices", found);
+    Ok(())
+}
+
+// Prints what the binary info of a program says about it, like `picotool info`
+fn print_program_info(info: Option<binary_info::ProgramInfo>) {
+    let Some(info) = info else {
+        say!("program: none with binary info");
+        report("program", json::Value::Null);
+        return;
+    };
+
+    let strings = [
+        ("name", &info.name),
+        ("version", &info.version),
+        ("build date", &info.build_date),
+        ("url", &info.url),
+        ("description", &info.description),
+        ("sdk version", &info.sdk_version),
+        ("board", &info.board),
+        ("boot2", &info.boot2),
+    ];
+    let mut fields = vec![];
+    for (name, value) in strings {
+        if let Some(value) = value {
+            say!("program {}: {}", name, value);
+        }
+        fields.push((name.replace(' ', "_"), value.clone().into()));
+    }
+    if let Some(end) = info.binary_end {
+        say!(
+            "program size: {} bytes",
+            end.saturating_sub(picousb::PICO_FLASH_START)
+        );
+    }
+    fields.push(("binary_end".into(), info.binary_end.map(hex).into()));
+    for feature in &info.features {
+        say!("program feature: {}", feature);
+    }
+    for attr in &info.build_attributes {
+        say!("program build attribute: {}", attr);
+    }
+    fields.push(("features".into(), info.features.clone().into()));
+    fields.push((
+        "build_attributes".into(),
+        info.build_attributes.clone().into(),
+    ));
+    let mut pins = vec![];
+    for (pin, uses) in &info.pins {
+        say!("pin {}: {}", pin, uses.join(", "));
+        pins.push(json::Value::Object(vec![
+            ("pin".into(), (*pin as u32).into()),
+            ("uses".into(), uses.clone().into()),
+        ]));
+    }
+    fields.push(("pins".into(), pins.into()));
+    report("program", json::Value::Object(fields));
+}
+
+// Prints the binary info of firmware files, no device needed. Pin functions are numbered
+// differently on each chip, which only the family of a UF2 tells, so others are taken as RP2040s
+fn info_files(inputs: &[String]) -> CliResult {
+    let segments = image::load_inputs(inputs).map_err(|e| e.to_string())?;
+    let rp2350 = [
+        uf2::FAMILY_ID_RP2350_ARM_S,
+        uf2::FAMILY_ID_RP2350_RISCV,
+        uf2::FAMILY_ID_RP2350_ARM_NS,
+    ];
+    let target = if segments
+        .iter()
+        .any(|s| s.family.is_some_and(|f| rp2350.contains(&f)))
+    {
+        picousb::TargetID::Rp2350
+    } else {
+        picousb::TargetID::Rp2040
+    };
+    say!("chip: {:?}", target);
+    report("chip", format!("{:?}", target));
+    print_program_info(binary_info::ProgramInfo::from_segments(&segments, target));
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub(super) struct InfoArgs {
+    #[arg(value_name = "FILE[@ADDR]")]
+    inputs: Vec<String>,
+}
+
+// Prints what is known about the connected device, including the secure boot state of RP2350s.
+// Given firmware files it describes those instead
+pub(super) fn info(globals: &Globals, args: &InfoArgs) -> CliResult {
+    if !args.inputs.is_empty() {
+        return info_files(&args.inputs);
+    }
+
+    let mut conn = globals.open()?;
+    let target = conn.get_device_type().ok_or("no known RP chip found")?;
+    let identity = conn.identify().context("failed to identify chip")?;
+    match identity.revision() {
+        Some(revision) => say!("chip: {} {}", identity.model(), revision),
+        None => say!("chip: {}", identity.model()),
+    }
+    let strings = conn.transport().strings();
+    let unknown = || "unknown".to_string();
+    say!("serial: {}", strings.serial.clone().unwrap_or_else(unknown));
+    say!(
+        "usb: {}, {}",
+        strings.manufacturer.clone().unwrap_or_else(unknown),
+        strings.product.clone().unwrap_or_else(unknown)
+    );
+    report("serial", strings.serial);
+    report("manufacturer", strings.manufacturer);
+    report("product", strings.product);
+    report("chip", format!("{:?}", target));
+    report("model", identity.model());
+    if let Some(revision) = identity.revision() {
+        report("revision", revision);
+    }
+    print_program_info(conn.program_info().context("failed to read binary info")?);
+    if let picousb::TargetID::Rp2350 = target {
+        let sys = conn.sys_info().context("failed to get system info")?;
+        if let Some(chip) = sys.chip {
+            say!(
+                "device id: {:#010X}, wafer id: {:#010X}",
+                chip.device_id,
+                chip.wafer_id
+            );
+            report("device_id", hex(chip.device_id));
+            report("wafer_id", hex(chip.wafer_id));
+        }
+        if let Some(riscv) = sys.cpu_riscv {
+            let arch = if riscv { "RISC-V" } else { "ARM" };
+            say!("running on: {}", arch);
+            report("running_on", arch);
+        }
+        let mut flash = vec![];
+        for cs in 0..2 {
+            match sys.flash_size(cs) {
+                Some(0) | None => {}
+                Some(size) => {
+                    say!("flash on CS{}: {} KiB", cs, size / 1024);
+                    flash.push(json::Value::Object(vec![
+                        ("cs".into(), (cs as u32).into()),
+                        ("size".into(), size.into()),
+                    ]));
+                }
+            }
+        }
+        report("flash", flash);
+
+        let pt = conn
+            .get_partition_table()
+            .context("failed to read partition table")?;
+        let mut partitions = vec![];
+        if pt.has_table {
+            say!("partitions: {}", pt.partitions.len());
+            for (i, p) in pt.partitions.iter().enumerate() {
+                let (start, end) = p.range();
+                let families: Vec<String> =
+                    p.families().into_iter().map(uf2::family_id_name).collect();
+                let id =
+                    p.id.map_or(String::new(), |id| format!(" id: {:#018X}", id));
+                say!(
+                    "  {}: {:#010X}..{:#010X} {} families: {}{}",
+                    i,
+                    start,
+                    end,
+                    p.permissions,
+                    families.join(", "),
+                    id
+                );
+                partitions.push(json::Value::Object(vec![
+                    ("start".into(), hex(start)),
+                    ("end".into(), hex(end)),
+                    ("permissions".into(), p.permissions.to_string().into()),
+                    ("families".into(), families.into()),
+                    ("id".into(), p.id.map(|id| format!("{:#018X}", id)).into()),
+                ]));
+            }
+        } else {
+            say!("partitions: none, flash is unpartitioned");
+        }
+        report("partitioned", pt.has_table);
+        report("partitions", partitions);
+
+        let state = otp::SecureBootState::read(&mut conn).context("failed to read OTP")?;
+        say!(
+            "secure boot: {}",
+            if state.requires_signed_images() {
+                "enabled, only signed images will boot"
+            } else {
+                "disabled, unsigned images will boot"
+            }
+        );
+        say!("secure debug disabled: {}", state.secure_debug_disabled);
+        say!("debug disabled: {}", state.debug_disabled);
+        say!("glitch detector enabled: {}", state.glitch_detector_enabled);
+        let boot_arch = if state.boot_arch_riscv {
+            "RISC-V"
+        } else {
+            "ARM"
+        };
+        say!("boot architecture: {}", boot_arch);
+        let mut keys = vec![];
+        for (i, key) in state.boot_keys.iter().enumerate() {
+            say!("boot key {}: {:?}", i, key);
+            keys.push(format!("{:?}", key));
+        }
+        report("secure_boot", state.requires_signed_images());
+        report("secure_debug_disabled", state.secure_debug_disabled);
+        report("debug_disabled", state.debug_disabled);
+        report("glitch_detector_enabled", state.glitch_detector_enabled);
+        report("boot_architecture", boot_arch);
+        report("boot_keys", keys);
+    }
+    Ok(())
+}
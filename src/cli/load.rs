@@ -0,0 +1,618 @@
+// `load` and the subcommands built on it, flashing firmware to one device
+
+use super::args::{self, flash_addr};
+use super::globals::{connected_device, Globals};
+use super::output::{
+    failure, print_report, report, set_exit_code, CliResult, ErrorContext, ExitCode, ProgressBar,
+};
+use super::session::Session;
+use super::signal;
+use super::WATCH_POLL_INTERVAL;
+use crate::picousb::{self, PicobootConnection, UsbConnection, PICO_PAGE_SIZE};
+use crate::transport::Transport;
+use crate::{bootsel, image, json, msc, picobin, uf2, FlashAddr};
+use std::time::Duration;
+
+// How long `--wait-app` waits for the application to show up after rebooting
+const APP_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Whether flash already holds the firmware pages, going by CRCs the device computes itself, so
+// the image isn't read back over USB. The RP2350 can't, see `verify_crc32`. False if interrupted
+fn flash_matches_device_crc<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    fw_pages: &[(u32, Vec<u8>)],
+) -> CliResult<bool> {
+    for (addr, data) in image::page_runs(fw_pages) {
+        match conn.verify_crc32(flash_addr(addr)?, &data) {
+            Ok(()) => {}
+            Err(
+                picousb::PicobootError::CrcMismatch { .. } | picousb::PicobootError::Interrupted,
+            ) => return Ok(false),
+            Err(e) => return Err(e).context("failed to check flash"),
+        }
+    }
+    Ok(true)
+}
+
+// How a device is flashed, by `load`, `fleet` and `provision`
+pub(super) struct FlashOptions {
+    pub(super) skip_if_same: bool,
+    pub(super) skip_unchanged: bool,
+    pub(super) read_retries: u32,
+    pub(super) device_crc: bool,
+    pub(super) sequencing: picousb::CommandSequencing,
+    pub(super) reboot: Option<picousb::RebootMode>,
+    pub(super) reboot_delay: u32,
+    // say and report nothing while flashing, for `fleet` workers and `provision`, which print
+    // their own progress
+    pub(super) quiet: bool,
+}
+impl FlashOptions {
+    pub(super) fn verify(&self) -> picousb::VerifyMode {
+        if self.device_crc {
+            picousb::VerifyMode::DeviceCrc
+        } else {
+            picousb::VerifyMode::ReadBack {
+                retries: self.read_retries,
+            }
+        }
+    }
+}
+
+// How commands are sent, from `--picotool-compat`
+pub(super) fn sequencing(picotool_compat: bool) -> picousb::CommandSequencing {
+    if picotool_compat {
+        picousb::CommandSequencing::Picotool
+    } else {
+        picousb::CommandSequencing::Default
+    }
+}
+
+// The image as flashed to one kind of chip, which can differ as multi-family UF2s are split
+pub(super) struct PreparedImage {
+    pub(super) fw_pages: Vec<(u32, Vec<u8>)>,
+    pub(super) ram_segments: Vec<image::Segment>,
+    pub(super) reboot: Option<picousb::RebootMode>,
+}
+
+// How to reboot into an image. One living only in SRAM is started at the entry point of the first
+// ELF among the inputs, instead of booting from flash
+pub(super) fn entry_for(
+    inputs: &[String],
+    flash_segments: &[image::Segment],
+    target: picousb::TargetID,
+    reboot: Option<picousb::RebootMode>,
+) -> CliResult<Option<picousb::RebootMode>> {
+    if !flash_segments.is_empty() || reboot != Some(picousb::RebootMode::Normal) {
+        return Ok(reboot);
+    }
+    let pc = image::entry_point(inputs)
+        .map_err(|e| e.to_string())?
+        .ok_or("inputs only load SRAM, but no ELF gives an entry point to start it at")?;
+    let sp = match target {
+        picousb::TargetID::Rp2040 => picousb::PICO_STACK_POINTER,
+        picousb::TargetID::Rp2350 => picousb::PICO2_STACK_POINTER,
+    };
+    Ok(Some(picousb::RebootMode::Run { pc, sp }))
+}
+
+// Claims the device, flashes the image (unless `skip_if_same` and it already has it), loads its
+// SRAM and reboots it as the image says. Returns the bytes written to flash
+pub(super) fn flash_device<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    image: &PreparedImage,
+    opts: &FlashOptions,
+) -> CliResult<usize> {
+    let say = |msg: &str| {
+        if !opts.quiet {
+            say!("{}", msg);
+        }
+    };
+    let note = |key: &str, value: json::Value| {
+        if !opts.quiet {
+            report(key, value);
+        }
+    };
+    let interrupted = || {
+        say("interrupted, releasing device");
+        Err(failure(ExitCode::Interrupted, "interrupted"))
+    };
+    let fw_pages = &image.fw_pages;
+
+    say("resetting interface");
+    conn.reset_interface()
+        .context("failed to reset interface")?;
+    say("reset interface");
+    say("claiming access");
+    // picotool only asks for exclusive access, without ejecting the mass storage drive.
+    // Access is given back and the interface reset if we stop before rebooting
+    let eject = opts.sequencing == picousb::CommandSequencing::Default;
+    let mut conn = conn
+        .exclusive_access_guard(eject)
+        .context("failed to claim access")?
+        .reset_on_drop(true);
+    say("claimed access");
+    conn.exit_xip().context("failed to exit from xip mode")?;
+    // only checked now, as on an RP2040 finding the flash size runs code on the device
+    if let (Some((start, _)), Some((last, _))) = (fw_pages.first(), fw_pages.last()) {
+        let size = last + PICO_PAGE_SIZE as u32 - start;
+        conn.check_flash_range(flash_addr(*start)?, size)
+            .context("the image doesn't fit in flash")?;
+    }
+
+    // however flashing is verified, the device is asked for CRCs rather than reading it all back
+    let already_flashed = opts.skip_if_same && {
+        say("checking flash against image");
+        flash_matches_device_crc(&mut conn, fw_pages)?
+    };
+    if signal::interrupted() {
+        return interrupted();
+    }
+
+    note("already_flashed", already_flashed.into());
+    let mut written = 0;
+    if already_flashed {
+        say("device already has this image, skipping flash");
+    } else {
+        // each contiguous run of pages is programmed and verified in one go
+        for (addr, data) in image::page_runs(fw_pages) {
+            say(&format!(
+                "programming {} bytes at addr={:#X}",
+                data.len(),
+                addr
+            ));
+            match conn.flash_program(flash_addr(addr)?, &data, opts.verify()) {
+                Ok(n) => {
+                    say("\tprogram success");
+                    written += n;
+                }
+                Err(picousb::PicobootError::Interrupted) => return interrupted(),
+                Err(e) => return Err(e).context("failed to program flash"),
+            }
+        }
+        say("sector success!!!");
+        note("bytes_written", written.into());
+    }
+
+    // SRAM needs no erase, and is loaded every time as it doesn't survive a reboot anyway
+    let ram_segments = &image.ram_segments;
+    if !ram_segments.is_empty() {
+        let bytes: usize = ram_segments.iter().map(|s| s.data.len()).sum();
+        say(&format!(
+            "loading {} bytes into SRAM in {} segments",
+            bytes,
+            ram_segments.len()
+        ));
+        conn.load_ram(ram_segments)
+            .context("failed to write SRAM")?;
+        note("ram_bytes_written", bytes.into());
+    }
+
+    // leave the device in BOOTSEL, so further commands can be run against it
+    let Some(reboot) = image.reboot else {
+        say("not rebooting, device left in BOOTSEL");
+        return Ok(written);
+    };
+    conn.reboot_into(reboot, opts.reboot_delay)
+        .context("failed to reboot device")?;
+    conn.disarm();
+
+    say("reboot success");
+    note("rebooted", true.into());
+    Ok(written)
+}
+
+// Loads the inputs, keeping only the parts of multi-family UF2s meant for `target`. With
+// `ignore_family` UF2 blocks for other chips are written anyway
+pub(super) fn target_segments(
+    inputs: &[String],
+    target: picousb::TargetID,
+    ignore_family: bool,
+) -> CliResult<Vec<image::Segment>> {
+    let segments = image::load_inputs(inputs).map_err(|e| e.to_string())?;
+
+    let foreign: Vec<String> = uf2::foreign_families(&segments, target)
+        .into_iter()
+        .map(uf2::family_id_name)
+        .collect();
+    if foreign.is_empty() {
+        return Ok(segments);
+    }
+    if ignore_family {
+        say!(
+            "Warning: writing UF2 blocks for {} to the connected {:?}",
+            foreign.join(", "),
+            target
+        );
+        return Ok(segments);
+    }
+
+    let segments = uf2::filter_for_target(segments, target);
+    if segments.is_empty() {
+        set_exit_code(ExitCode::Incompatible);
+        return Err(format!(
+            "inputs are for {}, not the connected {:?} (pass --ignore-family to write them anyway)",
+            foreign.join(", "),
+            target
+        ));
+    }
+    say!(
+        "skipping UF2 blocks for {}, which the {:?} doesn't accept",
+        foreign.join(", "),
+        target
+    );
+    Ok(segments)
+}
+
+// Checks an RP2350 image at the start of flash the way the bootrom will, so one it won't boot is
+// refused before anything is erased, returning the architecture it's built for. Data written
+// elsewhere in flash (e.g. a filesystem) isn't an image, so it's left alone
+pub(super) fn check_image(
+    segments: &[image::Segment],
+    target: picousb::TargetID,
+) -> CliResult<Option<picobin::Cpu>> {
+    let flash = || segments.iter().filter(|s| FlashAddr::new(s.addr).is_some());
+    let base = picousb::PICO_FLASH_START;
+    if !matches!(target, picousb::TargetID::Rp2350) || flash().all(|s| s.addr != base) {
+        return Ok(None);
+    }
+    let end = flash().map(|s| s.end()).max().unwrap();
+    let data = image::read_range(segments, base, (end - base as u64) as u32);
+    let def = match picobin::check_image(&data, base) {
+        Ok(Some(def)) => def,
+        Ok(None) => {
+            say!("image is a partition table, not checking it");
+            return Ok(None);
+        }
+        Err(e) => {
+            return Err(failure(
+                ExitCode::Incompatible,
+                format!(
+                    "the RP2350 won't boot this image: {} (pass --no-image-check to flash it \
+                     anyway)",
+                    e
+                ),
+            ))
+        }
+    };
+    let family_cpu = match flash().find_map(|s| s.family) {
+        Some(uf2::FAMILY_ID_RP2350_ARM_S | uf2::FAMILY_ID_RP2350_ARM_NS) => Some(picobin::Cpu::Arm),
+        Some(uf2::FAMILY_ID_RP2350_RISCV) => Some(picobin::Cpu::RiscV),
+        _ => None,
+    };
+    if let Some(cpu) = family_cpu.filter(|&cpu| cpu != def.cpu) {
+        set_exit_code(ExitCode::Incompatible);
+        return Err(format!(
+            "the image is built for {} but its UF2 family is for {} (pass --no-image-check to \
+             flash it anyway)",
+            def.cpu, cpu
+        ));
+    }
+    say!(
+        "image checked: {}{}{}",
+        def.cpu,
+        if def.hashed { ", hash matches" } else { "" },
+        if def.signed {
+            ", signature matches"
+        } else {
+            ""
+        }
+    );
+    report("image_cpu", def.cpu.to_string());
+    Ok(Some(def.cpu))
+}
+
+// Switches the architecture the device reboots into, for `--arch`
+pub(super) fn set_reboot_arch(
+    conn: &mut UsbConnection,
+    arch: Option<picousb::RebootArch>,
+) -> CliResult {
+    match conn.set_reboot_arch(arch) {
+        Err(picousb::PicobootError::NotSupported) => Err("--arch needs an RP2350".into()),
+        res => res.context("failed to set the reboot architecture"),
+    }
+}
+
+// Flashes through the BOOTSEL mass storage drive instead of PICOBOOT. The bootrom always reboots
+// into the new firmware once it's copied, so none of the reboot options apply here
+fn load_msc(inputs: &[String], ignore_family: bool, image_check: bool) -> CliResult {
+    let drive = msc::find_bootsel_drive().ok_or("no BOOTSEL drive found either")?;
+    say!(
+        "found {:?} BOOTSEL drive at {}",
+        drive.target,
+        drive.path.display()
+    );
+
+    let segments = target_segments(inputs, drive.target, ignore_family)?;
+    if image_check {
+        check_image(&segments, drive.target)?;
+    }
+    let family = match drive.target {
+        picousb::TargetID::Rp2040 => uf2::FAMILY_ID_RP2040,
+        picousb::TargetID::Rp2350 => uf2::FAMILY_ID_RP2350_ARM_S,
+    };
+    let uf2 = uf2::encode(&segments, family).map_err(|e| e.to_string())?;
+    drive
+        .flash_uf2(&uf2, Duration::from_secs(10))
+        .context("failed to flash through BOOTSEL drive")?;
+    say!("flashed through BOOTSEL drive, device has rebooted");
+    Ok(())
+}
+
+// The options of `load`, which `watch` and `run` take too
+#[derive(clap::Args)]
+pub(super) struct LoadOptions {
+    #[arg(long, help = "don't flash if the device already has the image")]
+    skip_if_same: bool,
+    #[arg(long, help = "only erase and write sectors that differ from the image")]
+    skip_unchanged: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        help = "re-read failed or mismatching pages N times"
+    )]
+    read_retries: u32,
+    #[arg(long, help = "verify by CRC computed on the device (RP2040 only)")]
+    device_crc: bool,
+    #[arg(long, help = "leave the device in BOOTSEL after flashing")]
+    no_reboot: bool,
+    #[arg(long, conflicts_with = "no_reboot", help = "reboot back into BOOTSEL")]
+    reboot_bootsel: bool,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 500,
+        help = "delay before rebooting"
+    )]
+    reboot_delay: u32,
+    #[arg(long, help = "wait for the application to enumerate after rebooting")]
+    wait_app: bool,
+    #[arg(long, help = "print a report of the USB connection")]
+    diagnostics: bool,
+    #[arg(long, help = "flash through the BOOTSEL drive if PICOBOOT fails")]
+    msc_fallback: bool,
+    #[arg(long, help = "sequence commands exactly like picotool")]
+    picotool_compat: bool,
+    #[arg(
+        long,
+        help = "write UF2 blocks even if their family is for another chip"
+    )]
+    ignore_family: bool,
+    #[arg(long, help = "flash RP2350 images the bootrom would refuse to boot")]
+    no_image_check: bool,
+    #[arg(
+        long,
+        value_parser = args::arch,
+        help = "reboot the cores as arm or riscv (RP2350 only)"
+    )]
+    arch: Option<picousb::RebootArch>,
+}
+
+#[derive(clap::Args)]
+pub(super) struct LoadArgs {
+    #[command(flatten)]
+    options: LoadOptions,
+    #[arg(value_name = "FILE[@ADDR]")]
+    inputs: Vec<String>,
+}
+
+// Flashes firmware inputs to a connected device, then reboots it
+pub(super) fn load(globals: &Globals, args: &LoadArgs) -> CliResult {
+    let inputs = globals.firmware(&args.inputs, "load")?;
+    load_inputs(globals, &args.options, &inputs)
+}
+
+fn load_inputs(globals: &Globals, opts: &LoadOptions, inputs: &[String]) -> CliResult {
+    signal::install_handler();
+
+    let arch = opts.arch;
+    let image_check = !opts.no_image_check;
+    let sequencing = sequencing(opts.picotool_compat);
+    let reboot = match (opts.no_reboot, opts.reboot_bootsel) {
+        (true, _) => None,
+        (_, true) => Some(picousb::RebootMode::Bootsel),
+        _ => Some(picousb::RebootMode::Normal),
+    };
+
+    // create connection object
+    let mut conn = match globals.connect(globals.context()?) {
+        Ok(conn) => conn,
+        Err(e) if opts.msc_fallback => {
+            say!("Could not use PICOBOOT ({}), trying the BOOTSEL drive", e);
+            if arch.is_some() {
+                say!("Warning: --arch doesn't apply to the BOOTSEL drive, the bootrom picks");
+            }
+            return load_msc(inputs, opts.ignore_family, image_check);
+        }
+        Err(e) => return Err(e).context("could not open device"),
+    };
+    conn.set_sequencing(sequencing);
+    conn.set_skip_unchanged(opts.skip_unchanged);
+    // only writing is shown, erasing and verifying follow it a sector at a time
+    let mut bar = ProgressBar::new("written");
+    conn.set_progress_handler(move |p| {
+        if p.op == picousb::ProgressOp::Write {
+            bar.update(p);
+        }
+    });
+
+    say!("Connected to PicoBoot!");
+
+    let target = conn.get_device_type().ok_or("no known RP chip found")?;
+    report("chip", format!("{:?}", target));
+    if opts.device_crc && target == picousb::TargetID::Rp2350 {
+        return Err(failure(
+            ExitCode::Incompatible,
+            "--device-crc needs an RP2040",
+        ));
+    }
+    set_reboot_arch(&mut conn, arch)?;
+    let segments = target_segments(inputs, target, opts.ignore_family)?;
+    let image_cpu = if image_check {
+        check_image(&segments, target)?
+    } else {
+        None
+    };
+    if let (Some(cpu), Some(arch)) = (image_cpu, arch) {
+        // Varmulet images run on the ARM cores
+        let image_arch = match cpu {
+            picobin::Cpu::RiscV => picousb::RebootArch::RiscV,
+            picobin::Cpu::Arm | picobin::Cpu::Varmulet => picousb::RebootArch::Arm,
+        };
+        if image_arch != arch {
+            say!(
+                "Warning: the image is built for {} but the device will reboot as {:?}",
+                cpu,
+                arch
+            );
+        }
+    }
+    let (flash_segments, ram_segments) = image::split_flash_ram(segments);
+    let reboot = entry_for(inputs, &flash_segments, target, reboot)?;
+    let image = PreparedImage {
+        fw_pages: image::pages(&flash_segments).map_err(|e| e.to_string())?,
+        ram_segments,
+        reboot,
+    };
+    let flash_opts = FlashOptions {
+        skip_if_same: opts.skip_if_same,
+        skip_unchanged: opts.skip_unchanged,
+        read_retries: opts.read_retries,
+        device_crc: opts.device_crc,
+        sequencing,
+        reboot,
+        reboot_delay: opts.reboot_delay,
+        quiet: false,
+    };
+
+    let (location, serial) = connected_device(&conn);
+    let session = Session {
+        command: "load",
+        started: std::time::SystemTime::now(),
+        location,
+        serial,
+        chip: target,
+        inputs,
+        verify: flash_opts.verify(),
+    };
+    let start = std::time::Instant::now();
+    let res = flash_device(&mut conn, &image, &flash_opts);
+    // report diagnostics even when flashing fails part way, as that's when they matter
+    if opts.diagnostics {
+        say!("connection diagnostics:\n{}", conn.diagnostics());
+    }
+    report("sectors_skipped", conn.diagnostics().sectors_skipped);
+    let recorded = globals.record_session(&session, &res, start.elapsed());
+    res?;
+    recorded?;
+
+    let rebooted_into_app = matches!(
+        reboot,
+        Some(picousb::RebootMode::Normal | picousb::RebootMode::Run { .. })
+    );
+    if opts.wait_app && rebooted_into_app {
+        let device = conn.transport().device();
+        let ports = device.port_numbers().unwrap_or_default();
+        if ports.is_empty() {
+            say!("can't tell where the device is plugged in, not waiting for the application");
+            return Ok(());
+        }
+        let location = picousb::DeviceLocation::PortPath {
+            bus: device.bus_number(),
+            ports,
+        };
+        drop(conn);
+        say!("waiting for the application to enumerate at {}", location);
+        let found = bootsel::wait_for_application(
+            &globals.context()?,
+            &location,
+            APP_WAIT_TIMEOUT,
+            Some(&signal::token()),
+        )
+        .context("failed waiting for the application")?;
+        report("application_enumerated", found);
+        if !found {
+            return Err(format!(
+                "nothing enumerated at {} within {}s of rebooting",
+                location,
+                APP_WAIT_TIMEOUT.as_secs()
+            ));
+        }
+        say!("application is up");
+    }
+    Ok(())
+}
+
+// Modification time and size of a watched file, None while it doesn't exist (e.g. part way
+// through a rebuild)
+fn file_stamp(path: &str) -> Option<(std::time::SystemTime, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+// Re-flashes whenever the firmware files change, for an edit-build-flash loop. The device is
+// waited for and forced into BOOTSEL each time, so whatever it's running is replaced. Failures
+// are reported and the next change waited for, until Ctrl-C
+pub(super) fn watch(globals: &Globals, args: &LoadArgs) -> CliResult {
+    let inputs = globals.firmware(&args.inputs, "watch")?;
+    let paths: Vec<&str> = inputs
+        .iter()
+        .map(|arg| arg.rsplit_once('@').map_or(arg.as_str(), |(path, _)| path))
+        .collect();
+    signal::install_handler();
+    let globals = globals.waiting().forcing();
+
+    let stamps = || paths.iter().map(|p| file_stamp(p)).collect::<Vec<_>>();
+    let mut flashed = stamps();
+    say!("watching {}, press Ctrl-C to stop", paths.join(", "));
+    loop {
+        // a change only counts once the files have settled, so half written ones aren't flashed
+        let mut last = stamps();
+        loop {
+            std::thread::sleep(WATCH_POLL_INTERVAL);
+            if signal::interrupted() {
+                return Ok(());
+            }
+            let now = stamps();
+            if now == last && now != flashed && now.iter().all(Option::is_some) {
+                break;
+            }
+            last = now;
+        }
+        flashed = last;
+
+        say!("firmware changed, flashing");
+        let res = load_inputs(&globals, &args.options, &inputs);
+        if res.is_err() && signal::interrupted() {
+            return Ok(());
+        }
+        print_report("load", &res);
+        match res {
+            Ok(()) => say!("flashed, watching for changes"),
+            Err(e) => say!("flashing failed: {}, watching for changes", e),
+        }
+    }
+}
+
+#[derive(clap::Args)]
+#[command(trailing_var_arg = true)]
+pub(super) struct RunArgs {
+    #[command(flatten)]
+    options: LoadOptions,
+    #[arg(value_name = "ELF")]
+    elf: String,
+    #[arg(allow_hyphen_values = true, hide = true)]
+    args: Vec<String>,
+}
+
+// Flashes and starts an ELF when used as a cargo runner, i.e. `runner = "picoboot run"` in
+// `.cargo/config.toml`. Cargo appends the program's arguments after the ELF, which mean nothing to
+// a Pico and are dropped. A board still running the previous build is forced into BOOTSEL
+pub(super) fn runner(globals: &Globals, args: &RunArgs) -> CliResult {
+    load_inputs(
+        &globals.forcing(),
+        &args.options,
+        std::slice::from_ref(&args.elf),
+    )
+}
@@ -0,0 +1,199 @@
+// The `picoboot` command line flasher, one module per group of subcommands. Each subcommand takes
+// the global options resolved into `Globals` and its own arguments, parsed by clap
+
+#[macro_use]
+mod output;
+mod args;
+mod bench;
+mod capture;
+mod device;
+mod fleet;
+mod globals;
+mod info;
+mod load;
+mod offline;
+mod otp;
+mod partition;
+mod provision;
+mod session;
+mod signal;
+
+use crate::extension;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use globals::{GlobalArgs, Globals};
+use output::{exit_code, failure, print_report, CliResult, ExitCode};
+use std::time::Duration;
+
+// How often `watch` checks the firmware files, and `fleet` and `provision` look for devices
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Parser)]
+#[command(
+    name = "picoboot",
+    about = "Flash and manage RP2040 and RP2350 devices over PICOBOOT"
+)]
+struct Cli {
+    #[command(flatten)]
+    global: GlobalArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "flash firmware (UF2, ELF, HEX or raw binary) and reboot")]
+    Load(load::LoadArgs),
+    #[command(about = "load with -f and start an ELF, for use as a cargo runner")]
+    Run(load::RunArgs),
+    #[command(about = "load with -w and -f every time the firmware changes")]
+    Watch(load::LoadArgs),
+    #[command(about = "flash every connected device at once, e.g. a hub full of boards")]
+    Fleet(fleet::FleetArgs),
+    #[command(about = "flash a self-test, check it passed, then flash the production image")]
+    Provision(provision::ProvisionArgs),
+    #[command(about = "reboot the device, into its firmware or somewhere else")]
+    Reboot(device::RebootArgs),
+    #[command(about = "erase whole sectors of flash, or all of it")]
+    Erase(device::EraseArgs),
+    #[command(about = "compare flash against firmware, without writing anything")]
+    Verify(device::VerifyArgs),
+    #[command(about = "read the program in flash (or more) back into a file")]
+    Save(device::SaveArgs),
+    #[command(about = "list connected devices in BOOTSEL mode, with their serial and port")]
+    List,
+    #[command(about = "show the chip and its program, or the program in firmware files")]
+    Info(info::InfoArgs),
+    #[command(about = "read and write OTP rows by name or number (RP2350 only)")]
+    Otp(otp::OtpArgs),
+    #[command(about = "build an RP2350 partition table, into a file or onto the device")]
+    Partition(partition::PartitionArgs),
+    #[command(about = "run a stub in SRAM and print what it returns (RP2040 only)")]
+    Exec(device::ExecArgs),
+    #[command(about = "repeatedly erase, write and verify a scratch region")]
+    Stress(bench::StressArgs),
+    #[command(about = "measure erase, write and read speed over a scratch region")]
+    Bench(bench::BenchArgs),
+    #[command(about = "combine inputs into a single UF2, no device needed")]
+    Merge(offline::MergeArgs),
+    #[command(about = "sign an RP2350 image for secure boot, no device needed")]
+    Sign(offline::SignArgs),
+    #[command(about = "split a UF2 into one file per family, no device needed")]
+    Split(offline::SplitArgs),
+    #[command(about = "decode PICOBOOT traffic in a usbmon, pcap or pcapng capture")]
+    Decode(offline::DecodeArgs),
+    #[command(about = "replay a --trace session against the mock device, no device needed")]
+    Replay(offline::ReplayArgs),
+    #[command(about = "list subcommands added by extensions")]
+    Extensions,
+    // a subcommand added by an extension, with the arguments following its name
+    #[command(external_subcommand)]
+    External(Vec<String>),
+}
+
+static LOGGER: output::StderrLogger = output::StderrLogger;
+
+// Runs the CLI with the given extensions available as extra subcommands, and exits with its
+// exit code
+pub fn main(extensions: &extension::Extensions) {
+    log::set_logger(&LOGGER).ok();
+    log::set_max_level(log::LevelFilter::Info);
+
+    // extensions are listed in the help, as they aren't known to clap. The built in subcommands
+    // come first, so one named the same can't be run
+    let mut command = Cli::command();
+    let mut listed = vec![];
+    for ext in extensions.iter() {
+        if command.find_subcommand(ext.name()).is_some() {
+            log::warn!(
+                "extension {} is hidden by the built in subcommand",
+                ext.name()
+            );
+        } else {
+            listed.push(format!("  {:<12}{}", ext.name(), ext.usage()));
+        }
+    }
+    if !listed.is_empty() {
+        command = command.after_help(format!("Extensions:\n{}", listed.join("\n")));
+    }
+    let matches = command.get_matches();
+    let name = matches.subcommand_name().unwrap_or("help").to_string();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    log::set_max_level(cli.global.log_level());
+    output::JSON.store(cli.global.json, std::sync::atomic::Ordering::Relaxed);
+    let res = cli.global.resolve().and_then(|globals| {
+        let res = run(&globals, cli.command, extensions);
+        // the trace is written even when the command fails, as that's when it's wanted
+        match (res, globals.write_trace()) {
+            (Ok(()), traced) => traced,
+            (Err(e), Err(traced)) => Err(format!("{}\n{}", e, traced)),
+            (Err(e), Ok(())) => Err(e),
+        }
+    });
+    print_report(&name, &res);
+    if let Err(e) = &res {
+        eprintln!("error: {}", e);
+    }
+    match exit_code(&res) {
+        0 => {}
+        code => std::process::exit(code),
+    }
+}
+
+fn run(
+    globals: &Globals,
+    command: Option<Command>,
+    extensions: &extension::Extensions,
+) -> CliResult {
+    let Some(command) = command else {
+        Cli::command().print_help().ok();
+        return Ok(());
+    };
+    match command {
+        Command::Load(args) => load::load(globals, &args),
+        Command::Run(args) => load::runner(globals, &args),
+        Command::Watch(args) => load::watch(globals, &args),
+        Command::Fleet(args) => fleet::fleet(globals, &args),
+        Command::Provision(args) => provision::provision(globals, &args),
+        Command::Reboot(args) => device::reboot(globals, &args),
+        Command::Erase(args) => device::erase(globals, &args),
+        Command::Verify(args) => device::verify(globals, &args),
+        Command::Save(args) => device::save(globals, &args),
+        Command::List => info::list(globals),
+        Command::Info(args) => info::info(globals, &args),
+        Command::Otp(args) => otp::otp(globals, &args),
+        Command::Partition(args) => partition::partition(globals, &args),
+        Command::Exec(args) => device::exec(globals, &args),
+        Command::Stress(args) => bench::stress(globals, &args),
+        Command::Bench(args) => bench::bench(globals, &args),
+        Command::Merge(args) => offline::merge(&args),
+        Command::Sign(args) => offline::sign(&args),
+        Command::Split(args) => offline::split(&args),
+        Command::Decode(args) => offline::decode(globals, &args),
+        Command::Replay(args) => offline::replay(&args),
+        Command::Extensions => {
+            for ext in extensions.iter() {
+                say!("{} {}", ext.name(), ext.usage());
+            }
+            Ok(())
+        }
+        Command::External(args) => match extensions.find(&args[0]) {
+            Some(ext) => run_extension(globals, ext, &args[1..]),
+            None => Err(failure(
+                ExitCode::Usage,
+                format!("unknown command: {}, see `picoboot help`", args[0]),
+            )),
+        },
+    }
+}
+
+// Opens the device and hands it to an extension's subcommand
+fn run_extension(
+    globals: &Globals,
+    ext: &dyn extension::PicobootExtension,
+    args: &[String],
+) -> CliResult {
+    let mut conn = globals.open()?;
+    ext.run(&mut conn, args)
+        .map_err(|e| format!("{} failed: {}", ext.name(), e))
+}
@@ -0,0 +1,234 @@
+// Subcommands working on files alone, no device needed
+
+use super::args;
+use super::capture;
+use super::globals::Globals;
+use super::output::{report, CliResult, ErrorContext};
+use crate::{image, keys, picobin, picousb, replay, trace, uf2};
+
+#[derive(clap::Args)]
+pub(super) struct MergeArgs {
+    #[arg(required = true, value_name = "FILE[@ADDR]")]
+    inputs: Vec<String>,
+    #[arg(short, long, value_name = "OUT.UF2")]
+    output: String,
+    #[arg(
+        long,
+        value_name = "NAME",
+        value_parser = args::family,
+        help = "family for inputs without one, e.g. rp2350-arm-s"
+    )]
+    family: Option<u32>,
+}
+
+// Combines firmware inputs offline into a single UF2, no device needed
+pub(super) fn merge(args: &MergeArgs) -> CliResult {
+    let segments = image::load_inputs(&args.inputs).map_err(|e| e.to_string())?;
+
+    // inputs without a family (ELF, BIN) take the family of the UF2 inputs, if they agree on one
+    let family = args.family.unwrap_or_else(|| {
+        let mut families: Vec<u32> = segments.iter().filter_map(|s| s.family).collect();
+        families.dedup();
+        match families[..] {
+            [f] => f,
+            _ => uf2::FAMILY_ID_RP2040,
+        }
+    });
+
+    let out = uf2::encode(&segments, family).map_err(|e| e.to_string())?;
+    std::fs::write(&args.output, out).context("failed to write output")?;
+    say!("merged {} inputs into {}", args.inputs.len(), args.output);
+    report("output", args.output.as_str());
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub(super) struct SplitArgs {
+    #[arg(value_name = "FILE.UF2")]
+    input: std::path::PathBuf,
+    #[arg(
+        short,
+        long = "output",
+        value_name = "DIR",
+        help = "where to write the files (next to the input)"
+    )]
+    out_dir: Option<std::path::PathBuf>,
+}
+
+// Splits a multi-family UF2 into one UF2 file per family, no device needed
+pub(super) fn split(args: &SplitArgs) -> CliResult {
+    let input = &args.input;
+    let out_dir = match &args.out_dir {
+        Some(dir) => dir.clone(),
+        None => input.parent().unwrap().to_path_buf(),
+    };
+
+    let bytes = std::fs::read(input).context("failed to read input")?;
+    let segments = uf2::decode(&bytes).map_err(|e| e.to_string())?;
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let mut outputs = vec![];
+    for (family, segs) in uf2::split_families(&segments, uf2::FAMILY_ID_RP2040) {
+        let name = uf2::family_id_name(family);
+        let path = out_dir.join(format!("{}.{}.uf2", stem, name));
+        let out = uf2::encode(&segs, family).map_err(|e| e.to_string())?;
+        std::fs::write(&path, &out).context("failed to write output")?;
+        say!(
+            "wrote {} blocks for {} to {}",
+            out.len() / uf2::UF2_BLOCK_SIZE,
+            name,
+            path.display()
+        );
+        outputs.push(path.display().to_string());
+    }
+    report("outputs", outputs);
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub(super) struct SignArgs {
+    #[arg(value_name = "FILE[@ADDR]")]
+    input: String,
+    #[arg(short, long, value_name = "OUT.UF2|OUT.BIN")]
+    output: String,
+    #[arg(long, value_name = "FILE", help = "secp256k1 private key, PEM or DER")]
+    key: String,
+    #[arg(
+        long,
+        value_name = "NAME",
+        value_parser = args::family,
+        help = "family of the UF2 output [default: rp2350-arm-s]"
+    )]
+    family: Option<u32>,
+}
+
+// Signs an RP2350 image for secure boot, no device needed. The signature covers the image as it
+// sits in flash, so only flash images can be signed, and are written as UF2 or BIN
+pub(super) fn sign(args: &SignArgs) -> CliResult {
+    let output = &args.output;
+    let uf2_output = if output.ends_with(".uf2") {
+        true
+    } else if output.ends_with(".bin") {
+        false
+    } else {
+        return Err(format!(
+            "can't write {}, sign writes .uf2 or .bin files",
+            output
+        ));
+    };
+
+    let bytes = std::fs::read(&args.key).context("failed to read private key")?;
+    let key = keys::PrivateKey::parse(&bytes).context("failed to read private key")?;
+
+    let segments =
+        image::load_inputs(std::slice::from_ref(&args.input)).map_err(|e| e.to_string())?;
+    let (flash, ram) = image::split_flash_ram(segments);
+    if !ram.is_empty() {
+        return Err("only images run from flash can be signed".into());
+    }
+    let family = args
+        .family
+        .or_else(|| flash.iter().find_map(|s| s.family))
+        .unwrap_or(uf2::FAMILY_ID_RP2350_ARM_S);
+    let base = flash.first().ok_or("nothing to sign in the input")?.addr;
+    let end = flash.last().unwrap().end();
+    let mut data = image::read_range(&flash, base, (end - base as u64) as u32);
+
+    picobin::sign(&mut data, base, &key).context("failed to sign image")?;
+    let size = data.len();
+    let out = if uf2_output {
+        let segment = image::Segment {
+            addr: base,
+            data,
+            family: Some(family),
+        };
+        uf2::encode(&[segment], family).map_err(|e| e.to_string())?
+    } else {
+        data
+    };
+    std::fs::write(output, out).context("failed to write output")?;
+    say!("signed {} bytes at addr={:#X} into {}", size, base, output);
+    report("output", output.as_str());
+    Ok(())
+}
+
+#[derive(clap::Args)]
+#[command(
+    after_help = "The global --device BUS:ADDRESS picks the device to decode, otherwise \
+                        it's the first sending PICOBOOT commands"
+)]
+pub(super) struct DecodeArgs {
+    #[arg(value_name = "CAPTURE")]
+    input: String,
+}
+
+// Prints the PICOBOOT traffic in a USB capture, no device needed. The global `--device`
+// picks the device to decode, as BUS:ADDRESS
+pub(super) fn decode(globals: &Globals, args: &DecodeArgs) -> CliResult {
+    let device = match &globals.location {
+        Some(picousb::DeviceLocation::BusAddress { bus, address }) => Some((*bus as u16, *address)),
+        Some(_) => return Err("decode needs --device as BUS:ADDRESS".into()),
+        None => None,
+    };
+
+    let bytes = std::fs::read(&args.input).context("failed to read capture")?;
+    let transfers = capture::parse(&bytes).context("failed to read capture")?;
+    let (bus, addr) = device
+        .or_else(|| capture::find_device(&transfers))
+        .ok_or("no PICOBOOT commands in the capture")?;
+    let events = capture::decode(&transfers, (bus, addr));
+    say!("device {}:{}, {} events", bus, addr, events.len());
+    let start = events.first().map_or(0, |(t, _)| *t);
+    for (time, event) in &events {
+        let secs = time.saturating_sub(start) as f64 / 1e6;
+        say!("{:>12.6} {}", secs, event);
+    }
+    report("device", format!("{}:{}", bus, addr));
+    report(
+        "events",
+        events
+            .iter()
+            .map(|(_, e)| e.to_string())
+            .collect::<Vec<_>>(),
+    );
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub(super) struct ReplayArgs {
+    #[arg(value_name = "TRACE.JSON")]
+    input: String,
+    #[arg(
+        long,
+        value_parser = args::chip,
+        help = "rp2040 or rp2350, if the trace doesn't say"
+    )]
+    chip: Option<picousb::TargetID>,
+}
+
+// Replays a session recorded with `--trace` against the mock device, failing if the mock answers
+// differently, so CI can check the mock still behaves like the device it was recorded from
+pub(super) fn replay(args: &ReplayArgs) -> CliResult {
+    let text = std::fs::read_to_string(&args.input).context("failed to read trace")?;
+    let trace = trace::Trace::from_json(&text).context("failed to read trace")?;
+    let target = trace
+        .target()
+        .or(args.chip)
+        .ok_or("the trace doesn't say which chip it's from, use --chip")?;
+    let entries = trace.entries();
+    let mut mock = replay::mock_for(&entries, target);
+    let mismatches = replay::replay_against_mock(&entries, &mut mock);
+    for (i, d) in &mismatches {
+        say!("transfer {}: {}", i, d);
+    }
+    report("transfers", entries.len());
+    report("mismatches", mismatches.len());
+    if !mismatches.is_empty() {
+        return Err(format!(
+            "mock differs from the recording in {} of {} transfers",
+            mismatches.len(),
+            entries.len()
+        ));
+    }
+    say!("replayed {} transfers, mock matches", entries.len());
+    Ok(())
+}
@@ -0,0 +1,722 @@
+// The `otp` subcommands, reading and writing the RP2350's one time programmable memory
+
+use super::args;
+use super::globals::Globals;
+use super::output::{failure, hex, print_report, report, CliResult, ErrorContext, ExitCode};
+use super::signal;
+use super::WATCH_POLL_INTERVAL;
+use crate::picousb::{self, UsbConnection};
+use crate::{image, json, keys, otp, provision, white_label};
+
+#[derive(clap::Args)]
+pub(super) struct OtpArgs {
+    #[command(subcommand)]
+    command: OtpCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum OtpCommand {
+    #[command(about = "read rows by name or number")]
+    Get(OtpGetArgs),
+    #[command(about = "program a row, or every copy of a redundant one")]
+    Set(OtpSetArgs),
+    #[command(about = "read every row, or save them as JSON with -o")]
+    Dump(OtpDumpArgs),
+    #[command(about = "list the known rows, or those starting with NAME, no device needed")]
+    List(OtpListArgs),
+    #[command(about = "show what loading a JSON file of rows would do, without writing")]
+    Check(OtpCheckArgs),
+    #[command(about = "program the rows of a JSON file, e.g. one saved by dump")]
+    Load(OtpLoadArgs),
+    #[command(about = "show the white-label settings, or program them from a JSON config")]
+    WhiteLabel(OtpWhiteLabelArgs),
+    #[command(about = "program the hash of a secure boot public key into a BOOTKEY slot")]
+    BootKey(OtpBootKeyArgs),
+    #[command(about = "give the device the next unused serial from a CSV or JSON manifest")]
+    Provision(OtpProvisionArgs),
+}
+
+pub(super) fn otp(globals: &Globals, args: &OtpArgs) -> CliResult {
+    match &args.command {
+        OtpCommand::Get(args) => otp_get(globals, args),
+        OtpCommand::Set(args) => otp_set(globals, args),
+        OtpCommand::Dump(args) => otp_dump(globals, args),
+        OtpCommand::List(args) => otp_list(args),
+        OtpCommand::Check(args) => otp_check(globals, args),
+        OtpCommand::Load(args) => otp_load(globals, args),
+        OtpCommand::WhiteLabel(args) => otp_white_label(globals, args),
+        OtpCommand::BootKey(args) => otp_boot_key(globals, args),
+        OtpCommand::Provision(args) => otp_provision(globals, args),
+    }
+}
+
+// Opens the device, which has to be an RP2350 as the RP2040 has no OTP
+fn open_otp(globals: &Globals) -> CliResult<UsbConnection> {
+    let conn = globals.open()?;
+    if let Some(picousb::TargetID::Rp2040) = conn.get_device_type() {
+        return Err("RP2040 devices have no OTP".into());
+    }
+    Ok(conn)
+}
+
+// Name of a row within its group for messages, e.g. " BOOTKEY0+3", or nothing for unknown rows
+fn otp_row_name(row: u16, info: Option<&otp::OtpRowInfo>) -> String {
+    info.map_or(String::new(), |i| format!(" {}+{}", i.name, row - i.row))
+}
+
+// The `--ecc` and `--raw` options of the subcommands reading and writing rows
+#[derive(clap::Args)]
+struct OtpMode {
+    #[arg(
+        short,
+        long,
+        conflicts_with = "raw",
+        help = "rows hold 16 bits with ECC"
+    )]
+    ecc: bool,
+    #[arg(short, long, help = "rows hold 24 raw bits")]
+    raw: bool,
+}
+impl OtpMode {
+    // Whether rows hold ECC data, None meaning the mode of each row from the row database
+    fn ecc(&self) -> Option<bool> {
+        match (self.ecc, self.raw) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+}
+
+// Lists the known OTP rows, or those whose names start with any of the given ones, no device
+// needed
+#[derive(clap::Args)]
+struct OtpListArgs {
+    names: Vec<String>,
+}
+
+fn otp_list(args: &OtpListArgs) -> CliResult {
+    let filters: Vec<String> = args.names.iter().map(|a| a.to_uppercase()).collect();
+    let mut rows = vec![];
+    for info in otp::known_rows() {
+        if !filters.is_empty() && !filters.iter().any(|f| info.name.starts_with(f)) {
+            continue;
+        }
+        let kind = if info.ecc {
+            "ecc"
+        } else if otp::is_redundant(&info) {
+            "raw, redundant copies"
+        } else {
+            "raw"
+        };
+        let critical = if info.is_critical() { ", critical" } else { "" };
+        let count = if info.count > 1 {
+            format!(" x{}", info.count)
+        } else {
+            String::new()
+        };
+        say!(
+            "{:#05X}{:<4} {} ({}{})",
+            info.row,
+            count,
+            info.name,
+            kind,
+            critical
+        );
+        rows.push(json::Value::Object(vec![
+            ("name".into(), info.name.clone().into()),
+            ("row".into(), hex(info.row.into())),
+            ("count".into(), (info.count as u32).into()),
+            ("ecc".into(), info.ecc.into()),
+            ("critical".into(), info.is_critical().into()),
+        ]));
+    }
+    if rows.is_empty() {
+        return Err(format!("no OTP rows named {}", args.names.join(", ")));
+    }
+    report("rows", rows);
+    Ok(())
+}
+
+// Reads rows given by name or number, in the mode the row database gives them unless told
+#[derive(clap::Args)]
+#[command(after_help = "e.g. `picoboot otp get CHIPID0 0x100`")]
+struct OtpGetArgs {
+    #[arg(required = true, value_name = "ROW", value_parser = args::otp_rows)]
+    rows: Vec<otp::OtpSelector>,
+    #[command(flatten)]
+    mode: OtpMode,
+}
+
+fn otp_get(globals: &Globals, args: &OtpGetArgs) -> CliResult {
+    let mut conn = open_otp(globals)?;
+    let mut rows = vec![];
+    for sel in &args.rows {
+        let ecc = args
+            .mode
+            .ecc()
+            .unwrap_or(sel.info.as_ref().is_some_and(|i| i.ecc));
+        let values =
+            otp::read_rows(&mut conn, sel.row, sel.count, ecc).context("failed to read OTP")?;
+        for (row, value) in (sel.row..).zip(values) {
+            let mode = if ecc { "ecc" } else { "raw" };
+            let name = otp_row_name(row, sel.info.as_ref());
+            say!("row {:#05X}{} ({}): {:#08X}", row, name, mode, value);
+            rows.push(json::Value::Object(vec![
+                ("row".into(), hex(row.into())),
+                ("ecc".into(), ecc.into()),
+                ("value".into(), hex(value)),
+            ]));
+        }
+    }
+    report("rows", rows);
+    Ok(())
+}
+
+// Programs a row given by name or number. A name covering redundant copies (e.g. BOOT_FLAGS0)
+// programs every copy. Nothing is written if any row can't take the value, and OTP bits can
+// never be cleared again
+#[derive(clap::Args)]
+#[command(after_help = "e.g. `picoboot otp set 0x100 0x1234`")]
+struct OtpSetArgs {
+    #[arg(value_parser = args::otp_rows)]
+    row: otp::OtpSelector,
+    value: String,
+    #[command(flatten)]
+    mode: OtpMode,
+}
+
+fn otp_set(globals: &Globals, args: &OtpSetArgs) -> CliResult {
+    let sel = &args.row;
+    if let Some(info) = sel.info.as_ref().filter(|_| sel.count > 1) {
+        if !otp::is_redundant(info) {
+            return Err(format!(
+                "{} is {} rows, pick one as {}+N",
+                info.name, info.count, info.name
+            ));
+        }
+    }
+    let ecc = args
+        .mode
+        .ecc()
+        .unwrap_or(sel.info.as_ref().is_some_and(|i| i.ecc));
+    let max = if ecc { 0xFFFF } else { 0xFFFFFF };
+    let value = image::parse_addr(&args.value)
+        .filter(|&v| v <= max)
+        .ok_or_else(|| {
+            let msg = format!("bad value for row {:#05X}: {}", sel.row, args.value);
+            failure(ExitCode::Usage, msg)
+        })?;
+    let writes: Vec<otp::OtpWrite> = (sel.row..sel.row + sel.count)
+        .map(|row| otp::OtpWrite {
+            row,
+            value,
+            ecc,
+            info: otp::row_info(row),
+        })
+        .collect();
+
+    let mut conn = open_otp(globals)?;
+    let results = otp::check_writes(&mut conn, &writes).context("failed to read OTP")?;
+    for r in &results {
+        let name = otp_row_name(r.write.row, r.write.info.as_ref());
+        match r.check {
+            otp::OtpCheck::Conflict { current } => {
+                return Err(format!(
+                    "row {:#05X}{} holds {:#08X}, which can't be changed to {:#08X}",
+                    r.write.row,
+                    name,
+                    current,
+                    r.write.raw_value()
+                ))
+            }
+            otp::OtpCheck::Locked(lock) => {
+                return Err(format!(
+                    "row {:#05X}{} is in a locked page ({:?})",
+                    r.write.row, name, lock
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    let mut programmed = 0;
+    for r in &results {
+        let name = otp_row_name(r.write.row, r.write.info.as_ref());
+        let otp::OtpCheck::Program { current } = r.check else {
+            say!(
+                "row {:#05X}{}: already {:#08X}",
+                r.write.row,
+                name,
+                r.write.raw_value()
+            );
+            continue;
+        };
+        otp::write_rows(&mut conn, r.write.row, &[value], ecc).context("failed to write OTP")?;
+        programmed += 1;
+        say!(
+            "row {:#05X}{}: {:#08X} -> {:#08X}",
+            r.write.row,
+            name,
+            current,
+            r.write.raw_value()
+        );
+    }
+    report("row", hex(sel.row.into()));
+    report("value", hex(value));
+    report("ecc", ecc);
+    report("programmed", programmed as u32);
+    Ok(())
+}
+
+// Reads every OTP row a page at a time, showing pages locked against the bootloader as dashes.
+// With -o the raw rows are saved as JSON that `otp load` (or picotool's) writes back
+#[derive(clap::Args)]
+struct OtpDumpArgs {
+    #[arg(
+        short,
+        long,
+        value_name = "ROWS.JSON",
+        conflicts_with = "ecc",
+        help = "save the raw rows as JSON instead"
+    )]
+    output: Option<String>,
+    #[command(flatten)]
+    mode: OtpMode,
+}
+
+fn otp_dump(globals: &Globals, args: &OtpDumpArgs) -> CliResult {
+    let output = &args.output;
+    let ecc = args.mode.ecc().unwrap_or(false);
+
+    let mut conn = open_otp(globals)?;
+    let mut rows = vec![];
+    for page in 0..otp::OTP_ROW_COUNT / otp::OTP_PAGE_ROWS {
+        let row = page * otp::OTP_PAGE_ROWS;
+        let values = match otp::read_page(&mut conn, page, ecc).context("failed to read OTP")? {
+            Some(values) => values.into_iter().map(Some).collect(),
+            None => vec![None; otp::OTP_PAGE_ROWS as usize],
+        };
+        if output.is_none() {
+            for (i, line) in values.chunks(8).enumerate() {
+                let cells: Vec<String> = line
+                    .iter()
+                    .map(|v| match (v, ecc) {
+                        (Some(v), true) => format!("{:04X}", v),
+                        (Some(v), false) => format!("{:06X}", v),
+                        (None, true) => "----".into(),
+                        (None, false) => "------".into(),
+                    })
+                    .collect();
+                say!("{:03X}: {}", row as usize + i * 8, cells.join(" "));
+            }
+        }
+        rows.extend(values);
+    }
+
+    if let Some(output) = output {
+        let json::Value::Object(members) = otp::rows_to_json(&rows) else {
+            unreachable!()
+        };
+        let lines: Vec<String> = members
+            .iter()
+            .map(|(k, v)| format!("  {}: {}", json::Value::String(k.clone()), v))
+            .collect();
+        let text = format!("{{\n{}\n}}\n", lines.join(",\n"));
+        std::fs::write(output, text).context("failed to write output")?;
+        let locked = rows.iter().filter(|r| r.is_none()).count() / otp::OTP_PAGE_ROWS as usize;
+        say!(
+            "saved {} programmed rows to {}, skipping {} locked pages",
+            members.len(),
+            output,
+            locked
+        );
+        report("output", output.as_str());
+        report("programmed_rows", members.len());
+        report("locked_pages", locked);
+        return Ok(());
+    }
+    report("ecc", ecc);
+    report(
+        "rows",
+        rows.into_iter().map(|v| v.map(hex)).collect::<Vec<_>>(),
+    );
+    Ok(())
+}
+
+// Reads rows to write from an OTP JSON file, as `otp check` and `otp load` take them
+fn load_otp_json(path: &str) -> CliResult<Vec<otp::OtpWrite>> {
+    let text = std::fs::read_to_string(path).context("failed to read OTP JSON file")?;
+    let json = json::parse(&text).map_err(|e| e.to_string())?;
+    otp::parse_json(&json).map_err(|e| e.to_string())
+}
+
+// Prints what writing each row would do, returning how many would be programmed and how many
+// can't be written
+fn print_otp_checks(results: &[otp::OtpCheckResult]) -> (usize, usize) {
+    let mut changes = 0;
+    let mut problems = 0;
+    let mut rows = vec![];
+    for r in results {
+        let name = otp_row_name(r.write.row, r.write.info.as_ref());
+        let mode = if r.write.ecc { "ecc" } else { "raw" };
+        let new = r.write.raw_value();
+        let (outcome, check) = match r.check {
+            otp::OtpCheck::Unchanged => (format!("already {:#08X}, no change", new), "unchanged"),
+            otp::OtpCheck::Program { current } => {
+                changes += 1;
+                let critical = r.write.info.as_ref().is_some_and(|i| i.is_critical());
+                let outcome = format!(
+                    "{:#08X} -> {:#08X}, irreversible{}",
+                    current,
+                    new,
+                    if critical { ", SECURITY CRITICAL" } else { "" }
+                );
+                (outcome, "program")
+            }
+            otp::OtpCheck::Conflict { current } => {
+                problems += 1;
+                let outcome = format!("cannot change {:#08X} to {:#08X}", current, new);
+                (outcome, "conflict")
+            }
+            otp::OtpCheck::Locked(lock) => {
+                problems += 1;
+                (format!("page is locked ({:?})", lock), "locked")
+            }
+        };
+        say!("row {:#05X}{} ({}): {}", r.write.row, name, mode, outcome);
+        rows.push(json::Value::Object(vec![
+            ("row".into(), hex(r.write.row.into())),
+            ("value".into(), hex(new)),
+            ("check".into(), check.into()),
+        ]));
+        if r.ecc_mismatch {
+            say!(
+                "\twarning: row is normally written {}",
+                if r.write.ecc { "raw" } else { "with ecc" }
+            );
+        }
+    }
+    report("rows", rows);
+    (changes, problems)
+}
+
+// Prints what writing each row of an OTP JSON file would do, without writing anything
+#[derive(clap::Args)]
+struct OtpCheckArgs {
+    #[arg(value_name = "ROWS.JSON")]
+    path: String,
+}
+
+fn otp_check(globals: &Globals, args: &OtpCheckArgs) -> CliResult {
+    let writes = load_otp_json(&args.path)?;
+
+    let mut conn = open_otp(globals)?;
+    let results = otp::check_writes(&mut conn, &writes).context("failed to read OTP")?;
+    let (changes, problems) = print_otp_checks(&results);
+    say!(
+        "{} rows checked, {} would be programmed, {} cannot be written",
+        results.len(),
+        changes,
+        problems
+    );
+    report("would_program", changes as u32);
+    report("cannot_write", problems as u32);
+    if problems != 0 {
+        return Err(failure(ExitCode::VerifyFailed, "OTP check failed"));
+    }
+    Ok(())
+}
+
+// Asks on the terminal before doing something that can't be undone. Without a terminal to ask
+// on, e.g. in scripts, nothing is done unless `--yes` was given
+fn confirm(question: &str) -> CliResult<bool> {
+    use std::io::{BufRead, IsTerminal, Write};
+    if !std::io::stdin().is_terminal() {
+        return Err("not asking without a terminal, pass --yes to go ahead".into());
+    }
+    eprint!("{} Type \"yes\" to go ahead: ", question);
+    std::io::stderr().flush().ok();
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .context("failed to read answer")?;
+    Ok(answer.trim() == "yes")
+}
+
+// Programs the rows of an OTP JSON file, e.g. one saved by `otp dump -o` from another device.
+// Every row is checked first as `otp check` does, and nothing is written if any can't be. As OTP
+// can never be erased, it asks before writing unless given --yes
+#[derive(clap::Args)]
+struct OtpLoadArgs {
+    #[arg(value_name = "ROWS.JSON")]
+    path: String,
+    #[arg(short, long, help = "write without asking")]
+    yes: bool,
+}
+
+fn otp_load(globals: &Globals, args: &OtpLoadArgs) -> CliResult {
+    let writes = load_otp_json(&args.path)?;
+
+    let mut conn = open_otp(globals)?;
+    program_otp(&mut conn, &writes, args.yes)
+}
+
+// Checks and prints the writes as `otp check` does, then programs them once confirmed (or given
+// `yes`). Nothing is written if any row can't be
+fn program_otp(conn: &mut UsbConnection, writes: &[otp::OtpWrite], yes: bool) -> CliResult {
+    let results = otp::check_writes(conn, writes).context("failed to read OTP")?;
+    let (changes, problems) = print_otp_checks(&results);
+    if problems != 0 {
+        return Err(format!(
+            "{} rows cannot be written, nothing written",
+            problems
+        ));
+    }
+    if changes == 0 {
+        say!("OTP already holds every row, nothing to write");
+        report("programmed", 0u32);
+        return Ok(());
+    }
+    let question = format!("Program {} OTP rows? This can never be undone.", changes);
+    if !yes && !confirm(&question)? {
+        return Err("not confirmed, nothing written".into());
+    }
+
+    let mut programmed = 0u32;
+    for r in &results {
+        if let otp::OtpCheck::Program { .. } = r.check {
+            let res = otp::write_rows(conn, r.write.row, &[r.write.value], r.write.ecc);
+            if let Err(e) = res {
+                report("programmed", programmed);
+                return Err(e).context(&format!("failed to write OTP row {:#05X}", r.write.row));
+            }
+            programmed += 1;
+        }
+    }
+    say!("programmed {} OTP rows", programmed);
+    report("programmed", programmed);
+    Ok(())
+}
+
+// Gives the device the next unused serial from a CSV or JSON manifest (see `provision`), writing
+// it to OTP at `--row`, reading it back and marking the entry used with the chip's ID. A device
+// that already has its serial is left alone. With `--repeat` it goes on to each next device
+// plugged in, until the manifest runs out or Ctrl-C
+#[derive(clap::Args)]
+#[command(after_help = "e.g. `picoboot otp provision serials.csv`")]
+struct OtpProvisionArgs {
+    #[arg(value_name = "MANIFEST.CSV|JSON")]
+    path: String,
+    #[arg(
+        long,
+        value_parser = args::row,
+        default_value_t = provision::DEFAULT_SERIAL_ROW,
+        hide_default_value = true,
+        help = "where to put the serial's rows (0x200)"
+    )]
+    row: u16,
+    #[arg(short, long, help = "write without asking")]
+    yes: bool,
+    #[arg(long, help = "go on to each next device plugged in")]
+    repeat: bool,
+}
+
+fn otp_provision(globals: &Globals, args: &OtpProvisionArgs) -> CliResult {
+    let (path, row, yes) = (args.path.as_str(), args.row, args.yes);
+    if !args.repeat {
+        let mut conn = open_otp(globals)?;
+        return provision_device(&mut conn, path, row, yes);
+    }
+
+    signal::install_handler();
+    let globals = globals.waiting();
+    loop {
+        let mut conn = match open_otp(&globals) {
+            Err(_) if signal::interrupted() => return Ok(()),
+            conn => conn?,
+        };
+        let device = conn.transport().device();
+        let (bus, address) = (device.bus_number(), device.address());
+        let res = provision_device(&mut conn, path, row, yes);
+        drop(conn);
+        print_report("otp", &res);
+        if let Err(e) = &res {
+            say!("provisioning failed: {}", e);
+        }
+        let text = std::fs::read_to_string(path).context("failed to read manifest")?;
+        let manifest = provision::Manifest::parse(&text).map_err(|e| e.to_string())?;
+        if manifest.unused() == 0 {
+            say!("the manifest has no unused serials left");
+            return Ok(());
+        }
+
+        say!("unplug the device and plug in the next one");
+        let gone = |d: &picousb::DeviceInfo| d.bus != bus || d.address != address;
+        while globals
+            .builder()
+            .list_devices(&globals.context()?)
+            .context("failed to list devices")?
+            .iter()
+            .any(|d| !gone(d))
+        {
+            if signal::interrupted() {
+                return Ok(());
+            }
+            std::thread::sleep(WATCH_POLL_INTERVAL);
+        }
+    }
+}
+
+// Provisions one device from the manifest at `path`, which is written back once its serial is
+// verified
+fn provision_device(conn: &mut UsbConnection, path: &str, row: u16, yes: bool) -> CliResult {
+    let text = std::fs::read_to_string(path).context("failed to read manifest")?;
+    let mut manifest = provision::Manifest::parse(&text).map_err(|e| e.to_string())?;
+    let chip_id = provision::read_chip_id(conn).context("failed to read the chip ID")?;
+    let id = provision::format_chip_id(chip_id);
+    report("chip_id", id.as_str());
+
+    let mark_used = |manifest: &mut provision::Manifest, index: usize| -> CliResult {
+        manifest.entries[index].chip_id = Some(chip_id);
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, manifest.to_text()).context("failed to write manifest")?;
+        std::fs::rename(&tmp, path).context("failed to write manifest")?;
+        report("serial", manifest.entries[index].serial.as_str());
+        report("remaining", manifest.unused());
+        Ok(())
+    };
+    let current = provision::read_serial(conn, row).context("failed to read OTP")?;
+    let allocation = provision::allocate(&manifest, chip_id, current.as_deref());
+    let index = match allocation.map_err(|e| e.to_string())? {
+        provision::Allocation::Provisioned(i) => {
+            let serial = manifest.entries[i].serial.as_str();
+            say!("chip {} already has serial {}", id, serial);
+            report("serial", serial);
+            report("programmed", 0u32);
+            return Ok(());
+        }
+        provision::Allocation::Unrecorded(i) => {
+            say!(
+                "chip {} already has serial {}, marking it used",
+                id,
+                manifest.entries[i].serial
+            );
+            report("programmed", 0u32);
+            return mark_used(&mut manifest, i);
+        }
+        provision::Allocation::Assign(i) => i,
+    };
+
+    let serial = manifest.entries[index].serial.clone();
+    say!("giving chip {} serial {}", id, serial);
+    let writes = provision::serial_writes(&serial, row).map_err(|e| e.to_string())?;
+    program_otp(conn, &writes, yes)?;
+    let read = provision::read_serial(conn, row).context("failed to read OTP back")?;
+    if read.as_deref() != Some(serial.as_str()) {
+        return Err(format!(
+            "OTP reads back {:?} rather than serial {}",
+            read.unwrap_or_default(),
+            serial
+        ));
+    }
+    mark_used(&mut manifest, index)?;
+    say!(
+        "serial {} verified and marked used, {} left",
+        serial,
+        manifest.unused()
+    );
+    Ok(())
+}
+
+// Shows the white-label settings in OTP, or given a JSON config programs them, with the struct
+// at `--row` (0x100 by default). Once set up, the struct can't be moved or changed
+#[derive(clap::Args)]
+struct OtpWhiteLabelArgs {
+    #[arg(value_name = "CONFIG.JSON")]
+    path: Option<String>,
+    #[arg(
+        long,
+        value_parser = args::row,
+        default_value_t = white_label::DEFAULT_WHITE_LABEL_ROW,
+        hide_default_value = true,
+        help = "where to put the white-label struct (0x100)"
+    )]
+    row: u16,
+    #[arg(short, long, help = "write without asking")]
+    yes: bool,
+}
+
+fn otp_white_label(globals: &Globals, args: &OtpWhiteLabelArgs) -> CliResult {
+    let Some(path) = &args.path else {
+        let mut conn = open_otp(globals)?;
+        let wl = white_label::WhiteLabel::read(&mut conn).context("failed to read OTP")?;
+        let Some(wl) = wl else {
+            say!("white-label: none, the bootrom's defaults are used");
+            report("white_label", json::Value::Null);
+            return Ok(());
+        };
+        let json = wl.to_json();
+        if let json::Value::Object(sections) = &json {
+            for (section, fields) in sections {
+                if let json::Value::Object(fields) = fields {
+                    for (key, value) in fields {
+                        say!("{}.{}: {}", section, key, value);
+                    }
+                }
+            }
+        }
+        report("white_label", json);
+        return Ok(());
+    };
+
+    let text = std::fs::read_to_string(path).context("failed to read white-label JSON file")?;
+    let json = json::parse(&text).map_err(|e| e.to_string())?;
+    let wl = white_label::WhiteLabel::from_json(&json).map_err(|e| e.to_string())?;
+
+    let mut conn = open_otp(globals)?;
+    let flags = white_label::read_usb_boot_flags(&mut conn).context("failed to read OTP")?;
+    let writes = wl.to_writes(args.row, flags).map_err(|e| e.to_string())?;
+    program_otp(&mut conn, &writes, args.yes)
+}
+
+// Programs the hash of a secure boot public key into a BOOTKEY slot and marks it valid. Secure
+// boot itself still has to be enabled in CRIT1 afterwards
+#[derive(clap::Args)]
+#[command(after_help = "e.g. `picoboot otp boot-key public.pem`")]
+struct OtpBootKeyArgs {
+    #[arg(value_name = "KEY.PEM")]
+    path: String,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        help = "the BOOTKEY slot to use"
+    )]
+    slot: usize,
+    #[arg(short, long, help = "write without asking")]
+    yes: bool,
+}
+
+fn otp_boot_key(globals: &Globals, args: &OtpBootKeyArgs) -> CliResult {
+    let slot = args.slot;
+    let bytes = std::fs::read(&args.path).context("failed to read public key")?;
+    let key = keys::PublicKey::parse(&bytes).context("failed to read public key")?;
+    let hash = key.hash();
+    let hash_hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+    say!("public key hash: {}", hash_hex);
+    report("slot", slot);
+    report("key_hash", hash_hex);
+
+    let mut conn = open_otp(globals)?;
+    let flags = otp::read_boot_flags1(&mut conn).context("failed to read OTP")?;
+    let writes = otp::boot_key_writes(slot, &hash, flags).ok_or_else(|| {
+        format!(
+            "bad value for --slot: {}, there are {} boot key slots",
+            slot,
+            otp::OTP_BOOT_KEY_COUNT
+        )
+    })?;
+    program_otp(&mut conn, &writes, args.yes)
+}
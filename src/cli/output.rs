@@ -0,0 +1,252 @@
+// What the CLI prints and how it fails: messages for people, the `--json` outcome and the exit
+// code. Like the log level, these are process wide, as progress handlers and the `fleet` workers
+// print too
+
+use super::signal;
+use crate::{json, picousb};
+
+// Errors are reported as a message and a non-zero exit code, rather than a panic
+pub(super) type CliResult<T = ()> = Result<T, String>;
+
+// Whether to print the outcome as JSON, from the global `--json` option. Messages meant for
+// people then go to stderr, so stdout only holds the one object `main` prints at the end
+pub(super) static JSON: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// Fields commands add to the JSON outcome as they go, so a failure still reports what was done
+static REPORT: std::sync::Mutex<Vec<(String, json::Value)>> = std::sync::Mutex::new(vec![]);
+
+// println!, except on stderr when printing JSON
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::cli::output::JSON.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+pub(super) fn report(key: &str, value: impl Into<json::Value>) {
+    let mut report = REPORT.lock().unwrap();
+    // a key reported again replaces its value, as JSON readers (ours included) reject repeats
+    match report.iter_mut().find(|(k, _)| k == key) {
+        Some((_, v)) => *v = value.into(),
+        None => report.push((key.to_string(), value.into())),
+    }
+}
+
+// Addresses are reported as hex strings, as in the OTP and partition table JSON files
+pub(super) fn hex(addr: u32) -> json::Value {
+    json::Value::String(format!("{:#X}", addr))
+}
+
+// With `--json`, prints the outcome of a command and everything it reported as one line of JSON,
+// taking the report so the next command (e.g. each flash of `watch`) starts afresh
+pub(super) fn print_report(command: &str, res: &CliResult) {
+    if !JSON.load(std::sync::atomic::Ordering::Relaxed) {
+        return;
+    }
+    let mut fields = vec![
+        ("command".to_string(), command.into()),
+        ("ok".to_string(), res.is_ok().into()),
+    ];
+    if let Err(e) = res {
+        fields.push(("error".to_string(), e.as_str().into()));
+    }
+    fields.push(("exit_code".to_string(), (exit_code(res) as u32).into()));
+    fields.append(&mut REPORT.lock().unwrap());
+    println!("{}", json::Value::Object(fields));
+}
+
+// Adds what was being done to an error, e.g. "failed to read flash: USB error: Timeout". The
+// status of a failed command is also reported, and the cause of a device error noted for the exit
+// code, for tools to act on without parsing the message
+pub(super) trait ErrorContext<T> {
+    fn context(self, what: &str) -> CliResult<T>;
+}
+impl<T, E: std::fmt::Display + 'static> ErrorContext<T> for Result<T, E> {
+    fn context(self, what: &str) -> CliResult<T> {
+        self.map_err(|e| {
+            let any: &dyn std::any::Any = &e;
+            if let Some(picousb::PicobootError::Command {
+                cmd_id,
+                token,
+                status,
+            }) = any.downcast_ref()
+            {
+                let status = json::Value::Object(vec![
+                    ("command".into(), (*cmd_id as u32).into()),
+                    ("token".into(), (*token).into()),
+                    ("code".into(), (*status as u32).into()),
+                    ("name".into(), format!("{:?}", status).into()),
+                ]);
+                report("status", status);
+            }
+            if let Some(e) = any.downcast_ref::<picousb::PicobootError>() {
+                set_exit_code(e.into());
+            } else if let Some(e) = any.downcast_ref::<rusb::Error>() {
+                set_exit_code(e.into());
+            }
+            format!("{}: {}", what, e)
+        })
+    }
+}
+
+// What the process exits with, so scripts and CI can tell why a command failed without parsing
+// the message. They're listed in the README, and a code never changes meaning once released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ExitCode {
+    // anything not covered below, e.g. a file that can't be read
+    Failure = 1,
+    // bad arguments or an unknown command
+    Usage = 2,
+    NoDevice = 3,
+    // the device was found but can't be opened, e.g. missing udev rules or driver
+    PermissionDenied = 4,
+    // talking to the device failed, e.g. a timeout, stall or it going away
+    Usb = 5,
+    // the bootrom refused a command
+    Rejected = 6,
+    // flash or OTP doesn't hold what was written or expected
+    VerifyFailed = 7,
+    // the image is for another chip, won't boot on it or doesn't fit in its flash
+    Incompatible = 8,
+    // the self-test of `provision` failed
+    TestFailed = 9,
+    // Ctrl-C, as shells report a process killed by SIGINT
+    Interrupted = 130,
+}
+impl From<&picousb::PicobootError> for ExitCode {
+    fn from(e: &picousb::PicobootError) -> Self {
+        use picousb::PicobootError as E;
+        match e {
+            E::Usb(e) => e.into(),
+            E::NoDriver { .. } | E::PermissionDenied { .. } => ExitCode::PermissionDenied,
+            E::DeviceNotFound | E::InterfaceNotFound => ExitCode::NoDevice,
+            E::ShortTransfer { .. } | E::BadResponse => ExitCode::Usb,
+            E::Command { .. } | E::NotSupported => ExitCode::Rejected,
+            E::VerifyFailed { .. } | E::UnstableRead { .. } | E::CrcMismatch { .. } => {
+                ExitCode::VerifyFailed
+            }
+            E::PastFlashEnd { .. } => ExitCode::Incompatible,
+            E::InvalidArgument(_) | E::Address(_) => ExitCode::Usage,
+            E::Interrupted => ExitCode::Interrupted,
+        }
+    }
+}
+impl From<&rusb::Error> for ExitCode {
+    fn from(e: &rusb::Error) -> Self {
+        match e {
+            rusb::Error::Access => ExitCode::PermissionDenied,
+            _ => ExitCode::Usb,
+        }
+    }
+}
+
+// Why the command failed, from the first error to say. Errors deeper down come first, so it's
+// the cause rather than what the command was doing at the time
+static EXIT_CODE: std::sync::Mutex<Option<ExitCode>> = std::sync::Mutex::new(None);
+
+pub(super) fn set_exit_code(code: ExitCode) {
+    EXIT_CODE.lock().unwrap().get_or_insert(code);
+}
+
+// An error message, noting why the command failed for its exit code
+pub(super) fn failure(code: ExitCode, msg: impl Into<String>) -> String {
+    set_exit_code(code);
+    msg.into()
+}
+
+// The code to exit with after `res`. Ctrl-C is reported even if the command stopped cleanly, as
+// whatever it was doing wasn't finished
+pub(super) fn exit_code(res: &CliResult) -> i32 {
+    if signal::interrupted() {
+        return ExitCode::Interrupted as i32;
+    }
+    match res {
+        Ok(()) => 0,
+        Err(_) => EXIT_CODE.lock().unwrap().unwrap_or(ExitCode::Failure) as i32,
+    }
+}
+
+// Prints the library's log records on stderr, at the level the global `-v`/`-q` options pick
+pub(super) struct StderrLogger;
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // info is what the CLI always printed, so it goes out as is
+        match record.level() {
+            log::Level::Info => eprintln!("{}", record.args()),
+            level => eprintln!("{}: {}", level.as_str().to_lowercase(), record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Progress of an erase, flash or read, always on stderr so it never mixes with output on stdout.
+// On a terminal it's a bar redrawn in place, otherwise (e.g. in CI logs) a line every 10%. Each
+// flash_program call reports from zero again, which starts a new bar
+pub(super) struct ProgressBar {
+    verb: &'static str,
+    tty: bool,
+    start: std::time::Instant,
+    last: Option<(u64, u64)>,
+}
+impl ProgressBar {
+    const WIDTH: u64 = 30;
+
+    pub(super) fn new(verb: &'static str) -> Self {
+        use std::io::IsTerminal;
+        ProgressBar {
+            verb,
+            tty: std::io::stderr().is_terminal(),
+            start: std::time::Instant::now(),
+            last: None,
+        }
+    }
+
+    pub(super) fn update(&mut self, p: picousb::ProgressEvent) {
+        let total = p.total.max(1);
+        if self.last.is_none_or(|(done, _)| p.done < done) {
+            self.start = std::time::Instant::now();
+            self.last = None;
+        }
+        if !self.tty {
+            let tenths = p.done * 10 / total;
+            if self.last.is_none_or(|(_, shown)| shown != tenths) {
+                eprintln!("\t{} {}/{} bytes", self.verb, p.done, p.total);
+            }
+            self.last = Some((p.done, tenths));
+            return;
+        }
+
+        let filled = p.done * Self::WIDTH / total;
+        let secs = self.start.elapsed().as_secs_f64();
+        let rate = if secs > 0.0 {
+            p.done as f64 / 1024.0 / secs
+        } else {
+            0.0
+        };
+        eprint!(
+            "\r{:>8} [{}{}] {:>3}% {}/{} KiB {:.0} KiB/s ",
+            self.verb,
+            "#".repeat(filled as usize),
+            "-".repeat((Self::WIDTH - filled) as usize),
+            p.done * 100 / total,
+            p.done / 1024,
+            p.total / 1024,
+            rate
+        );
+        if p.done >= p.total {
+            eprintln!();
+        }
+        self.last = Some((p.done, filled));
+    }
+}
@@ -0,0 +1,96 @@
+// The `partition` subcommands, building RP2350 partition tables
+
+use super::globals::Globals;
+use super::output::{report, CliResult, ErrorContext};
+use crate::{image, json, partition, picousb, uf2};
+
+#[derive(clap::Args)]
+pub(super) struct PartitionArgs {
+    #[command(subcommand)]
+    command: PartitionCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum PartitionCommand {
+    #[command(about = "build the table into a UF2 or binary file, no device needed")]
+    Create(PartitionCreateArgs),
+    #[command(about = "program the table over the start of flash")]
+    Write(PartitionWriteArgs),
+}
+
+pub(super) fn partition(globals: &Globals, args: &PartitionArgs) -> CliResult {
+    match &args.command {
+        PartitionCommand::Create(args) => partition_create(args),
+        PartitionCommand::Write(args) => partition_write(globals, args),
+    }
+}
+
+fn load_partition_table(path: &str) -> CliResult<partition::PartitionTable> {
+    let text = std::fs::read_to_string(path).context("failed to read partition table JSON")?;
+    let json = json::parse(&text).map_err(|e| e.to_string())?;
+    partition::PartitionTable::from_json(&json).map_err(|e| e.to_string())
+}
+
+#[derive(clap::Args)]
+struct PartitionCreateArgs {
+    #[arg(value_name = "TABLE.JSON")]
+    input: String,
+    #[arg(short, long, value_name = "OUT.UF2|OUT.BIN")]
+    output: String,
+}
+
+// Builds the partition table block offline, as a UF2 (to drag onto the BOOTSEL drive) or a
+// raw binary for the start of flash
+fn partition_create(args: &PartitionCreateArgs) -> CliResult {
+    let output = &args.output;
+    let table = load_partition_table(&args.input)?;
+    let block = table.to_block();
+    let bytes = if output.to_lowercase().ends_with(".uf2") {
+        let seg = image::Segment {
+            addr: picousb::PICO_FLASH_START,
+            data: block,
+            family: None,
+        };
+        uf2::encode(&[seg], uf2::FAMILY_ID_ABSOLUTE).map_err(|e| e.to_string())?
+    } else {
+        block
+    };
+    std::fs::write(output, bytes).context("failed to write output")?;
+    report("partitions", table.partitions.len());
+    report("output", output.as_str());
+    say!(
+        "wrote a table of {} partitions to {}",
+        table.partitions.len(),
+        output
+    );
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct PartitionWriteArgs {
+    #[arg(value_name = "TABLE.JSON")]
+    input: String,
+}
+
+// Programs a partition table over the start of flash, taking effect on the next reboot
+fn partition_write(globals: &Globals, args: &PartitionWriteArgs) -> CliResult {
+    let table = load_partition_table(&args.input)?;
+
+    let mut conn = globals.open()?;
+    if let Some(picousb::TargetID::Rp2040) = conn.get_device_type() {
+        return Err("RP2040 devices have no partition tables".into());
+    }
+    let mut conn = conn
+        .exclusive_access_guard(true)
+        .context("failed to claim access")?
+        .reset_on_drop(true);
+    conn.exit_xip().context("failed to exit from xip mode")?;
+    conn.write_partition_table(&table)
+        .context("failed to write partition table")?;
+    report("partitions", table.partitions.len());
+    say!(
+        "wrote a table of {} partitions, reboot the device to use it",
+        table.partitions.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,342 @@
+// `provision`, testing a board with a self-test image before flashing it for production
+
+use super::args::{self, flash_addr};
+use super::fleet::prepare_image;
+use super::globals::{connected_device, Globals};
+use super::load::{flash_device, sequencing, FlashOptions};
+use super::output::{
+    failure, report, set_exit_code, CliResult, ErrorContext, ExitCode, ProgressBar,
+};
+use super::session::Session;
+use super::signal;
+use super::WATCH_POLL_INTERVAL;
+use crate::picousb::{self, UsbConnection, PICO_PAGE_SIZE};
+use crate::FlashAddr;
+use std::time::Duration;
+
+// Where the self-test of `provision` leaves its result unless told otherwise, the last sector of
+// a Pico's 2MB of flash
+const DEFAULT_MAILBOX: u32 = 0x101F_F000;
+
+// The self-test's result in the mailbox: "PTST" (as a little-endian word), then a word that's 0
+// if the test passed or else a failure code, then optionally a message in ASCII up to a NUL
+const MAILBOX_MAGIC: u32 = 0x5453_5450;
+const MAILBOX_MESSAGE_LEN: usize = 248;
+
+// What the self-test reported
+struct TestResult {
+    code: u32,
+    message: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ResultMode {
+    Mailbox,
+    Bootsel,
+}
+
+#[derive(clap::Args)]
+pub(super) struct ProvisionArgs {
+    #[arg(value_name = "TEST IMAGE")]
+    test: String,
+    #[arg(value_name = "PRODUCTION IMAGE")]
+    production: String,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "mailbox",
+        help = "how the test reports its result"
+    )]
+    result: ResultMode,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = args::addr,
+        help = "flash address the test writes its result to [default: 0x101FF000]"
+    )]
+    mailbox: Option<u32>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 60,
+        help = "how long the test gets to finish"
+    )]
+    timeout: u64,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        help = "re-read failed or mismatching pages N times"
+    )]
+    read_retries: u32,
+    #[arg(long, help = "verify by CRC computed on the device (RP2040 only)")]
+    device_crc: bool,
+    #[arg(long, help = "leave the device in BOOTSEL after the production image")]
+    no_reboot: bool,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 500,
+        help = "delay before rebooting"
+    )]
+    reboot_delay: u32,
+    #[arg(long, help = "sequence commands exactly like picotool")]
+    picotool_compat: bool,
+    #[arg(
+        long,
+        help = "write UF2 blocks even if their family is for another chip"
+    )]
+    ignore_family: bool,
+    #[arg(long, help = "flash RP2350 images the bootrom would refuse to boot")]
+    no_image_check: bool,
+}
+
+// Runs a production test in one go: flashes a self-test image and boots it, waits for the test to
+// reboot into BOOTSEL, checks its result, and only if it passed flashes the production image.
+// With the default mailbox result, the test writes its result to a flash sector that's erased
+// before the test runs. With `--result bootsel`, getting back into BOOTSEL at all is a pass, for
+// tests that hang or keep running when they fail
+pub(super) fn provision(globals: &Globals, args: &ProvisionArgs) -> CliResult {
+    signal::install_handler();
+
+    let opts = FlashOptions {
+        skip_if_same: false,
+        skip_unchanged: false,
+        read_retries: args.read_retries,
+        device_crc: args.device_crc,
+        sequencing: sequencing(args.picotool_compat),
+        reboot: (!args.no_reboot).then_some(picousb::RebootMode::Normal),
+        reboot_delay: args.reboot_delay,
+        quiet: true,
+    };
+    let timeout = Duration::from_secs(args.timeout);
+    let (ignore_family, image_check) = (args.ignore_family, !args.no_image_check);
+    let (test_input, production_input) = (&args.test, &args.production);
+    let mailbox = match (args.result, args.mailbox) {
+        (ResultMode::Bootsel, Some(_)) => {
+            return Err(failure(
+                ExitCode::Usage,
+                "--mailbox only applies to --result mailbox",
+            ))
+        }
+        (ResultMode::Bootsel, None) => None,
+        (ResultMode::Mailbox, addr) => {
+            let addr = flash_addr(addr.unwrap_or(DEFAULT_MAILBOX))?;
+            Some(addr.align_down(picousb::PICO_SECTOR_SIZE))
+        }
+    };
+
+    let mut conn = globals.open()?;
+    let target = conn.get_device_type().ok_or("no known RP chip found")?;
+    report("chip", format!("{:?}", target));
+    let test_opts = FlashOptions {
+        reboot: Some(picousb::RebootMode::Normal),
+        ..opts
+    };
+    let mut test = prepare_image(
+        std::slice::from_ref(test_input),
+        target,
+        ignore_family,
+        image_check,
+        &test_opts,
+    )?;
+    let mut production = prepare_image(
+        std::slice::from_ref(production_input),
+        target,
+        ignore_family,
+        image_check,
+        &opts,
+    )?;
+    // the mailbox is erased along with writing each image, by writing it as erased pages, so an
+    // old result never counts and the production image starts with it clean
+    if let Some(mailbox) = mailbox {
+        let sector = mailbox.get()..mailbox.get() + picousb::PICO_SECTOR_SIZE;
+        for image in [&mut test, &mut production] {
+            if image.fw_pages.iter().any(|(addr, _)| sector.contains(addr)) {
+                return Err(format!(
+                    "the mailbox sector at {} overlaps an image, pick another with --mailbox",
+                    mailbox
+                ));
+            }
+            for addr in sector.clone().step_by(PICO_PAGE_SIZE) {
+                image.fw_pages.push((addr, vec![0xFF; PICO_PAGE_SIZE]));
+            }
+            image.fw_pages.sort_by_key(|(addr, _)| *addr);
+        }
+    }
+    // the port path is where the board shows up again after rebooting, the bus address changes
+    let device = conn.transport().device();
+    let ports = device.port_numbers().unwrap_or_default();
+    let location = (!ports.is_empty()).then(|| picousb::DeviceLocation::PortPath {
+        bus: device.bus_number(),
+        ports,
+    });
+    if location.is_none() {
+        say!("Warning: can't tell where the device is plugged in, any device will be taken back");
+    }
+
+    // the whole pipeline is one session, recorded with the production image as what the board got
+    let (name, serial) = connected_device(&conn);
+    let session = Session {
+        command: "provision",
+        started: std::time::SystemTime::now(),
+        location: name,
+        serial,
+        chip: target,
+        inputs: std::slice::from_ref(production_input),
+        verify: opts.verify(),
+    };
+    let start = std::time::Instant::now();
+    let res = (move || -> CliResult<usize> {
+        say!("flashing the self-test");
+        let mut bar = ProgressBar::new("written");
+        conn.set_progress_handler(move |p| {
+            if p.op == picousb::ProgressOp::Write {
+                bar.update(p);
+            }
+        });
+        conn.set_sequencing(opts.sequencing);
+        flash_device(&mut conn, &test, &test_opts)?;
+        drop(conn);
+
+        say!(
+            "waiting up to {}s for the self-test to finish and re-enter BOOTSEL",
+            timeout.as_secs()
+        );
+        let mut conn = match wait_for_bootsel(globals, location.as_ref(), timeout)? {
+            Some(conn) => conn,
+            None => {
+                report("test_passed", false);
+                set_exit_code(ExitCode::TestFailed);
+                return Err(format!(
+                    "self-test failed: the device didn't re-enter BOOTSEL within {}s",
+                    timeout.as_secs()
+                ));
+            }
+        };
+        if let Some(mailbox) = mailbox {
+            let result = read_mailbox(&mut conn, mailbox, opts.read_retries)?;
+            let Some(result) = result else {
+                report("test_passed", false);
+                return Err(failure(
+                    ExitCode::TestFailed,
+                    "self-test failed: it left no result in the mailbox",
+                ));
+            };
+            report("test_code", result.code);
+            report("test_message", result.message.as_str());
+            if result.code != 0 {
+                report("test_passed", false);
+                let message = match result.message.as_str() {
+                    "" => String::new(),
+                    m => format!(": {}", m),
+                };
+                set_exit_code(ExitCode::TestFailed);
+                return Err(format!(
+                    "self-test failed with code {}{}",
+                    result.code, message
+                ));
+            }
+            match result.message.as_str() {
+                "" => say!("self-test passed"),
+                m => say!("self-test passed: {}", m),
+            }
+        } else {
+            say!("self-test passed, the device re-entered BOOTSEL");
+        }
+        report("test_passed", true);
+
+        say!("flashing the production image");
+        let mut bar = ProgressBar::new("written");
+        conn.set_progress_handler(move |p| {
+            if p.op == picousb::ProgressOp::Write {
+                bar.update(p);
+            }
+        });
+        conn.set_sequencing(opts.sequencing);
+        let written = flash_device(&mut conn, &production, &opts)?;
+        report("bytes_written", written);
+        report("rebooted", opts.reboot.is_some());
+        say!("production image flashed");
+        Ok(written)
+    })();
+    let recorded = globals.record_session(&session, &res, start.elapsed());
+    res?;
+    recorded
+}
+
+// Waits for the device to leave BOOTSEL and come back, as after a self-test, returning the new
+// connection or None if it doesn't within `timeout`
+fn wait_for_bootsel(
+    globals: &Globals,
+    location: Option<&picousb::DeviceLocation>,
+    timeout: Duration,
+) -> CliResult<Option<UsbConnection>> {
+    let start = std::time::Instant::now();
+    let ctx = globals.context()?;
+    let here = |devices: &[picousb::DeviceInfo]| {
+        devices.iter().any(|d| {
+            let path = picousb::DeviceLocation::PortPath {
+                bus: d.bus,
+                ports: d.ports.clone(),
+            };
+            location.is_none_or(|l| *l == path)
+        })
+    };
+    // the reboot is delayed, so it first has to go away
+    while here(
+        &globals
+            .builder()
+            .list_devices(&ctx)
+            .context("failed to list devices")?,
+    ) {
+        if start.elapsed() >= timeout {
+            return Ok(None);
+        }
+        if signal::interrupted() {
+            return Err("interrupted".into());
+        }
+        std::thread::sleep(WATCH_POLL_INTERVAL);
+    }
+    let mut builder = globals.builder().wait(timeout - start.elapsed());
+    if let Some(location) = location {
+        builder = builder.location(location.clone());
+    }
+    let mut conn = match builder.build(ctx) {
+        Ok(conn) => conn,
+        Err(picousb::PicobootError::DeviceNotFound) => return Ok(None),
+        Err(e) => return Err(e).context("could not open device"),
+    };
+    conn.reset_interface()
+        .context("failed to reset interface")?;
+    Ok(Some(conn))
+}
+
+// Reads the self-test's result from the mailbox sector, None if it holds none
+fn read_mailbox(
+    conn: &mut UsbConnection,
+    mailbox: FlashAddr,
+    retries: u32,
+) -> CliResult<Option<TestResult>> {
+    let mut conn = conn
+        .exclusive_access_guard(false)
+        .context("failed to claim access")?
+        .reset_on_drop(true);
+    conn.exit_xip().context("failed to exit from xip mode")?;
+    let data = conn
+        .flash_read_retry(mailbox, 8 + MAILBOX_MESSAGE_LEN as u32, retries)
+        .context("failed to read the mailbox")?;
+    let word = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+    if word(0) != MAILBOX_MAGIC {
+        return Ok(None);
+    }
+    let message = &data[8..];
+    let end = message.iter().position(|&b| b == 0 || b == 0xFF);
+    let message = &message[..end.unwrap_or(message.len())];
+    Ok(Some(TestResult {
+        code: word(4),
+        message: String::from_utf8_lossy(message).into_owned(),
+    }))
+}
+
+// Whether `arg` is a `load` option followed by a value. They're listed with it in the help, e.g.
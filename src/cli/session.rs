@@ -0,0 +1,158 @@
+// The `--session-report` records of each device flashed, for manufacturing traceability
+
+use super::globals::Globals;
+use super::output::{CliResult, ErrorContext};
+use super::signal;
+use crate::{json, picousb};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+// The columns of a `--session-report` CSV, and the fields of each JSON line
+const SESSION_FIELDS: &[&str] = &[
+    "time",
+    "command",
+    "location",
+    "serial",
+    "chip",
+    "image",
+    "image_sha256",
+    "bytes_written",
+    "duration_ms",
+    "verify",
+    "ok",
+    "error",
+];
+
+// A device being flashed, for the `--session-report` record of it
+pub(super) struct Session<'a> {
+    pub(super) command: &'static str,
+    pub(super) started: std::time::SystemTime,
+    pub(super) location: String,
+    pub(super) serial: Option<String>,
+    pub(super) chip: picousb::TargetID,
+    pub(super) inputs: &'a [String],
+    pub(super) verify: picousb::VerifyMode,
+}
+
+impl Globals {
+    // Appends how a session went to the `--session-report` file, if one was given: a CSV row for a
+    // .csv file, with the header written first if it's new, otherwise a line of JSON. These are the
+    // manufacturing records of which board got which image, so failed sessions are recorded too
+    pub(super) fn record_session(
+        &self,
+        session: &Session,
+        res: &CliResult<usize>,
+        duration: Duration,
+    ) -> CliResult {
+        let Some(path) = &self.session_report else {
+            return Ok(());
+        };
+        // flashing stops early but cleanly on Ctrl-C, which still leaves the board unflashed
+        let res = match res {
+            Ok(_) if signal::interrupted() => Err("interrupted".to_string()),
+            res => res.clone(),
+        };
+        let verify = match session.verify {
+            picousb::VerifyMode::None => "none",
+            picousb::VerifyMode::ReadBack { .. } => "read-back",
+            picousb::VerifyMode::DeviceCrc => "device-crc",
+        };
+        let values: Vec<json::Value> = vec![
+            utc_timestamp(session.started).into(),
+            session.command.into(),
+            session.location.as_str().into(),
+            session.serial.clone().into(),
+            format!("{:?}", session.chip).into(),
+            session.inputs.join(" ").into(),
+            image_sha256(session.inputs).into(),
+            res.as_ref().ok().copied().into(),
+            (duration.as_millis() as u64).into(),
+            verify.into(),
+            res.is_ok().into(),
+            res.as_ref().err().map(String::as_str).into(),
+        ];
+
+        let csv = path.ends_with(".csv");
+        let new = std::fs::metadata(path).map_or(true, |m| m.len() == 0);
+        let mut out = String::new();
+        if csv {
+            if new {
+                out += &SESSION_FIELDS.join(",");
+                out.push('\n');
+            }
+            let cells: Vec<String> = values.iter().map(csv_cell).collect();
+            out += &cells.join(",");
+        } else {
+            let fields = SESSION_FIELDS.iter().map(|f| f.to_string()).zip(values);
+            out += &json::Value::Object(fields.collect()).to_string();
+        }
+        out.push('\n');
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("failed to open the session report")?;
+        std::io::Write::write_all(&mut file, out.as_bytes())
+            .context("failed to write the session report")
+    }
+}
+
+// A field of a CSV row, quoted if it holds a comma, quote or newline
+fn csv_cell(value: &json::Value) -> String {
+    let text = match value {
+        json::Value::Null => String::new(),
+        json::Value::Bool(b) => b.to_string(),
+        json::Value::Number(n) | json::Value::String(n) => n.clone(),
+        v => v.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+// SHA-256 of the input files one after the other, which for a single file is what `sha256sum`
+// gives for it. None if any can no longer be read
+fn image_sha256(inputs: &[String]) -> Option<String> {
+    let mut sha = Sha256::new();
+    for spec in inputs {
+        // as in `image::load_input`, anything after an @ is the address of a binary
+        let path = spec
+            .rsplit_once('@')
+            .map_or(spec.as_str(), |(path, _)| path);
+        sha.update(&std::fs::read(path).ok()?);
+    }
+    Some(
+        sha.finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+// A time as an RFC 3339 UTC timestamp, e.g. 2024-05-01T12:34:56Z
+fn utc_timestamp(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // the civil date of a day count, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let (era, doe) = (z / 146097, z % 146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
@@ -0,0 +1,31 @@
+// Ctrl-C handling. The handler only sets the cancel token every connection is built with, so
+// long running operations and loops stop between steps and the connection is cleaned up
+// normally. A second Ctrl-C aborts outright
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Once, OnceLock};
+
+static TOKEN: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+// The token Ctrl-C sets, for `PicobootConnectionBuilder::cancel_token`
+pub(super) fn token() -> Arc<AtomicBool> {
+    TOKEN.get_or_init(Default::default).clone()
+}
+
+pub(super) fn install_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let token = token();
+        // this is the only handler ever set, so it can't already be taken
+        ctrlc::set_handler(move || {
+            if token.swap(true, Ordering::SeqCst) {
+                std::process::abort();
+            }
+        })
+        .expect("failed to set the Ctrl-C handler");
+    });
+}
+
+pub(super) fn interrupted() -> bool {
+    token().load(Ordering::SeqCst)
+}
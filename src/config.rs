@@ -0,0 +1,421 @@
+// Defaults for the CLI from a config file, so flags given every time can be left out. The file is
+// a small subset of TOML: `key = value` lines with string, integer and boolean values, `[usb]` as
+// the only table, and `#` comments:
+//   serial = "E6614103E7452D2F"
+//   wait = true
+//   firmware = "build/fw_blink.uf2"
+//
+//   [usb]
+//   vid = 0x2E8A
+//   read_timeout_ms = 10000
+// `./picoboot.toml` is read first, then the user's `~/.config/picoboot/config.toml` for whatever
+// it leaves unset. Options given on the command line win over both
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::picousb::{DeviceLocation, PicobootConnectionBuilder, RetryPolicy, TargetID};
+use crate::protocol::{PICOBOOT_PID_RP2040, PICOBOOT_PID_RP2350, PICOBOOT_VID};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    // counting from 1
+    pub line: usize,
+    pub msg: String,
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    // the device to use, as with `--serial` and `--device`
+    pub serial: Option<String>,
+    pub device: Option<DeviceLocation>,
+    // wait for a device to be connected, as with `--wait`
+    pub wait: Option<bool>,
+    // what to flash when a command is given no firmware
+    pub firmware: Option<String>,
+    // for boards enumerating with their own VID/PID in BOOTSEL
+    pub vid: Option<u16>,
+    pub rp2040_pid: Option<u16>,
+    pub rp2350_pid: Option<u16>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub control_timeout: Option<Duration>,
+    // as with `--usb-attempts`
+    pub usb_attempts: Option<u32>,
+}
+
+enum Value {
+    String(String),
+    Integer(u64),
+    Bool(bool),
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut config = Config::default();
+        let mut table = String::new();
+        let mut seen = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let err = |msg: &str| ConfigError {
+                line: i + 1,
+                msg: msg.to_string(),
+            };
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[') {
+                let name = name
+                    .strip_suffix(']')
+                    .ok_or_else(|| err("unclosed table header"))?;
+                table = name.trim().to_string();
+                if table != "usb" {
+                    return Err(err(&format!("unknown table [{}]", table)));
+                }
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| err("expected `key = value`"))?;
+            let key = key.trim();
+            // as in TOML, a key can only be given once
+            if seen.contains(&(table.clone(), key)) {
+                return Err(err(&format!("duplicate key {}", key)));
+            }
+            seen.push((table.clone(), key));
+            let value = parse_value(value.trim()).ok_or_else(|| err("bad value"))?;
+            config.set(&table, key, value).map_err(|msg| err(&msg))?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, table: &str, key: &str, value: Value) -> Result<(), String> {
+        let wrong = |expected: &str| format!("{} should be {}", key, expected);
+        let string = |v: Value| match v {
+            Value::String(s) => Ok(s),
+            _ => Err(wrong("a string")),
+        };
+        let integer = |v: Value, max: u64| match v {
+            Value::Integer(n) if n <= max => Ok(n),
+            _ => Err(wrong(&format!("a number up to {}", max))),
+        };
+        let id = |v| integer(v, u16::MAX as u64).map(|n| Some(n as u16));
+        let millis = |v| integer(v, u32::MAX as u64).map(|n| Some(Duration::from_millis(n)));
+        match (table, key) {
+            ("", "serial") => self.serial = Some(string(value)?),
+            ("", "device") => {
+                let location = string(value)?;
+                let parsed = location.parse().map_err(|_| {
+                    format!(
+                        "bad device {:?}, expected BUS:ADDRESS or a port path like 1-4.2",
+                        location
+                    )
+                })?;
+                self.device = Some(parsed);
+            }
+            ("", "wait") => match value {
+                Value::Bool(b) => self.wait = Some(b),
+                _ => return Err(wrong("true or false")),
+            },
+            ("", "firmware") => self.firmware = Some(string(value)?),
+            ("usb", "vid") => self.vid = id(value)?,
+            ("usb", "rp2040_pid") => self.rp2040_pid = id(value)?,
+            ("usb", "rp2350_pid") => self.rp2350_pid = id(value)?,
+            ("usb", "read_timeout_ms") => self.read_timeout = millis(value)?,
+            ("usb", "write_timeout_ms") => self.write_timeout = millis(value)?,
+            ("usb", "control_timeout_ms") => self.control_timeout = millis(value)?,
+            ("usb", "attempts") => match integer(value, u32::MAX as u64)? {
+                0 => return Err(wrong("at least 1")),
+                n => self.usb_attempts = Some(n as u32),
+            },
+            ("", key) => return Err(format!("unknown key {}", key)),
+            (table, key) => return Err(format!("unknown key {} in [{}]", key, table)),
+        }
+        Ok(())
+    }
+
+    // This config, with whatever it leaves unset taken from `other`
+    pub fn or(self, other: Config) -> Config {
+        Config {
+            serial: self.serial.or(other.serial),
+            device: self.device.or(other.device),
+            wait: self.wait.or(other.wait),
+            firmware: self.firmware.or(other.firmware),
+            vid: self.vid.or(other.vid),
+            rp2040_pid: self.rp2040_pid.or(other.rp2040_pid),
+            rp2350_pid: self.rp2350_pid.or(other.rp2350_pid),
+            read_timeout: self.read_timeout.or(other.read_timeout),
+            write_timeout: self.write_timeout.or(other.write_timeout),
+            control_timeout: self.control_timeout.or(other.control_timeout),
+            usb_attempts: self.usb_attempts.or(other.usb_attempts),
+        }
+    }
+
+    // Applies the `[usb]` settings to `builder`. Which device to open is left to the caller, as
+    // commands going through every device (e.g. `fleet`) pick their own
+    pub fn apply(&self, mut builder: PicobootConnectionBuilder) -> PicobootConnectionBuilder {
+        if self.vid.is_some() || self.rp2040_pid.is_some() || self.rp2350_pid.is_some() {
+            let vid = self.vid.unwrap_or(PICOBOOT_VID);
+            builder = builder.ids(vec![
+                (
+                    vid,
+                    self.rp2040_pid.unwrap_or(PICOBOOT_PID_RP2040),
+                    TargetID::Rp2040,
+                ),
+                (
+                    vid,
+                    self.rp2350_pid.unwrap_or(PICOBOOT_PID_RP2350),
+                    TargetID::Rp2350,
+                ),
+            ]);
+        }
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = self.write_timeout {
+            builder = builder.write_timeout(timeout);
+        }
+        if let Some(timeout) = self.control_timeout {
+            builder = builder.control_timeout(timeout);
+        }
+        if let Some(max_attempts) = self.usb_attempts {
+            builder = builder.retry_policy(RetryPolicy {
+                max_attempts,
+                ..Default::default()
+            });
+        }
+        builder
+    }
+
+    // The config files read, in order: the project's, then the user's
+    pub fn paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from("picoboot.toml")];
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home.map(|h| PathBuf::from(h).join(".config")));
+        if let Some(dir) = dir {
+            paths.push(dir.join("picoboot").join("config.toml"));
+        }
+        paths
+    }
+
+    // Reads and merges whichever config files exist, failing with the path of one that doesn't
+    // parse. No files at all is an empty config
+    pub fn load() -> Result<Config, (PathBuf, ConfigError)> {
+        let mut config = Config::default();
+        for path in Config::paths() {
+            let Ok(text) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            let file = Config::parse(&text).map_err(|e| (path, e))?;
+            config = config.or(file);
+        }
+        Ok(config)
+    }
+}
+
+// The line up to a `#` outside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// A TOML basic string, integer (decimal, or hex with 0x, with optional underscores) or boolean
+fn parse_value(s: &str) -> Option<Value> {
+    if let Some(inner) = s.strip_prefix('"') {
+        let inner = inner.strip_suffix('"')?;
+        let mut out = String::new();
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return None,
+                '\\' => out.push(match chars.next()? {
+                    'n' => '\n',
+                    't' => '\t',
+                    '"' => '"',
+                    '\\' => '\\',
+                    _ => return None,
+                }),
+                c => out.push(c),
+            }
+        }
+        return Some(Value::String(out));
+    }
+    match s {
+        "true" => return Some(Value::Bool(true)),
+        "false" => return Some(Value::Bool(false)),
+        _ => {}
+    }
+    let digits = s.replace('_', "");
+    let n = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    Some(Value::Integer(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn err(text: &str) -> String {
+        Config::parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_every_key() {
+        let text = r#"
+            serial = "E6614103E7452D2F"
+            device = "1-4.2"
+            wait = true
+            firmware = "build/fw_blink.uf2"
+
+            [usb]
+            vid = 0x2E8A
+            rp2040_pid = 3
+            rp2350_pid = 0x000F
+            read_timeout_ms = 10_000
+            write_timeout_ms = 500
+            control_timeout_ms = 0xFFFF_FFFF
+            attempts = 5
+        "#;
+        let config = Config::parse(text).unwrap();
+        assert_eq!(config.serial.as_deref(), Some("E6614103E7452D2F"));
+        assert_eq!(config.device, "1-4.2".parse().ok());
+        assert_eq!(config.wait, Some(true));
+        assert_eq!(config.firmware.as_deref(), Some("build/fw_blink.uf2"));
+        assert_eq!(
+            (config.vid, config.rp2040_pid, config.rp2350_pid),
+            (Some(0x2E8A), Some(3), Some(0xF))
+        );
+        assert_eq!(config.read_timeout, Some(Duration::from_millis(10_000)));
+        assert_eq!(config.write_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(
+            config.control_timeout,
+            Some(Duration::from_millis(u32::MAX as u64))
+        );
+        assert_eq!(config.usb_attempts, Some(5));
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn strings_are_quoted_and_escaped() {
+        let firmware = |line: &str| Config::parse(line).map(|c| c.firmware.unwrap());
+        assert_eq!(firmware(r#"firmware = "a # b""#).unwrap(), "a # b");
+        assert_eq!(
+            firmware(r#"firmware = "say \"hi\"" # done"#).unwrap(),
+            "say \"hi\""
+        );
+        assert_eq!(
+            firmware(r#"firmware = "C:\\fw\\blink.uf2""#).unwrap(),
+            "C:\\fw\\blink.uf2"
+        );
+        assert_eq!(
+            firmware(r#"firmware = "tab\tnewline\n""#).unwrap(),
+            "tab\tnewline\n"
+        );
+        assert_eq!(firmware(r#"firmware = """#).unwrap(), "");
+        assert_eq!(firmware(r#"firmware="x=y""#).unwrap(), "x=y");
+
+        for bad in [
+            r#"firmware = "unterminated"#,
+            r#"firmware = "two" "strings""#,
+            r#"firmware = "bad \q escape""#,
+            r#"firmware = "trailing \""#,
+            // literal strings aren't part of the subset
+            "firmware = 'single'",
+            "firmware = bare",
+        ] {
+            assert_eq!(err(bad), "line 1: bad value", "{}", bad);
+        }
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let text = "# a comment\n  # indented\nwait = false # trailing\n\n[usb] # the table\nvid = 1#no space";
+        let config = Config::parse(text).unwrap();
+        assert_eq!((config.wait, config.vid), (Some(false), Some(1)));
+    }
+
+    #[test]
+    fn only_the_usb_table_is_known() {
+        let config = Config::parse("[ usb ]\nattempts = 2").unwrap();
+        assert_eq!(config.usb_attempts, Some(2));
+        assert_eq!(err("[flash]\nsize = 1"), "line 1: unknown table [flash]");
+        assert_eq!(err("wait = true\n[usb"), "line 2: unclosed table header");
+        // keys belong to the table above them
+        assert_eq!(
+            err("[usb]\nwait = true"),
+            "line 2: unknown key wait in [usb]"
+        );
+        assert_eq!(err("vid = 1"), "line 1: unknown key vid");
+    }
+
+    #[test]
+    fn unknown_keys_and_bad_values_are_errors() {
+        assert_eq!(err("\n\nserail = \"x\""), "line 3: unknown key serail");
+        assert_eq!(
+            err("[usb]\nvid = 0x10000"),
+            "line 2: vid should be a number up to 65535"
+        );
+        assert_eq!(
+            err("[usb]\nattempts = 0"),
+            "line 2: attempts should be at least 1"
+        );
+        assert_eq!(err("wait = 1"), "line 1: wait should be true or false");
+        assert_eq!(err("serial = 1"), "line 1: serial should be a string");
+        assert_eq!(
+            err("[usb]\nvid = \"0x2E8A\""),
+            "line 2: vid should be a number up to 65535"
+        );
+        assert_eq!(err("[usb]\nvid = -1"), "line 2: bad value");
+        assert_eq!(err("[usb]\nvid = 0xG"), "line 2: bad value");
+        assert_eq!(err("wait"), "line 1: expected `key = value`");
+        assert!(err("device = \"usb0\"").starts_with("line 1: bad device \"usb0\""));
+    }
+
+    #[test]
+    fn keys_can_only_be_given_once() {
+        assert_eq!(
+            err("wait = true\nwait = false"),
+            "line 2: duplicate key wait"
+        );
+        assert_eq!(
+            err("[usb]\nvid = 1\n\n[usb]\nvid = 2"),
+            "line 5: duplicate key vid"
+        );
+        // the same name in another table is another key
+        assert_eq!(
+            err("wait = true\n[usb]\nwait = true"),
+            "line 3: unknown key wait in [usb]"
+        );
+    }
+
+    #[test]
+    fn earlier_configs_win() {
+        let project = Config::parse("wait = true\n[usb]\nvid = 1").unwrap();
+        let user = Config::parse("wait = false\nserial = \"A\"\n[usb]\nattempts = 3").unwrap();
+        let config = project.or(user);
+        assert_eq!(config.wait, Some(true));
+        assert_eq!(config.serial.as_deref(), Some("A"));
+        assert_eq!((config.vid, config.usb_attempts), (Some(1), Some(3)));
+    }
+}
@@ -0,0 +1,63 @@
+// Small CRC-32 (IEEE 802.3) implementation, used for comparing firmware images
+// against what is already on the device without pulling in another dependency
+
+pub(crate) const CRC32_POLY: u32 = 0xEDB88320;
+
+pub struct Crc32 {
+    state: u32,
+}
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { state: 0xFFFFFFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.state ^= b as u32;
+            for _ in 0..8 {
+                let mask = (self.state & 1).wrapping_neg();
+                self.state = (self.state >> 1) ^ (CRC32_POLY & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crc(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
+
+    #[test]
+    fn known_answers() {
+        assert_eq!(crc(b""), 0);
+        assert_eq!(crc(b"123456789"), 0xCBF43926);
+        assert_eq!(crc(b"a"), 0xE8B7BE43);
+        assert_eq!(crc(&[0; 32]), 0x190A55AD);
+        assert_eq!(crc(&[0xFF; 32]), 0xFF6CAB0B);
+    }
+
+    #[test]
+    fn updates_can_be_split_anywhere() {
+        let data: Vec<u8> = (0..=255).collect();
+        for split in [0, 1, 100, 255, 256] {
+            let mut split_crc = Crc32::new();
+            split_crc.update(&data[..split]);
+            split_crc.update(&data[split..]);
+            assert_eq!(split_crc.finish(), crc(&data));
+        }
+    }
+}
@@ -0,0 +1,78 @@
+// Minimal ELF32 loader, pulling out the PT_LOAD segments of a firmware image.
+// Like picotool, segments are placed at their physical (load) address
+
+use std::fmt;
+
+use crate::image::Segment;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const PT_LOAD: u32 = 1;
+
+#[derive(Debug)]
+pub enum ElfError {
+    NotElf32Le,
+    Truncated,
+}
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::NotElf32Le => write!(f, "not a 32-bit little-endian ELF file"),
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+        }
+    }
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ELF_MAGIC)
+}
+
+// Address execution starts at, for images that are run straight from SRAM
+pub fn entry_point(bytes: &[u8]) -> Result<u32, ElfError> {
+    if !is_elf(bytes) || bytes.len() < 52 || bytes[4] != ELFCLASS32 || bytes[5] != ELFDATA2LSB {
+        return Err(ElfError::NotElf32Le);
+    }
+    Ok(u32::from_le_bytes(bytes[24..28].try_into().unwrap()))
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Segment>, ElfError> {
+    if !is_elf(bytes) || bytes.len() < 52 || bytes[4] != ELFCLASS32 || bytes[5] != ELFDATA2LSB {
+        return Err(ElfError::NotElf32Le);
+    }
+
+    let word = |off: usize| -> Result<u32, ElfError> {
+        bytes
+            .get(off..off + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or(ElfError::Truncated)
+    };
+    let half = |off: usize| u16::from_le_bytes(bytes[off..off + 2].try_into().unwrap());
+
+    let phoff = word(28)? as usize;
+    let phentsize = half(42) as usize;
+    let phnum = half(44) as usize;
+
+    let mut segments = vec![];
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        let p_type = word(ph)?;
+        let p_offset = word(ph + 4)? as usize;
+        let p_paddr = word(ph + 12)?;
+        let p_filesz = word(ph + 16)? as usize;
+        if p_type != PT_LOAD || p_filesz == 0 {
+            continue;
+        }
+
+        let data = bytes
+            .get(p_offset..p_offset + p_filesz)
+            .ok_or(ElfError::Truncated)?;
+        segments.push(Segment {
+            addr: p_paddr,
+            data: data.to_vec(),
+            family: None,
+        });
+    }
+
+    Ok(segments)
+}
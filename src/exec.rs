@@ -0,0 +1,358 @@
+// Running user supplied stubs in SRAM, for custom factory tests without rebuilding this tool.
+//
+// Arguments and results are passed through a mailbox in SRAM. Before the stub runs, the first
+// word of the mailbox holds the argument length in bytes, followed by the argument bytes. Before
+// returning, the stub writes its result the same way: length first, then the bytes
+
+use crate::transport::Transport;
+
+use crate::memmap::SramAddr;
+use crate::picousb::{self, PicobootConnection, PicobootError};
+
+pub const DEFAULT_LOAD_ADDR: SramAddr = SramAddr(0x20038000);
+// the 256 bytes just below the default load address
+pub const DEFAULT_MAILBOX_ADDR: SramAddr = SramAddr(0x20037F00);
+pub const MAILBOX_SIZE: u32 = 256;
+
+pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Loads `stub` at `load_addr`, passes `args` through the mailbox and runs it, returning the
+// result it leaves in the mailbox
+pub fn run_stub<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    stub: &[u8],
+    load_addr: SramAddr,
+    mailbox: SramAddr,
+    args: &[u8],
+) -> picousb::Result<Vec<u8>> {
+    if args.len() as u32 > MAILBOX_SIZE - 4 {
+        return Err(PicobootError::InvalidArgument(
+            "stub arguments too long for mailbox",
+        ));
+    }
+    let mut mail = (args.len() as u32).to_le_bytes().to_vec();
+    mail.extend_from_slice(args);
+    // pad to a whole word, as the bootrom only writes RAM a word at a time
+    mail.resize(mail.len().next_multiple_of(4), 0);
+
+    conn.flash_write(load_addr, stub.to_vec())?;
+    conn.flash_write(mailbox, mail)?;
+    conn.exec(load_addr)?;
+
+    let mail = conn.flash_read(mailbox, MAILBOX_SIZE)?;
+    let len = u32::from_le_bytes(mail[0..4].try_into().unwrap()).min(MAILBOX_SIZE - 4);
+    Ok(mail[4..4 + len as usize].to_vec())
+}
+
+// Thumb (ARMv6-M) code computing the CRC-32 of flash, used by `device_crc32`. It takes the start
+// address and size from the mailbox and leaves the CRC there. The word at `CRC32_STUB_MAILBOX`
+// is the mailbox address
+//
+//     push {r4-r6, lr}      ldr r0, =mailbox      ldr r1, [r0, #4]      ldr r2, [r0, #8]
+//     ldr r3, =0xEDB88320   movs r4, #0           mvns r4, r4           adds r2, r2, r1
+// 1:  cmp r1, r2            beq 4f                ldrb r5, [r1]         adds r1, #1
+//     eors r4, r5           movs r6, #8
+// 2:  lsrs r4, r4, #1       bcc 3f                eors r4, r3
+// 3:  subs r6, #1           bne 2b                b 1b
+// 4:  mvns r4, r4           movs r5, #4           str r5, [r0]          str r4, [r0, #4]
+//     pop {r4-r6, pc}
+const CRC32_STUB: [u16; 26] = [
+    0xB570, 0x480C, 0x6841, 0x6882, 0x4B0B, 0x2400, 0x43E4, 0x1852, 0x4291, 0xD009, 0x780D, 0x3101,
+    0x406C, 0x2608, 0x0864, 0xD300, 0x405C, 0x3E01, 0xD1FA, 0xE7F3, 0x43E4, 0x2504, 0x6005, 0x6044,
+    0xBD70, 0x46C0,
+];
+const CRC32_STUB_MAILBOX: usize = 52;
+// reads through this alias skip the XIP cache, which may still hold what flash had before it
+// was last written
+const XIP_NOCACHE_NOALLOC_BASE: u32 = 0x13000000;
+
+// The stub with its literal pool, to be loaded at `DEFAULT_LOAD_ADDR`
+fn crc32_stub() -> Vec<u8> {
+    let mut stub: Vec<u8> = CRC32_STUB.iter().flat_map(|i| i.to_le_bytes()).collect();
+    stub.extend_from_slice(&DEFAULT_MAILBOX_ADDR.get().to_le_bytes());
+    stub.extend_from_slice(&crate::crc32::CRC32_POLY.to_le_bytes());
+    debug_assert_eq!(stub.len(), CRC32_STUB_MAILBOX + 8);
+    stub
+}
+
+// RP2040 only. Has the device compute the CRC-32 (as in `crc32`) of `size` bytes of flash at
+// `addr`, so it can be checked without reading it all back over USB. Leaves XIP exited, ready
+// for erasing and writing
+pub fn device_crc32<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    addr: u32,
+    size: u32,
+) -> picousb::Result<u32> {
+    let stub = crc32_stub();
+    let start = addr - picousb::PICO_FLASH_START + XIP_NOCACHE_NOALLOC_BASE;
+    let mut args = start.to_le_bytes().to_vec();
+    args.extend_from_slice(&size.to_le_bytes());
+
+    conn.enter_cmd_xip()?;
+    let res = run_stub(conn, &stub, DEFAULT_LOAD_ADDR, DEFAULT_MAILBOX_ADDR, &args);
+    conn.exit_xip()?;
+    match res?[..] {
+        [a, b, c, d] => Ok(u32::from_le_bytes([a, b, c, d])),
+        _ => Err(PicobootError::BadResponse),
+    }
+}
+
+// Thumb code sending a command to the RP2040's flash and reading back its response, as pico-sdk's
+// flash_do_cmd does. The mailbox holds the bytes to send, which are replaced by the bytes
+// received, with chip select held low by overriding it through IO_QSPI. The SSI has to be set up
+// for serial commands, as exiting XIP leaves it. The words after the code are the mailbox, the
+// SS pad's control register and the SSI's base
+//
+//     push {r4-r7, lr}      ldr r0, =mailbox      ldr r7, [r0]          adds r1, r0, #4
+//     ldr r2, =ss_ctrl      ldr r3, [r2]          movs r4, #3           lsls r4, r4, #8
+//     bics r3, r4           movs r5, #2           lsls r5, r5, #8       orrs r3, r5
+//     str r3, [r2]          ldr r6, =ssi          movs r5, #0
+// 1:  cmp r5, r7            beq 3f                ldrb r3, [r1, r5]     str r3, [r6, #0x60]
+// 2:  ldr r3, [r6, #0x28]   lsls r3, r3, #28      bpl 2b                ldr r3, [r6, #0x60]
+//     strb r3, [r1, r5]     adds r5, #1           b 1b
+// 3:  ldr r3, [r2]          orrs r3, r4           str r3, [r2]          pop {r4-r7, pc}
+const FLASH_CMD_STUB: [u16; 30] = [
+    0xB5F0, 0x480E, 0x6807, 0x1D01, 0x4A0D, 0x6813, 0x2403, 0x0224, 0x43A3, 0x2502, 0x022D, 0x432B,
+    0x6013, 0x4E0A, 0x2500, 0x42BD, 0xD008, 0x5D4B, 0x6633, 0x6AB3, 0x071B, 0xD5FC, 0x6E33, 0x554B,
+    0x3501, 0xE7F4, 0x6813, 0x4323, 0x6013, 0xBDF0,
+];
+const IO_QSPI_SS_CTRL: u32 = 0x4001800C;
+const SSI_BASE: u32 = 0x18000000;
+const FLASH_CMD_READ_JEDEC_ID: u8 = 0x9F;
+
+// RP2040 only. Sends `cmd` to the flash and returns as many bytes as were sent, the first of
+// which arrives while the command goes out. Leaves XIP exited, ready for erasing and writing
+pub fn flash_command<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    cmd: &[u8],
+) -> picousb::Result<Vec<u8>> {
+    if !matches!(conn.get_device_type(), Some(picousb::TargetID::Rp2040)) {
+        return Err(PicobootError::NotSupported);
+    }
+    let mut stub: Vec<u8> = FLASH_CMD_STUB
+        .iter()
+        .flat_map(|i| i.to_le_bytes())
+        .collect();
+    for word in [DEFAULT_MAILBOX_ADDR.get(), IO_QSPI_SS_CTRL, SSI_BASE] {
+        stub.extend_from_slice(&word.to_le_bytes());
+    }
+
+    conn.exit_xip()?;
+    let res = run_stub(conn, &stub, DEFAULT_LOAD_ADDR, DEFAULT_MAILBOX_ADDR, cmd)?;
+    if res.len() != cmd.len() {
+        return Err(PicobootError::BadResponse);
+    }
+    Ok(res)
+}
+
+// RP2040 only. The flash's JEDEC ID: manufacturer, memory type and capacity
+pub fn flash_jedec_id<T: Transport>(conn: &mut PicobootConnection<T>) -> picousb::Result<[u8; 3]> {
+    let res = flash_command(conn, &[FLASH_CMD_READ_JEDEC_ID, 0, 0, 0])?;
+    Ok([res[1], res[2], res[3]])
+}
+
+// The size of a flash from its JEDEC ID, whose capacity byte is the log2 of the size in bytes for
+// just about every QSPI flash. None if it's out of the range an RP2040 can address
+pub fn jedec_flash_size(id: [u8; 3]) -> Option<u32> {
+    let size = 1u32.checked_shl(id[2] as u32)?;
+    (64 * 1024..=16 * 1024 * 1024)
+        .contains(&size)
+        .then_some(size)
+}
+
+// Thumb code rebooting an RP2040 into BOOTSEL through the bootrom's reset_usb_boot, as its
+// PICOBOOT has no command for it. It looks the function up in the ROM table and calls it with the
+// two words after the ROM table code: a mask of GPIOs for an activity LED, and a mask of USB
+// interfaces to leave out (bit 0 mass storage, bit 1 PICOBOOT)
+//
+//     movs r0, #0x18        ldrh r3, [r0]         movs r0, #0x14        ldrh r0, [r0]
+//     ldr r1, =code         blx r3                mov r2, r0            ldr r0, =gpio_mask
+//     ldr r1, =disable      blx r2                b .                   nop
+const RESET_USB_BOOT_STUB: [u16; 12] = [
+    0x2018, 0x8803, 0x2014, 0x8800, 0x4903, 0x4798, 0x4602, 0x4803, 0x4903, 0x4790, 0xE7FE, 0x46C0,
+];
+const ROM_FUNC_RESET_USB_BOOT: u32 = u32::from_le_bytes([b'U', b'B', 0, 0]);
+
+// RP2040 only. Reboots into BOOTSEL by running reset_usb_boot, see `RESET_USB_BOOT_STUB`. The
+// device resets without acking the EXEC, so the USB error that follows is taken as success
+pub fn reset_usb_boot<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    led_gpio: Option<u8>,
+    disable_msc: bool,
+    disable_picoboot: bool,
+) -> picousb::Result<()> {
+    let gpio_mask = led_gpio.map_or(0, |gpio| 1u32 << gpio);
+    let disable = disable_msc as u32 | (disable_picoboot as u32) << 1;
+    let mut stub: Vec<u8> = RESET_USB_BOOT_STUB
+        .iter()
+        .flat_map(|i| i.to_le_bytes())
+        .collect();
+    for word in [ROM_FUNC_RESET_USB_BOOT, gpio_mask, disable] {
+        stub.extend_from_slice(&word.to_le_bytes());
+    }
+
+    conn.flash_write(DEFAULT_LOAD_ADDR, stub)?;
+    match conn.exec(DEFAULT_LOAD_ADDR) {
+        Ok(()) | Err(PicobootError::Usb(_)) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc32::Crc32;
+
+    // Memory as a few regions, each a base address and its bytes
+    struct Mem(Vec<(u32, Vec<u8>)>);
+    impl Mem {
+        fn byte(&mut self, addr: u32) -> &mut u8 {
+            let (base, bytes) = self
+                .0
+                .iter_mut()
+                .find(|(base, bytes)| addr >= *base && addr - *base < bytes.len() as u32)
+                .unwrap_or_else(|| panic!("access outside memory at {:08X}", addr));
+            &mut bytes[(addr - *base) as usize]
+        }
+
+        fn load(&mut self, addr: u32, size: u32) -> u32 {
+            (0..size)
+                .rev()
+                .fold(0, |w, i| w << 8 | *self.byte(addr + i) as u32)
+        }
+
+        fn store(&mut self, addr: u32, word: u32) {
+            for (i, b) in word.to_le_bytes().into_iter().enumerate() {
+                *self.byte(addr + i as u32) = b;
+            }
+        }
+    }
+
+    // Runs Thumb code at `pc` until it pops its return address. Only the instructions the CRC
+    // stub uses are decoded, and only the Z and C flags are kept as nothing else branches on N
+    // or V
+    fn run_thumb(mem: &mut Mem, mut pc: u32) {
+        let mut r = [0u32; 8];
+        let (mut z, mut c) = (false, false);
+        for _ in 0..10_000_000 {
+            let op = mem.load(pc, 2);
+            let (rd, rn, rm) = (
+                (op & 7) as usize,
+                (op >> 3 & 7) as usize,
+                (op >> 6 & 7) as usize,
+            );
+            let (imm5, imm8, hi) = (op >> 6 & 0x1F, op & 0xFF, (op >> 8 & 7) as usize);
+            let mut next = pc + 2;
+            match op >> 11 {
+                // LSRS Rd, Rm, #imm5
+                0b00001 if imm5 != 0 => {
+                    c = r[rn] >> (imm5 - 1) & 1 != 0;
+                    r[rd] = r[rn] >> imm5;
+                    z = r[rd] == 0;
+                }
+                // ADDS Rd, Rn, Rm
+                0b00011 if op >> 9 & 3 == 0 => {
+                    (r[rd], c) = r[rn].overflowing_add(r[rm]);
+                    z = r[rd] == 0;
+                }
+                // MOVS, ADDS and SUBS Rdn, #imm8
+                0b00100 => {
+                    r[hi] = imm8;
+                    z = imm8 == 0;
+                }
+                0b00110 => {
+                    (r[hi], c) = r[hi].overflowing_add(imm8);
+                    z = r[hi] == 0;
+                }
+                0b00111 => {
+                    c = r[hi] >= imm8;
+                    r[hi] = r[hi].wrapping_sub(imm8);
+                    z = r[hi] == 0;
+                }
+                // EORS, CMP and MVNS between low registers
+                0b01000 if op >> 10 & 1 == 0 => match op >> 6 & 0xF {
+                    0x1 => {
+                        r[rd] ^= r[rn];
+                        z = r[rd] == 0;
+                    }
+                    0xA => {
+                        z = r[rd] == r[rn];
+                        c = r[rd] >= r[rn];
+                    }
+                    0xF => {
+                        r[rd] = !r[rn];
+                        z = r[rd] == 0;
+                    }
+                    _ => panic!("unexpected {:04X} at {:08X}", op, pc),
+                },
+                // LDR Rt, [PC, #imm8 * 4]
+                0b01001 => r[hi] = mem.load(((pc + 4) & !3) + imm8 * 4, 4),
+                // STR, LDR and LDRB Rt, [Rn, #imm5]
+                0b01100 => mem.store(r[rn] + imm5 * 4, r[rd]),
+                0b01101 => r[rd] = mem.load(r[rn] + imm5 * 4, 4),
+                0b01111 => r[rd] = mem.load(r[rn] + imm5, 1),
+                // PUSH and POP, the stack itself doesn't matter
+                0b10110 => {}
+                0b10111 => return,
+                // B<cond> and B
+                0b11010 | 0b11011 => {
+                    let taken = match op >> 8 & 0xF {
+                        0x0 => z,
+                        0x1 => !z,
+                        0x2 => c,
+                        0x3 => !c,
+                        _ => panic!("unexpected {:04X} at {:08X}", op, pc),
+                    };
+                    if taken {
+                        next = (pc + 4).wrapping_add_signed(imm8 as i8 as i32 * 2);
+                    }
+                }
+                0b11100 => next = (pc + 4).wrapping_add_signed(((op << 21) as i32) >> 20),
+                _ => panic!("unexpected {:04X} at {:08X}", op, pc),
+            }
+            pc = next;
+        }
+        panic!("stub never returned");
+    }
+
+    fn host_crc(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
+
+    #[test]
+    fn crc32_stub_matches_the_host_crc() {
+        let long: Vec<u8> = (0..5000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+        for data in [&b"123456789"[..], &[], &[0xFF; 64], &long] {
+            let mailbox = DEFAULT_MAILBOX_ADDR.get();
+            let mut sram = vec![0; (DEFAULT_LOAD_ADDR.get() - mailbox) as usize];
+            sram.extend(crc32_stub());
+            let mut mem = Mem(vec![
+                (mailbox, sram),
+                (XIP_NOCACHE_NOALLOC_BASE + 0x1000, data.to_vec()),
+            ]);
+            mem.store(mailbox, 8);
+            mem.store(mailbox + 4, XIP_NOCACHE_NOALLOC_BASE + 0x1000);
+            mem.store(mailbox + 8, data.len() as u32);
+
+            run_thumb(&mut mem, DEFAULT_LOAD_ADDR.get());
+            assert_eq!(mem.load(mailbox, 4), 4);
+            assert_eq!(
+                mem.load(mailbox + 4, 4),
+                host_crc(data),
+                "{} bytes",
+                data.len()
+            );
+        }
+    }
+}
@@ -0,0 +1,40 @@
+// Custom subcommands from downstream crates. An extension names its subcommand and parses its
+// own arguments, while finding and opening the device and reporting errors is left to the CLI,
+// so every extension behaves the same as the built in subcommands
+
+use crate::picousb::UsbConnection;
+
+pub trait PicobootExtension {
+    // The subcommand name, e.g. "provision" for `picoboot provision ...`
+    fn name(&self) -> &str;
+
+    // One line describing the arguments, shown when listing subcommands
+    fn usage(&self) -> &str;
+
+    // Runs the subcommand against an opened device, with the arguments following its name
+    fn run(&self, conn: &mut UsbConnection, args: &[String]) -> Result<(), String>;
+}
+
+#[derive(Default)]
+pub struct Extensions {
+    extensions: Vec<Box<dyn PicobootExtension>>,
+}
+impl Extensions {
+    pub fn register(&mut self, extension: Box<dyn PicobootExtension>) {
+        if self.find(extension.name()).is_some() {
+            panic!("extension {} registered twice", extension.name());
+        }
+        self.extensions.push(extension);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&dyn PicobootExtension> {
+        self.extensions
+            .iter()
+            .find(|e| e.name() == name)
+            .map(|e| e.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn PicobootExtension> {
+        self.extensions.iter().map(|e| e.as_ref())
+    }
+}
@@ -0,0 +1,197 @@
+// A small C API, for factory tooling in C or C++ to flash devices through this crate. Built into
+// a shared library with `cargo rustc --release --lib --features ffi --crate-type cdylib`, and
+// declared for C in `include/picoboot.h`, which also gives each function's safety requirements.
+// Every function returns 0 on success and -1 on failure, with `picoboot_last_error` describing
+// the failure
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+
+use crate::image;
+use crate::memmap::MemAddr;
+use crate::picousb::{
+    PicobootConnectionBuilder, PicobootError, RebootMode, UsbConnection, VerifyMode,
+};
+
+// How many times a page that reads back wrong is read again before flashing fails
+const READ_RETRIES: u32 = 3;
+
+thread_local! {
+    // The last failure on this thread, kept until the next one so the pointer handed out stays
+    // valid
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+// An open device, opaque to C
+pub struct PicobootDevice {
+    conn: UsbConnection,
+}
+
+fn set_error(msg: String) {
+    let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = msg);
+}
+
+// Runs `f`, turning its outcome (or a panic, which mustn't unwind into C) into a return code
+fn status(f: impl FnOnce() -> Result<(), String>) -> c_int {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_error(e);
+            -1
+        }
+        Err(_) => {
+            set_error("internal error".to_string());
+            -1
+        }
+    }
+}
+
+fn fail(what: &str, e: PicobootError) -> String {
+    format!("{}: {}", what, e)
+}
+
+// A C string argument, which may be NULL
+unsafe fn string_arg(s: *const c_char) -> Result<Option<String>, String> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    let s = CStr::from_ptr(s).to_str();
+    s.map(|s| Some(s.to_string()))
+        .map_err(|_| "string argument is not UTF-8".to_string())
+}
+
+// Opens the device with the given serial number, or the first found if `serial` is NULL, and
+// stores it in `*out` for the other calls. It must be closed with `picoboot_close`
+#[no_mangle]
+pub unsafe extern "C" fn picoboot_open(
+    serial: *const c_char,
+    out: *mut *mut PicobootDevice,
+) -> c_int {
+    status(|| {
+        if out.is_null() {
+            return Err("out is NULL".to_string());
+        }
+        let serial = string_arg(serial)?;
+        let ctx =
+            rusb::Context::new().map_err(|e| format!("could not initialize libusb: {}", e))?;
+        let mut builder = PicobootConnectionBuilder::new();
+        if let Some(serial) = &serial {
+            builder = builder.serial(serial);
+        }
+        let mut conn = builder
+            .build(ctx)
+            .map_err(|e| fail("could not open device", e))?;
+        conn.reset_interface()
+            .map_err(|e| fail("failed to reset interface", e))?;
+        *out = Box::into_raw(Box::new(PicobootDevice { conn }));
+        Ok(())
+    })
+}
+
+// Closes a device from `picoboot_open`. NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn picoboot_close(dev: *mut PicobootDevice) {
+    if !dev.is_null() {
+        drop(Box::from_raw(dev));
+    }
+}
+
+unsafe fn device<'a>(dev: *mut PicobootDevice) -> Result<&'a mut UsbConnection, String> {
+    dev.as_mut()
+        .map(|d| &mut d.conn)
+        .ok_or_else(|| "device is NULL".to_string())
+}
+
+// Flashes a UF2, ELF, Intel HEX or binary file (binaries go at the start of flash), verifying
+// what's written by reading it back, and loads any SRAM segments. The device isn't rebooted, see
+// `picoboot_reboot`
+#[no_mangle]
+pub unsafe extern "C" fn picoboot_flash_file(
+    dev: *mut PicobootDevice,
+    path: *const c_char,
+) -> c_int {
+    status(|| {
+        let conn = device(dev)?;
+        let path = string_arg(path)?.ok_or("path is NULL")?;
+        let segments = image::load_input(&path).map_err(|e| e.to_string())?;
+        let segments = match conn.get_device_type() {
+            Some(target) => crate::uf2::filter_for_target(segments, target),
+            None => segments,
+        };
+        if segments.is_empty() {
+            return Err(format!("{} has nothing for the connected chip", path));
+        }
+        let mut conn = conn
+            .exclusive_access_guard(true)
+            .map_err(|e| fail("failed to claim access", e))?
+            .reset_on_drop(true);
+        conn.exit_xip()
+            .map_err(|e| fail("failed to exit from xip mode", e))?;
+        let verify = VerifyMode::ReadBack {
+            retries: READ_RETRIES,
+        };
+        conn.program_segments(&segments, verify)
+            .map_err(|e| fail("failed to flash image", e))?;
+        Ok(())
+    })
+}
+
+// Reads `len` bytes of flash (or other memory) at `addr` into `buf`
+#[no_mangle]
+pub unsafe extern "C" fn picoboot_read(
+    dev: *mut PicobootDevice,
+    addr: u32,
+    buf: *mut u8,
+    len: u32,
+) -> c_int {
+    status(|| {
+        let conn = device(dev)?;
+        if buf.is_null() && len != 0 {
+            return Err("buf is NULL".to_string());
+        }
+        let addr = MemAddr::new(addr)
+            .ok_or_else(|| format!("{:#X} is not in ROM, flash or SRAM", addr))?;
+        let mut conn = conn
+            .exclusive_access_guard(false)
+            .map_err(|e| fail("failed to claim access", e))?
+            .reset_on_drop(true);
+        conn.exit_xip()
+            .map_err(|e| fail("failed to exit from xip mode", e))?;
+        let data = conn
+            .flash_read_all(addr, len, READ_RETRIES)
+            .map_err(|e| fail("failed to read", e))?;
+        if len != 0 {
+            std::slice::from_raw_parts_mut(buf, len as usize).copy_from_slice(&data);
+        }
+        Ok(())
+    })
+}
+
+// Reboots into the flashed firmware after `delay_ms` milliseconds, or back into BOOTSEL if
+// `bootsel` is non-zero. The device should be closed afterwards, as it leaves the bus
+#[no_mangle]
+pub unsafe extern "C" fn picoboot_reboot(
+    dev: *mut PicobootDevice,
+    delay_ms: u32,
+    bootsel: c_int,
+) -> c_int {
+    status(|| {
+        let conn = device(dev)?;
+        let mode = if bootsel != 0 {
+            RebootMode::Bootsel
+        } else {
+            RebootMode::Normal
+        };
+        conn.reboot_into(mode, delay_ms)
+            .map_err(|e| fail("failed to reboot device", e))
+    })
+}
+
+// What the last call to fail on this thread went wrong with, an empty string if none has. The
+// string stays valid until the next failure on the same thread
+#[no_mangle]
+pub extern "C" fn picoboot_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
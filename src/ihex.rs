@@ -0,0 +1,168 @@
+// Intel HEX decoder, turning data records into segments. Extended segment and linear address
+// records are applied, start address records are ignored
+
+use std::fmt;
+
+use crate::image::Segment;
+
+const REC_DATA: u8 = 0x00;
+const REC_EOF: u8 = 0x01;
+const REC_EXT_SEGMENT_ADDR: u8 = 0x02;
+const REC_START_SEGMENT_ADDR: u8 = 0x03;
+const REC_EXT_LINEAR_ADDR: u8 = 0x04;
+const REC_START_LINEAR_ADDR: u8 = 0x05;
+
+#[derive(Debug)]
+pub enum IhexError {
+    // line numbers start at 1
+    BadRecord(usize),
+    BadChecksum(usize),
+    UnknownRecord(usize, u8),
+    MissingEof,
+}
+impl fmt::Display for IhexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IhexError::BadRecord(line) => write!(f, "malformed record on line {}", line),
+            IhexError::BadChecksum(line) => write!(f, "bad checksum on line {}", line),
+            IhexError::UnknownRecord(line, t) => {
+                write!(f, "unknown record type {:#04X} on line {}", t, line)
+            }
+            IhexError::MissingEof => write!(f, "no end of file record"),
+        }
+    }
+}
+
+// Whether the file starts with something that looks like a HEX record, so raw binaries that
+// happen to start with ':' aren't mistaken for one
+pub fn is_ihex(bytes: &[u8]) -> bool {
+    let line = bytes.split(|&b| b == b'\n').next().unwrap_or(&[]);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    match line.split_first() {
+        Some((b':', hex)) => hex.len() >= 10 && hex.iter().all(u8::is_ascii_hexdigit),
+        _ => false,
+    }
+}
+
+fn parse_record(line: &str, n: usize) -> Result<Vec<u8>, IhexError> {
+    let hex = line
+        .as_bytes()
+        .strip_prefix(b":")
+        .ok_or(IhexError::BadRecord(n))?;
+    if hex.len() < 10 || !hex.len().is_multiple_of(2) || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return Err(IhexError::BadRecord(n));
+    }
+    let digit = |c: u8| (c as char).to_digit(16).unwrap_or(0) as u8;
+    let bytes: Vec<u8> = hex
+        .chunks_exact(2)
+        .map(|pair| digit(pair[0]) << 4 | digit(pair[1]))
+        .collect();
+    if bytes.len() != bytes[0] as usize + 5 {
+        return Err(IhexError::BadRecord(n));
+    }
+    if bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b)) != 0 {
+        return Err(IhexError::BadChecksum(n));
+    }
+    Ok(bytes)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Segment>, IhexError> {
+    let text = String::from_utf8_lossy(bytes);
+
+    let mut segments: Vec<Segment> = vec![];
+    let mut base = 0u32;
+    for (i, line) in text.lines().enumerate() {
+        let n = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let rec = parse_record(line, n)?;
+        let data = &rec[4..rec.len() - 1];
+        let offset = u16::from_be_bytes([rec[1], rec[2]]) as u32;
+        match rec[3] {
+            REC_DATA => {
+                let addr = base.wrapping_add(offset);
+                // extend the last segment if this record carries on from it
+                match segments.last_mut() {
+                    Some(seg) if seg.end() == addr as u64 => seg.data.extend_from_slice(data),
+                    _ => segments.push(Segment {
+                        addr,
+                        data: data.to_vec(),
+                        family: None,
+                    }),
+                }
+            }
+            REC_EOF => return Ok(segments),
+            REC_EXT_SEGMENT_ADDR | REC_EXT_LINEAR_ADDR => {
+                if data.len() != 2 {
+                    return Err(IhexError::BadRecord(n));
+                }
+                let value = u16::from_be_bytes([data[0], data[1]]) as u32;
+                base = if rec[3] == REC_EXT_SEGMENT_ADDR {
+                    value << 4
+                } else {
+                    value << 16
+                };
+            }
+            REC_START_SEGMENT_ADDR | REC_START_LINEAR_ADDR => {}
+            t => return Err(IhexError::UnknownRecord(n, t)),
+        }
+    }
+
+    Err(IhexError::MissingEof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_data_and_linear_address_records() {
+        let hex = ":020000041000EA\n\
+                   :0400000001020304F2\n\
+                   :020004000506EF\n\
+                   :04000005100001E9FD\n\
+                   :00000001FF\n";
+        let segments = decode(hex.as_bytes()).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].addr, 0x1000_0000);
+        assert_eq!(segments[0].data, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn applies_extended_segment_address() {
+        let hex = ":020000021000EC\r\n:01000000AA55\r\n:00000001FF\r\n";
+        let segments = decode(hex.as_bytes()).unwrap();
+        assert_eq!(segments[0].addr, 0x10000);
+        assert_eq!(segments[0].data, [0xAA]);
+    }
+
+    #[test]
+    fn starts_a_new_segment_on_a_gap() {
+        let hex = ":0100000011EE\n:0100100022CD\n:00000001FF\n";
+        let segments = decode(hex.as_bytes()).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1].addr, 0x10);
+    }
+
+    #[test]
+    fn rejects_bad_records() {
+        let bad = |hex: &str| decode(hex.as_bytes()).unwrap_err().to_string();
+        assert_eq!(bad(":0100000011EF\n"), "bad checksum on line 1");
+        assert_eq!(bad(":0200000011EE\n"), "malformed record on line 1");
+        assert_eq!(bad("0100000011EE\n"), "malformed record on line 1");
+        assert_eq!(bad(":01000000+1EE\n"), "malformed record on line 1");
+        assert_eq!(bad(":0100000011E\n"), "malformed record on line 1");
+        assert_eq!(bad(":0100000611E8\n"), "unknown record type 0x06 on line 1");
+        assert_eq!(bad(":0100000011EE\n"), "no end of file record");
+    }
+
+    #[test]
+    fn rejects_non_ascii_without_panicking() {
+        assert!(decode(":0\u{e9}0000000FF".as_bytes()).is_err());
+        assert!(decode(":\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}".as_bytes()).is_err());
+        assert!(!is_ihex(":0\u{e9}0000000FF".as_bytes()));
+    }
+}
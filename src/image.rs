@@ -0,0 +1,381 @@
+// Firmware images as a set of address/data segments, so multiple inputs (UF2s, ELFs, raw
+// binaries placed at given addresses) can be merged into one plan before anything is written
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::elf::{self, ElfError};
+use crate::ihex::{self, IhexError};
+use crate::memmap::FlashAddr;
+use crate::picousb::{PICO_FLASH_START, PICO_PAGE_SIZE};
+use crate::uf2::{self, Uf2Error};
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+    pub family: Option<u32>,
+}
+impl Segment {
+    pub fn end(&self) -> u64 {
+        self.addr as u64 + self.data.len() as u64
+    }
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(String, std::io::Error),
+    Uf2(String, Uf2Error),
+    Elf(String, ElfError),
+    Ihex(String, IhexError),
+    BadAddress(String),
+    Overlaps(Vec<Overlap>),
+    // a segment, by its address, with data past the end of the address space
+    PastEnd(u32),
+}
+
+// Two segments putting different bytes at some of the same addresses, each given as (input,
+// start, end)
+#[derive(Debug)]
+pub struct Overlap {
+    pub a: (String, u32, u64),
+    pub b: (String, u32, u64),
+}
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (a, b) = (&self.a, &self.b);
+        write!(
+            f,
+            "{} [{:#010X}..{:#010X}) overlaps {} [{:#010X}..{:#010X})",
+            a.0, a.1, a.2, b.0, b.1, b.2
+        )
+    }
+}
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(path, e) => write!(f, "could not read {}: {}", path, e),
+            ImageError::Uf2(path, e) => write!(f, "could not decode {}: {}", path, e),
+            ImageError::Elf(path, e) => write!(f, "could not decode {}: {}", path, e),
+            ImageError::Ihex(path, e) => write!(f, "could not decode {}: {}", path, e),
+            ImageError::BadAddress(spec) => write!(f, "bad load address in {:?}", spec),
+            ImageError::Overlaps(overlaps) => {
+                write!(f, "inputs overlap in {} places:", overlaps.len())?;
+                for o in overlaps {
+                    write!(f, "\n\t{}", o)?;
+                }
+                Ok(())
+            }
+            ImageError::PastEnd(addr) => write!(
+                f,
+                "segment at {:#010X} runs past the end of the address space",
+                addr
+            ),
+        }
+    }
+}
+
+pub fn parse_addr(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// Splits an input given as `path[@addr]`. Only a suffix that parses as an address is one, so
+// paths with an '@' in them still work
+fn split_spec(spec: &str) -> (&str, Option<u32>) {
+    spec.rsplit_once('@')
+        .and_then(|(path, addr)| Some((path, Some(parse_addr(addr)?))))
+        .unwrap_or((spec, None))
+}
+
+// Loads a single input given as `path[@addr]`. UF2, ELF and HEX files carry their own addresses,
+// anything else is treated as a raw binary placed at `addr` (or the start of flash)
+pub fn load_input(spec: &str) -> Result<Vec<Segment>, ImageError> {
+    let (path, addr) = split_spec(spec);
+
+    let bytes = std::fs::read(path).map_err(|e| ImageError::Io(path.to_string(), e))?;
+    if uf2::is_uf2(&bytes) || elf::is_elf(&bytes) || ihex::is_ihex(&bytes) {
+        if addr.is_some() {
+            return Err(ImageError::BadAddress(spec.to_string()));
+        }
+        if uf2::is_uf2(&bytes) {
+            uf2::decode(&bytes).map_err(|e| ImageError::Uf2(path.to_string(), e))
+        } else if elf::is_elf(&bytes) {
+            elf::decode(&bytes).map_err(|e| ImageError::Elf(path.to_string(), e))
+        } else {
+            ihex::decode(&bytes).map_err(|e| ImageError::Ihex(path.to_string(), e))
+        }
+    } else {
+        Ok(vec![Segment {
+            addr: addr.unwrap_or(PICO_FLASH_START),
+            data: bytes,
+            family: None,
+        }])
+    }
+}
+
+// Loads every input and checks that none of them put different bytes at the same address
+// (including conflicting blocks within a single UF2), returning them sorted by address. Overlaps
+// holding the same bytes, e.g. one UF2 given twice, are fine. Every conflict is reported, not
+// just the first. Segments for different UF2 families never conflict, as only one family ends up
+// on a device
+pub fn load_inputs(specs: &[String]) -> Result<Vec<Segment>, ImageError> {
+    let mut named = vec![];
+    for spec in specs {
+        for seg in load_input(spec)? {
+            named.push((spec.clone(), seg));
+        }
+    }
+    named.sort_by_key(|(_, s)| s.addr);
+
+    let mut overlaps = vec![];
+    for (i, (a_name, a)) in named.iter().enumerate() {
+        for (b_name, b) in named[i + 1..].iter() {
+            if b.addr as u64 >= a.end() {
+                break;
+            }
+            let same_family = a.family.is_none() || b.family.is_none() || a.family == b.family;
+            let end = std::cmp::min(a.end(), b.end());
+            let a_bytes = &a.data[(b.addr - a.addr) as usize..(end - a.addr as u64) as usize];
+            let b_bytes = &b.data[..(end - b.addr as u64) as usize];
+            if same_family && a_bytes != b_bytes {
+                overlaps.push(Overlap {
+                    a: (a_name.clone(), a.addr, a.end()),
+                    b: (b_name.clone(), b.addr, b.end()),
+                });
+            }
+        }
+    }
+    if !overlaps.is_empty() {
+        return Err(ImageError::Overlaps(overlaps));
+    }
+
+    Ok(named.into_iter().map(|(_, s)| s).collect())
+}
+
+// Splits segments into those going to flash and those loaded straight into SRAM, e.g. the
+// initialized data or a whole RAM only image from an ELF
+pub fn split_flash_ram(segments: Vec<Segment>) -> (Vec<Segment>, Vec<Segment>) {
+    segments
+        .into_iter()
+        .partition(|s| FlashAddr::new(s.addr).is_some())
+}
+
+// Entry point of the first ELF among the inputs, if any
+pub fn entry_point(specs: &[String]) -> Result<Option<u32>, ImageError> {
+    for spec in specs {
+        let (path, addr) = split_spec(spec);
+        if addr.is_some() {
+            continue;
+        }
+        let bytes = std::fs::read(path).map_err(|e| ImageError::Io(path.to_string(), e))?;
+        if elf::is_elf(&bytes) {
+            let entry =
+                elf::entry_point(&bytes).map_err(|e| ImageError::Elf(path.to_string(), e))?;
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
+
+// Reads `size` bytes at `addr` out of the segments, as if they'd been flashed. Bytes no segment
+// covers read as 0xFF, like erased flash
+pub fn read_range(segments: &[Segment], addr: u32, size: u32) -> Vec<u8> {
+    let mut buf = vec![0xFF; size as usize];
+    let end = addr as u64 + size as u64;
+    for seg in segments {
+        let from = std::cmp::max(seg.addr as u64, addr as u64);
+        let to = std::cmp::min(seg.end(), end);
+        if from < to {
+            let (f, t) = (
+                (from - seg.addr as u64) as usize,
+                (to - seg.addr as u64) as usize,
+            );
+            buf[(from - addr as u64) as usize..(to - addr as u64) as usize]
+                .copy_from_slice(&seg.data[f..t]);
+        }
+    }
+    buf
+}
+
+// Splits segments into page-aligned, page-sized chunks, padding partial pages with zeroes.
+// Segments sharing a page are combined into the same page
+pub fn pages(segments: &[Segment]) -> Result<Vec<(u32, Vec<u8>)>, ImageError> {
+    let mut pages: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+    for seg in segments {
+        // None once the segment has reached the end of the address space
+        let mut next = Some(seg.addr);
+        let mut data = &seg.data[..];
+        while !data.is_empty() {
+            let addr = next.ok_or(ImageError::PastEnd(seg.addr))?;
+            let page_addr = addr - (addr % PICO_PAGE_SIZE as u32);
+            let offset = (addr - page_addr) as usize;
+            let len = std::cmp::min(PICO_PAGE_SIZE - offset, data.len());
+            let page = pages
+                .entry(page_addr)
+                .or_insert_with(|| vec![0; PICO_PAGE_SIZE]);
+            page[offset..offset + len].copy_from_slice(&data[..len]);
+            next = addr.checked_add(len as u32);
+            data = &data[len..];
+        }
+    }
+    Ok(pages.into_iter().collect())
+}
+
+// Joins pages, as `pages` returns them, into runs of contiguous flash to program in one go, so
+// each sector is only erased once
+pub fn page_runs(pages: &[(u32, Vec<u8>)]) -> Vec<(u32, Vec<u8>)> {
+    let mut runs: Vec<(u32, Vec<u8>)> = vec![];
+    for (addr, page) in pages {
+        match runs.last_mut() {
+            Some((start, data)) if *start as u64 + data.len() as u64 == *addr as u64 => {
+                data.extend_from_slice(page)
+            }
+            _ => runs.push((*addr, page.clone())),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seg(addr: u32, data: &[u8]) -> Segment {
+        Segment {
+            addr,
+            data: data.to_vec(),
+            family: None,
+        }
+    }
+
+    // Writes an input file for `load_inputs`, returning its path
+    fn input(name: &str, data: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("picoboot-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn ranges(res: Result<Vec<Segment>, ImageError>) -> Vec<(u32, u64)> {
+        res.unwrap().iter().map(|s| (s.addr, s.end())).collect()
+    }
+
+    #[test]
+    fn overlaps_holding_the_same_bytes_are_allowed() {
+        let data: Vec<u8> = (0..32).collect();
+        let a = input("same-a.bin", &data[..16]);
+        let b = input("same-b.bin", &data[8..]);
+        assert_eq!(
+            ranges(load_inputs(&[format!("{}@0x10000008", b), a.clone()])),
+            [(0x10000000, 0x10000010), (0x10000008, 0x10000020)]
+        );
+        // the same input twice, and one inside another
+        assert!(load_inputs(&[a.clone(), a.clone()]).is_ok());
+        let c = input("same-c.bin", &data[4..6]);
+        assert!(load_inputs(&[a, format!("{}@0x10000004", c)]).is_ok());
+    }
+
+    #[test]
+    fn overlaps_with_different_bytes_are_errors() {
+        let a = input("diff-a.bin", &[1; 16]);
+        let b = input("diff-b.bin", &[1, 1, 2, 1]);
+        let c = input("diff-c.bin", &[9; 4]);
+        let d = input("diff-d.bin", &[8; 4]);
+        let inputs = [
+            a.clone(),
+            format!("{}@0x1000000C", b),
+            format!("{}@0x20000000", c),
+            format!("{}@0x20000002", d),
+        ];
+        let Err(ImageError::Overlaps(overlaps)) = load_inputs(&inputs) else {
+            panic!("overlaps not found");
+        };
+        assert_eq!(overlaps.len(), 2);
+        assert_eq!(overlaps[0].a, (a, 0x10000000, 0x10000010));
+        assert_eq!(overlaps[0].b, (inputs[1].clone(), 0x1000000C, 0x10000010));
+        assert_eq!((overlaps[1].a.1, overlaps[1].b.1), (0x20000000, 0x20000002));
+
+        // only one family ends up on a device, so they can't conflict
+        let family = |f: u32, byte: u8| Segment {
+            addr: PICO_FLASH_START,
+            data: vec![byte; 256],
+            family: Some(f),
+        };
+        let uf2 = uf2::encode(
+            &[
+                family(uf2::FAMILY_ID_RP2040, 1),
+                family(uf2::FAMILY_ID_RP2350_ARM_S, 2),
+            ],
+            uf2::FAMILY_ID_RP2040,
+        )
+        .unwrap();
+        assert_eq!(
+            load_inputs(&[input("families.uf2", &uf2)]).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn adjacent_segments_dont_overlap() {
+        let a = input("next-a.bin", &[1; 16]);
+        let b = input("next-b.bin", &[2; 16]);
+        assert_eq!(
+            ranges(load_inputs(&[format!("{}@0x10000010", b), a])),
+            [(0x10000000, 0x10000010), (0x10000010, 0x10000020)]
+        );
+    }
+
+    #[test]
+    fn only_an_address_suffix_is_split_off() {
+        let path = input("fw@v2.bin", &[7; 4]);
+        assert_eq!(ranges(load_input(&path)), [(PICO_FLASH_START, 0x10000004)]);
+        assert_eq!(
+            ranges(load_input(&format!("{}@0x10001000", path))),
+            [(0x10001000, 0x10001004)]
+        );
+        assert_eq!(
+            ranges(load_input(&format!("{}@4096", path))),
+            [(4096, 4100)]
+        );
+        // a suffix that isn't an address is part of the path
+        assert!(matches!(
+            load_input(&format!("{}@0xZZ", path)),
+            Err(ImageError::Io(p, _)) if p.ends_with("fw@v2.bin@0xZZ")
+        ));
+    }
+
+    #[test]
+    fn pages_stop_at_the_end_of_the_address_space() {
+        let last = pages(&[seg(0xFFFFFF00, &[5; 256])]).unwrap();
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0], (0xFFFFFF00, vec![5; 256]));
+        assert!(matches!(
+            pages(&[seg(0xFFFFFF80, &[5; 129])]),
+            Err(ImageError::PastEnd(0xFFFFFF80))
+        ));
+    }
+
+    #[test]
+    fn pages_join_into_contiguous_runs() {
+        let base = PICO_FLASH_START;
+        let pages = pages(&[
+            seg(base, &[1; 300]),
+            seg(base + 0x200, &[2; 10]),
+            seg(base + 0x1000, &[3; 4]),
+        ])
+        .unwrap();
+        assert_eq!(
+            pages.iter().map(|(a, _)| a - base).collect::<Vec<_>>(),
+            [0, 0x100, 0x200, 0x1000]
+        );
+        let runs = page_runs(&pages);
+        assert_eq!(runs.len(), 2);
+        assert_eq!((runs[0].0, runs[0].1.len()), (base, 0x300));
+        assert_eq!(runs[0].1[299..301], [1, 0]);
+        assert_eq!(runs[0].1[0x200], 2);
+        assert_eq!((runs[1].0, runs[1].1.len()), (base + 0x1000, 0x100));
+        assert!(page_runs(&[]).is_empty());
+    }
+}
@@ -0,0 +1,539 @@
+// Minimal JSON reader and writer, enough for OTP description files and machine readable output.
+// Object keys keep their order, and numbers keep their original text until they're used
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+impl Value {
+    // Reads an unsigned integer, also accepting strings holding hex ("0x1F") or decimal numbers
+    pub fn as_u64(&self) -> Option<u64> {
+        let s = match self {
+            Value::Number(n) => n.as_str(),
+            Value::String(s) => s.as_str(),
+            _ => return None,
+        };
+        match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+// Conversions for building machine readable output
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+impl From<u32> for Value {
+    fn from(n: u32) -> Self {
+        Value::Number(n.to_string())
+    }
+}
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Number(n.to_string())
+    }
+}
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        Value::Number(n.to_string())
+    }
+}
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Value::Null, Into::into)
+    }
+}
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Self {
+        Value::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub offset: usize,
+    pub msg: &'static str,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid JSON at byte {}: {}", self.offset, self.msg)
+    }
+}
+
+// How deep arrays and objects may nest, so a malformed file can't overflow the stack
+const MAX_DEPTH: usize = 128;
+
+pub fn parse(s: &str) -> Result<Value, ParseError> {
+    let mut p = Parser {
+        bytes: s.as_bytes(),
+        pos: 0,
+        depth: 0,
+    };
+    let v = p.value()?;
+    p.skip_ws();
+    if p.pos != p.bytes.len() {
+        return Err(p.err("trailing characters"));
+    }
+    Ok(v)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+impl Parser<'_> {
+    fn err(&self, msg: &'static str) -> ParseError {
+        ParseError {
+            offset: self.pos,
+            msg,
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        self.skip_ws();
+        if self.bytes.get(self.pos) == Some(&b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn literal(&mut self, lit: &str, v: Value) -> Result<Value, ParseError> {
+        if self.bytes[self.pos..].starts_with(lit.as_bytes()) {
+            self.pos += lit.len();
+            Ok(v)
+        } else {
+            Err(self.err("unknown literal"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        self.skip_ws();
+        match self.bytes.get(self.pos) {
+            Some(b'{' | b'[') if self.depth == MAX_DEPTH => Err(self.err("nested too deeply")),
+            Some(b'{') => self.nested(Self::object),
+            Some(b'[') => self.nested(Self::array),
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.err("unexpected character")),
+            None => Err(self.err("unexpected end of input")),
+        }
+    }
+
+    fn nested(
+        &mut self,
+        f: fn(&mut Self) -> Result<Value, ParseError>,
+    ) -> Result<Value, ParseError> {
+        self.depth += 1;
+        let v = f(self);
+        self.depth -= 1;
+        v
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.pos += 1;
+        let mut members = vec![];
+        if self.eat(b'}') {
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_ws();
+            if self.bytes.get(self.pos) != Some(&b'"') {
+                return Err(self.err("expected object key"));
+            }
+            let key_at = self.pos;
+            let key = self.string()?;
+            // a repeated key would leave it unclear which value is meant
+            if members.iter().any(|(k, _)| *k == key) {
+                self.pos = key_at;
+                return Err(self.err("duplicate key"));
+            }
+            if !self.eat(b':') {
+                return Err(self.err("expected ':'"));
+            }
+            members.push((key, self.value()?));
+            if self.eat(b'}') {
+                return Ok(Value::Object(members));
+            }
+            if !self.eat(b',') {
+                return Err(self.err("expected ',' or '}'"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.pos += 1;
+        let mut items = vec![];
+        if self.eat(b']') {
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            if self.eat(b']') {
+                return Ok(Value::Array(items));
+            }
+            if !self.eat(b',') {
+                return Err(self.err("expected ',' or ']'"));
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let start = self.pos;
+            while let Some(&b) = self.bytes.get(self.pos) {
+                if b == b'"' || b == b'\\' || b < 0x20 {
+                    break;
+                }
+                self.pos += 1;
+            }
+            // the input came from a &str and we only split on ASCII, so this stays valid UTF-8
+            out.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).unwrap());
+            match self.bytes.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    let c = match self.bytes.get(self.pos + 1) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return Err(self.err("bad escape")),
+                    };
+                    out.push(c);
+                    self.pos += 2;
+                }
+                Some(_) => return Err(self.err("control character in string")),
+                None => return Err(self.err("unterminated string")),
+            }
+        }
+    }
+
+    // The four hex digits after the `\u` at `pos`
+    fn hex4(&self, pos: usize) -> Option<u32> {
+        let digits = self.bytes.get(pos + 2..pos + 6)?;
+        digits
+            .iter()
+            .try_fold(0, |n, &d| (d as char).to_digit(16).map(|d| n << 4 | d))
+    }
+
+    // A `\u` escape, joining a UTF-16 surrogate pair written as two of them. Leaves `pos` on the
+    // last escape, for the caller to step over
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let hi = self
+            .hex4(self.pos)
+            .ok_or_else(|| self.err("bad unicode escape"))?;
+        let code = match hi {
+            0xD800..=0xDBFF => {
+                let next = self.pos + 6;
+                let lo = match self.bytes.get(next..next + 2) {
+                    Some(b"\\u") => self.hex4(next),
+                    _ => None,
+                };
+                let Some(lo @ 0xDC00..=0xDFFF) = lo else {
+                    return Err(self.err("unpaired surrogate"));
+                };
+                self.pos = next;
+                0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00)
+            }
+            0xDC00..=0xDFFF => return Err(self.err("unpaired surrogate")),
+            c => c,
+        };
+        self.pos += 4;
+        // every value left is a valid char
+        Ok(char::from_u32(code).unwrap_or('\u{FFFD}'))
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.bytes.get(self.pos) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    // -? (0 | [1-9][0-9]*) (. [0-9]+)? ([eE] [+-]? [0-9]+)?
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        if self.bytes.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        let int_start = self.pos;
+        match self.digits() {
+            0 => return Err(self.err("bad number")),
+            n if n > 1 && self.bytes[int_start] == b'0' => {
+                self.pos = int_start;
+                return Err(self.err("leading zero in number"));
+            }
+            _ => {}
+        }
+        if self.bytes.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            if self.digits() == 0 {
+                return Err(self.err("bad number"));
+            }
+        }
+        if let Some(b'e' | b'E') = self.bytes.get(self.pos) {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.bytes.get(self.pos) {
+                self.pos += 1;
+            }
+            if self.digits() == 0 {
+                return Err(self.err("bad number"));
+            }
+        }
+        let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+        Ok(Value::Number(text.to_string()))
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write_str(f, s),
+            Value::Array(items) => {
+                write!(f, "[")?;
+                for (i, v) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn err(s: &str) -> &'static str {
+        parse(s).unwrap_err().msg
+    }
+
+    #[test]
+    fn parses_nested_values() {
+        let v = parse(r#" { "a": [1, -2.5e3, {"b": null}], "c": {"d": [true, false, []]} } "#);
+        let v = v.unwrap();
+        let a = v.get("a").unwrap();
+        assert_eq!(
+            *a,
+            Value::Array(vec![
+                Value::Number("1".into()),
+                Value::Number("-2.5e3".into()),
+                Value::Object(vec![("b".into(), Value::Null)]),
+            ])
+        );
+        let d = v.get("c").and_then(|c| c.get("d")).unwrap();
+        assert_eq!(d.to_string(), "[true,false,[]]");
+    }
+
+    #[test]
+    fn reads_numbers_and_hex_strings() {
+        assert_eq!(parse("0").unwrap().as_u64(), Some(0));
+        assert_eq!(parse("4096").unwrap().as_u64(), Some(4096));
+        assert_eq!(parse(r#""0x2E8A""#).unwrap().as_u64(), Some(0x2E8A));
+        assert_eq!(parse("-0.5").unwrap(), Value::Number("-0.5".into()));
+    }
+
+    #[test]
+    fn rejects_bad_numbers() {
+        assert_eq!(err("01"), "leading zero in number");
+        assert_eq!(err("-007"), "leading zero in number");
+        assert_eq!(err("[1, 02]"), "leading zero in number");
+        assert_eq!(err("1."), "bad number");
+        assert_eq!(err("-"), "bad number");
+        assert_eq!(err("1e"), "bad number");
+        assert_eq!(err("+1"), "unexpected character");
+        assert_eq!(err(".5"), "unexpected character");
+        assert_eq!(err("1.5.5"), "trailing characters");
+    }
+
+    #[test]
+    fn rejects_duplicate_keys() {
+        let e = parse(r#"{"row": 1, "row": 2}"#).unwrap_err();
+        assert_eq!(e.msg, "duplicate key");
+        assert_eq!(e.offset, 11);
+        assert_eq!(err(r#"{"a": {"b": 1, "b": 1}}"#), "duplicate key");
+        // the same key in different objects is fine
+        assert!(parse(r#"[{"a": 1}, {"a": 2}]"#).is_ok());
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        let v = parse(r#""q\"b\\s\/n\nt\tu\u00e9\ud83d\ude00""#).unwrap();
+        assert_eq!(v, Value::String("q\"b\\s/n\nt\tu\u{e9}\u{1F600}".into()));
+        assert_eq!(err(r#""\x""#), "bad escape");
+        assert_eq!(err(r#""\u12g4""#), "bad unicode escape");
+        assert_eq!(err(r#""\u+123""#), "bad unicode escape");
+        assert_eq!(err(r#""\ud83d""#), "unpaired surrogate");
+        assert_eq!(err(r#""\ude00""#), "unpaired surrogate");
+        assert_eq!(err("\"a\nb\""), "control character in string");
+        assert_eq!(err(r#""abc"#), "unterminated string");
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let text = r#"{"s":"a\"\\\n\u0001","n":[1,2.5],"o":{}}"#;
+        assert_eq!(parse(text).unwrap().to_string(), text);
+    }
+
+    #[test]
+    fn keeps_large_numbers_exact() {
+        // past 2^53 a double can't hold every integer, the text can
+        assert_eq!(
+            parse("9007199254740993").unwrap().as_u64(),
+            Some((1 << 53) + 1)
+        );
+        assert_eq!(
+            parse("18446744073709551615").unwrap().as_u64(),
+            Some(u64::MAX)
+        );
+        assert_eq!(
+            parse(r#""0xFFFFFFFFFFFFFFFF""#).unwrap().as_u64(),
+            Some(u64::MAX)
+        );
+        assert_eq!(parse("18446744073709551616").unwrap().as_u64(), None);
+        assert_eq!(
+            parse("123456789012345678901234567890").unwrap().to_string(),
+            "123456789012345678901234567890"
+        );
+        // not unsigned integers
+        for s in ["-1", "1.0", "1e3", r#""0x""#, r#""12a""#, "true", "null"] {
+            assert_eq!(parse(s).unwrap().as_u64(), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn decodes_surrogate_pairs() {
+        let v = parse(r#""\uD834\uDD1E \ud83d\ude00 \uffff \udbff\udfff""#).unwrap();
+        assert_eq!(
+            v,
+            Value::String("\u{1D11E} \u{1F600} \u{FFFF} \u{10FFFF}".into())
+        );
+        // raw UTF-8 passes through untouched, and is written back as is
+        let v = parse("\"é😀\"").unwrap();
+        assert_eq!(v, Value::String("é😀".into()));
+        assert_eq!(v.to_string(), "\"é😀\"");
+        // a high surrogate needs a low one straight after it
+        assert_eq!(err(r#""\ud83d\u0041""#), "unpaired surrogate");
+        assert_eq!(err(r#""\ud83d\ud83d""#), "unpaired surrogate");
+        assert_eq!(err(r#""\ud83d x""#), "unpaired surrogate");
+        assert_eq!(err(r#""\ud83d\u""#), "unpaired surrogate");
+        assert_eq!(err(r#""\u12""#), "bad unicode escape");
+        assert_eq!(err(r#""\"#), "bad escape");
+    }
+
+    #[test]
+    fn rejects_malformed_structure() {
+        assert_eq!(err("[1, 2"), "expected ',' or ']'");
+        assert_eq!(err(r#"{"a" 1}"#), "expected ':'");
+        assert_eq!(err("{1: 2}"), "expected object key");
+        assert_eq!(err("tru"), "unknown literal");
+        assert_eq!(err(""), "unexpected end of input");
+        assert_eq!(err("[] x"), "trailing characters");
+        assert_eq!(err("[1,]"), "unexpected character");
+        assert_eq!(err(r#"{"a": 1,}"#), "expected object key");
+        assert_eq!(err("[1 2]"), "expected ',' or ']'");
+        assert_eq!(err(r#"{"a": 1 "b": 2}"#), "expected ',' or '}'");
+        assert_eq!(err("{"), "expected object key");
+        assert_eq!(err("'a'"), "unexpected character");
+        assert_eq!(err("nul"), "unknown literal");
+        assert_eq!(err("1 // comment"), "trailing characters");
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(err(&nested(MAX_DEPTH + 1)), "nested too deeply");
+        // deep enough to overflow the stack without the limit
+        assert_eq!(err(&"[{\"a\":".repeat(100_000)), "nested too deeply");
+    }
+}
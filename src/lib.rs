@@ -0,0 +1,57 @@
+// Communicating with RP2040 and RP2350 devices in BOOTSEL mode over the PICOBOOT USB interface.
+// `picousb` holds the protocol and connection, the other modules are the pieces the flasher
+// binary is built from: firmware image formats, OTP handling and the CLI extension points
+
+pub mod binary_info;
+pub mod bootsel;
+mod capture;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "cli")]
+mod config;
+mod crc32;
+pub mod elf;
+pub mod exec;
+pub mod extension;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod ihex;
+pub mod image;
+mod json;
+pub mod keys;
+pub mod memmap;
+pub mod mock;
+pub mod msc;
+#[cfg(feature = "node")]
+pub mod node;
+pub mod otp;
+pub mod partition;
+pub mod picobin;
+pub mod picousb;
+pub mod picousb_async;
+#[cfg(feature = "cli")]
+mod provision;
+#[cfg(feature = "cli")]
+mod replay;
+mod secp256k1;
+mod shared;
+mod trace;
+pub mod transport;
+pub mod uf2;
+pub mod white_label;
+#[cfg(all(windows, feature = "windows-driver"))]
+mod windriver;
+
+pub use memmap::{FlashAddr, MemAddr, SramAddr};
+pub use picoboot_protocol as protocol;
+pub use picousb::{
+    list_devices, ChipIdentity, ChipInfo, DeviceInfo, DeviceLocation, InfoType, Mismatch, Package,
+    PicobootConnection, PicobootConnectionBuilder, PicobootError, PicobootStatus, ProgressEvent,
+    ProgressOp, Reboot2Kind, RebootArch, RetryPolicy, SysInfo, TargetID, UsbConnection, UsbStrings,
+    VerifyMode, PICO2_STACK_POINTER, PICO_FLASH_START, PICO_PAGE_SIZE, PICO_SECTOR_SIZE,
+    PICO_STACK_POINTER,
+};
+pub use picousb_async::PicobootConnectionAsync;
+pub use shared::SharedPicoboot;
+pub use trace::Trace;
+pub use transport::{RusbTransport, Transport};
@@ -0,0 +1,439 @@
+// The address spaces of each chip that PICOBOOT commands can reach, for refusing a read, write
+// or erase that misses them before it goes to the device, with a better error than the bootrom's
+
+use std::fmt;
+
+use crate::picousb::{TargetID, PICO_FLASH_START};
+
+const SRAM_START: u32 = 0x20000000;
+// the flash and SRAM address spaces each take 256M, whatever's actually fitted
+const SPACE_SIZE: u32 = 0x10000000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Rom,
+    // the XIP window onto the QSPI flash, of which only the flash's own size is backed
+    Flash,
+    Sram,
+    // RP2350 only, read and written through the OTP commands rather than READ and WRITE
+    Otp,
+}
+
+// What a command does with memory, as each region only allows some of these
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Erase,
+}
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "written"),
+            Access::Erase => write!(f, "erased"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub name: &'static str,
+    pub kind: RegionKind,
+    pub start: u32,
+    pub end: u32,
+}
+impl Region {
+    pub fn contains(&self, addr: u32) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    pub fn size(&self) -> u32 {
+        self.end - self.start
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match self.kind {
+            RegionKind::Rom => access == Access::Read,
+            RegionKind::Flash => true,
+            RegionKind::Sram => access != Access::Erase,
+            RegionKind::Otp => false,
+        }
+    }
+}
+
+const RP2040_REGIONS: &[Region] = &[
+    Region {
+        name: "ROM",
+        kind: RegionKind::Rom,
+        start: 0x00000000,
+        end: 0x00004000,
+    },
+    Region {
+        name: "flash",
+        kind: RegionKind::Flash,
+        start: 0x10000000,
+        end: 0x11000000,
+    },
+    Region {
+        name: "SRAM",
+        kind: RegionKind::Sram,
+        start: 0x20000000,
+        end: 0x20042000,
+    },
+];
+
+const RP2350_REGIONS: &[Region] = &[
+    Region {
+        name: "ROM",
+        kind: RegionKind::Rom,
+        start: 0x00000000,
+        end: 0x00008000,
+    },
+    // both chip selects, 16M each
+    Region {
+        name: "flash",
+        kind: RegionKind::Flash,
+        start: 0x10000000,
+        end: 0x12000000,
+    },
+    Region {
+        name: "SRAM",
+        kind: RegionKind::Sram,
+        start: 0x20000000,
+        end: 0x20082000,
+    },
+    // the ECC and raw views of the OTP rows
+    Region {
+        name: "OTP",
+        kind: RegionKind::Otp,
+        start: 0x40130000,
+        end: 0x40140000,
+    },
+];
+
+// An address in the flash (XIP) address space, as opposed to an offset into flash or an image.
+// APIs that only make sense for flash take one, so an offset can't be passed by mistake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FlashAddr(pub(crate) u32);
+impl FlashAddr {
+    pub const START: FlashAddr = FlashAddr(PICO_FLASH_START);
+
+    // None if `addr` isn't in the flash address space
+    pub fn new(addr: u32) -> Option<Self> {
+        (addr.wrapping_sub(PICO_FLASH_START) < SPACE_SIZE).then_some(FlashAddr(addr))
+    }
+
+    // The address `offset` bytes into flash
+    pub fn from_offset(offset: u32) -> Option<Self> {
+        (offset < SPACE_SIZE).then_some(FlashAddr(PICO_FLASH_START + offset))
+    }
+
+    pub fn get(self) -> u32 {
+        self.0
+    }
+
+    // How far into flash the address is
+    pub fn offset(self) -> u32 {
+        self.0 - PICO_FLASH_START
+    }
+
+    pub fn is_aligned(self, align: u32) -> bool {
+        self.0.is_multiple_of(align)
+    }
+
+    // The address rounded down to a multiple of `align`, e.g. the start of its sector
+    pub fn align_down(self, align: u32) -> Self {
+        FlashAddr(self.0 - self.0 % align)
+    }
+}
+
+// An address in the SRAM address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SramAddr(pub(crate) u32);
+impl SramAddr {
+    pub const START: SramAddr = SramAddr(SRAM_START);
+
+    // None if `addr` isn't in the SRAM address space
+    pub fn new(addr: u32) -> Option<Self> {
+        (addr.wrapping_sub(SRAM_START) < SPACE_SIZE).then_some(SramAddr(addr))
+    }
+
+    pub fn get(self) -> u32 {
+        self.0
+    }
+
+    // How far into SRAM the address is
+    pub fn offset(self) -> u32 {
+        self.0 - SRAM_START
+    }
+}
+
+// Arithmetic and conversions shared by both address types. Adding to an address steps through
+// memory and subtracting two gives the distance between them, None where the result would
+// leave the address space or go negative
+macro_rules! addr_ops {
+    ($t:ident, $variant:ident, $start:expr) => {
+        impl $t {
+            pub fn checked_add(self, rhs: u32) -> Option<$t> {
+                let addr = self.0.checked_add(rhs)?;
+                (addr - $start < SPACE_SIZE).then_some($t(addr))
+            }
+
+            pub fn checked_sub(self, rhs: $t) -> Option<u32> {
+                self.0.checked_sub(rhs.0)
+            }
+        }
+        impl From<$t> for u32 {
+            fn from(addr: $t) -> u32 {
+                addr.0
+            }
+        }
+        impl From<$t> for MemAddr {
+            fn from(addr: $t) -> MemAddr {
+                MemAddr::$variant(addr)
+            }
+        }
+        impl fmt::Display for $t {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{:#010X}", self.0)
+            }
+        }
+    };
+}
+addr_ops!(FlashAddr, Flash, PICO_FLASH_START);
+addr_ops!(SramAddr, Sram, SRAM_START);
+
+// An address READ and WRITE can reach: in flash, in SRAM or in ROM, below flash, which is only
+// read. The memory commands take one, so an offset can't be sent as an address by mistake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemAddr {
+    Rom(u32),
+    Flash(FlashAddr),
+    Sram(SramAddr),
+}
+impl MemAddr {
+    // None if `addr` isn't in the ROM, flash or SRAM address space
+    pub fn new(addr: u32) -> Option<Self> {
+        if addr < PICO_FLASH_START {
+            return Some(MemAddr::Rom(addr));
+        }
+        FlashAddr::new(addr)
+            .map(MemAddr::Flash)
+            .or_else(|| SramAddr::new(addr).map(MemAddr::Sram))
+    }
+
+    pub fn get(self) -> u32 {
+        match self {
+            MemAddr::Rom(addr) => addr,
+            MemAddr::Flash(addr) => addr.0,
+            MemAddr::Sram(addr) => addr.0,
+        }
+    }
+
+    // None where the result would leave the address space it's in
+    pub fn checked_add(self, rhs: u32) -> Option<Self> {
+        match self {
+            MemAddr::Rom(addr) => addr
+                .checked_add(rhs)
+                .filter(|&a| a < PICO_FLASH_START)
+                .map(MemAddr::Rom),
+            MemAddr::Flash(addr) => addr.checked_add(rhs).map(MemAddr::Flash),
+            MemAddr::Sram(addr) => addr.checked_add(rhs).map(MemAddr::Sram),
+        }
+    }
+}
+impl fmt::Display for MemAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#010X}", self.get())
+    }
+}
+
+// Formats an address with its halves split, e.g. 0x2004_3000, as the datasheets do
+pub struct Addr(pub u64);
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:04X}_{:04X}", self.0 >> 16, self.0 & 0xFFFF)
+    }
+}
+
+fn chip_name(chip: &TargetID) -> &'static str {
+    match chip {
+        TargetID::Rp2040 => "RP2040",
+        TargetID::Rp2350 => "RP2350",
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AddressError {
+    // the address isn't in any region, `after` being the region it's past the end of, if any
+    Unmapped {
+        addr: u32,
+        chip: TargetID,
+        after: Option<Region>,
+    },
+    // the range starts in `region` but runs past its end
+    PastEnd {
+        addr: u32,
+        size: u32,
+        chip: TargetID,
+        region: Region,
+    },
+    // the region doesn't allow the access, e.g. writing ROM
+    NotAllowed {
+        chip: TargetID,
+        region: Region,
+        access: Access,
+    },
+}
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::Unmapped {
+                addr,
+                chip,
+                after: Some(region),
+            } => write!(
+                f,
+                "address {} is past end of {} {}",
+                Addr(*addr as u64),
+                chip_name(chip),
+                region.name
+            ),
+            AddressError::Unmapped {
+                addr,
+                chip,
+                after: None,
+            } => write!(
+                f,
+                "address {} is not in any {} memory",
+                Addr(*addr as u64),
+                chip_name(chip)
+            ),
+            AddressError::PastEnd {
+                addr,
+                size,
+                chip,
+                region,
+            } => write!(
+                f,
+                "{}+{:#X} runs past end of {} {}, which ends at {}",
+                Addr(*addr as u64),
+                size,
+                chip_name(chip),
+                region.name,
+                Addr(region.end as u64)
+            ),
+            AddressError::NotAllowed {
+                chip,
+                region,
+                access,
+            } => match region.kind {
+                RegionKind::Otp => write!(
+                    f,
+                    "{} OTP can't be {} like memory, use the OTP commands",
+                    chip_name(chip),
+                    access
+                ),
+                _ => write!(f, "{} {} can't be {}", chip_name(chip), region.name, access),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMap {
+    pub chip: TargetID,
+    pub regions: &'static [Region],
+}
+impl MemoryMap {
+    pub fn for_target(chip: TargetID) -> Self {
+        let regions = match chip {
+            TargetID::Rp2040 => RP2040_REGIONS,
+            TargetID::Rp2350 => RP2350_REGIONS,
+        };
+        MemoryMap { chip, regions }
+    }
+
+    pub fn region(&self, addr: u32) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(addr))
+    }
+
+    // Checks that `size` bytes at `addr` are all in one region which allows `access`, returning
+    // that region
+    pub fn check(&self, addr: u32, size: u32, access: Access) -> Result<&Region, AddressError> {
+        let Some(region) = self.region(addr) else {
+            // only a region in the same 256M of the address space is taken as the one missed
+            let after = self
+                .regions
+                .iter()
+                .rev()
+                .find(|r| r.end <= addr && r.start >> 28 == addr >> 28)
+                .copied();
+            return Err(AddressError::Unmapped {
+                addr,
+                chip: self.chip,
+                after,
+            });
+        };
+        if !region.allows(access) {
+            return Err(AddressError::NotAllowed {
+                chip: self.chip,
+                region: *region,
+                access,
+            });
+        }
+        if addr as u64 + size as u64 > region.end as u64 {
+            return Err(AddressError::PastEnd {
+                addr,
+                size,
+                chip: self.chip,
+                region: *region,
+            });
+        }
+        Ok(region)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic_stays_in_its_space() {
+        let flash = FlashAddr::new(0x10000000).unwrap();
+        assert_eq!(flash.checked_add(0x100), FlashAddr::new(0x10000100));
+        assert_eq!(
+            flash.checked_add(SPACE_SIZE - 1),
+            FlashAddr::new(0x1FFFFFFF)
+        );
+        assert_eq!(flash.checked_add(SPACE_SIZE), None);
+        assert_eq!(flash.checked_add(u32::MAX), None);
+        let later = flash.checked_add(0x1000).unwrap();
+        assert_eq!(later.checked_sub(flash), Some(0x1000));
+        assert_eq!(flash.checked_sub(later), None);
+
+        let sram = SramAddr::new(0x2FFFFFF0).unwrap();
+        assert_eq!(sram.checked_add(0x10), None);
+        assert_eq!(
+            MemAddr::Rom(0x0FFFFFFF).checked_add(1),
+            None,
+            "ROM doesn't run on into flash"
+        );
+    }
+
+    #[test]
+    fn mem_addr_sorts_addresses_into_spaces() {
+        assert_eq!(MemAddr::new(0x00000010), Some(MemAddr::Rom(0x10)));
+        assert_eq!(
+            MemAddr::new(0x10000010),
+            Some(MemAddr::Flash(FlashAddr(0x10000010)))
+        );
+        assert_eq!(
+            MemAddr::new(0x20000010),
+            Some(MemAddr::Sram(SramAddr(0x20000010)))
+        );
+        assert_eq!(MemAddr::new(0x40130000), None);
+        assert_eq!(MemAddr::from(SramAddr::START).get(), SRAM_START);
+    }
+}
@@ -0,0 +1,779 @@
+use crate::memmap::{FlashAddr, SramAddr};
+use crate::otp::{self, OtpLock, OTP_PAGE_ROWS, OTP_ROW_COUNT};
+use crate::picousb::TargetID;
+use crate::protocol::{
+    PicobootCmd, PicobootOtpCmd, PicobootStatus, PicobootStatusCmd, PICO_PAGE_SIZE,
+    PICO_SECTOR_SIZE,
+};
+use crate::transport::Transport;
+
+use std::time::Duration;
+
+// bootrom status codes, as reported by GET_COMMAND_STATUS
+pub const STATUS_OK: u32 = PicobootStatus::Ok as u32;
+pub const STATUS_UNKNOWN_CMD: u32 = PicobootStatus::UnknownCmd as u32;
+pub const STATUS_INVALID_CMD_LENGTH: u32 = PicobootStatus::InvalidCmdLength as u32;
+pub const STATUS_INVALID_TRANSFER_LENGTH: u32 = PicobootStatus::InvalidTransferLength as u32;
+pub const STATUS_INVALID_ADDRESS: u32 = PicobootStatus::InvalidAddress as u32;
+pub const STATUS_BAD_ALIGNMENT: u32 = PicobootStatus::BadAlignment as u32;
+pub const STATUS_NOT_PERMITTED: u32 = PicobootStatus::NotPermitted as u32;
+
+// What the mock expects the host to do next
+#[derive(Debug)]
+enum Phase {
+    Command,
+    // data the host still has to send for the current command
+    DataOut {
+        addr: u32,
+        buf: Vec<u8>,
+        len: usize,
+    },
+    // OTP rows the host still has to send, 2 bytes a row with `ecc` or 4 raw
+    OtpOut {
+        row: u16,
+        ecc: bool,
+        buf: Vec<u8>,
+        len: usize,
+    },
+    // data waiting for the host to read
+    DataIn(Vec<u8>),
+    // the zero length ack, in the opposite direction of the data
+    AckIn,
+    AckOut,
+    // an error stalled the endpoints, until the interface is reset
+    Stalled,
+}
+
+// A reboot the host asked for, with the raw words of the command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reboot {
+    pub cmd_id: u8,
+    pub words: [u32; 4],
+}
+
+// In-memory stand-in for a device in BOOTSEL mode, to exercise the protocol and flashing code
+// without hardware. It keeps a flash array with real erase semantics (writes can only clear
+// bits, erases must be whole sectors), SRAM, OTP rows on the RP2350, exclusive access and reboot
+// requests, and answers with the same status codes and endpoint stalls as the bootrom
+pub struct MockPicoboot {
+    target: TargetID,
+    flash: Vec<u8>,
+    sram: Vec<u8>,
+    otp: Vec<u32>,
+    phase: Phase,
+
+    token: u32,
+    cmd_id: u8,
+    status: u32,
+
+    exclusive: u8,
+    reboots: Vec<Reboot>,
+    commands: Vec<u8>,
+    fail: Option<(u8, u32)>,
+}
+
+impl MockPicoboot {
+    // An erased device with the usual flash and SRAM sizes of `target`
+    pub fn new(target: TargetID) -> Self {
+        match target {
+            TargetID::Rp2040 => Self::with_sizes(target, 2 * 1024 * 1024, 264 * 1024),
+            TargetID::Rp2350 => Self::with_sizes(target, 4 * 1024 * 1024, 520 * 1024),
+        }
+    }
+
+    pub fn with_sizes(target: TargetID, flash_size: usize, sram_size: usize) -> Self {
+        MockPicoboot {
+            target,
+            flash: vec![0xFF; flash_size],
+            sram: vec![0; sram_size],
+            otp: vec![0; OTP_ROW_COUNT as usize],
+            phase: Phase::Command,
+            token: 0,
+            cmd_id: 0,
+            status: STATUS_OK,
+            exclusive: 0,
+            reboots: vec![],
+            commands: vec![],
+            fail: None,
+        }
+    }
+
+    pub fn target(&self) -> TargetID {
+        self.target
+    }
+
+    // Flash contents, starting at `PICO_FLASH_START`
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    pub fn flash_mut(&mut self) -> &mut [u8] {
+        &mut self.flash
+    }
+
+    pub fn sram(&self) -> &[u8] {
+        &self.sram
+    }
+
+    // OTP contents as the 24 raw bits of each row. Page locks in the PAGEn_LOCK1 rows are kept
+    // to as the bootloader would, so a test can lock a page by setting its row
+    pub fn otp(&self) -> &[u32] {
+        &self.otp
+    }
+
+    pub fn otp_mut(&mut self) -> &mut [u32] {
+        &mut self.otp
+    }
+
+    // The last exclusive access level asked for: 0 none, 1 exclusive, 2 exclusive and ejected
+    pub fn exclusive(&self) -> u8 {
+        self.exclusive
+    }
+
+    pub fn reboots(&self) -> &[Reboot] {
+        &self.reboots
+    }
+
+    // IDs of every command received so far, in order
+    pub fn commands(&self) -> &[u8] {
+        &self.commands
+    }
+
+    // Makes the next command with `cmd_id` fail with `status`, as if the bootrom rejected it
+    pub fn fail_next(&mut self, cmd_id: u8, status: u32) {
+        self.fail = Some((cmd_id, status));
+    }
+
+    // Makes the next command with `cmd_id` stall with no status to say why, as a flaky hub or
+    // cable can, which hosts may retry
+    pub fn stall_next(&mut self, cmd_id: u8) {
+        self.fail = Some((cmd_id, STATUS_OK));
+    }
+
+    fn stall(&mut self, status: u32) -> rusb::Error {
+        self.status = status;
+        self.phase = Phase::Stalled;
+        rusb::Error::Pipe
+    }
+
+    // Finishes the current command, moving on to its ack
+    fn done(&mut self) {
+        self.phase = if self.cmd_id & 0x80 != 0 {
+            Phase::AckOut
+        } else {
+            Phase::AckIn
+        };
+    }
+
+    // Maps `addr..addr+len` to flash or SRAM
+    fn region(&mut self, addr: u32, len: usize) -> Option<(&mut Vec<u8>, usize, bool)> {
+        let (mem, offset, is_flash) = if let Some(addr) = SramAddr::new(addr) {
+            (&mut self.sram, addr.offset(), false)
+        } else {
+            (&mut self.flash, FlashAddr::new(addr)?.offset(), true)
+        };
+        let offset = offset as usize;
+        if offset + len > mem.len() {
+            return None;
+        }
+        Some((mem, offset, is_flash))
+    }
+
+    // Checks every page `row..row+count` covers can be read, or also written with `write`
+    fn otp_access(&self, row: u16, count: u16, write: bool) -> std::result::Result<(), u32> {
+        if count == 0 || row as u32 + count as u32 > OTP_ROW_COUNT as u32 {
+            return Err(STATUS_INVALID_ADDRESS);
+        }
+        for page in row / OTP_PAGE_ROWS..=(row + count - 1) / OTP_PAGE_ROWS {
+            match otp::decode_page_lock(self.otp[otp::page_lock1_row(page) as usize]) {
+                OtpLock::ReadWrite => {}
+                OtpLock::ReadOnly if !write => {}
+                _ => return Err(STATUS_NOT_PERMITTED),
+            }
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, buf: &[u8]) -> std::result::Result<(), u32> {
+        let Some(cmd) = PicobootCmd::parse(buf) else {
+            return Err(STATUS_UNKNOWN_CMD);
+        };
+        self.token = cmd.token;
+        self.cmd_id = cmd.cmd_id;
+        let transfer_len = cmd.transfer_len as usize;
+        let word = |i: usize| u32::from_le_bytes(cmd.args[i..i + 4].try_into().unwrap());
+        let args: [u32; 4] = [word(0), word(4), word(8), word(12)];
+        self.commands.push(self.cmd_id);
+
+        if let Some((id, status)) = self.fail {
+            if id == self.cmd_id {
+                self.fail = None;
+                return Err(status);
+            }
+        }
+        // data in commands have the top bit set, and must ask for data if they move any
+        if cmd.is_data_in() != (transfer_len != 0) && !matches!(self.cmd_id, 0x5 | 0xD) {
+            return Err(STATUS_INVALID_TRANSFER_LENGTH);
+        }
+
+        let rp2350 = matches!(self.target, TargetID::Rp2350);
+        match self.cmd_id {
+            // EXCLUSIVE_ACCESS
+            0x1 => self.exclusive = cmd.args[0],
+            // REBOOT, REBOOT2
+            0x2 if !rp2350 => self.reboots.push(Reboot {
+                cmd_id: self.cmd_id,
+                words: args,
+            }),
+            0xA if rp2350 => self.reboots.push(Reboot {
+                cmd_id: self.cmd_id,
+                words: args,
+            }),
+            // FLASH_ERASE
+            0x3 => {
+                let (addr, size) = (args[0], args[1] as usize);
+                if !addr.is_multiple_of(PICO_SECTOR_SIZE)
+                    || !size.is_multiple_of(PICO_SECTOR_SIZE as usize)
+                {
+                    return Err(STATUS_BAD_ALIGNMENT);
+                }
+                match self.region(addr, size) {
+                    Some((mem, offset, true)) => mem[offset..offset + size].fill(0xFF),
+                    _ => return Err(STATUS_INVALID_ADDRESS),
+                }
+            }
+            // READ
+            0x84 => {
+                let (addr, size) = (args[0], args[1] as usize);
+                if size != transfer_len {
+                    return Err(STATUS_INVALID_TRANSFER_LENGTH);
+                }
+                let data = match self.region(addr, size) {
+                    Some((mem, offset, _)) => mem[offset..offset + size].to_vec(),
+                    None => return Err(STATUS_INVALID_ADDRESS),
+                };
+                self.phase = Phase::DataIn(data);
+                return Ok(());
+            }
+            // WRITE
+            0x5 => {
+                let (addr, size) = (args[0], args[1] as usize);
+                if size != transfer_len || size == 0 {
+                    return Err(STATUS_INVALID_TRANSFER_LENGTH);
+                }
+                match self.region(addr, size) {
+                    Some((_, _, true))
+                        if !addr.is_multiple_of(PICO_PAGE_SIZE as u32)
+                            || !size.is_multiple_of(PICO_PAGE_SIZE) =>
+                    {
+                        return Err(STATUS_BAD_ALIGNMENT)
+                    }
+                    Some(_) => {}
+                    None => return Err(STATUS_INVALID_ADDRESS),
+                }
+                self.phase = Phase::DataOut {
+                    addr,
+                    buf: Vec::with_capacity(size),
+                    len: size,
+                };
+                return Ok(());
+            }
+            // EXIT_XIP, ENTER_CMD_XIP
+            0x6 | 0x7 => {}
+            // EXEC, the mock has no CPU so this only checks the address
+            0x8 if !rp2350 => {
+                if !matches!(self.region(args[0] & !1, 2), Some((_, _, false))) {
+                    return Err(STATUS_INVALID_ADDRESS);
+                }
+            }
+            // VECTORIZE_FLASH, the mock has no flash functions to copy
+            0x9 if !rp2350 => {
+                if !matches!(self.region(args[0], 4), Some((_, _, false))) {
+                    return Err(STATUS_INVALID_ADDRESS);
+                }
+            }
+            // GET_INFO, for system info and the partition table
+            0x8B if rp2350 => {
+                let words = match args[0] & 0xFF {
+                    1 => self.sys_info(),
+                    // no partition table, with all of flash open to everyone
+                    2 => vec![3, 0x1 | 0x10, 0, 0xFC000000],
+                    _ => return Err(STATUS_NOT_PERMITTED),
+                };
+                let mut buf: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+                buf.resize(transfer_len, 0);
+                self.phase = Phase::DataIn(buf);
+                return Ok(());
+            }
+            // OTP_READ, ECC rows as their 16 bits of data and raw rows as 24 bits in a word
+            0x8C if rp2350 => {
+                let otp = PicobootOtpCmd::parse(&cmd.args);
+                let row_size = if otp.ecc { 2 } else { 4 };
+                if transfer_len != otp.row_count as usize * row_size {
+                    return Err(STATUS_INVALID_TRANSFER_LENGTH);
+                }
+                self.otp_access(otp.row, otp.row_count, false)?;
+                let rows = &self.otp[otp.row as usize..(otp.row + otp.row_count) as usize];
+                let buf = rows
+                    .iter()
+                    .flat_map(|&raw| {
+                        if otp.ecc {
+                            (raw as u16).to_le_bytes().to_vec()
+                        } else {
+                            raw.to_le_bytes().to_vec()
+                        }
+                    })
+                    .collect();
+                self.phase = Phase::DataIn(buf);
+                return Ok(());
+            }
+            // OTP_WRITE
+            0xD if rp2350 => {
+                let otp = PicobootOtpCmd::parse(&cmd.args);
+                let row_size = if otp.ecc { 2 } else { 4 };
+                if transfer_len == 0 || transfer_len != otp.row_count as usize * row_size {
+                    return Err(STATUS_INVALID_TRANSFER_LENGTH);
+                }
+                self.otp_access(otp.row, otp.row_count, true)?;
+                self.phase = Phase::OtpOut {
+                    row: otp.row,
+                    ecc: otp.ecc,
+                    buf: Vec::with_capacity(transfer_len),
+                    len: transfer_len,
+                };
+                return Ok(());
+            }
+            _ => return Err(STATUS_UNKNOWN_CMD),
+        }
+        self.done();
+        Ok(())
+    }
+
+    // GET_INFO system info response with the chip info, CPU and flash device info words
+    fn sys_info(&self) -> Vec<u32> {
+        // the flash size field of cs0 is log2 of the size in 4K sectors
+        let cs0 = (self.flash.len() as u32 / 4096).trailing_zeros();
+        vec![6, 0x1 | 0x4 | 0x8, 0, 0x4, 0xDEC0DE, 0, cs0 << 8]
+    }
+}
+
+impl Transport for MockPicoboot {
+    fn bulk_read(&mut self, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        match std::mem::replace(&mut self.phase, Phase::Command) {
+            Phase::DataIn(mut data) => {
+                let len = std::cmp::min(buf.len(), data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                // the rest is left for the next read, as when a host reads in chunks
+                if len < data.len() {
+                    self.phase = Phase::DataIn(data.split_off(len));
+                } else {
+                    self.done();
+                }
+                Ok(len)
+            }
+            Phase::AckIn => Ok(0),
+            Phase::Stalled => {
+                self.phase = Phase::Stalled;
+                Err(rusb::Error::Pipe)
+            }
+            _ => Err(self.stall(STATUS_INVALID_CMD_LENGTH)),
+        }
+    }
+
+    fn bulk_write(&mut self, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        match std::mem::replace(&mut self.phase, Phase::Command) {
+            Phase::Command => {
+                self.status = STATUS_OK;
+                match self.command(buf) {
+                    Ok(()) => Ok(buf.len()),
+                    // the command itself is taken, the stall shows on the next transfer
+                    Err(status) => {
+                        self.stall(status);
+                        Ok(buf.len())
+                    }
+                }
+            }
+            Phase::DataOut {
+                addr,
+                buf: mut data,
+                len,
+            } => {
+                let take = std::cmp::min(buf.len(), len - data.len());
+                data.extend_from_slice(&buf[..take]);
+                if data.len() < len {
+                    self.phase = Phase::DataOut {
+                        addr,
+                        buf: data,
+                        len,
+                    };
+                    return Ok(take);
+                }
+                let (mem, offset, is_flash) = self.region(addr, len).unwrap();
+                let dst = &mut mem[offset..offset + len];
+                if is_flash {
+                    // programming flash can only clear bits
+                    dst.iter_mut().zip(&data).for_each(|(d, s)| *d &= s);
+                } else {
+                    dst.copy_from_slice(&data);
+                }
+                self.done();
+                Ok(take)
+            }
+            Phase::OtpOut {
+                row,
+                ecc,
+                buf: mut data,
+                len,
+            } => {
+                let take = std::cmp::min(buf.len(), len - data.len());
+                data.extend_from_slice(&buf[..take]);
+                if data.len() < len {
+                    self.phase = Phase::OtpOut {
+                        row,
+                        ecc,
+                        buf: data,
+                        len,
+                    };
+                    return Ok(take);
+                }
+                // programming OTP can only set bits, the bootrom adds the ECC bits itself
+                let values: Vec<u32> = if ecc {
+                    data.chunks_exact(2)
+                        .map(|c| otp::ecc_encode(u16::from_le_bytes([c[0], c[1]])))
+                        .collect()
+                } else {
+                    data.chunks_exact(4)
+                        .map(|c| u32::from_le_bytes(c.try_into().unwrap()) & 0xFFFFFF)
+                        .collect()
+                };
+                for (dst, value) in self.otp[row as usize..].iter_mut().zip(values) {
+                    *dst |= value;
+                }
+                self.done();
+                Ok(take)
+            }
+            Phase::AckOut => Ok(buf.len()),
+            Phase::Stalled => {
+                self.phase = Phase::Stalled;
+                Err(rusb::Error::Pipe)
+            }
+            _ => Err(self.stall(STATUS_INVALID_CMD_LENGTH)),
+        }
+    }
+
+    fn command_status(&mut self, buf: &mut [u8; 16], _timeout: Duration) -> rusb::Result<()> {
+        *buf = PicobootStatusCmd {
+            token: self.token,
+            status_code: self.status,
+            cmd_id: self.cmd_id,
+            in_progress: false,
+        }
+        .pack();
+        Ok(())
+    }
+
+    fn clear_halt(&mut self, _endpoint: u8) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn reset_interface(&mut self, _timeout: Duration) -> rusb::Result<()> {
+        self.phase = Phase::Command;
+        self.status = STATUS_OK;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memmap::FlashAddr;
+    use crate::picousb::{PicobootConnection, PicobootError, RetryPolicy, VerifyMode};
+    use crate::protocol::PICO_STACK_POINTER;
+
+    type Conn = PicobootConnection<MockPicoboot>;
+
+    fn connect(target: TargetID) -> Conn {
+        PicobootConnection::with_transport(MockPicoboot::new(target), Some(target))
+    }
+
+    // a connection retrying as by default, without waiting in between
+    fn connect_retrying(target: TargetID) -> Conn {
+        let mut conn = connect(target);
+        conn.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::ZERO,
+        });
+        conn
+    }
+
+    fn sent(conn: &Conn, cmd_id: u8) -> usize {
+        let commands = conn.transport().commands();
+        commands.iter().filter(|&&id| id == cmd_id).count()
+    }
+
+    fn flash_addr(addr: u32) -> FlashAddr {
+        FlashAddr::new(addr).unwrap()
+    }
+
+    fn flash(conn: &Conn, addr: u32, len: usize) -> &[u8] {
+        let offset = flash_addr(addr).offset() as usize;
+        &conn.transport().flash()[offset..offset + len]
+    }
+
+    fn status(e: PicobootError) -> PicobootStatus {
+        match e {
+            PicobootError::Command { status, .. } => status,
+            e => panic!("expected a command status, got {:?}", e),
+        }
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    #[test]
+    fn flash_program_writes_and_verifies() {
+        for target in [TargetID::Rp2040, TargetID::Rp2350] {
+            let mut conn = connect(target);
+            let data = image(3 * PICO_SECTOR_SIZE as usize + 512);
+            let verify = VerifyMode::ReadBack { retries: 2 };
+            conn.flash_program(flash_addr(0x10010000), &data, verify)
+                .unwrap();
+            assert_eq!(flash(&conn, 0x10010000, data.len()), &data[..]);
+            conn.verify_program(flash_addr(0x10010000), &data, 0)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn flash_program_keeps_the_rest_of_a_partial_sector() {
+        let mut conn = connect(TargetID::Rp2040);
+        let old = image(PICO_SECTOR_SIZE as usize);
+        conn.flash_program(flash_addr(0x10000000), &old, VerifyMode::None)
+            .unwrap();
+        conn.flash_program(flash_addr(0x10000100), &[0x11; 256], VerifyMode::None)
+            .unwrap();
+        let flash = flash(&conn, 0x10000000, PICO_SECTOR_SIZE as usize);
+        assert_eq!(&flash[..256], &old[..256]);
+        assert_eq!(&flash[256..512], &[0x11; 256]);
+        assert_eq!(&flash[512..], &old[512..]);
+    }
+
+    #[test]
+    fn flash_program_skips_unchanged_sectors() {
+        let mut conn = connect(TargetID::Rp2040);
+        let data = image(2 * PICO_SECTOR_SIZE as usize);
+        conn.flash_program(flash_addr(0x10000000), &data, VerifyMode::None)
+            .unwrap();
+        conn.set_skip_unchanged(true);
+        let mut changed = data.clone();
+        changed[PICO_SECTOR_SIZE as usize] ^= 0xFF;
+        let programmed = conn
+            .flash_program(flash_addr(0x10000000), &changed, VerifyMode::None)
+            .unwrap();
+        assert_eq!(programmed, PICO_SECTOR_SIZE as usize);
+        assert_eq!(conn.diagnostics().sectors_skipped, 1);
+        assert_eq!(flash(&conn, 0x10000000, changed.len()), &changed[..]);
+    }
+
+    #[test]
+    fn flash_erase_clears_whole_sectors() {
+        let mut conn = connect(TargetID::Rp2040);
+        let data = image(2 * PICO_SECTOR_SIZE as usize);
+        conn.flash_program(flash_addr(0x10000000), &data, VerifyMode::None)
+            .unwrap();
+        conn.flash_erase(flash_addr(0x10001000), PICO_SECTOR_SIZE)
+            .unwrap();
+        assert_eq!(flash(&conn, 0x10000000, 0x1000), &data[..0x1000]);
+        assert!(flash(&conn, 0x10001000, 0x1000).iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn writes_only_clear_bits() {
+        let mut conn = connect(TargetID::Rp2040);
+        conn.flash_write(flash_addr(0x10000000), vec![0x0F; PICO_PAGE_SIZE])
+            .unwrap();
+        conn.flash_write(flash_addr(0x10000000), vec![0xF3; PICO_PAGE_SIZE])
+            .unwrap();
+        assert_eq!(flash(&conn, 0x10000000, 4), &[0x03; 4]);
+        // which the verify after a write without an erase catches
+        let e = conn
+            .verify_program(flash_addr(0x10000000), &[0xF3; PICO_PAGE_SIZE], 1)
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            PicobootError::VerifyFailed {
+                addr: 0x10000000,
+                mismatched: PICO_PAGE_SIZE
+            }
+        ));
+    }
+
+    #[test]
+    fn verify_program_finds_changed_flash() {
+        let mut conn = connect(TargetID::Rp2350);
+        let data = image(PICO_SECTOR_SIZE as usize);
+        conn.flash_program(flash_addr(0x10000000), &data, VerifyMode::None)
+            .unwrap();
+        conn.transport_mut().flash_mut()[0x345] ^= 0x01;
+        let e = conn
+            .verify_program(flash_addr(0x10000000), &data, 2)
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            PicobootError::VerifyFailed {
+                addr: 0x10000300,
+                mismatched: 1
+            }
+        ));
+        let found = conn
+            .find_mismatches(flash_addr(0x10000000), &data, 1, 10)
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].addr, 0x10000345);
+        assert_eq!(found[0].expected, data[0x345]);
+    }
+
+    #[test]
+    fn device_crc_reads_back_on_the_rp2350() {
+        let mut conn = connect(TargetID::Rp2350);
+        let data = image(0x18000);
+        conn.flash_program(flash_addr(0x10000000), &data, VerifyMode::DeviceCrc)
+            .unwrap();
+        // no stub is loaded, the chunks are read back instead
+        assert!(!conn.transport().commands().contains(&0x8));
+        assert!(conn.transport().sram().iter().all(|&b| b == 0));
+
+        conn.transport_mut().flash_mut()[0x12345] ^= 0x80;
+        let e = conn
+            .verify_crc32(flash_addr(0x10000000), &data)
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            PicobootError::CrcMismatch {
+                addr: 0x10010000,
+                size: 0x8000
+            }
+        ));
+        conn.verify_crc32(flash_addr(0x10000000), &data[..0x10000])
+            .unwrap();
+    }
+
+    #[test]
+    fn misaligned_erase_stalls_and_recovers() {
+        let mut conn = connect(TargetID::Rp2040);
+        let e = conn.flash_erase(flash_addr(0x10000100), PICO_SECTOR_SIZE);
+        assert_eq!(status(e.unwrap_err()), PicobootStatus::BadAlignment);
+        let e = conn.flash_erase(flash_addr(0x10000000), 0x100);
+        assert_eq!(status(e.unwrap_err()), PicobootStatus::BadAlignment);
+        assert!(conn.diagnostics().interface_resets >= 2);
+        assert_eq!(
+            conn.diagnostics().clear_halts,
+            2 * conn.diagnostics().interface_resets
+        );
+        // the interface was reset, so the next command goes through
+        conn.flash_erase(flash_addr(0x10000000), PICO_SECTOR_SIZE)
+            .unwrap();
+    }
+
+    #[test]
+    fn misaligned_write_stalls_and_recovers() {
+        let mut conn = connect(TargetID::Rp2040);
+        let e = conn.flash_write(flash_addr(0x10000010), vec![0; PICO_PAGE_SIZE]);
+        assert_eq!(status(e.unwrap_err()), PicobootStatus::BadAlignment);
+        let e = conn.flash_write(flash_addr(0x10000000), vec![0; 100]);
+        assert_eq!(status(e.unwrap_err()), PicobootStatus::BadAlignment);
+        conn.flash_write(flash_addr(0x10000000), vec![0; PICO_PAGE_SIZE])
+            .unwrap();
+        assert_eq!(flash(&conn, 0x10000000, 4), &[0; 4]);
+    }
+
+    #[test]
+    fn fail_next_rejects_one_command() {
+        let mut conn = connect(TargetID::Rp2040);
+        conn.transport_mut().fail_next(0x5, STATUS_NOT_PERMITTED);
+        let e = conn.flash_program(flash_addr(0x10000000), &[0x42; 256], VerifyMode::None);
+        assert_eq!(status(e.unwrap_err()), PicobootStatus::NotPermitted);
+        assert!(flash(&conn, 0x10000000, 256).iter().all(|&b| b == 0xFF));
+        // only the next WRITE fails, so doing it again works
+        conn.flash_program(flash_addr(0x10000000), &[0x42; 256], VerifyMode::None)
+            .unwrap();
+        assert_eq!(flash(&conn, 0x10000000, 256), &[0x42; 256]);
+        assert_eq!(conn.diagnostics().status_errors, 1);
+    }
+
+    #[test]
+    fn stalled_commands_are_retried() {
+        let mut conn = connect_retrying(TargetID::Rp2040);
+        conn.transport_mut().stall_next(0x5);
+        conn.flash_write(flash_addr(0x10000000), vec![0x42; PICO_PAGE_SIZE])
+            .unwrap();
+        assert_eq!(flash(&conn, 0x10000000, 4), &[0x42; 4]);
+        assert_eq!(sent(&conn, 0x5), 2);
+        assert_eq!(conn.diagnostics().usb_retries, 1);
+    }
+
+    #[test]
+    fn rejected_commands_arent_retried() {
+        let mut conn = connect_retrying(TargetID::Rp2040);
+        conn.transport_mut().fail_next(0x5, STATUS_NOT_PERMITTED);
+        let e = conn.flash_write(flash_addr(0x10000000), vec![0x42; PICO_PAGE_SIZE]);
+        assert_eq!(status(e.unwrap_err()), PicobootStatus::NotPermitted);
+        assert_eq!(sent(&conn, 0x5), 1);
+        assert_eq!(conn.diagnostics().usb_retries, 0);
+    }
+
+    #[test]
+    fn reboots_and_execs_arent_retried() {
+        // each may have happened even though its ack was lost
+        let mut conn = connect_retrying(TargetID::Rp2040);
+        conn.transport_mut().stall_next(0x2);
+        let e = conn.reboot(0, PICO_STACK_POINTER, 10).unwrap_err();
+        assert!(matches!(e, PicobootError::Usb(rusb::Error::Pipe)));
+        assert_eq!(sent(&conn, 0x2), 1);
+
+        conn.transport_mut().stall_next(0x8);
+        let e = conn.exec(SramAddr::START).unwrap_err();
+        assert!(matches!(e, PicobootError::Usb(rusb::Error::Pipe)));
+        assert_eq!(sent(&conn, 0x8), 1);
+
+        let mut conn = connect_retrying(TargetID::Rp2350);
+        conn.transport_mut().stall_next(0xA);
+        let e = conn.reboot2_normal(10).unwrap_err();
+        assert!(matches!(e, PicobootError::Usb(rusb::Error::Pipe)));
+        assert_eq!(sent(&conn, 0xA), 1);
+        assert!(conn.transport().reboots().is_empty());
+        assert_eq!(conn.diagnostics().usb_retries, 0);
+    }
+
+    #[test]
+    fn reads_outside_memory_are_refused() {
+        // without a known chip the connection can't check addresses itself, so the mock does
+        let mut conn =
+            PicobootConnection::with_transport(MockPicoboot::new(TargetID::Rp2040), None);
+        let e = conn.flash_read(flash_addr(0x10000000 + 2 * 1024 * 1024), 256);
+        assert_eq!(status(e.unwrap_err()), PicobootStatus::InvalidAddress);
+        assert_eq!(
+            conn.flash_read(flash_addr(0x10000000), 4).unwrap(),
+            [0xFF; 4]
+        );
+    }
+
+    #[test]
+    fn loads_sram_and_reboots() {
+        let mut conn = connect(TargetID::Rp2040);
+        let segment = crate::image::Segment {
+            addr: SramAddr::START.get() + 0x100,
+            data: vec![1, 2, 3, 4],
+            family: None,
+        };
+        conn.load_ram(std::slice::from_ref(&segment)).unwrap();
+        assert_eq!(&conn.transport().sram()[0x100..0x104], &[1, 2, 3, 4]);
+        conn.reboot_into(crate::picousb::RebootMode::Normal, 10)
+            .unwrap();
+        let reboots = conn.transport().reboots();
+        assert_eq!(reboots.len(), 1);
+        assert_eq!(reboots[0].cmd_id, 0x2);
+        assert_eq!(reboots[0].words[2], 10);
+    }
+}
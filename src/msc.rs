@@ -0,0 +1,77 @@
+// Fallback flashing through the BOOTSEL mass storage drive (RPI-RP2 or RP2350), for when the
+// PICOBOOT interface can't be claimed because of driver or permission problems. The bootrom
+// flashes any UF2 copied onto the drive, then reboots, which makes the drive go away
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::picousb::TargetID;
+
+const INFO_FILE: &str = "INFO_UF2.TXT";
+
+pub struct BootselDrive {
+    pub path: PathBuf,
+    pub target: TargetID,
+}
+
+// Places a BOOTSEL drive may be mounted, for the platform we're running on
+fn mount_points() -> Vec<PathBuf> {
+    if cfg!(windows) {
+        (b'A'..=b'Z')
+            .map(|l| PathBuf::from(format!("{}:\\", l as char)))
+            .collect()
+    } else if cfg!(target_os = "macos") {
+        std::fs::read_dir("/Volumes")
+            .map(|d| d.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+            .unwrap_or_default()
+    } else {
+        // second field of each line of /proc/mounts is the mount point, with spaces escaped
+        std::fs::read_to_string("/proc/mounts")
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| l.split(' ').nth(1))
+            .map(|p| PathBuf::from(p.replace("\\040", " ")))
+            .collect()
+    }
+}
+
+// Works out the chip from the Board-ID line of INFO_UF2.TXT
+fn drive_target(path: &Path) -> Option<TargetID> {
+    let info = std::fs::read_to_string(path.join(INFO_FILE)).ok()?;
+    let board = info
+        .lines()
+        .find_map(|l| l.strip_prefix("Board-ID:"))?
+        .trim();
+    if board.starts_with("RPI-RP2") {
+        Some(TargetID::Rp2040)
+    } else if board.starts_with("RP2350") {
+        Some(TargetID::Rp2350)
+    } else {
+        None
+    }
+}
+
+pub fn find_bootsel_drive() -> Option<BootselDrive> {
+    mount_points()
+        .into_iter()
+        .find_map(|path| drive_target(&path).map(|target| BootselDrive { path, target }))
+}
+
+impl BootselDrive {
+    // Copies a UF2 onto the drive, then waits up to `timeout` for the device to reboot
+    pub fn flash_uf2(&self, uf2: &[u8], timeout: Duration) -> std::io::Result<()> {
+        std::fs::write(self.path.join("firmware.uf2"), uf2)?;
+
+        let start = Instant::now();
+        while self.path.join(INFO_FILE).exists() {
+            if start.elapsed() > timeout {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "drive did not go away after copying firmware",
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+// Node.js bindings, so Electron flashing GUIs and other JavaScript tools can use this crate
+// natively instead of spawning the CLI. Built into an addon with `cargo rustc --release --lib
+// --features node --crate-type cdylib`, then renamed to `picoboot.node`, and typed for
+// TypeScript in `include/picoboot.d.ts`. Flashing, reading and rebooting run on the libuv thread
+// pool and return promises, so the event loop never waits on USB. Failures reject with an
+// `Error` saying what went wrong, as `picoboot_last_error` does for the C API
+
+use napi::bindgen_prelude::{AsyncTask, Buffer};
+use napi::threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{Env, Error, JsFunction, Result, Task};
+use napi_derive::napi;
+
+use crate::image;
+use crate::memmap::MemAddr;
+use crate::picousb::{
+    PicobootConnectionBuilder, PicobootError, ProgressEvent, ProgressOp, RebootMode, TargetID,
+    VerifyMode,
+};
+use crate::shared::SharedPicoboot;
+use crate::transport::RusbTransport;
+
+// How many times a page that reads back wrong is read again before flashing fails
+const READ_RETRIES: u32 = 3;
+
+fn fail(what: &str, e: PicobootError) -> Error {
+    Error::from_reason(format!("{}: {}", what, e))
+}
+
+fn chip_name(target: TargetID) -> String {
+    match target {
+        TargetID::Rp2040 => "rp2040".to_string(),
+        TargetID::Rp2350 => "rp2350".to_string(),
+    }
+}
+
+fn context() -> Result<rusb::Context> {
+    rusb::Context::new()
+        .map_err(|e| Error::from_reason(format!("could not initialize libusb: {}", e)))
+}
+
+// A PICOBOOT device found by `listDevices`, see `picousb::DeviceInfo`
+#[napi(object)]
+pub struct DeviceInfo {
+    pub bus: u32,
+    pub address: u32,
+    pub ports: Vec<u32>,
+    pub serial: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    // "rp2040" or "rp2350"
+    pub chip: String,
+    pub in_use: Option<bool>,
+}
+
+// Passed to the progress callback of `flashFile` and `read`, with `op` one of "erase",
+// "write", "verify" or "read"
+#[napi(object)]
+pub struct Progress {
+    pub op: String,
+    pub done: f64,
+    pub total: f64,
+}
+impl From<ProgressEvent> for Progress {
+    fn from(p: ProgressEvent) -> Self {
+        let op = match p.op {
+            ProgressOp::Erase => "erase",
+            ProgressOp::Write => "write",
+            ProgressOp::Verify => "verify",
+            ProgressOp::Read => "read",
+        };
+        Progress {
+            op: op.to_string(),
+            done: p.done as f64,
+            total: p.total as f64,
+        }
+    }
+}
+
+// Every connected device in BOOTSEL mode, without opening any of them
+#[napi]
+pub fn list_devices() -> Result<Vec<DeviceInfo>> {
+    let devices =
+        crate::picousb::list_devices(&context()?).map_err(|e| fail("could not list devices", e))?;
+    Ok(devices
+        .into_iter()
+        .map(|d| DeviceInfo {
+            bus: d.bus.into(),
+            address: d.address.into(),
+            ports: d.ports.into_iter().map(Into::into).collect(),
+            serial: d.serial,
+            manufacturer: d.manufacturer,
+            product: d.product,
+            chip: chip_name(d.target),
+            in_use: d.in_use,
+        })
+        .collect())
+}
+
+type Connection = SharedPicoboot<RusbTransport<rusb::Context>>;
+type ProgressCallback = ThreadsafeFunction<Progress, ErrorStrategy::Fatal>;
+
+// An open device. Operations on it are queued one after another, and `close` releases it once
+// any still running are done
+#[napi]
+pub struct Device {
+    conn: Option<Connection>,
+}
+
+#[napi]
+impl Device {
+    // Opens the device with the given serial number, or the first found
+    #[napi(factory)]
+    pub fn open(serial: Option<String>) -> Result<Device> {
+        let mut builder = PicobootConnectionBuilder::new();
+        if let Some(serial) = &serial {
+            builder = builder.serial(serial);
+        }
+        let mut conn = builder
+            .build(context()?)
+            .map_err(|e| fail("could not open device", e))?;
+        conn.reset_interface()
+            .map_err(|e| fail("failed to reset interface", e))?;
+        Ok(Device {
+            conn: Some(SharedPicoboot::new(conn)),
+        })
+    }
+
+    fn conn(&self) -> Result<Connection> {
+        self.conn
+            .clone()
+            .ok_or_else(|| Error::from_reason("device is closed"))
+    }
+
+    // "rp2040" or "rp2350", or null if the chip isn't known
+    #[napi(getter)]
+    pub fn chip(&self) -> Result<Option<String>> {
+        Ok(self.conn()?.lock().get_device_type().map(chip_name))
+    }
+
+    // Flashes a UF2, ELF, Intel HEX or binary file (binaries go at the start of flash),
+    // verifying what's written by reading it back, and loads any SRAM segments. Resolves to the
+    // bytes of flash programmed. The device isn't rebooted, see `reboot`
+    #[napi(ts_args_type = "path: string, onProgress?: (progress: Progress) => void")]
+    pub fn flash_file(
+        &self,
+        path: String,
+        on_progress: Option<JsFunction>,
+    ) -> Result<AsyncTask<FlashFile>> {
+        Ok(AsyncTask::new(FlashFile {
+            conn: self.conn()?,
+            path,
+            progress: progress_callback(on_progress)?,
+        }))
+    }
+
+    // Reads `len` bytes of flash (or other memory) at `addr`
+    #[napi(ts_args_type = "addr: number, len: number, onProgress?: (progress: Progress) => void")]
+    pub fn read(
+        &self,
+        addr: u32,
+        len: u32,
+        on_progress: Option<JsFunction>,
+    ) -> Result<AsyncTask<Read>> {
+        let addr = MemAddr::new(addr).ok_or_else(|| {
+            Error::from_reason(format!("{:#X} is not in ROM, flash or SRAM", addr))
+        })?;
+        Ok(AsyncTask::new(Read {
+            conn: self.conn()?,
+            addr,
+            len,
+            progress: progress_callback(on_progress)?,
+        }))
+    }
+
+    // Reboots into the flashed firmware after `delayMs` milliseconds, or back into BOOTSEL if
+    // `bootsel` is true. The device should be closed afterwards, as it leaves the bus
+    #[napi]
+    pub fn reboot(
+        &self,
+        delay_ms: Option<u32>,
+        bootsel: Option<bool>,
+    ) -> Result<AsyncTask<Reboot>> {
+        let mode = if bootsel == Some(true) {
+            RebootMode::Bootsel
+        } else {
+            RebootMode::Normal
+        };
+        Ok(AsyncTask::new(Reboot {
+            conn: self.conn()?,
+            mode,
+            delay: delay_ms.unwrap_or(0),
+        }))
+    }
+
+    #[napi]
+    pub fn close(&mut self) {
+        self.conn = None;
+    }
+}
+
+fn progress_callback(f: Option<JsFunction>) -> Result<Option<ProgressCallback>> {
+    f.map(|f| {
+        f.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<Progress>| {
+            Ok(vec![ctx.value])
+        })
+    })
+    .transpose()
+}
+
+// Runs `f` with progress going to `progress`, if given, from the worker thread
+fn with_progress<R>(
+    conn: &Connection,
+    progress: &Option<ProgressCallback>,
+    f: impl FnOnce(&mut crate::picousb::UsbConnection) -> Result<R>,
+) -> Result<R> {
+    conn.with(|conn| {
+        if let Some(progress) = progress.clone() {
+            conn.set_progress_handler(move |p| {
+                progress.call(p.into(), ThreadsafeFunctionCallMode::NonBlocking);
+            });
+        }
+        let res = f(conn);
+        conn.clear_progress_handler();
+        res
+    })
+}
+
+pub struct FlashFile {
+    conn: Connection,
+    path: String,
+    progress: Option<ProgressCallback>,
+}
+impl Task for FlashFile {
+    type Output = usize;
+    type JsValue = f64;
+
+    fn compute(&mut self) -> Result<usize> {
+        let path = &self.path;
+        with_progress(&self.conn, &self.progress, |conn| {
+            let segments =
+                image::load_input(path).map_err(|e| Error::from_reason(e.to_string()))?;
+            let segments = match conn.get_device_type() {
+                Some(target) => crate::uf2::filter_for_target(segments, target),
+                None => segments,
+            };
+            if segments.is_empty() {
+                return Err(Error::from_reason(format!(
+                    "{} has nothing for the connected chip",
+                    path
+                )));
+            }
+            let mut conn = conn
+                .exclusive_access_guard(true)
+                .map_err(|e| fail("failed to claim access", e))?
+                .reset_on_drop(true);
+            conn.exit_xip()
+                .map_err(|e| fail("failed to exit from xip mode", e))?;
+            let verify = VerifyMode::ReadBack {
+                retries: READ_RETRIES,
+            };
+            conn.program_segments(&segments, verify)
+                .map_err(|e| fail("failed to flash image", e))
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, programmed: usize) -> Result<f64> {
+        Ok(programmed as f64)
+    }
+}
+
+pub struct Read {
+    conn: Connection,
+    addr: MemAddr,
+    len: u32,
+    progress: Option<ProgressCallback>,
+}
+impl Task for Read {
+    type Output = Vec<u8>;
+    type JsValue = Buffer;
+
+    fn compute(&mut self) -> Result<Vec<u8>> {
+        let (addr, len) = (self.addr, self.len);
+        with_progress(&self.conn, &self.progress, |conn| {
+            let mut conn = conn
+                .exclusive_access_guard(false)
+                .map_err(|e| fail("failed to claim access", e))?
+                .reset_on_drop(true);
+            conn.exit_xip()
+                .map_err(|e| fail("failed to exit from xip mode", e))?;
+            conn.flash_read_all(addr, len, READ_RETRIES)
+                .map_err(|e| fail("failed to read", e))
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, data: Vec<u8>) -> Result<Buffer> {
+        Ok(data.into())
+    }
+}
+
+pub struct Reboot {
+    conn: Connection,
+    mode: RebootMode,
+    delay: u32,
+}
+impl Task for Reboot {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<()> {
+        self.conn
+            .lock()
+            .reboot_into(self.mode, self.delay)
+            .map_err(|e| fail("failed to reboot device", e))
+    }
+
+    fn resolve(&mut self, _env: Env, _: ()) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,1011 @@
+// Helpers for interpreting the RP2350 OTP, see section 13 of the RP2350 datasheet
+// https://datasheets.raspberrypi.com/rp2350/rp2350-datasheet.pdf
+
+use crate::transport::Transport;
+
+use crate::picousb::{self, PicobootConnection};
+
+// CRIT1 is stored raw in 8 redundant rows, a bit counts as set if it's set in 3 of them
+pub const OTP_ROW_CRIT1: u16 = 0x040;
+const OTP_CRIT1_COPIES: u16 = 8;
+const OTP_CRIT1_VOTES: usize = 3;
+const CRIT1_SECURE_BOOT_ENABLE: u32 = 1 << 0;
+const CRIT1_SECURE_DEBUG_DISABLE: u32 = 1 << 1;
+const CRIT1_DEBUG_DISABLE: u32 = 1 << 2;
+const CRIT1_BOOT_ARCH: u32 = 1 << 3;
+const CRIT1_GLITCH_DETECTOR_ENABLE: u32 = 1 << 4;
+
+// BOOT_FLAGS1 is stored raw in 3 redundant rows, a bit counts as set if it's set in 2 of them
+pub const OTP_ROW_BOOT_FLAGS1: u16 = 0x04B;
+const OTP_BOOT_FLAGS_COPIES: u16 = 3;
+const OTP_BOOT_FLAGS_VOTES: usize = 2;
+const BOOT_FLAGS1_KEY_VALID_SHIFT: u32 = 0;
+const BOOT_FLAGS1_KEY_INVALID_SHIFT: u32 = 8;
+
+pub const OTP_BOOT_KEY_COUNT: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootKeyState {
+    Unset,
+    Valid,
+    Invalid,
+}
+
+#[derive(Debug, Clone)]
+pub struct SecureBootState {
+    pub secure_boot_enabled: bool,
+    pub secure_debug_disabled: bool,
+    pub debug_disabled: bool,
+    pub glitch_detector_enabled: bool,
+    pub boot_arch_riscv: bool,
+    pub boot_keys: [BootKeyState; OTP_BOOT_KEY_COUNT],
+}
+impl SecureBootState {
+    pub fn read<T: Transport>(conn: &mut PicobootConnection<T>) -> picousb::Result<Self> {
+        let crit1 = read_redundant(conn, OTP_ROW_CRIT1, OTP_CRIT1_COPIES, OTP_CRIT1_VOTES)?;
+        let boot_flags1 = read_redundant(
+            conn,
+            OTP_ROW_BOOT_FLAGS1,
+            OTP_BOOT_FLAGS_COPIES,
+            OTP_BOOT_FLAGS_VOTES,
+        )?;
+
+        let mut boot_keys = [BootKeyState::Unset; OTP_BOOT_KEY_COUNT];
+        for (i, key) in boot_keys.iter_mut().enumerate() {
+            let valid = boot_flags1 & (1 << (BOOT_FLAGS1_KEY_VALID_SHIFT + i as u32)) != 0;
+            let invalid = boot_flags1 & (1 << (BOOT_FLAGS1_KEY_INVALID_SHIFT + i as u32)) != 0;
+            *key = match (valid, invalid) {
+                (_, true) => BootKeyState::Invalid,
+                (true, false) => BootKeyState::Valid,
+                (false, false) => BootKeyState::Unset,
+            };
+        }
+
+        Ok(SecureBootState {
+            secure_boot_enabled: crit1 & CRIT1_SECURE_BOOT_ENABLE != 0,
+            secure_debug_disabled: crit1 & CRIT1_SECURE_DEBUG_DISABLE != 0,
+            debug_disabled: crit1 & CRIT1_DEBUG_DISABLE != 0,
+            glitch_detector_enabled: crit1 & CRIT1_GLITCH_DETECTOR_ENABLE != 0,
+            boot_arch_riscv: crit1 & CRIT1_BOOT_ARCH != 0,
+            boot_keys,
+        })
+    }
+
+    // With secure boot enabled the bootrom will only boot images signed by a valid boot key
+    pub fn requires_signed_images(&self) -> bool {
+        self.secure_boot_enabled
+    }
+}
+
+// Reads BOOT_FLAGS1, which holds the valid and invalid bits of each boot key
+pub fn read_boot_flags1<T: Transport>(conn: &mut PicobootConnection<T>) -> picousb::Result<u32> {
+    read_redundant(
+        conn,
+        OTP_ROW_BOOT_FLAGS1,
+        OTP_BOOT_FLAGS_COPIES,
+        OTP_BOOT_FLAGS_VOTES,
+    )
+}
+
+// The OTP writes installing a boot key hash (see `keys::PublicKey::hash`) in a BOOTKEYn slot
+// and marking it valid in BOOT_FLAGS1, given what that holds now. The hash goes in 16 ECC rows,
+// 2 bytes to a row. None for a slot past the last. This alone doesn't turn on secure boot,
+// which takes CRIT1 too
+pub fn boot_key_writes(slot: usize, hash: &[u8; 32], boot_flags1: u32) -> Option<Vec<OtpWrite>> {
+    if slot >= OTP_BOOT_KEY_COUNT {
+        return None;
+    }
+    let first = find_row(&format!("BOOTKEY{}", slot))?.row;
+    let mut writes: Vec<OtpWrite> = hash
+        .chunks(2)
+        .zip(first..)
+        .map(|(pair, row)| OtpWrite {
+            row,
+            value: u16::from_le_bytes([pair[0], pair[1]]) as u32,
+            ecc: true,
+            info: row_info(row),
+        })
+        .collect();
+    let flags = boot_flags1 | 1 << (BOOT_FLAGS1_KEY_VALID_SHIFT + slot as u32);
+    for copy in 0..OTP_BOOT_FLAGS_COPIES {
+        let row = OTP_ROW_BOOT_FLAGS1 + copy;
+        writes.push(OtpWrite {
+            row,
+            value: flags,
+            ecc: false,
+            info: row_info(row),
+        });
+    }
+    Some(writes)
+}
+
+// Reads raw OTP rows, each as the 24 bits of data they hold
+pub fn read_raw_rows<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    row: u16,
+    row_count: u16,
+) -> picousb::Result<Vec<u32>> {
+    let buf = conn.otp_read(row, row_count, false)?;
+    Ok(buf
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()) & 0xFFFFFF)
+        .collect())
+}
+
+// Reads OTP rows as 16 bits of ECC corrected data each with `ecc`, otherwise as 24 raw bits
+pub fn read_rows<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    row: u16,
+    row_count: u16,
+    ecc: bool,
+) -> picousb::Result<Vec<u32>> {
+    if !ecc {
+        return read_raw_rows(conn, row, row_count);
+    }
+    let buf = conn.otp_read(row, row_count, true)?;
+    Ok(buf
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes(c.try_into().unwrap()) as u32)
+        .collect())
+}
+
+// Programs consecutive OTP rows, each 16 bits of data the bootrom adds ECC to with `ecc`,
+// otherwise 24 raw bits
+pub fn write_rows<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    row: u16,
+    values: &[u32],
+    ecc: bool,
+) -> picousb::Result<()> {
+    let data = values
+        .iter()
+        .flat_map(|&v| {
+            if ecc {
+                (v as u16).to_le_bytes().to_vec()
+            } else {
+                (v & 0xFFFFFF).to_le_bytes().to_vec()
+            }
+        })
+        .collect();
+    conn.otp_write(row, ecc, data)
+}
+
+// Reads a whole page of rows as `read_rows` does, or None if the page is locked against the
+// bootloader
+pub fn read_page<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    page: u16,
+    ecc: bool,
+) -> picousb::Result<Option<Vec<u32>>> {
+    match read_rows(conn, page * OTP_PAGE_ROWS, OTP_PAGE_ROWS, ecc) {
+        Ok(values) => Ok(Some(values)),
+        Err(picousb::PicobootError::Command {
+            status: picousb::PicobootStatus::NotPermitted,
+            ..
+        }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Reads a value stored redundantly across `copies` raw rows, where each bit is
+// considered set when it is set in at least `votes` of the copies
+pub(crate) fn read_redundant<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    row: u16,
+    copies: u16,
+    votes: usize,
+) -> picousb::Result<u32> {
+    let rows = read_raw_rows(conn, row, copies)?;
+    Ok((0..24)
+        .filter(|bit| rows.iter().filter(|r| *r & (1 << bit) != 0).count() >= votes)
+        .fold(0, |acc, bit| acc | (1 << bit)))
+}
+
+pub const OTP_ROW_COUNT: u16 = 4096;
+pub const OTP_PAGE_ROWS: u16 = 64;
+const OTP_ROW_PAGE0_LOCK0: u16 = 0xF80;
+const LOCK1_BL_SHIFT: u32 = 4;
+
+// Known OTP rows as (name, first row, row count, ECC protected), from the OTP data listing in
+// the RP2350 datasheet. Redundant raw rows are listed once, with the copies as extra rows
+const OTP_ROWS: &[(&str, u16, u16, bool)] = &[
+    ("CHIPID0", 0x000, 1, true),
+    ("CHIPID1", 0x001, 1, true),
+    ("CHIPID2", 0x002, 1, true),
+    ("CHIPID3", 0x003, 1, true),
+    ("RANDID0", 0x004, 1, true),
+    ("RANDID1", 0x005, 1, true),
+    ("RANDID2", 0x006, 1, true),
+    ("RANDID3", 0x007, 1, true),
+    ("RANDID4", 0x008, 1, true),
+    ("RANDID5", 0x009, 1, true),
+    ("RANDID6", 0x00A, 1, true),
+    ("RANDID7", 0x00B, 1, true),
+    ("ROSC_CALIB", 0x010, 1, true),
+    ("LPOSC_CALIB", 0x011, 1, true),
+    ("NUM_GPIOS", 0x018, 1, true),
+    ("INFO_CRC0", 0x036, 1, true),
+    ("INFO_CRC1", 0x037, 1, true),
+    ("CRIT0", 0x038, 8, false),
+    ("CRIT1", OTP_ROW_CRIT1, 8, false),
+    ("BOOT_FLAGS0", 0x048, 3, false),
+    ("BOOT_FLAGS1", OTP_ROW_BOOT_FLAGS1, 3, false),
+    ("DEFAULT_BOOT_VERSION0", 0x04E, 3, false),
+    ("DEFAULT_BOOT_VERSION1", 0x051, 3, false),
+    ("FLASH_DEVINFO", 0x054, 1, true),
+    ("FLASH_PARTITION_SLOT_SIZE", 0x055, 1, true),
+    ("BOOTSEL_LED_CFG", 0x056, 1, true),
+    ("BOOTSEL_PLL_CFG", 0x057, 1, true),
+    ("BOOTSEL_XOSC_CFG", 0x058, 1, true),
+    ("USB_BOOT_FLAGS", 0x059, 3, false),
+    ("USB_WHITE_LABEL_ADDR", 0x05C, 1, true),
+    ("OTPBOOT_SRC", 0x05E, 1, true),
+    ("OTPBOOT_LEN", 0x05F, 1, true),
+    ("OTPBOOT_DST0", 0x060, 1, true),
+    ("OTPBOOT_DST1", 0x061, 1, true),
+    ("BOOTKEY0", 0x080, 16, true),
+    ("BOOTKEY1", 0x090, 16, true),
+    ("BOOTKEY2", 0x0A0, 16, true),
+    ("BOOTKEY3", 0x0B0, 16, true),
+    ("KEY1", 0xF48, 8, true),
+    ("KEY2", 0xF50, 8, true),
+    ("KEY3", 0xF58, 8, true),
+    ("KEY4", 0xF60, 8, true),
+    ("KEY5", 0xF68, 8, true),
+    ("KEY6", 0xF70, 8, true),
+    ("KEY1_VALID", 0xF79, 1, false),
+    ("KEY2_VALID", 0xF7A, 1, false),
+    ("KEY3_VALID", 0xF7B, 1, false),
+    ("KEY4_VALID", 0xF7C, 1, false),
+    ("KEY5_VALID", 0xF7D, 1, false),
+    ("KEY6_VALID", 0xF7E, 1, false),
+];
+
+// Rows where a bad write can permanently change how (or whether) the chip boots
+const OTP_CRITICAL_ROWS: &[&str] = &[
+    "CRIT0",
+    "CRIT1",
+    "BOOT_FLAGS0",
+    "BOOT_FLAGS1",
+    "BOOTKEY0",
+    "BOOTKEY1",
+    "BOOTKEY2",
+    "BOOTKEY3",
+];
+
+#[derive(Debug, Clone)]
+pub struct OtpRowInfo {
+    pub name: String,
+    pub row: u16,
+    pub count: u16,
+    pub ecc: bool,
+}
+impl OtpRowInfo {
+    pub fn is_critical(&self) -> bool {
+        OTP_CRITICAL_ROWS.contains(&self.name.as_str()) || self.name.starts_with("PAGE")
+    }
+}
+
+// Looks up a row by name. Page lock rows are named PAGEn_LOCK0 and PAGEn_LOCK1
+pub fn find_row(name: &str) -> Option<OtpRowInfo> {
+    let name = name.to_uppercase();
+    if let Some((page, lock)) = name
+        .strip_prefix("PAGE")
+        .and_then(|s| s.split_once("_LOCK"))
+    {
+        let page: u16 = page.parse().ok()?;
+        let lock: u16 = lock.parse().ok()?;
+        if page >= OTP_ROW_COUNT / OTP_PAGE_ROWS || lock > 1 {
+            return None;
+        }
+        return Some(OtpRowInfo {
+            name,
+            row: OTP_ROW_PAGE0_LOCK0 + page * 2 + lock,
+            count: 1,
+            ecc: false,
+        });
+    }
+
+    OTP_ROWS
+        .iter()
+        .find(|(n, _, _, _)| *n == name)
+        .map(|&(name, row, count, ecc)| OtpRowInfo {
+            name: name.to_string(),
+            row,
+            count,
+            ecc,
+        })
+}
+
+// Every known row (or group of rows), in row order, page locks included
+pub fn known_rows() -> Vec<OtpRowInfo> {
+    let mut rows: Vec<OtpRowInfo> = OTP_ROWS
+        .iter()
+        .map(|&(name, row, count, ecc)| OtpRowInfo {
+            name: name.to_string(),
+            row,
+            count,
+            ecc,
+        })
+        .collect();
+    rows.extend((OTP_ROW_PAGE0_LOCK0..OTP_ROW_COUNT).filter_map(row_info));
+    rows.sort_by_key(|r| r.row);
+    rows
+}
+
+// Whether the extra rows of a group are redundant copies of the first rather than more data,
+// as for the raw flag rows like CRIT1 and BOOT_FLAGS0
+pub fn is_redundant(info: &OtpRowInfo) -> bool {
+    !info.ecc && info.count > 1
+}
+
+// Rows picked on the command line, by name (`BOOT_FLAGS0`, covering the whole group), by name
+// and offset into a group (`BOOTKEY0+3`) or by number (`0x100`)
+#[derive(Debug, Clone)]
+pub struct OtpSelector {
+    pub row: u16,
+    pub count: u16,
+    pub info: Option<OtpRowInfo>,
+}
+impl std::str::FromStr for OtpSelector {
+    type Err = OtpError;
+
+    fn from_str(s: &str) -> Result<Self, OtpError> {
+        let unknown = || OtpError::UnknownRow(s.to_string());
+        let (name, offset) = match s.split_once('+') {
+            Some((name, offset)) => (
+                name,
+                Some(crate::image::parse_addr(offset).ok_or_else(unknown)?),
+            ),
+            None => (s, None),
+        };
+        if let Some(info) = find_row(name) {
+            return match offset {
+                None => Ok(OtpSelector {
+                    row: info.row,
+                    count: info.count,
+                    info: Some(info),
+                }),
+                Some(offset) if offset < info.count as u32 => Ok(OtpSelector {
+                    row: info.row + offset as u16,
+                    count: 1,
+                    info: Some(info),
+                }),
+                Some(_) => Err(unknown()),
+            };
+        }
+        let row = crate::image::parse_addr(s)
+            .filter(|&r| r < OTP_ROW_COUNT as u32)
+            .ok_or_else(unknown)? as u16;
+        Ok(OtpSelector {
+            row,
+            count: 1,
+            info: row_info(row),
+        })
+    }
+}
+
+// Finds the known row (or group of rows) that `row` belongs to
+pub fn row_info(row: u16) -> Option<OtpRowInfo> {
+    if (OTP_ROW_PAGE0_LOCK0..OTP_ROW_COUNT).contains(&row) {
+        let page = (row - OTP_ROW_PAGE0_LOCK0) / 2;
+        let lock = (row - OTP_ROW_PAGE0_LOCK0) % 2;
+        return find_row(&format!("PAGE{}_LOCK{}", page, lock));
+    }
+
+    OTP_ROWS
+        .iter()
+        .find(|(_, start, count, _)| row >= *start && row < start + count)
+        .map(|&(name, row, count, ecc)| OtpRowInfo {
+            name: name.to_string(),
+            row,
+            count,
+            ecc,
+        })
+}
+
+// Computes the 24 bit raw row value for 16 bits of ECC protected data. This is a (22, 16)
+// modified Hamming code, the top two bit-repair bits are left clear
+pub fn ecc_encode(value: u16) -> u32 {
+    let parity = |x: u32| x.count_ones() & 1;
+    let x = value as u32;
+    let mut p = x;
+    p |= parity(x & 0xAD5B) << 16;
+    p |= parity(x & 0x366D) << 17;
+    p |= parity(x & 0xC78E) << 18;
+    p |= parity(x & 0x07F0) << 19;
+    p |= parity(x & 0xF800) << 20;
+    p |= parity(p) << 21;
+    p
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtpLock {
+    ReadWrite,
+    ReadOnly,
+    Inaccessible,
+}
+
+// Reads the lock state of a page as seen by the bootloader, which is what PICOBOOT goes through.
+// The lock fields are stored 3 times over in the bytes of the raw PAGEn_LOCK1 row
+pub fn page_lock<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    page: u16,
+) -> picousb::Result<OtpLock> {
+    let raw = read_raw_rows(conn, page_lock1_row(page), 1)?[0];
+    Ok(decode_page_lock(raw))
+}
+
+// The raw PAGEn_LOCK1 row of a page
+pub(crate) fn page_lock1_row(page: u16) -> u16 {
+    OTP_ROW_PAGE0_LOCK0 + page * 2 + 1
+}
+
+// Decodes the bootloader lock field of a raw PAGEn_LOCK1 row, voting 2 of 3 on its bytes
+pub(crate) fn decode_page_lock(raw: u32) -> OtpLock {
+    let copies = [raw & 0xFF, (raw >> 8) & 0xFF, (raw >> 16) & 0xFF];
+    let lock = (0..8)
+        .filter(|bit| copies.iter().filter(|c| *c & (1 << bit) != 0).count() >= 2)
+        .fold(0, |acc, bit| acc | (1 << bit));
+    match (lock >> LOCK1_BL_SHIFT) & 0b11 {
+        0 => OtpLock::ReadWrite,
+        1 => OtpLock::ReadOnly,
+        _ => OtpLock::Inaccessible,
+    }
+}
+
+#[derive(Debug)]
+pub enum OtpError {
+    UnknownRow(String),
+    BadValue(String),
+    NotAnObject,
+}
+impl std::fmt::Display for OtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OtpError::UnknownRow(name) => write!(f, "unknown OTP row {:?}", name),
+            OtpError::BadValue(name) => write!(f, "bad value for OTP row {:?}", name),
+            OtpError::NotAnObject => write!(f, "expected a JSON object of OTP rows"),
+        }
+    }
+}
+
+// A single row to be written, `value` being 16 bits of data for ECC rows or 24 raw bits otherwise
+#[derive(Debug, Clone)]
+pub struct OtpWrite {
+    pub row: u16,
+    pub value: u32,
+    pub ecc: bool,
+    pub info: Option<OtpRowInfo>,
+}
+impl OtpWrite {
+    // The raw 24 bits that will end up in the row
+    pub fn raw_value(&self) -> u32 {
+        if self.ecc {
+            ecc_encode(self.value as u16)
+        } else {
+            self.value
+        }
+    }
+}
+
+// Reads rows to write from a JSON object. Keys are row names or numbers, and values are either
+// a number, or an object of `value` (a number, or array for consecutive rows) and `ecc`:
+// { "BOOT_FLAGS1": "0x1", "0x100": { "ecc": true, "value": [1, 2, 3] } }
+// When `ecc` isn't given, the mode from the row database is used, or raw for unknown rows
+#[cfg(feature = "cli")]
+pub(crate) fn parse_json(v: &crate::json::Value) -> Result<Vec<OtpWrite>, OtpError> {
+    let crate::json::Value::Object(members) = v else {
+        return Err(OtpError::NotAnObject);
+    };
+
+    let mut writes = vec![];
+    for (key, value) in members {
+        let info = find_row(key);
+        let row = match &info {
+            Some(info) => info.row,
+            None => crate::image::parse_addr(key)
+                .filter(|&r| r < OTP_ROW_COUNT as u32)
+                .ok_or_else(|| OtpError::UnknownRow(key.clone()))? as u16,
+        };
+
+        let (ecc, values) = match value {
+            crate::json::Value::Object(_) => (
+                value.get("ecc").and_then(|e| e.as_bool()),
+                value
+                    .get("value")
+                    .ok_or_else(|| OtpError::BadValue(key.clone()))?,
+            ),
+            v => (None, v),
+        };
+        let ecc = ecc.unwrap_or(info.as_ref().is_some_and(|i| i.ecc));
+        let values = match values {
+            crate::json::Value::Array(items) => items.iter().collect(),
+            v => vec![v],
+        };
+        if info
+            .as_ref()
+            .is_some_and(|i| values.len() > i.count as usize)
+        {
+            return Err(OtpError::BadValue(key.clone()));
+        }
+
+        let max = if ecc { 0xFFFF } else { 0xFFFFFF };
+        for (i, v) in values.into_iter().enumerate() {
+            let value = v
+                .as_u64()
+                .filter(|&v| v <= max)
+                .ok_or_else(|| OtpError::BadValue(key.clone()))? as u32;
+            let row = row + i as u16;
+            if row >= OTP_ROW_COUNT {
+                return Err(OtpError::BadValue(key.clone()));
+            }
+            writes.push(OtpWrite {
+                row,
+                value,
+                ecc,
+                info: row_info(row),
+            });
+        }
+    }
+
+    Ok(writes)
+}
+
+// Builds the JSON `parse_json` reads (the layout of picotool's `otp load`) from raw row values
+// as `read_rows` gives them, one per row and None for unreadable ones. Only rows with bits set
+// are kept, keyed by number. Rows normally written with ECC that hold a valid ECC encoding are
+// given as their 16 bits of data, the rest as raw bits, so loading the file writes back the same
+// raw contents either way
+#[cfg(feature = "cli")]
+pub(crate) fn rows_to_json(rows: &[Option<u32>]) -> crate::json::Value {
+    let mut members = vec![];
+    for (row, raw) in rows.iter().enumerate() {
+        let Some(raw) = raw.filter(|&r| r != 0) else {
+            continue;
+        };
+        let ecc = row_info(row as u16).is_some_and(|i| i.ecc) && ecc_encode(raw as u16) == raw;
+        let value = if ecc { raw & 0xFFFF } else { raw };
+        members.push((
+            format!("{:#05X}", row),
+            crate::json::Value::Object(vec![
+                ("ecc".into(), ecc.into()),
+                ("value".into(), format!("{:#X}", value).into()),
+            ]),
+        ));
+    }
+    crate::json::Value::Object(members)
+}
+
+#[derive(Debug, Clone)]
+pub enum OtpCheck {
+    // the row already holds this value
+    Unchanged,
+    // bits will be programmed, which can never be undone
+    Program { current: u32 },
+    // bits would need clearing, or an ECC row already holds different data
+    Conflict { current: u32 },
+    // the page can't be written through the bootloader
+    Locked(OtpLock),
+}
+
+#[derive(Debug, Clone)]
+pub struct OtpCheckResult {
+    pub write: OtpWrite,
+    pub check: OtpCheck,
+    // set when the write uses a different ECC mode than the row database says the row uses
+    pub ecc_mismatch: bool,
+}
+
+// Works out what writing each row would do against the current contents and lock state,
+// without writing anything
+pub fn check_writes<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    writes: &[OtpWrite],
+) -> picousb::Result<Vec<OtpCheckResult>> {
+    let mut results = vec![];
+    for write in writes {
+        let lock = page_lock(conn, write.row / OTP_PAGE_ROWS)?;
+        let current = match lock {
+            OtpLock::Inaccessible => None,
+            _ => Some(read_raw_rows(conn, write.row, 1)?[0]),
+        };
+        let new = write.raw_value();
+
+        let check = match current {
+            Some(current) if current == new => OtpCheck::Unchanged,
+            Some(_) | None if lock != OtpLock::ReadWrite => OtpCheck::Locked(lock),
+            Some(current) if (current & !new) != 0 || (write.ecc && current != 0) => {
+                OtpCheck::Conflict { current }
+            }
+            Some(current) => OtpCheck::Program { current },
+            None => OtpCheck::Locked(lock),
+        };
+
+        results.push(OtpCheckResult {
+            ecc_mismatch: write.info.as_ref().is_some_and(|i| i.ecc != write.ecc),
+            write: write.clone(),
+            check,
+        });
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPicoboot;
+    use crate::picousb::TargetID;
+
+    type Conn = PicobootConnection<MockPicoboot>;
+
+    fn connect() -> Conn {
+        PicobootConnection::with_transport(
+            MockPicoboot::new(TargetID::Rp2350),
+            Some(TargetID::Rp2350),
+        )
+    }
+
+    fn set_rows(conn: &mut Conn, row: u16, values: &[u32]) {
+        let row = row as usize;
+        conn.transport_mut().otp_mut()[row..row + values.len()].copy_from_slice(values);
+    }
+
+    #[test]
+    fn rows_are_found_by_name_and_number() {
+        let info = find_row("bootkey1").unwrap();
+        assert_eq!(
+            (info.name.as_str(), info.row, info.count),
+            ("BOOTKEY1", 0x090, 16)
+        );
+        assert!(info.ecc);
+        assert!(find_row("BOOT_FLAGS1").is_some_and(|i| !i.ecc && is_redundant(&i)));
+        assert!(find_row("NOT_A_ROW").is_none());
+
+        let lock = find_row("PAGE63_LOCK1").unwrap();
+        assert_eq!((lock.row, lock.ecc), (0xFFF, false));
+        assert_eq!(find_row("PAGE0_LOCK0").unwrap().row, 0xF80);
+        assert!(find_row("PAGE64_LOCK0").is_none());
+        assert!(find_row("PAGE1_LOCK2").is_none());
+
+        // a row inside a group belongs to the group, rows outside any are unknown
+        assert_eq!(row_info(0x085).unwrap().name, "BOOTKEY0");
+        assert_eq!(row_info(0x04D).unwrap().name, "BOOT_FLAGS1");
+        assert_eq!(row_info(0xFFE).unwrap().name, "PAGE63_LOCK0");
+        assert!(row_info(0x0C0).is_none());
+    }
+
+    #[test]
+    fn selectors_take_names_offsets_and_numbers() {
+        let sel: OtpSelector = "BOOT_FLAGS0".parse().unwrap();
+        assert_eq!((sel.row, sel.count), (0x048, 3));
+        let sel: OtpSelector = "BOOTKEY0+3".parse().unwrap();
+        assert_eq!((sel.row, sel.count), (0x083, 1));
+        assert_eq!(sel.info.unwrap().name, "BOOTKEY0");
+        let sel: OtpSelector = "PAGE63_LOCK1".parse().unwrap();
+        assert_eq!((sel.row, sel.count), (0xFFF, 1));
+        let sel: OtpSelector = "0x100".parse().unwrap();
+        assert_eq!((sel.row, sel.count), (0x100, 1));
+        assert!(sel.info.is_none());
+        let sel: OtpSelector = "4095".parse().unwrap();
+        assert_eq!(sel.row, 0xFFF);
+
+        for bad in [
+            "BOOTKEY0+16",
+            "BOOTKEY0+x",
+            "PAGE64_LOCK0",
+            "4096",
+            "0x1000",
+            "nope",
+        ] {
+            assert!(
+                matches!(bad.parse::<OtpSelector>(), Err(OtpError::UnknownRow(_))),
+                "{} was accepted",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn ecc_encoding_matches_known_values() {
+        for (data, raw) in [
+            (0x0000, 0x000000),
+            (0x0001, 0x230001),
+            (0x8000, 0x158000),
+            (0x1234, 0x191234),
+            (0xABCD, 0x11ABCD),
+            (0x5A5A, 0x395A5A),
+            (0xFFFF, 0x1EFFFF),
+        ] {
+            assert_eq!(ecc_encode(data), raw, "ecc_encode({:#06X})", data);
+        }
+    }
+
+    #[test]
+    fn ecc_corrects_any_single_bit_error() {
+        // flipping any one of the 22 bits must give a distinct, non-zero syndrome, or the
+        // bootrom couldn't tell which bit to repair
+        let syndrome = |raw: u32| (raw ^ ecc_encode(raw as u16)) >> 16;
+        let mut seen = vec![];
+        for value in [0x0000, 0x1234, 0xFFFF] {
+            let raw = ecc_encode(value);
+            for bit in 0..22 {
+                let s = syndrome(raw ^ (1 << bit));
+                assert_ne!(s, 0, "flipping bit {} of {:#06X} goes unseen", bit, value);
+                seen.push((value, s));
+            }
+        }
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 3 * 22);
+    }
+
+    #[test]
+    fn redundant_rows_are_voted_bit_by_bit() {
+        let mut conn = connect();
+        // bit 0 is set in 3 of 8 copies, bit 1 in only 2, bit 23 in all of them
+        set_rows(
+            &mut conn,
+            OTP_ROW_CRIT1,
+            &[
+                0x800003, 0x800003, 0x800001, 0x800000, 0x800000, 0x800000, 0x800000, 0x800000,
+            ],
+        );
+        let crit1 = read_redundant(&mut conn, OTP_ROW_CRIT1, 8, 3).unwrap();
+        assert_eq!(crit1, 0x800001);
+        assert_eq!(
+            read_redundant(&mut conn, OTP_ROW_CRIT1, 8, 2).unwrap(),
+            0x800003
+        );
+
+        set_rows(&mut conn, OTP_ROW_BOOT_FLAGS1, &[0x101, 0x001, 0x100]);
+        assert_eq!(read_boot_flags1(&mut conn).unwrap(), 0x101);
+        let state = SecureBootState::read(&mut conn).unwrap();
+        assert!(state.secure_boot_enabled && !state.secure_debug_disabled);
+        assert_eq!(state.boot_keys[0], BootKeyState::Invalid);
+        assert_eq!(state.boot_keys[1], BootKeyState::Unset);
+    }
+
+    #[test]
+    fn page_locks_are_decoded_from_the_three_copies() {
+        // the bootloader field is bits 4 and 5 of each byte
+        for (raw, lock) in [
+            (0x000000, OtpLock::ReadWrite),
+            (0x101010, OtpLock::ReadOnly),
+            (0x202020, OtpLock::Inaccessible),
+            (0x303030, OtpLock::Inaccessible),
+            // 2 of 3 bytes agree
+            (0x001010, OtpLock::ReadOnly),
+            (0x100010, OtpLock::ReadOnly),
+            (0x000010, OtpLock::ReadWrite),
+            // each bit is voted on separately
+            (0x102030, OtpLock::Inaccessible),
+            (0x100020, OtpLock::ReadWrite),
+            // other lock fields don't matter
+            (0x0F0F0F, OtpLock::ReadWrite),
+        ] {
+            assert_eq!(decode_page_lock(raw), lock, "{:#08X}", raw);
+        }
+
+        let mut conn = connect();
+        set_rows(&mut conn, page_lock1_row(2), &[0x101010]);
+        set_rows(&mut conn, page_lock1_row(3), &[0x202000]);
+        assert_eq!(page_lock(&mut conn, 1).unwrap(), OtpLock::ReadWrite);
+        assert_eq!(page_lock(&mut conn, 2).unwrap(), OtpLock::ReadOnly);
+        assert_eq!(page_lock(&mut conn, 3).unwrap(), OtpLock::Inaccessible);
+        assert!(read_page(&mut conn, 2, true).unwrap().is_some());
+        assert!(read_page(&mut conn, 3, true).unwrap().is_none());
+    }
+
+    fn write(row: u16, value: u32, ecc: bool) -> OtpWrite {
+        OtpWrite {
+            row,
+            value,
+            ecc,
+            info: row_info(row),
+        }
+    }
+
+    #[test]
+    fn writes_are_checked_against_the_current_rows() {
+        let mut conn = connect();
+        set_rows(&mut conn, 0x100, &[ecc_encode(0x1234), 0x00000F, 0x0000F0]);
+        set_rows(&mut conn, 0x103, &[ecc_encode(0x0001)]);
+        // page 5 is read only, page 6 can't even be read
+        set_rows(&mut conn, page_lock1_row(5), &[0x101010]);
+        set_rows(&mut conn, page_lock1_row(6), &[0x202020]);
+        set_rows(&mut conn, 5 * OTP_PAGE_ROWS, &[0x000001]);
+
+        let writes = [
+            write(0x100, 0x1234, true),
+            write(0x101, 0x0000FF, false),
+            // would clear bits 4 to 7
+            write(0x102, 0x00000F, false),
+            // only sets bits, but ECC rows can't be written twice
+            write(0x103, 0x000F, true),
+            write(0x104, 0x0001, true),
+            write(5 * OTP_PAGE_ROWS, 0x000003, false),
+            write(5 * OTP_PAGE_ROWS, 0x000001, false),
+            write(6 * OTP_PAGE_ROWS, 0x000001, false),
+        ];
+        assert_eq!(ecc_encode(0x000F) & ecc_encode(0x0001), ecc_encode(0x0001));
+        let results = check_writes(&mut conn, &writes).unwrap();
+        let checks: Vec<&OtpCheck> = results.iter().map(|r| &r.check).collect();
+        assert!(matches!(checks[0], OtpCheck::Unchanged));
+        assert!(matches!(checks[1], OtpCheck::Program { current: 0x00000F }));
+        assert!(matches!(
+            checks[2],
+            OtpCheck::Conflict { current: 0x0000F0 }
+        ));
+        assert!(matches!(
+            checks[3],
+            OtpCheck::Conflict { current: 0x230001 }
+        ));
+        assert!(matches!(checks[4], OtpCheck::Program { current: 0 }));
+        assert!(matches!(checks[5], OtpCheck::Locked(OtpLock::ReadOnly)));
+        assert!(matches!(checks[6], OtpCheck::Unchanged));
+        assert!(matches!(checks[7], OtpCheck::Locked(OtpLock::Inaccessible)));
+        // raw writes to rows the database lists as ECC are flagged
+        assert!(results.iter().all(|r| !r.ecc_mismatch));
+        let results = check_writes(&mut conn, &[write(0x080, 0x1, false)]).unwrap();
+        assert!(results[0].ecc_mismatch);
+    }
+
+    #[cfg(feature = "cli")]
+    fn parse(json: &str) -> Result<Vec<OtpWrite>, OtpError> {
+        parse_json(&crate::json::parse(json).unwrap())
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn json_rows_are_parsed() {
+        let writes = parse(
+            r#"{ "BOOT_FLAGS1": "0x1", "0x100": { "ecc": true, "value": [1, 2, "0x3"] },
+                 "BOOTKEY0": { "value": 5 }, "0x200": 16777215 }"#,
+        )
+        .unwrap();
+        let rows: Vec<(u16, u32, bool)> = writes.iter().map(|w| (w.row, w.value, w.ecc)).collect();
+        assert_eq!(
+            rows,
+            [
+                (0x04B, 1, false),
+                (0x100, 1, true),
+                (0x101, 2, true),
+                (0x102, 3, true),
+                (0x080, 5, true),
+                (0x200, 0xFFFFFF, false),
+            ]
+        );
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn json_rows_out_of_range_are_rejected() {
+        let bad_value = |json: &str| matches!(parse(json), Err(OtpError::BadValue(_)));
+        // ECC rows hold 16 bits, raw rows 24
+        assert!(bad_value(r#"{ "BOOTKEY0": "0x10000" }"#));
+        assert!(bad_value(r#"{ "0x100": { "ecc": true, "value": 65536 } }"#));
+        assert!(bad_value(r#"{ "0x100": "0x1000000" }"#));
+        assert!(parse(r#"{ "0x100": "0xFFFFFF" }"#).is_ok());
+        // more values than the group has rows
+        assert!(bad_value(r#"{ "BOOT_FLAGS1": [1, 1, 1, 1] }"#));
+        assert!(parse(r#"{ "BOOT_FLAGS1": [1, 1, 1] }"#).is_ok());
+        // running off the end of the OTP
+        assert!(bad_value(r#"{ "0xFFE": { "value": [1, 2, 3] } }"#));
+        assert!(parse(r#"{ "0xFFE": { "value": [1, 2] } }"#).is_ok());
+        // values that aren't numbers, or objects without one
+        assert!(bad_value(r#"{ "0x100": -1 }"#));
+        assert!(bad_value(r#"{ "0x100": { "ecc": true } }"#));
+        assert!(bad_value(r#"{ "0x100": true }"#));
+
+        assert!(matches!(
+            parse(r#"{ "0x1000": 1 }"#),
+            Err(OtpError::UnknownRow(_))
+        ));
+        assert!(matches!(
+            parse(r#"{ "BOOTKEY9": 1 }"#),
+            Err(OtpError::UnknownRow(_))
+        ));
+        assert!(matches!(parse("[1, 2]"), Err(OtpError::NotAnObject)));
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn saved_rows_load_back_the_same() {
+        let mut rows = vec![Some(0); OTP_ROW_COUNT as usize];
+        // an ECC row holding a valid encoding, and one in the same group that doesn't
+        rows[0x080] = Some(ecc_encode(0xBEEF));
+        rows[0x081] = Some(0x000123);
+        rows[0x04B] = Some(0x000101);
+        rows[0x200] = Some(0xFFFFFF);
+        // unreadable rows and blank rows are left out
+        rows[0x0C0] = None;
+        rows[0xFC0..].fill(None);
+
+        let json = rows_to_json(&rows);
+        let members = match &json {
+            crate::json::Value::Object(members) => members,
+            v => panic!("expected an object, got {}", v),
+        };
+        let keys: Vec<&str> = members.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["0x04B", "0x080", "0x081", "0x200"]);
+        assert_eq!(json.get("0x080").unwrap().get("ecc"), Some(&true.into()));
+        assert_eq!(
+            json.get("0x080").unwrap().get("value"),
+            Some(&"0xBEEF".into())
+        );
+        assert_eq!(json.get("0x081").unwrap().get("ecc"), Some(&false.into()));
+
+        // through the text, as `otp save` and `otp load` would
+        let writes = parse(&json.to_string()).unwrap();
+        let loaded: Vec<(u16, u32)> = writes.iter().map(|w| (w.row, w.raw_value())).collect();
+        assert_eq!(
+            loaded,
+            [
+                (0x04B, 0x000101),
+                (0x080, ecc_encode(0xBEEF)),
+                (0x081, 0x000123),
+                (0x200, 0xFFFFFF),
+            ]
+        );
+        assert!(writes[1].ecc && !writes[2].ecc);
+    }
+
+    #[test]
+    fn boot_keys_fill_their_slot_and_are_marked_valid() {
+        let hash: [u8; 32] = std::array::from_fn(|i| i as u8);
+        // key 0 is already valid and key 3 invalid, both are kept
+        let writes = boot_key_writes(2, &hash, 0x801).unwrap();
+        assert_eq!(writes.len(), 16 + 3);
+
+        for (i, w) in writes[..16].iter().enumerate() {
+            assert_eq!(w.row, 0x0A0 + i as u16);
+            assert!(w.ecc);
+            let (lo, hi) = (2 * i as u32, 2 * i as u32 + 1);
+            assert_eq!(w.value, lo | hi << 8, "BOOTKEY2+{} isn't little endian", i);
+            assert_eq!(w.info.as_ref().unwrap().name, "BOOTKEY2");
+        }
+        for (copy, w) in writes[16..].iter().enumerate() {
+            assert_eq!(w.row, OTP_ROW_BOOT_FLAGS1 + copy as u16);
+            assert!(!w.ecc);
+            assert_eq!(w.value, 0x801 | 1 << 2);
+        }
+
+        let writes = boot_key_writes(0, &hash, 0).unwrap();
+        assert_eq!((writes[0].row, writes[0].value), (0x080, 0x0100));
+        assert_eq!((writes[15].row, writes[15].value), (0x08F, 0x1F1E));
+        assert!(writes[16..].iter().all(|w| w.value == 0x1));
+
+        assert!(boot_key_writes(OTP_BOOT_KEY_COUNT, &hash, 0).is_none());
+        assert!(boot_key_writes(usize::MAX, &hash, 0).is_none());
+    }
+
+    #[test]
+    fn boot_keys_written_read_back_as_valid() {
+        let mut conn = connect();
+        let hash = [0xA5; 32];
+        for w in boot_key_writes(1, &hash, 0).unwrap() {
+            write_rows(&mut conn, w.row, &[w.value], w.ecc).unwrap();
+        }
+        assert_eq!(read_rows(&mut conn, 0x090, 16, true).unwrap(), [0xA5A5; 16]);
+        let state = SecureBootState::read(&mut conn).unwrap();
+        assert_eq!(state.boot_keys[1], BootKeyState::Valid);
+        assert_eq!(state.boot_keys[0], BootKeyState::Unset);
+        assert!(!state.requires_signed_images());
+    }
+
+    #[test]
+    fn rows_written_come_back_the_same() {
+        let mut conn = connect();
+        write_rows(&mut conn, 0x100, &[0x1234, 0xABCD], true).unwrap();
+        write_rows(&mut conn, 0x102, &[0x123456], false).unwrap();
+        assert_eq!(
+            read_rows(&mut conn, 0x100, 2, true).unwrap(),
+            [0x1234, 0xABCD]
+        );
+        assert_eq!(
+            read_raw_rows(&mut conn, 0x100, 3).unwrap(),
+            [0x191234, 0x11ABCD, 0x123456]
+        );
+    }
+}
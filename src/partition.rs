@@ -0,0 +1,618 @@
+// RP2350 partition tables, as reported by the bootrom's GET_INFO PARTITION_TABLE, and built
+// from a JSON description into the PICOBIN block the bootrom looks for at the start of flash
+
+use std::fmt;
+
+#[cfg(feature = "cli")]
+use crate::json::Value;
+use crate::picousb::{PicobootError, Result, PICO_FLASH_START, PICO_SECTOR_SIZE};
+use crate::uf2;
+use sha2::{Digest, Sha256};
+
+// Flags selecting what `InfoType::PartitionTable` returns, the response has them in this order
+pub(crate) const PT_INFO_PT_INFO: u32 = 0x0001;
+pub(crate) const PT_INFO_PARTITION_LOCATION_AND_FLAGS: u32 = 0x0010;
+pub(crate) const PT_INFO_PARTITION_ID: u32 = 0x0020;
+pub(crate) const PT_INFO_PARTITION_FAMILY_IDS: u32 = 0x0040;
+
+const LOCATION_FIRST_SECTOR_MASK: u32 = 0x1FFF;
+const LOCATION_LAST_SECTOR_LSB: u32 = 13;
+const PERMISSIONS_LSB: u32 = 26;
+
+// PICOBIN block framing and the items making up a hashed partition table block
+const BLOCK_MARKER_START: u32 = 0xFFFFDED3;
+const BLOCK_MARKER_END: u32 = 0xAB123579;
+const ITEM_PARTITION_TABLE: u32 = 0x0A;
+const ITEM_HASH_DEF: u32 = 0x47;
+const ITEM_HASH_VALUE: u32 = 0x4B;
+const ITEM_LAST: u32 = 0xFF;
+const HASH_TYPE_SHA256: u32 = 0x01;
+
+pub const MAX_PARTITIONS: usize = 16;
+
+const FLAGS_HAS_ID: u32 = 0x1;
+const FLAGS_NUM_EXTRA_FAMILIES_LSB: u32 = 7;
+const FLAGS_NUM_EXTRA_FAMILIES_MASK: u32 = 0x3;
+// default families a partition accepts, one bit each from bit 9 up
+const FLAGS_DEFAULT_FAMILIES: [(u32, u32); 6] = [
+    (0x0200, uf2::FAMILY_ID_ABSOLUTE),
+    (0x0400, uf2::FAMILY_ID_RP2040),
+    (0x0800, uf2::FAMILY_ID_RP2350_ARM_S),
+    (0x1000, uf2::FAMILY_ID_RP2350_RISCV),
+    (0x2000, uf2::FAMILY_ID_RP2350_ARM_NS),
+    (0x4000, uf2::FAMILY_ID_DATA),
+];
+
+// Access allowed to a partition, or to flash outside of any partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub secure_read: bool,
+    pub secure_write: bool,
+    pub nonsecure_read: bool,
+    pub nonsecure_write: bool,
+    pub bootloader_read: bool,
+    pub bootloader_write: bool,
+}
+impl Permissions {
+    pub const ALL: Permissions = Permissions {
+        secure_read: true,
+        secure_write: true,
+        nonsecure_read: true,
+        nonsecure_write: true,
+        bootloader_read: true,
+        bootloader_write: true,
+    };
+
+    fn to_word(self) -> u32 {
+        [
+            self.secure_read,
+            self.secure_write,
+            self.nonsecure_read,
+            self.nonsecure_write,
+            self.bootloader_read,
+            self.bootloader_write,
+        ]
+        .iter()
+        .enumerate()
+        .filter(|(_, &set)| set)
+        .fold(0, |w, (n, _)| w | 1 << (PERMISSIONS_LSB + n as u32))
+    }
+
+    fn from_word(word: u32) -> Self {
+        let bit = |n: u32| word & (1 << (PERMISSIONS_LSB + n)) != 0;
+        Permissions {
+            secure_read: bit(0),
+            secure_write: bit(1),
+            nonsecure_read: bit(2),
+            nonsecure_write: bit(3),
+            bootloader_read: bit(4),
+            bootloader_write: bit(5),
+        }
+    }
+}
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rw = |r: bool, w: bool| match (r, w) {
+            (true, true) => "rw",
+            (true, false) => "r-",
+            (false, true) => "-w",
+            (false, false) => "--",
+        };
+        write!(
+            f,
+            "S:{} NS:{} B:{}",
+            rw(self.secure_read, self.secure_write),
+            rw(self.nonsecure_read, self.nonsecure_write),
+            rw(self.bootloader_read, self.bootloader_write)
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Partition {
+    // inclusive range of flash sectors the partition covers
+    pub first_sector: u32,
+    pub last_sector: u32,
+    pub permissions: Permissions,
+    // the raw flags word, see `accepts_family` for the families it names
+    pub flags: u32,
+    pub id: Option<u64>,
+    // UF2 families accepted on top of the default ones flagged in `flags`
+    pub extra_families: Vec<u32>,
+}
+impl Partition {
+    // Flash address range of the partition, end exclusive
+    pub fn range(&self) -> (u32, u32) {
+        (
+            PICO_FLASH_START + self.first_sector * PICO_SECTOR_SIZE,
+            PICO_FLASH_START + (self.last_sector + 1) * PICO_SECTOR_SIZE,
+        )
+    }
+
+    // Whether a UF2 of the given family may be downloaded into this partition
+    pub fn accepts_family(&self, family: u32) -> bool {
+        self.extra_families.contains(&family)
+            || FLAGS_DEFAULT_FAMILIES
+                .iter()
+                .any(|&(bit, id)| id == family && self.flags & bit != 0)
+    }
+
+    // Every family the partition accepts
+    pub fn families(&self) -> Vec<u32> {
+        FLAGS_DEFAULT_FAMILIES
+            .iter()
+            .filter(|&&(bit, _)| self.flags & bit != 0)
+            .map(|&(_, id)| id)
+            .chain(self.extra_families.iter().copied())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionTable {
+    // false if the device has no partition table, in which case all of flash is unpartitioned
+    pub has_table: bool,
+    // a singleton table is the only one in flash, so there's no A/B pair of tables to pick from
+    pub singleton: bool,
+    // permissions of flash outside of any partition
+    pub unpartitioned: Permissions,
+    // the raw flags word for flash outside of any partition, including the families it accepts
+    pub unpartitioned_flags: u32,
+    pub partitions: Vec<Partition>,
+}
+
+#[derive(Debug)]
+pub enum PartitionError {
+    NotAnObject,
+    // the field, and what's wrong with it
+    BadField(String, &'static str),
+    TooMany,
+    Overlap(usize, usize),
+}
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionError::NotAnObject => write!(f, "expected a JSON object"),
+            PartitionError::BadField(field, why) => write!(f, "bad {}: {}", field, why),
+            PartitionError::TooMany => {
+                write!(f, "at most {} partitions are supported", MAX_PARTITIONS)
+            }
+            PartitionError::Overlap(a, b) => write!(f, "partitions {} and {} overlap", a, b),
+        }
+    }
+}
+
+// A size or offset in bytes, as a number or a string with an optional K or M suffix
+#[cfg(feature = "cli")]
+fn parse_size(v: &Value) -> Option<u32> {
+    if let Value::String(s) = v {
+        let (num, mult) = match s.strip_suffix(['K', 'k']) {
+            Some(n) => (n, 1024),
+            None => match s.strip_suffix(['M', 'm']) {
+                Some(n) => (n, 1024 * 1024),
+                None => (s.as_str(), 1),
+            },
+        };
+        return crate::image::parse_addr(num)?.checked_mul(mult);
+    }
+    v.as_u64().and_then(|n| u32::try_from(n).ok())
+}
+
+// Permissions given as e.g. { "secure": "rw", "nonsecure": "r", "bootloader": "" }. Left out
+// entirely, everything is allowed
+#[cfg(feature = "cli")]
+fn parse_permissions(
+    v: Option<&Value>,
+    field: &str,
+) -> std::result::Result<Permissions, PartitionError> {
+    let Some(v) = v else {
+        return Ok(Permissions::ALL);
+    };
+    let bad = || PartitionError::BadField(field.to_string(), "expected r, w or rw per domain");
+    let get = |key: &str| -> std::result::Result<(bool, bool), PartitionError> {
+        match v.get(key) {
+            None => Ok((false, false)),
+            Some(Value::String(s)) if s.chars().all(|c| c == 'r' || c == 'w') => {
+                Ok((s.contains('r'), s.contains('w')))
+            }
+            Some(_) => Err(bad()),
+        }
+    };
+    if !matches!(v, Value::Object(_)) {
+        return Err(bad());
+    }
+    let (secure_read, secure_write) = get("secure")?;
+    let (nonsecure_read, nonsecure_write) = get("nonsecure")?;
+    let (bootloader_read, bootloader_write) = get("bootloader")?;
+    Ok(Permissions {
+        secure_read,
+        secure_write,
+        nonsecure_read,
+        nonsecure_write,
+        bootloader_read,
+        bootloader_write,
+    })
+}
+
+// Accepted families given by name, split into default family flags and up to 3 extra families
+#[cfg(feature = "cli")]
+fn parse_families(
+    v: Option<&Value>,
+    field: &str,
+) -> std::result::Result<(u32, Vec<u32>), PartitionError> {
+    let bad = |why| PartitionError::BadField(field.to_string(), why);
+    let names = match v {
+        None => return Ok((0, vec![])),
+        Some(Value::Array(names)) => names,
+        Some(_) => return Err(bad("expected an array of family names")),
+    };
+    let mut flags = 0;
+    let mut extra = vec![];
+    for name in names {
+        let id = match name {
+            Value::String(s) => uf2::family_id_from_str(s),
+            v => v.as_u64().and_then(|n| u32::try_from(n).ok()),
+        }
+        .ok_or(bad("unknown family"))?;
+        match FLAGS_DEFAULT_FAMILIES.iter().find(|&&(_, f)| f == id) {
+            Some(&(bit, _)) => flags |= bit,
+            None if extra.len() < 3 => extra.push(id),
+            None => return Err(bad("at most 3 families besides the default ones")),
+        }
+    }
+    Ok((flags, extra))
+}
+
+impl PartitionTable {
+    // Builds a table from a description like
+    // { "unpartitioned": { "families": ["absolute"] },
+    //   "partitions": [ { "start": "8K", "size": "480K", "id": 1,
+    //                     "families": ["rp2350-arm-s"], "permissions": { "secure": "rw" } } ] }
+    // Starts are offsets into flash. The table itself goes in the first sector, so partitions
+    // have to leave it free
+    #[cfg(feature = "cli")]
+    pub(crate) fn from_json(v: &Value) -> std::result::Result<Self, PartitionError> {
+        if !matches!(v, Value::Object(_)) {
+            return Err(PartitionError::NotAnObject);
+        }
+        let singleton = match v.get("singleton") {
+            None => false,
+            Some(s) => s.as_bool().ok_or(PartitionError::BadField(
+                "singleton".to_string(),
+                "expected true or false",
+            ))?,
+        };
+        let unpartitioned = v.get("unpartitioned");
+        let perms = parse_permissions(
+            unpartitioned.and_then(|u| u.get("permissions")),
+            "unpartitioned permissions",
+        )?;
+        let (family_flags, extra) = parse_families(
+            unpartitioned.and_then(|u| u.get("families")),
+            "unpartitioned families",
+        )?;
+        if !extra.is_empty() {
+            return Err(PartitionError::BadField(
+                "unpartitioned families".to_string(),
+                "only the default families can be accepted outside partitions",
+            ));
+        }
+
+        let parts = match v.get("partitions") {
+            Some(Value::Array(parts)) => parts,
+            _ => {
+                return Err(PartitionError::BadField(
+                    "partitions".to_string(),
+                    "expected an array",
+                ))
+            }
+        };
+        if parts.len() > MAX_PARTITIONS {
+            return Err(PartitionError::TooMany);
+        }
+
+        let mut table = PartitionTable {
+            has_table: true,
+            singleton,
+            unpartitioned: perms,
+            unpartitioned_flags: perms.to_word() | family_flags,
+            partitions: vec![],
+        };
+        for (i, p) in parts.iter().enumerate() {
+            let field = |name: &str| format!("partition {} {}", i, name);
+            let sectors = |name: &str| -> std::result::Result<u32, PartitionError> {
+                p.get(name)
+                    .and_then(parse_size)
+                    .filter(|n| n.is_multiple_of(PICO_SECTOR_SIZE))
+                    .map(|n| n / PICO_SECTOR_SIZE)
+                    .ok_or_else(|| {
+                        PartitionError::BadField(field(name), "expected a whole number of sectors")
+                    })
+            };
+            let (first, count) = (sectors("start")?, sectors("size")?);
+            if first == 0 {
+                return Err(PartitionError::BadField(
+                    field("start"),
+                    "the first sector holds the partition table",
+                ));
+            }
+            let last = first + count - 1;
+            if count == 0 || last > LOCATION_FIRST_SECTOR_MASK {
+                return Err(PartitionError::BadField(
+                    field("size"),
+                    "must be non-empty and end within 32M",
+                ));
+            }
+
+            let permissions = parse_permissions(p.get("permissions"), &field("permissions"))?;
+            let (family_flags, extra_families) =
+                parse_families(p.get("families"), &field("families"))?;
+            let id =
+                match p.get("id") {
+                    None => None,
+                    Some(id) => Some(id.as_u64().ok_or_else(|| {
+                        PartitionError::BadField(field("id"), "expected a number")
+                    })?),
+                };
+            let mut flags = permissions.to_word()
+                | family_flags
+                | (extra_families.len() as u32) << FLAGS_NUM_EXTRA_FAMILIES_LSB;
+            if id.is_some() {
+                flags |= FLAGS_HAS_ID;
+            }
+            table.partitions.push(Partition {
+                first_sector: first,
+                last_sector: last,
+                permissions,
+                flags,
+                id,
+                extra_families,
+            });
+        }
+
+        for (i, a) in table.partitions.iter().enumerate() {
+            for (j, b) in table.partitions.iter().enumerate().skip(i + 1) {
+                if a.first_sector <= b.last_sector && b.first_sector <= a.last_sector {
+                    return Err(PartitionError::Overlap(i, j));
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    // Encodes the table as a PICOBIN block holding a PARTITION_TABLE item, hashed with SHA-256
+    // so the bootrom can tell it's intact. It forms a block loop on its own, and goes at the
+    // start of flash
+    pub fn to_block(&self) -> Vec<u8> {
+        let mut pt = vec![0, self.unpartitioned_flags];
+        for p in &self.partitions {
+            let location = p.first_sector
+                | p.last_sector << LOCATION_LAST_SECTOR_LSB
+                | p.permissions.to_word();
+            pt.push(location);
+            pt.push(p.flags);
+            if let Some(id) = p.id {
+                pt.push(id as u32);
+                pt.push((id >> 32) as u32);
+            }
+            pt.extend_from_slice(&p.extra_families);
+        }
+        pt[0] = ITEM_PARTITION_TABLE
+            | (pt.len() as u32) << 8
+            | (self.partitions.len() as u32) << 16
+            | (self.singleton as u32) << 24;
+
+        // the hash covers the block from its start marker up to and including the HASH_DEF item
+        let mut words = vec![BLOCK_MARKER_START];
+        words.extend(pt);
+        words.push(ITEM_HASH_DEF | 2 << 8 | HASH_TYPE_SHA256 << 24);
+        words.push(words.len() as u32 + 1);
+        let mut sha = Sha256::new();
+        words.iter().for_each(|w| sha.update(w.to_le_bytes()));
+        let hash: [u8; 32] = sha.finalize().into();
+
+        words.push(ITEM_HASH_VALUE | 9 << 8);
+        words.extend(
+            hash.chunks(4)
+                .map(|c| u32::from_le_bytes(c.try_into().unwrap())),
+        );
+        // size of every item before the last one, then the offset of the next block in the loop,
+        // which is this one
+        words.push(ITEM_LAST | (words.len() as u32 - 1) << 8);
+        words.push(0);
+        words.push(BLOCK_MARKER_END);
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    pub(crate) fn parse(words: &[u32]) -> Result<Self> {
+        // first the number of words returned, then the flags actually included
+        let (&included, mut rest) = words
+            .get(1..)
+            .and_then(|w| w.split_first())
+            .ok_or(PicobootError::BadResponse)?;
+        let mut take = |n: usize| -> Result<&[u32]> {
+            if rest.len() < n {
+                return Err(PicobootError::BadResponse);
+            }
+            let (taken, left) = rest.split_at(n);
+            rest = left;
+            Ok(taken)
+        };
+
+        if included & PT_INFO_PT_INFO == 0 || included & PT_INFO_PARTITION_LOCATION_AND_FLAGS == 0 {
+            return Err(PicobootError::BadResponse);
+        }
+        let w = take(2)?;
+        let count = w[0] & 0xFF;
+        let mut table = PartitionTable {
+            has_table: w[0] & 0x100 != 0,
+            singleton: false,
+            unpartitioned: Permissions::from_word(w[1]),
+            unpartitioned_flags: w[1],
+            partitions: vec![],
+        };
+
+        for _ in 0..count {
+            let w = take(2)?;
+            let (location, flags) = (w[0], w[1]);
+            let mut partition = Partition {
+                first_sector: location & LOCATION_FIRST_SECTOR_MASK,
+                last_sector: (location >> LOCATION_LAST_SECTOR_LSB) & LOCATION_FIRST_SECTOR_MASK,
+                permissions: Permissions::from_word(location),
+                flags,
+                id: None,
+                extra_families: vec![],
+            };
+            if included & PT_INFO_PARTITION_ID != 0 && flags & FLAGS_HAS_ID != 0 {
+                let w = take(2)?;
+                partition.id = Some(w[0] as u64 | (w[1] as u64) << 32);
+            }
+            if included & PT_INFO_PARTITION_FAMILY_IDS != 0 {
+                let n = (flags >> FLAGS_NUM_EXTRA_FAMILIES_LSB) & FLAGS_NUM_EXTRA_FAMILIES_MASK;
+                partition.extra_families = take(n as usize)?.to_vec();
+            }
+            table.partitions.push(partition);
+        }
+        Ok(table)
+    }
+}
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use super::*;
+
+    fn table(json: &str) -> std::result::Result<PartitionTable, PartitionError> {
+        PartitionTable::from_json(&crate::json::parse(json).unwrap())
+    }
+
+    // Turns the PARTITION_TABLE item of a built block into what GET_INFO reports for it, which
+    // lays out each partition the same way
+    fn as_info(block: &[u8]) -> Vec<u32> {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(words[0], BLOCK_MARKER_START);
+        assert_eq!(*words.last().unwrap(), BLOCK_MARKER_END);
+        let header = words[1];
+        assert_eq!(header & 0xFF, ITEM_PARTITION_TABLE);
+        let len = (header >> 8 & 0xFF) as usize;
+        let included = PT_INFO_PT_INFO
+            | PT_INFO_PARTITION_LOCATION_AND_FLAGS
+            | PT_INFO_PARTITION_ID
+            | PT_INFO_PARTITION_FAMILY_IDS;
+        let mut info = vec![0, included, header >> 16 & 0xFF | 0x100];
+        info.extend(&words[2..1 + len]);
+        info[0] = info.len() as u32 - 1;
+        info
+    }
+
+    #[test]
+    fn tables_read_back_as_built() {
+        let built = table(
+            r#"{ "unpartitioned": { "families": ["absolute"],
+                                    "permissions": { "secure": "rw", "bootloader": "r" } },
+                 "partitions": [
+                   { "start": "8K", "size": "1M", "id": 1,
+                     "families": ["rp2350-arm-s", "rp2350-riscv"],
+                     "permissions": { "secure": "rw", "nonsecure": "r" } },
+                   { "start": 1056768, "size": "1M", "id": 81985529216486895,
+                     "families": ["rp2350-arm-s", 305419896, 2271560481] },
+                   { "start": "2056K", "size": "64K", "families": ["data"],
+                     "permissions": { "bootloader": "w" } } ] }"#,
+        )
+        .unwrap();
+        let read = PartitionTable::parse(&as_info(&built.to_block())).unwrap();
+
+        assert!(read.has_table);
+        assert_eq!(read.unpartitioned, built.unpartitioned);
+        assert_eq!(read.unpartitioned_flags, built.unpartitioned_flags);
+        assert!(read.unpartitioned.bootloader_read && !read.unpartitioned.bootloader_write);
+        assert_eq!(read.partitions.len(), 3);
+        for (r, b) in read.partitions.iter().zip(&built.partitions) {
+            assert_eq!(r.range(), b.range());
+            assert_eq!(r.permissions, b.permissions);
+            assert_eq!(r.flags, b.flags);
+            assert_eq!(r.id, b.id);
+            assert_eq!(r.families(), b.families());
+        }
+
+        let p = &read.partitions;
+        assert_eq!(
+            p[0].range(),
+            (PICO_FLASH_START + 0x2000, PICO_FLASH_START + 0x102000)
+        );
+        assert_eq!(p[0].id, Some(1));
+        assert!(p[0].permissions.nonsecure_read && !p[0].permissions.nonsecure_write);
+        assert!(!p[0].permissions.bootloader_read);
+        assert!(p[0].accepts_family(uf2::FAMILY_ID_RP2350_RISCV));
+        assert!(!p[0].accepts_family(uf2::FAMILY_ID_ABSOLUTE));
+        assert_eq!(p[1].id, Some(0x0123456789ABCDEF));
+        assert_eq!(p[1].extra_families, [0x12345678, 0x87654321]);
+        assert!(p[1].accepts_family(0x87654321));
+        assert_eq!(p[1].permissions, Permissions::ALL);
+        assert_eq!(p[2].id, None);
+        assert_eq!(p[2].families(), [uf2::FAMILY_ID_DATA]);
+        assert!(p[2].permissions.bootloader_write && !p[2].permissions.secure_read);
+    }
+
+    #[test]
+    fn bad_layouts_are_rejected() {
+        assert!(matches!(
+            table(
+                r#"{ "partitions": [ { "start": "8K", "size": "64K" },
+                                     { "start": "128K", "size": "64K" },
+                                     { "start": "64K", "size": "8K" } ] }"#
+            ),
+            Err(PartitionError::Overlap(0, 2))
+        ));
+        // sharing a single sector is still an overlap
+        assert!(matches!(
+            table(
+                r#"{ "partitions": [ { "start": "8K", "size": "8K" },
+                                     { "start": "12K", "size": "8K" } ] }"#
+            ),
+            Err(PartitionError::Overlap(0, 1))
+        ));
+        assert!(table(
+            r#"{ "partitions": [ { "start": "8K", "size": "8K" },
+                                 { "start": "16K", "size": "8K" } ] }"#
+        )
+        .is_ok());
+
+        for (start, size) in [("8K", "6K"), ("6K", "8K"), ("8K", "4097"), ("8K", "0")] {
+            let json = format!(
+                r#"{{ "partitions": [ {{ "start": "{}", "size": "{}" }} ] }}"#,
+                start, size
+            );
+            assert!(
+                matches!(table(&json), Err(PartitionError::BadField(..))),
+                "{} {}",
+                start,
+                size
+            );
+        }
+        // the first sector holds the table itself, and partitions end within 32M
+        assert!(table(r#"{ "partitions": [ { "start": 0, "size": "4K" } ] }"#).is_err());
+        assert!(table(r#"{ "partitions": [ { "start": "32M", "size": "4K" } ] }"#).is_err());
+
+        let parts = |n: usize| {
+            let parts: Vec<String> = (1..=n)
+                .map(|i| format!(r#"{{ "start": {}, "size": "4K" }}"#, i * 4096))
+                .collect();
+            format!(r#"{{ "partitions": [{}] }}"#, parts.join(", "))
+        };
+        assert_eq!(table(&parts(MAX_PARTITIONS)).unwrap().partitions.len(), 16);
+        assert!(matches!(
+            table(&parts(MAX_PARTITIONS + 1)),
+            Err(PartitionError::TooMany)
+        ));
+        // more than 3 families past the default ones don't fit in the flags
+        assert!(matches!(
+            table(
+                r#"{ "partitions": [ { "start": "8K", "size": "8K", "families": [1, 2, 3, 4] } ] }"#
+            ),
+            Err(PartitionError::BadField(..))
+        ));
+    }
+}
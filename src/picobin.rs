@@ -0,0 +1,587 @@
+// RP2350 PICOBIN blocks, the metadata the bootrom looks for in an image: a loop of blocks, the
+// first in the first 4K of the image, holding items like the IMAGE_DEF describing the image and
+// the hash or signature covering it. See section 5.9 of the RP2350 datasheet
+
+use std::fmt;
+
+use crate::keys::PrivateKey;
+use sha2::{Digest, Sha256};
+
+pub const BLOCK_MARKER_START: u32 = 0xFFFFDED3;
+pub const BLOCK_MARKER_END: u32 = 0xAB123579;
+// the first block of the loop has to start in this many bytes from the start of the image
+pub const BLOCK_SEARCH_SIZE: usize = 4096;
+
+pub const ITEM_VECTOR_TABLE: u8 = 0x03;
+pub const ITEM_ROLLING_WINDOW_DELTA: u8 = 0x05;
+pub const ITEM_LOAD_MAP: u8 = 0x06;
+pub const ITEM_SIGNATURE: u8 = 0x09;
+pub const ITEM_PARTITION_TABLE: u8 = 0x0A;
+pub const ITEM_SALT: u8 = 0x0C;
+pub const ITEM_NEXT_BLOCK_OFFSET: u8 = 0x41;
+pub const ITEM_IMAGE_DEF: u8 = 0x42;
+pub const ITEM_ENTRY_POINT: u8 = 0x44;
+pub const ITEM_HASH_DEF: u8 = 0x47;
+pub const ITEM_VERSION: u8 = 0x48;
+pub const ITEM_HASH_VALUE: u8 = 0x4B;
+pub const ITEM_IGNORED: u8 = 0xFE;
+pub const ITEM_LAST: u8 = 0xFF;
+// item types with this bit set have a 2 byte size, the rest a 1 byte size
+const ITEM_SIZE_2_BYTES: u8 = 0x80;
+
+// IMAGE_DEF flags, in the top half of the item's first word
+const IMAGE_TYPE_EXE: u32 = 0x1;
+const IMAGE_CHIP_RP2350: u32 = 0x1;
+
+const HASH_TYPE_SHA256: u32 = 0x01;
+const SIGNATURE_TYPE_SECP256K1: u32 = 0x01;
+const LOAD_MAP_ABSOLUTE: u32 = 1 << 31;
+
+#[derive(Debug)]
+pub enum PicobinError {
+    // no block starts in the first 4K of the image
+    NoBlock,
+    // the block at this offset into the image is malformed
+    BadBlock(usize),
+    // following the links from the first block never leads back to it
+    BrokenLoop,
+    // no block in the loop has an IMAGE_DEF item
+    NoImageDef,
+    // the loop isn't one a signature can be added to, e.g. more blocks than an image and its
+    // signature
+    Unsupported(&'static str),
+    // the IMAGE_DEF is for data or something else the bootrom won't run
+    NotExecutable,
+    // the IMAGE_DEF is for another chip, e.g. an RP2040
+    WrongChip,
+    // a load map entry refers to bytes outside the image
+    BadLoadMap,
+    // the hash of the image doesn't match its HASH_VALUE
+    HashMismatch,
+    // the signature doesn't match the image
+    BadSignature,
+}
+impl fmt::Display for PicobinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PicobinError::NoBlock => write!(
+                f,
+                "no PICOBIN block in the first {} bytes of the image",
+                BLOCK_SEARCH_SIZE
+            ),
+            PicobinError::BadBlock(offset) => {
+                write!(f, "malformed PICOBIN block at offset {:#X}", offset)
+            }
+            PicobinError::BrokenLoop => write!(f, "the PICOBIN block loop doesn't close"),
+            PicobinError::NoImageDef => write!(f, "no block has an IMAGE_DEF"),
+            PicobinError::Unsupported(why) => write!(f, "{}", why),
+            PicobinError::NotExecutable => write!(f, "the IMAGE_DEF isn't for an executable"),
+            PicobinError::WrongChip => write!(f, "the IMAGE_DEF isn't for an RP2350"),
+            PicobinError::BadLoadMap => {
+                write!(f, "the load map refers to bytes outside the image")
+            }
+            PicobinError::HashMismatch => write!(f, "the image doesn't match its hash"),
+            PicobinError::BadSignature => write!(f, "the image doesn't match its signature"),
+        }
+    }
+}
+
+// An item of a block, as its words including the header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub words: Vec<u32>,
+}
+impl Item {
+    pub fn kind(&self) -> u8 {
+        self.words[0] as u8
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    // byte offset of the start marker into the image
+    pub offset: usize,
+    pub items: Vec<Item>,
+    // offset in bytes from this block to the next one in the loop, 0 for a loop of one
+    pub link: i32,
+}
+impl Block {
+    // Reads the block whose start marker is at `offset`
+    pub fn parse(image: &[u8], offset: usize) -> Result<Self, PicobinError> {
+        let bad = PicobinError::BadBlock(offset);
+        let word = |i: usize| -> Result<u32, PicobinError> {
+            let at = offset + i * 4;
+            image
+                .get(at..at + 4)
+                .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+                .ok_or(PicobinError::BadBlock(offset))
+        };
+        if word(0)? != BLOCK_MARKER_START {
+            return Err(bad);
+        }
+        let mut items = vec![];
+        let mut i = 1;
+        loop {
+            let header = word(i)?;
+            let kind = header as u8;
+            let size = if kind & ITEM_SIZE_2_BYTES != 0 {
+                (header >> 8) & 0xFFFF
+            } else {
+                (header >> 8) & 0xFF
+            } as usize;
+            if kind == ITEM_LAST {
+                // the size of the last item is that of all the items before it
+                if size != i - 1 || word(i + 2)? != BLOCK_MARKER_END {
+                    return Err(bad);
+                }
+                let link = word(i + 1)? as i32;
+                return Ok(Block {
+                    offset,
+                    items,
+                    link,
+                });
+            }
+            if size == 0 {
+                return Err(bad);
+            }
+            let words = (i..i + size).map(word).collect::<Result<_, _>>()?;
+            items.push(Item { words });
+            i += size;
+        }
+    }
+
+    pub fn find(&self, kind: u8) -> Option<&Item> {
+        self.items.iter().find(|i| i.kind() == kind)
+    }
+
+    // The whole block, from its start marker to its end marker
+    pub fn to_words(&self) -> Vec<u32> {
+        let mut words = vec![BLOCK_MARKER_START];
+        for item in &self.items {
+            words.extend(&item.words);
+        }
+        words.push(ITEM_LAST as u32 | (words.len() as u32 - 1) << 8);
+        words.push(self.link as u32);
+        words.push(BLOCK_MARKER_END);
+        words
+    }
+
+    // Byte offset of the link word into the image
+    fn link_offset(&self) -> usize {
+        let item_words: usize = self.items.iter().map(|i| i.words.len()).sum();
+        self.offset + (item_words + 2) * 4
+    }
+
+    // Byte offset of an item into the image
+    fn item_offset(&self, item: &Item) -> usize {
+        let before: usize = self
+            .items
+            .iter()
+            .take_while(|i| !std::ptr::eq(*i, item))
+            .map(|i| i.words.len())
+            .sum();
+        self.offset + (before + 1) * 4
+    }
+
+    // Size of the block in bytes
+    pub fn size(&self) -> usize {
+        self.to_words().len() * 4
+    }
+}
+
+// The architecture an executable IMAGE_DEF is built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cpu {
+    Arm,
+    RiscV,
+    // the bootrom's RISC-V emulator running on ARM
+    Varmulet,
+}
+impl fmt::Display for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cpu::Arm => write!(f, "ARM"),
+            Cpu::RiscV => write!(f, "RISC-V"),
+            Cpu::Varmulet => write!(f, "Varmulet"),
+        }
+    }
+}
+
+// What the bootrom will find in an image that checked out
+#[derive(Debug, Clone, Copy)]
+pub struct ImageDef {
+    pub cpu: Cpu,
+    // whether a hash of the image was checked
+    pub hashed: bool,
+    // whether a signature of the image was checked, against the key in it rather than in OTP
+    pub signed: bool,
+}
+
+// Finds the first block in the image and follows the links from it, returning every block in
+// loop order
+pub fn block_loop(image: &[u8]) -> Result<Vec<Block>, PicobinError> {
+    let search = std::cmp::min(image.len(), BLOCK_SEARCH_SIZE);
+    let first = (0..search)
+        .step_by(4)
+        .find_map(|offset| Block::parse(image, offset).ok())
+        .ok_or(PicobinError::NoBlock)?;
+
+    let mut blocks = vec![first];
+    loop {
+        let last = blocks.last().unwrap();
+        let next = last.offset as i64 + last.link as i64;
+        if next == blocks[0].offset as i64 {
+            return Ok(blocks);
+        }
+        if next < 0 || blocks.iter().any(|b| b.offset as i64 == next) {
+            return Err(PicobinError::BrokenLoop);
+        }
+        blocks.push(Block::parse(image, next as usize)?);
+    }
+}
+
+// Signs a flash image whose first byte is at `base`, as `picotool seal --sign` does. A new block
+// holding the IMAGE_DEF, a load map covering the image, a hash definition and the signature is
+// appended to the image and linked into the loop after its first block. An image signed before
+// has its old signature block replaced
+pub fn sign(image: &mut Vec<u8>, base: u32, key: &PrivateKey) -> Result<(), PicobinError> {
+    let mut blocks = block_loop(image)?;
+    // a block at the very end of the image is a signature added before, so it's redone
+    if let [first, signed] = &blocks[..] {
+        if signed.offset > first.offset && signed.offset + signed.size() >= image.len() {
+            image.truncate(signed.offset);
+            blocks.truncate(1);
+            blocks[0].link = 0;
+        }
+    }
+    let [first] = &blocks[..] else {
+        return Err(PicobinError::Unsupported(
+            "only images with a single block can be signed",
+        ));
+    };
+    let mut first = first.clone();
+    if first.find(ITEM_IMAGE_DEF).is_none() {
+        return Err(PicobinError::NoImageDef);
+    }
+
+    image.resize(image.len().next_multiple_of(4), 0);
+    let offset = image.len();
+    first.link = (offset - first.offset) as i32;
+    let link_at = first.link_offset();
+    image[link_at..link_at + 4].copy_from_slice(&(first.link as u32).to_le_bytes());
+
+    let mut items: Vec<Item> = first
+        .items
+        .iter()
+        .filter(|i| {
+            ![
+                ITEM_LOAD_MAP,
+                ITEM_HASH_DEF,
+                ITEM_HASH_VALUE,
+                ITEM_SIGNATURE,
+                ITEM_IGNORED,
+            ]
+            .contains(&i.kind())
+        })
+        .cloned()
+        .collect();
+    // the image up to the new block is hashed in place, as it runs from flash
+    let end = base + offset as u32;
+    items.push(Item {
+        words: vec![
+            ITEM_LOAD_MAP as u32 | 4 << 8 | 1 << 24 | LOAD_MAP_ABSOLUTE,
+            base,
+            base,
+            end,
+        ],
+    });
+    items.push(Item {
+        words: vec![ITEM_HASH_DEF as u32 | 2 << 8 | HASH_TYPE_SHA256 << 24, 0],
+    });
+    // the hash covers the block from its start marker up to and including the HASH_DEF item
+    let hashed = 1 + items.iter().map(|i| i.words.len()).sum::<usize>();
+    items.last_mut().unwrap().words[1] = hashed as u32;
+    let mut block = Block {
+        offset,
+        items,
+        link: first.offset as i32 - offset as i32,
+    };
+
+    let mut sha = Sha256::new();
+    sha.update(&image[..]);
+    block.to_words()[..hashed]
+        .iter()
+        .for_each(|w| sha.update(w.to_le_bytes()));
+    let digest: [u8; 32] = sha.finalize().into();
+
+    let mut words = vec![ITEM_SIGNATURE as u32 | 33 << 8 | SIGNATURE_TYPE_SECP256K1 << 24];
+    let public_key = key.public_key().0;
+    let signature = key.sign(&digest);
+    for bytes in [&public_key[..], &signature[..]] {
+        words.extend(
+            bytes
+                .chunks(4)
+                .map(|c| u32::from_le_bytes(c.try_into().unwrap())),
+        );
+    }
+    block.items.push(Item { words });
+
+    image.extend(block.to_words().iter().flat_map(|w| w.to_le_bytes()));
+    Ok(())
+}
+
+// Checks a flash image whose first byte is at `base` the way the bootrom does before booting it:
+// the block loop has to close, the last IMAGE_DEF in it has to be for an RP2350 executable, and
+// any hash or signature has to match. Returns None for a partition table without an IMAGE_DEF,
+// as the images to boot are then in the partitions
+pub fn check_image(image: &[u8], base: u32) -> Result<Option<ImageDef>, PicobinError> {
+    let blocks = block_loop(image)?;
+    let Some((block, item)) = blocks
+        .iter()
+        .rev()
+        .find_map(|b| b.find(ITEM_IMAGE_DEF).map(|i| (b, i)))
+    else {
+        if blocks
+            .iter()
+            .any(|b| b.find(ITEM_PARTITION_TABLE).is_some())
+        {
+            return Ok(None);
+        }
+        return Err(PicobinError::NoImageDef);
+    };
+    let flags = item.words[0] >> 16;
+    if flags & 0xF != IMAGE_TYPE_EXE {
+        return Err(PicobinError::NotExecutable);
+    }
+    if flags >> 12 & 0x7 != IMAGE_CHIP_RP2350 {
+        return Err(PicobinError::WrongChip);
+    }
+    let cpu = match flags >> 8 & 0x7 {
+        0 => Cpu::Arm,
+        1 => Cpu::RiscV,
+        2 => Cpu::Varmulet,
+        _ => return Err(PicobinError::BadBlock(block.offset)),
+    };
+
+    let Some(hash_def) = block.find(ITEM_HASH_DEF) else {
+        return Ok(Some(ImageDef {
+            cpu,
+            hashed: false,
+            signed: false,
+        }));
+    };
+    let bad = PicobinError::BadBlock(block.offset);
+    let hashed = *hash_def
+        .words
+        .get(1)
+        .ok_or(PicobinError::BadBlock(block.offset))? as usize;
+    let words = block.to_words();
+    if hash_def.words[0] >> 24 != HASH_TYPE_SHA256 || hashed > words.len() {
+        return Err(bad);
+    }
+
+    let mut sha = Sha256::new();
+    if let Some(load_map) = block.find(ITEM_LOAD_MAP) {
+        let absolute = load_map.words[0] & LOAD_MAP_ABSOLUTE != 0;
+        let item_addr = base as u64 + block.item_offset(load_map) as u64;
+        for entry in load_map.words[1..].chunks(3) {
+            let &[storage, _runtime, size_or_end] = entry else {
+                return Err(bad);
+            };
+            // entries without storage are zero filled at runtime, so there's nothing to hash
+            if storage == 0 {
+                continue;
+            }
+            let (start, end) = if absolute {
+                (storage as u64, size_or_end as u64)
+            } else {
+                let start = item_addr + storage as u64;
+                (start, start + size_or_end as u64)
+            };
+            let range = start
+                .checked_sub(base as u64)
+                .zip(end.checked_sub(base as u64))
+                .map(|(s, e)| s as usize..e as usize)
+                .ok_or(PicobinError::BadLoadMap)?;
+            sha.update(image.get(range).ok_or(PicobinError::BadLoadMap)?);
+        }
+    }
+    words[..hashed]
+        .iter()
+        .for_each(|w| sha.update(w.to_le_bytes()));
+    let digest: [u8; 32] = sha.finalize().into();
+
+    if let Some(value) = block.find(ITEM_HASH_VALUE) {
+        // the value can be a truncated hash
+        let expected: Vec<u8> = value.words[1..]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        if expected.len() > digest.len() || digest[..expected.len()] != expected[..] {
+            return Err(PicobinError::HashMismatch);
+        }
+    }
+    let signed = match block.find(ITEM_SIGNATURE) {
+        Some(signature) => {
+            let bytes: Vec<u8> = signature.words[1..]
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect();
+            if signature.words[0] >> 24 != SIGNATURE_TYPE_SECP256K1 || bytes.len() != 128 {
+                return Err(PicobinError::BadBlock(block.offset));
+            }
+            let key = bytes[..64].try_into().unwrap();
+            let sig = bytes[64..].try_into().unwrap();
+            if !crate::secp256k1::verify(key, &digest, sig) {
+                return Err(PicobinError::BadSignature);
+            }
+            true
+        }
+        None => false,
+    };
+    Ok(Some(ImageDef {
+        cpu,
+        hashed: true,
+        signed,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{image, uf2};
+
+    // The blink example for the RP2350 in the repository, as one flash image
+    fn fixture() -> (Vec<u8>, u32) {
+        let segments = uf2::decode(include_bytes!("../fw_blink_rp2350.uf2")).unwrap();
+        let base = segments[0].addr;
+        let end = segments.last().unwrap().end();
+        (
+            image::read_range(&segments, base, (end - base as u64) as u32),
+            base,
+        )
+    }
+
+    // A SEC1 DER private key, as `openssl ec -outform der` writes
+    fn private_key(byte: u8) -> PrivateKey {
+        let mut der = vec![0x30, 0x25, 0x02, 0x01, 0x01, 0x04, 0x20];
+        der.extend([byte; 32]);
+        PrivateKey::parse(&der).unwrap()
+    }
+
+    #[test]
+    fn fixture_has_an_unsigned_image_def() {
+        let (image, base) = fixture();
+        assert_eq!(base, 0x10000000);
+        let blocks = block_loop(&image).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert!(blocks[0].find(ITEM_IMAGE_DEF).is_some());
+        let def = check_image(&image, base).unwrap().unwrap();
+        assert_eq!(def.cpu, Cpu::Arm);
+        assert!(!def.hashed && !def.signed);
+    }
+
+    #[test]
+    fn signing_appends_a_signature_block() {
+        let (unsigned, base) = fixture();
+        let mut image = unsigned.clone();
+        let key = private_key(0x11);
+        sign(&mut image, base, &key).unwrap();
+
+        // the image itself is untouched, apart from the first block's link
+        let offset = unsigned.len().next_multiple_of(4);
+        let first = &block_loop(&unsigned).unwrap()[0];
+        let link_at = first.link_offset();
+        assert_eq!(image[..link_at], unsigned[..link_at]);
+        assert_eq!(image[link_at + 4..unsigned.len()], unsigned[link_at + 4..]);
+        let link = u32::from_le_bytes(image[link_at..link_at + 4].try_into().unwrap());
+        assert_eq!(first.offset as u32 + link, offset as u32);
+
+        let blocks = block_loop(&image).unwrap();
+        assert_eq!(blocks.len(), 2);
+        let block = &blocks[1];
+        assert_eq!(block.offset, offset);
+        assert_eq!(block.offset + block.size(), image.len());
+        assert_eq!(block.offset as i32 + block.link, first.offset as i32);
+        let kinds: Vec<u8> = block.items.iter().map(Item::kind).collect();
+        assert_eq!(
+            kinds,
+            [ITEM_IMAGE_DEF, ITEM_LOAD_MAP, ITEM_HASH_DEF, ITEM_SIGNATURE]
+        );
+        assert_eq!(block.find(ITEM_IMAGE_DEF), first.find(ITEM_IMAGE_DEF));
+
+        // one absolute entry, hashing the image in place up to the new block
+        let load_map = &block.find(ITEM_LOAD_MAP).unwrap().words;
+        assert_eq!(
+            load_map[0],
+            0x80000000 | 1 << 24 | 4 << 8 | ITEM_LOAD_MAP as u32
+        );
+        assert_eq!(&load_map[1..], [base, base, base + offset as u32]);
+        // SHA-256 over the block up to and including the HASH_DEF: the marker and 2 items
+        let hash_def = &block.find(ITEM_HASH_DEF).unwrap().words;
+        let image_def_len = block.items[0].words.len() as u32;
+        assert_eq!(
+            hash_def[..],
+            [
+                0x01 << 24 | 2 << 8 | ITEM_HASH_DEF as u32,
+                1 + image_def_len + 4 + 2
+            ]
+        );
+        // secp256k1, with the 64 byte public key then the 64 byte signature
+        let signature = &block.find(ITEM_SIGNATURE).unwrap().words;
+        assert_eq!(signature.len(), 33);
+        assert_eq!(signature[0], 0x01 << 24 | 33 << 8 | ITEM_SIGNATURE as u32);
+        let bytes: Vec<u8> = signature[1..]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        assert_eq!(bytes[..64], key.public_key().0);
+
+        let def = check_image(&image, base).unwrap().unwrap();
+        assert!(def.hashed && def.signed);
+    }
+
+    #[test]
+    fn resigning_replaces_the_signature() {
+        let (unsigned, base) = fixture();
+        let mut once = unsigned.clone();
+        sign(&mut once, base, &private_key(0x11)).unwrap();
+        let mut twice = once.clone();
+        sign(&mut twice, base, &private_key(0x22)).unwrap();
+        assert_eq!(twice.len(), once.len());
+        assert_eq!(block_loop(&twice).unwrap().len(), 2);
+        let signed = check_image(&twice, base).unwrap().unwrap();
+        assert!(signed.signed);
+        let blocks = block_loop(&twice).unwrap();
+        let signature = &blocks[1].find(ITEM_SIGNATURE).unwrap().words;
+        let key: Vec<u8> = signature[1..17]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        assert_eq!(key, private_key(0x22).public_key().0);
+    }
+
+    #[test]
+    fn tampering_is_caught() {
+        let (mut image, base) = fixture();
+        sign(&mut image, base, &private_key(0x11)).unwrap();
+        // a byte of the program
+        let mut changed = image.clone();
+        changed[0x200] ^= 1;
+        assert!(matches!(
+            check_image(&changed, base),
+            Err(PicobinError::BadSignature)
+        ));
+        // the last byte of the signature's S
+        let mut changed = image.clone();
+        // the block ends with the LAST item, the link and the end marker
+        let end = changed.len() - 12;
+        changed[end - 1] ^= 1;
+        assert!(matches!(
+            check_image(&changed, base),
+            Err(PicobinError::BadSignature)
+        ));
+        // signed for another base address
+        assert!(check_image(&image, base + 0x1000).is_err());
+    }
+}
@@ -1,588 +1,2178 @@
 // This is a barebones implementation of PICOBOOT communication in rust
 // This is intended only to work with the RP2040, but could work with new chips with extra modifications
 
-use bincode;
-use rusb::{Device, DeviceDescriptor, DeviceHandle, Direction, TransferType, UsbContext};
-use serde::{Deserialize, Serialize};
+use crate::memmap::{Access, AddressError, FlashAddr, MemAddr, MemoryMap, SramAddr};
+use crate::trace::{Trace, TraceOp};
+use rusb::UsbContext;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub use crate::partition::PartitionTable;
+pub use crate::protocol::{
+    InfoType, PicobootStatus, PICO2_STACK_POINTER, PICO_FLASH_START, PICO_PAGE_SIZE,
+    PICO_SECTOR_SIZE, PICO_STACK_POINTER,
+};
+use crate::protocol::{
+    PicobootCmd, PicobootCmdId, PicobootGetInfoCmd, PicobootOtpCmd, PicobootRangeCmd,
+    PicobootReboot2Cmd, PicobootRebootCmd, PicobootStatusCmd, STATUS_SIZE,
+};
+pub(crate) use crate::protocol::{PICOBOOT_PID_RP2040, PICOBOOT_PID_RP2350, PICOBOOT_VID};
+pub use crate::transport::{RusbTransport, Transport};
 
 // see https://github.com/raspberrypi/picotool/blob/master/main.cpp#L4173
 // for loading firmware over a connection
 
-// see https://datasheets.raspberrypi.com/rp2040/rp2040-datasheet.pdf
-// section 2.8.5 for details on PICOBOOT interface
-
-pub const PICO_PAGE_SIZE: usize = 256;
-pub const PICO_SECTOR_SIZE: u32 = 4096;
-pub const PICO_FLASH_START: u32 = 0x10000000;
-pub const PICO_STACK_POINTER: u32 = 0x20042000;
-const PICOBOOT_VID: u16 = 0x2E8A;
-const PICOBOOT_PID_RP2040: u16 = 0x0003;
-const PICOBOOT_PID_RP2350: u16 = 0x000f;
-const PICOBOOT_MAGIC: u32 = 0x431FD10B;
+// the wire format itself lives in `protocol`
+// bulk transfers are split into chunks of this many of the endpoint's max size packets, each
+// with its own timeout, so large reads and writes don't rely on the OS or hubs taking them in one
+// go. That's 4K at full speed and 32K at high speed, and keeping chunks to whole packets means
+// only the last packet of a transfer can be short
+const BULK_CHUNK_PACKETS: usize = 64;
+// the ack of a flash erase only comes once the erase is done, so its timeout is extended by this
+// much per sector, the worst case sector erase time of common flash parts
+const ERASE_TIMEOUT_PER_SECTOR: Duration = Duration::from_millis(400);
+// `flash_read_all` reads this much per READ command, cutting the command round trips of large
+// dumps while the transfer itself still goes in bulk chunks
+const READ_CHUNK_SIZE: u32 = 64 * 1024;
+// how often to look for a device while waiting for one to be connected
+pub(crate) const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+// how long a board forced into BOOTSEL gets to reboot and show up again
+const FORCE_REBOOT_WAIT: Duration = Duration::from_secs(10);
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TargetID {
     Rp2040,
     Rp2350,
 }
 
-fn open_device<T: UsbContext>(
-    ctx: &mut T,
-    vid: u16,
-    pid: u16,
-) -> Option<(Device<T>, DeviceDescriptor, DeviceHandle<T>)> {
-    let devices = match ctx.devices() {
-        Ok(d) => d,
-        Err(_) => return None,
-    };
-
-    for device in devices.iter() {
-        let device_desc = match device.device_descriptor() {
-            Ok(d) => d,
-            Err(_) => continue,
-        };
+// Where to reboot to, see `PicobootConnection::reboot_into`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootMode {
+    // boot the firmware in flash
+    Normal,
+    // come back up in BOOTSEL mode
+    Bootsel,
+    // start running at `pc` with the stack at `sp`, e.g. an image loaded into SRAM
+    Run { pc: u32, sp: u32 },
+}
 
-        if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
-            match device.open() {
-                Ok(handle) => return Some((device, device_desc, handle)),
-                Err(e) => panic!("Device found but failed to open: {}", e),
+// What an RP2350 should reboot into with REBOOT2, see `PicobootConnection::reboot2`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reboot2Kind {
+    // boot the firmware in flash
+    Normal,
+    // BOOTSEL mode, with either USB interface left out if asked, and optionally an activity LED
+    // on the given GPIO
+    Bootsel {
+        disable_msc: bool,
+        disable_picoboot: bool,
+        led_gpio: Option<u8>,
+        led_active_low: bool,
+    },
+    // boot the image already loaded into `start..start+size` of SRAM
+    RamImage {
+        start: u32,
+        size: u32,
+    },
+    // boot flash as after a flash update, with `start` the start of the updated region
+    FlashUpdate {
+        start: u32,
+    },
+    // start running at `pc` with the stack at `sp`
+    PcSp {
+        pc: u32,
+        sp: u32,
+    },
+}
+impl Reboot2Kind {
+    // The REBOOT2 flags and its two parameters
+    fn params(self) -> (u32, u32, u32) {
+        match self {
+            Reboot2Kind::Normal => (0x0, 0, 0),
+            Reboot2Kind::Bootsel {
+                disable_msc,
+                disable_picoboot,
+                led_gpio,
+                led_active_low,
+            } => {
+                let mut p0 = 0;
+                if disable_msc {
+                    p0 |= 0x01;
+                }
+                if disable_picoboot {
+                    p0 |= 0x02;
+                }
+                if led_gpio.is_some() {
+                    p0 |= 0x20;
+                    if led_active_low {
+                        p0 |= 0x10;
+                    }
+                }
+                (0x2, p0, led_gpio.unwrap_or(0) as u32)
             }
+            Reboot2Kind::RamImage { start, size } => (0x3, start, size),
+            Reboot2Kind::FlashUpdate { start } => (0x4, start, 0),
+            Reboot2Kind::PcSp { pc, sp } => (0xD, pc, sp),
         }
     }
+}
 
-    None
+// The architecture an RP2350 switches its cores to when rebooting with REBOOT2, see
+// `PicobootConnection::set_reboot_arch`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootArch {
+    Arm,
+    RiscV,
+}
+impl RebootArch {
+    fn flag(self) -> u32 {
+        match self {
+            RebootArch::Arm => 0x10,
+            RebootArch::RiscV => 0x20,
+        }
+    }
+}
+
+// How commands are sequenced on the wire. `Picotool` mirrors picotool exactly, only asking for
+// command status when a transfer fails and leaving XIP before every flash erase and write, for
+// older or quirky bootroms that misbehave with the default sequencing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandSequencing {
+    Default,
+    Picotool,
+}
+
+// How commands failing with a transient USB error (a timeout, stall or I/O error, as flaky hubs
+// and long cables cause) are retried. Each retry resets the interface and sends the whole command
+// again, waiting `backoff` before the first and twice as long before each one after. Commands the
+// bootrom rejects aren't retried, nor reboots and execs, which may have happened anyway
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // tries in all, so 1 never retries
+    pub max_attempts: u32,
+    pub backoff: Duration,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    fn is_transient(e: &rusb::Error) -> bool {
+        matches!(
+            e,
+            rusb::Error::Timeout | rusb::Error::Pipe | rusb::Error::Io
+        )
+    }
+
+    // How long to wait before trying again after `attempt` failed
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(1 << (attempt - 1).min(16))
+    }
 }
 
-#[repr(u8)]
-#[derive(Debug)]
-enum PicobootCmdId {
-    Unknown = 0x0,
-    ExclusiveAccess = 0x1,
-    Reboot = 0x2,
-    FlashErase = 0x3,
-    Read = 0x84, // either RAM or FLASH
-    Write = 0x5, // either RAM or FLASH (does no erase)
-    ExitXip = 0x6,
-    EnterCmdXip = 0x7,
-    Exec = 0x8,
-    VectorizeFlash = 0x9,
-    // RP2350 only below here
-    Reboot2 = 0xA,
-    GetInfo = 0x8B,
-    OtpRead = 0x8C,
-    OtpWrite = 0xD,
-    //Exec2 = 0xE, // currently unused
-}
-impl TryFrom<u8> for PicobootCmdId {
-    type Error = ();
-
-    fn try_from(x: u8) -> Result<Self, Self::Error> {
-        match x {
-            x if x == Self::Unknown as u8 => Ok(Self::Unknown),
-            x if x == Self::ExclusiveAccess as u8 => Ok(Self::ExclusiveAccess),
-            x if x == Self::Reboot as u8 => Ok(Self::Reboot),
-            x if x == Self::FlashErase as u8 => Ok(Self::FlashErase),
-            x if x == Self::Read as u8 => Ok(Self::Read),
-            x if x == Self::Write as u8 => Ok(Self::Write),
-            x if x == Self::ExitXip as u8 => Ok(Self::ExitXip),
-            x if x == Self::EnterCmdXip as u8 => Ok(Self::EnterCmdXip),
-            x if x == Self::Exec as u8 => Ok(Self::Exec),
-            x if x == Self::VectorizeFlash as u8 => Ok(Self::VectorizeFlash),
-            x if x == Self::Reboot2 as u8 => Ok(Self::Reboot2),
-            x if x == Self::GetInfo as u8 => Ok(Self::GetInfo),
-            x if x == Self::OtpRead as u8 => Ok(Self::OtpRead),
-            x if x == Self::OtpWrite as u8 => Ok(Self::OtpWrite),
-            // x if x == Self::Exec2 as u8 => Ok(Self::Exec2),
-            _ => Err(()),
-        }
-    }
-}
-
-#[repr(u32)]
 #[derive(Debug)]
-enum PicobootStatus {
-    Ok = 0,
-    UnknownCmd = 1,
-    InvalidCmdLength = 2,
-    InvalidTransferLength = 3,
-    InvalidAddress = 4,
-    BadAlignment = 5,
-    InterleavedWrite = 6,
-    Rebooting = 7,
-    UnknownError = 8,
-    InvalidState = 9,
-    NotPermitted = 10,
-    InvalidArg = 11,
-    BufferTooSmall = 12,
-    PreconditionNotMet = 13,
-    ModifiedData = 14,
-    InvalidData = 15,
-    NotFound = 16,
-    UnsupportedModification = 17,
-}
-impl TryFrom<u32> for PicobootStatus {
-    type Error = ();
-
-    fn try_from(x: u32) -> Result<Self, Self::Error> {
-        match x {
-            x if x == Self::Ok as u32 => Ok(Self::Ok),
-            x if x == Self::UnknownCmd as u32 => Ok(Self::UnknownCmd),
-            x if x == Self::InvalidCmdLength as u32 => Ok(Self::InvalidCmdLength),
-            x if x == Self::InvalidTransferLength as u32 => Ok(Self::InvalidTransferLength),
-            x if x == Self::InvalidAddress as u32 => Ok(Self::InvalidAddress),
-            x if x == Self::BadAlignment as u32 => Ok(Self::BadAlignment),
-            x if x == Self::InterleavedWrite as u32 => Ok(Self::InterleavedWrite),
-            x if x == Self::Rebooting as u32 => Ok(Self::Rebooting),
-            x if x == Self::UnknownError as u32 => Ok(Self::UnknownError),
-            x if x == Self::InvalidState as u32 => Ok(Self::InvalidState),
-            x if x == Self::NotPermitted as u32 => Ok(Self::NotPermitted),
-            x if x == Self::InvalidArg as u32 => Ok(Self::InvalidArg),
-            x if x == Self::BufferTooSmall as u32 => Ok(Self::BufferTooSmall),
-            x if x == Self::PreconditionNotMet as u32 => Ok(Self::PreconditionNotMet),
-            x if x == Self::ModifiedData as u32 => Ok(Self::ModifiedData),
-            x if x == Self::InvalidData as u32 => Ok(Self::InvalidData),
-            x if x == Self::NotFound as u32 => Ok(Self::NotFound),
-            x if x == Self::UnsupportedModification as u32 => Ok(Self::UnsupportedModification),
-            _ => Err(()),
-        }
-    }
-}
-
-#[derive(Serialize)]
-#[repr(C, packed)]
-struct PicobootRangeCmd {
-    addr: u32,
-    size: u32,
-    _unused: u64,
-}
-impl PicobootRangeCmd {
-    pub fn ser(addr: u32, size: u32) -> [u8; 16] {
-        let c = PicobootRangeCmd {
-            addr: addr,
-            size: size,
-            _unused: 0,
+pub enum PicobootError {
+    Usb(rusb::Error),
+    // no RP2040 or RP2350 in BOOTSEL mode is connected
+    DeviceNotFound,
+    // the device has no PICOBOOT interface with matching bulk endpoints
+    InterfaceNotFound,
+    // the device is there but can't be opened for want of a driver, which happens on Windows
+    // until WinUSB (or another libusb compatible driver) is bound to its PICOBOOT interface
+    NoDriver {
+        vid: u16,
+        pid: u16,
+    },
+    // the device is there but the OS won't let it be opened, on Linux for want of a udev rule
+    // giving the user access
+    PermissionDenied {
+        vid: u16,
+        pid: u16,
+        bus: u8,
+        address: u8,
+    },
+    // the connected chip doesn't support the command
+    NotSupported,
+    // the bootrom rejected the command with the given token
+    Command {
+        cmd_id: u8,
+        token: u32,
+        status: PicobootStatus,
+    },
+    ShortTransfer {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidArgument(&'static str),
+    // a page read back from flash differs from what was written
+    VerifyFailed {
+        addr: u32,
+        mismatched: usize,
+    },
+    // a page read back differently every time, so the link can't be trusted to verify it
+    UnstableRead {
+        addr: u32,
+    },
+    // the device's CRC of a range of flash differs from that of what was written
+    CrcMismatch {
+        addr: u32,
+        size: u32,
+    },
+    // stopped early because the operation was cancelled, see
+    // `PicobootConnection::set_cancel_token`
+    Interrupted,
+    // the device answered with something that doesn't parse
+    BadResponse,
+    // the address is outside the chip's memory, or in memory that doesn't allow the access
+    Address(AddressError),
+    // a write or erase would run up to `end`, past the end of flash
+    PastFlashEnd {
+        end: u64,
+        flash_size: u32,
+    },
+}
+impl std::fmt::Display for PicobootError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PicobootError::Usb(e) => write!(f, "USB error: {}", e),
+            PicobootError::DeviceNotFound => write!(f, "could not find a PICOBOOT device"),
+            PicobootError::InterfaceNotFound => write!(f, "device has no PICOBOOT interface"),
+            PicobootError::NoDriver { vid, pid } => write!(
+                f,
+                "device {:04X}:{:04X} found but has no WinUSB driver, install one for \
+                 \"RP2 Boot (Interface 1)\" with Zadig (https://zadig.akeo.ie), or use the \
+                 UsbDk backend",
+                vid, pid
+            ),
+            PicobootError::PermissionDenied {
+                vid,
+                pid,
+                bus,
+                address,
+            } => {
+                write!(
+                    f,
+                    "device {:04X}:{:04X} found but permission to open it was denied",
+                    vid, pid
+                )?;
+                if cfg!(target_os = "linux") {
+                    write!(
+                        f,
+                        ", install picotool's udev rules (https://github.com/raspberrypi/\
+                         picotool/blob/master/udev/99-picotool.rules) and plug it in again, or \
+                         add yourself to the group owning /dev/bus/usb/{:03}/{:03}",
+                        bus, address
+                    )?;
+                }
+                Ok(())
+            }
+            PicobootError::NotSupported => write!(f, "command not supported by this chip"),
+            PicobootError::Command {
+                cmd_id,
+                token,
+                status,
+            } => {
+                write!(f, "command ")?;
+                match PicobootCmdId::try_from(*cmd_id) {
+                    Ok(id) => write!(f, "{:?}", id)?,
+                    Err(_) => write!(f, "{:#04X}", cmd_id)?,
+                }
+                write!(f, " (token {}) failed with status {:?}", token, status)
+            }
+            PicobootError::ShortTransfer { expected, actual } => {
+                write!(f, "transferred {} of {} bytes", actual, expected)
+            }
+            PicobootError::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            PicobootError::VerifyFailed { addr, mismatched } => write!(
+                f,
+                "page at {:#X} failed to verify, {} bytes differ",
+                addr, mismatched
+            ),
+            PicobootError::UnstableRead { addr } => write!(
+                f,
+                "page at {:#X} read back differently every time, USB link is unreliable",
+                addr
+            ),
+            PicobootError::CrcMismatch { addr, size } => write!(
+                f,
+                "{:#X} bytes at {:#X} failed to verify, CRC differs",
+                size, addr
+            ),
+            PicobootError::Interrupted => write!(f, "interrupted"),
+            PicobootError::BadResponse => write!(f, "bad response from device"),
+            PicobootError::Address(e) => write!(f, "{}", e),
+            PicobootError::PastFlashEnd { end, flash_size } => write!(
+                f,
+                "{:#X} is past the end of the {} KiB flash, which ends at {:#X}",
+                end,
+                flash_size / 1024,
+                PICO_FLASH_START as u64 + *flash_size as u64
+            ),
+        }
+    }
+}
+impl std::error::Error for PicobootError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PicobootError::Usb(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<rusb::Error> for PicobootError {
+    fn from(e: rusb::Error) -> Self {
+        PicobootError::Usb(e)
+    }
+}
+impl From<AddressError> for PicobootError {
+    fn from(e: AddressError) -> Self {
+        PicobootError::Address(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, PicobootError>;
+
+// Flags selecting what `InfoType::Sys` returns, the response has them in this order
+const SYS_INFO_CHIP_INFO: u32 = 0x01;
+const SYS_INFO_CRITICAL: u32 = 0x02;
+const SYS_INFO_CPU_INFO: u32 = 0x04;
+const SYS_INFO_FLASH_DEV_INFO: u32 = 0x08;
+const SYS_INFO_BOOT_RANDOM: u32 = 0x10;
+const SYS_INFO_NONCE: u32 = 0x20;
+const SYS_INFO_BOOT_INFO: u32 = 0x40;
+
+// the bootrom version byte, at the same place in both chips' ROMs
+const ROM_VERSION_ADDR: u32 = 0x13;
+// the CRIT1 bit turning secure boot on
+const CRIT1_SECURE_BOOT_ENABLE: u32 = 0x01;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChipInfo {
+    pub package_sel: u32,
+    pub device_id: u32,
+    pub wafer_id: u32,
+}
+impl ChipInfo {
+    pub fn package(&self) -> Package {
+        if self.package_sel & 1 != 0 {
+            Package::Qfn60
+        } else {
+            Package::Qfn80
+        }
+    }
+}
+
+// RP2350 packages, told apart by the PACKAGE_SEL register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Package {
+    // RP2350A, with 30 GPIOs
+    Qfn60,
+    // RP2350B, with 48 GPIOs
+    Qfn80,
+}
+
+// What a connected chip is, beyond what its USB PID says, see `PicobootConnection::identify`.
+// Each part is None if the chip couldn't tell
+#[derive(Debug, Clone)]
+pub struct ChipIdentity {
+    pub target: TargetID,
+    // the bootrom version, which tells silicon revisions apart
+    pub rom_version: Option<u8>,
+    // RP2350 only, the rest comes from GET_INFO
+    pub chip: Option<ChipInfo>,
+    pub secure_boot: Option<bool>,
+    pub cpu_riscv: Option<bool>,
+}
+impl ChipIdentity {
+    // The part name, e.g. RP2040 or RP2350A
+    pub fn model(&self) -> String {
+        match (self.target, self.chip.map(|c| c.package())) {
+            (TargetID::Rp2040, _) => "RP2040".into(),
+            (TargetID::Rp2350, Some(Package::Qfn60)) => "RP2350A".into(),
+            (TargetID::Rp2350, Some(Package::Qfn80)) => "RP2350B".into(),
+            (TargetID::Rp2350, None) => "RP2350".into(),
+        }
+    }
+
+    // The silicon revision as printed on the chip, e.g. B2 or A2, from the bootrom version
+    pub fn revision(&self) -> Option<String> {
+        let version = self.rom_version?;
+        match self.target {
+            TargetID::Rp2040 => Some(format!("B{}", version.checked_sub(1)?)),
+            TargetID::Rp2350 => Some(format!("A{}", version)),
+        }
+    }
+}
+
+// System information from an RP2350's bootrom. Each part is None if the bootrom left it out
+#[derive(Debug, Clone, Default)]
+pub struct SysInfo {
+    pub chip: Option<ChipInfo>,
+    // the CRIT1 OTP row, see `otp::SecureBootState` for what the bits mean
+    pub critical: Option<u32>,
+    pub cpu_riscv: Option<bool>,
+    // the FLASH_DEVINFO OTP row (or its default), use `flash_size` to get the sizes out
+    pub flash_dev_info: Option<u32>,
+    pub boot_random: Option<[u32; 4]>,
+    // the raw boot info words: boot type, diagnostics and the last reboot parameters
+    pub boot_info: Option<[u32; 4]>,
+}
+impl SysInfo {
+    fn parse(words: &[u32]) -> Result<Self> {
+        // first the number of words returned, then the flags actually included. The rest of the
+        // buffer is padding, so the flags are enough to know where each part is
+        let (&included, mut rest) = words
+            .get(1..)
+            .and_then(|w| w.split_first())
+            .ok_or(PicobootError::BadResponse)?;
+        let mut take = |n: usize| -> Result<&[u32]> {
+            if rest.len() < n {
+                return Err(PicobootError::BadResponse);
+            }
+            let (taken, left) = rest.split_at(n);
+            rest = left;
+            Ok(taken)
         };
-        bincode::serialize(&c)
-            .unwrap()
-            .try_into()
-            .unwrap_or_else(|v: Vec<u8>| {
-                panic!("Expected a Vec of length {} but it was {}", 16, v.len())
-            })
-    }
-}
-
-#[derive(Serialize)]
-#[repr(C, packed)]
-struct PicobootRebootCmd {
-    pc: u32,
-    sp: u32,
-    delay: u32,
-    _unused: u32,
-}
-impl PicobootRebootCmd {
-    pub fn ser(pc: u32, sp: u32, delay: u32) -> [u8; 16] {
-        let c = PicobootRebootCmd {
-            pc: pc,
-            sp: sp,
-            delay: delay,
-            _unused: 0,
+
+        let mut info = SysInfo::default();
+        if included & SYS_INFO_CHIP_INFO != 0 {
+            let w = take(3)?;
+            info.chip = Some(ChipInfo {
+                package_sel: w[0],
+                device_id: w[1],
+                wafer_id: w[2],
+            });
+        }
+        if included & SYS_INFO_CRITICAL != 0 {
+            info.critical = Some(take(1)?[0]);
+        }
+        if included & SYS_INFO_CPU_INFO != 0 {
+            info.cpu_riscv = Some(take(1)?[0] == 1);
+        }
+        if included & SYS_INFO_FLASH_DEV_INFO != 0 {
+            info.flash_dev_info = Some(take(1)?[0]);
+        }
+        if included & SYS_INFO_BOOT_RANDOM != 0 {
+            info.boot_random = Some(take(4)?.try_into().unwrap());
+        }
+        if included & SYS_INFO_NONCE != 0 {
+            take(2)?;
+        }
+        if included & SYS_INFO_BOOT_INFO != 0 {
+            info.boot_info = Some(take(4)?.try_into().unwrap());
+        }
+        Ok(info)
+    }
+
+    // Size in bytes of the flash on chip select `cs` (0 or 1), from the flash device info.
+    // None if there's no device info, Some(0) if nothing is connected
+    pub fn flash_size(&self, cs: u8) -> Option<u32> {
+        let field = (self.flash_dev_info? >> (8 + 4 * cs as u32)) & 0xF;
+        Some(if field == 0 { 0 } else { 4096 << field })
+    }
+}
+
+// The whole sectors covering `size` bytes at `addr`, as (start, size), i.e. what has to be
+// erased to erase that range
+pub fn sector_span(addr: u32, size: u32) -> (u32, u32) {
+    let start = addr - addr % PICO_SECTOR_SIZE;
+    let end = (addr as u64 + size as u64).next_multiple_of(PICO_SECTOR_SIZE as u64);
+    (start, (end - start as u64) as u32)
+}
+
+// A byte of flash holding something other than the image, see
+// `PicobootConnection::find_mismatches`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    pub addr: u32,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+// A PICOBOOT device found by `list_devices`, which isn't opened or claimed
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub bus: u8,
+    pub address: u8,
+    // ports from the root hub down to the device, empty if the OS doesn't say
+    pub ports: Vec<u8>,
+    // None if the serial number string couldn't be read, e.g. for lack of permissions
+    pub serial: Option<String>,
+    // likewise, "Raspberry Pi" and "RP2 Boot" unless white-labelled
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub target: TargetID,
+    // whether another program has the PICOBOOT interface claimed, e.g. picotool or another
+    // flasher part way through. None if it couldn't be told
+    pub in_use: Option<bool>,
+}
+
+// The string descriptors of a device, each None if it has none or it couldn't be read. The
+// bootrom gives the chip's unique board ID as the serial number, which is what `--serial` and
+// `PicobootConnectionBuilder::serial` match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsbStrings {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+}
+impl UsbStrings {
+    pub fn read<C: UsbContext>(
+        handle: &rusb::DeviceHandle<C>,
+        desc: &rusb::DeviceDescriptor,
+    ) -> Self {
+        UsbStrings {
+            manufacturer: handle.read_manufacturer_string_ascii(desc).ok(),
+            product: handle.read_product_string_ascii(desc).ok(),
+            serial: handle.read_serial_number_string_ascii(desc).ok(),
+        }
+    }
+}
+
+// Whether the PICOBOOT interface of an opened device is claimed elsewhere, found by claiming it
+// and letting go straight away
+fn interface_in_use<C: UsbContext>(handle: &rusb::DeviceHandle<C>) -> Option<bool> {
+    let config = handle.device().active_config_descriptor().ok()?;
+    let iface = config.interfaces().find(|i| {
+        i.descriptors()
+            .any(|d| (d.class_code(), d.sub_class_code(), d.protocol_code()) == (0xFF, 0, 0))
+    })?;
+    match handle.claim_interface(iface.number()) {
+        Ok(()) => {
+            let _ = handle.release_interface(iface.number());
+            Some(false)
+        }
+        Err(rusb::Error::Busy) => Some(true),
+        Err(_) => None,
+    }
+}
+
+// How much a bulk transfer moves per chunk on an endpoint with the given wMaxPacketSize. Sizes a
+// broken descriptor could give, which aren't a power of two from 8 to 1024, fall back to 64
+fn chunk_size(max_packet: u16) -> usize {
+    let packet = match max_packet {
+        8..=1024 if max_packet.is_power_of_two() => max_packet,
+        _ => 64,
+    };
+    packet as usize * BULK_CHUNK_PACKETS
+}
+
+pub(crate) fn target_for_pid(pid: u16) -> Option<TargetID> {
+    match pid {
+        PICOBOOT_PID_RP2040 => Some(TargetID::Rp2040),
+        PICOBOOT_PID_RP2350 => Some(TargetID::Rp2350),
+        _ => None,
+    }
+}
+
+// Lists every connected RP2040 and RP2350 in BOOTSEL mode. Devices are only opened briefly to
+// read their serial number and see whether they're in use, so this is safe to call while another
+// tool is using one
+pub fn list_devices<T: UsbContext>(ctx: &T) -> Result<Vec<DeviceInfo>> {
+    PicobootConnectionBuilder::new().list_devices(ctx)
+}
+
+fn list_matching<T: UsbContext>(ctx: &T, ids: &[(u16, u16, TargetID)]) -> Result<Vec<DeviceInfo>> {
+    let mut found = vec![];
+    for device in ctx.devices()?.iter() {
+        let Ok(desc) = device.device_descriptor() else {
+            continue;
         };
-        bincode::serialize(&c)
-            .unwrap()
-            .try_into()
-            .unwrap_or_else(|v: Vec<u8>| {
-                panic!("Expected a Vec of length {} but it was {}", 16, v.len())
-            })
-    }
-}
-
-#[derive(Serialize)]
-#[repr(C, packed)]
-struct PicobootReboot2Cmd {
-    flags: u32,
-    delay: u32,
-    p0: u32,
-    p1: u32,
-}
-impl PicobootReboot2Cmd {
-    pub fn ser(flags: u32, delay: u32, p0: u32, p1: u32) -> [u8; 16] {
-        let c = PicobootReboot2Cmd {
-            flags,
-            delay,
-            p0,
-            p1,
+        let id = (desc.vendor_id(), desc.product_id());
+        let Some(&(_, _, target)) = ids.iter().find(|&&(vid, pid, _)| (vid, pid) == id) else {
+            continue;
         };
-        bincode::serialize(&c)
-            .unwrap()
-            .try_into()
-            .unwrap_or_else(|v: Vec<u8>| {
-                panic!("Expected a Vec of length {} but it was {}", 16, v.len())
-            })
-    }
-}
-
-#[derive(Deserialize)]
-#[repr(C, packed)]
-struct PicobootStatusCmd {
-    token: u32,
-    status_code: u32,
-    cmd_id: u8,
-    in_progress: u8,
-    _unused: [u8; 6],
-}
-
-#[derive(Serialize)]
-#[repr(C, packed)]
-struct PicobootCmd {
-    magic: u32,
-    token: u32,
-    cmd_id: u8,
-    cmd_size: u8,
-    _unused: u16,
-    transfer_len: u32,
-    args: [u8; 16],
-}
-impl PicobootCmd {
-    pub fn new(cmd_id: PicobootCmdId, cmd_size: u8, transfer_len: u32, args: [u8; 16]) -> Self {
-        PicobootCmd {
-            magic: PICOBOOT_MAGIC,
-            token: 0,
-            cmd_id: cmd_id as u8,
-            cmd_size: cmd_size,
-            _unused: 0,
-            transfer_len: transfer_len,
-            args: args,
-        }
-    }
-}
-
-// #[derive(Debug)]
-#[allow(dead_code)]
-pub struct PicobootConnection<T: UsbContext> {
-    context: T,
-    device: Device<T>,
-    desc: DeviceDescriptor,
-    handle: DeviceHandle<T>,
-
-    cfg: u8,
-    iface: u8,
-    setting: u8,
-    in_addr: u8,
-    out_addr: u8,
+        let handle = device.open().ok();
+        let strings = handle
+            .as_ref()
+            .map(|h| UsbStrings::read(h, &desc))
+            .unwrap_or_default();
+        let in_use = handle.as_ref().and_then(interface_in_use);
+        found.push(DeviceInfo {
+            bus: device.bus_number(),
+            address: device.address(),
+            ports: device.port_numbers().unwrap_or_default(),
+            serial: strings.serial,
+            manufacturer: strings.manufacturer,
+            product: strings.product,
+            target,
+            in_use,
+        });
+    }
+    Ok(found)
+}
+
+// Where a device is plugged in. `bus:address` changes every time the device is reconnected
+// (including when it reboots), while a port path like `1-4.2` (bus 1, port 4 of the root hub,
+// then port 2 of the hub on it) stays the same as long as the cabling does, which makes it the
+// way to tell apart boards with the same or no serial number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceLocation {
+    BusAddress { bus: u8, address: u8 },
+    PortPath { bus: u8, ports: Vec<u8> },
+}
+impl DeviceLocation {
+    pub(crate) fn matches<C: UsbContext>(&self, device: &rusb::Device<C>) -> bool {
+        match self {
+            DeviceLocation::BusAddress { bus, address } => {
+                device.bus_number() == *bus && device.address() == *address
+            }
+            DeviceLocation::PortPath { bus, ports } => {
+                device.bus_number() == *bus && device.port_numbers().is_ok_and(|p| p == *ports)
+            }
+        }
+    }
+}
+impl std::str::FromStr for DeviceLocation {
+    type Err = PicobootError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bad =
+            || PicobootError::InvalidArgument("expected BUS:ADDRESS or a port path like 1-4.2");
+        if let Some((bus, address)) = s.split_once(':') {
+            return Ok(DeviceLocation::BusAddress {
+                bus: bus.parse().map_err(|_| bad())?,
+                address: address.parse().map_err(|_| bad())?,
+            });
+        }
+        let (bus, ports) = s.split_once('-').ok_or_else(bad)?;
+        Ok(DeviceLocation::PortPath {
+            bus: bus.parse().map_err(|_| bad())?,
+            ports: ports
+                .split('.')
+                .map(|p| p.parse().map_err(|_| bad()))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+impl std::fmt::Display for DeviceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeviceLocation::BusAddress { bus, address } => write!(f, "{}:{}", bus, address),
+            DeviceLocation::PortPath { bus, ports } => {
+                let ports: Vec<String> = ports.iter().map(u8::to_string).collect();
+                write!(f, "{}-{}", bus, ports.join("."))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressOp {
+    Erase,
+    Write,
+    Verify,
+    Read,
+}
+
+// Reported to the progress handler as long operations go, `done` and `total` are in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressEvent {
+    pub op: ProgressOp,
+    pub done: u64,
+    pub total: u64,
+}
 
+pub type ProgressHandler = Box<dyn FnMut(ProgressEvent) + Send>;
+
+// How `PicobootConnection::flash_program` checks what it wrote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyMode {
+    None,
+    // read each page back and compare it. A mismatching page is re-read up to `retries` times,
+    // so a transfer corrupted on the way back isn't mistaken for bad flash
+    ReadBack { retries: u32 },
+    // have the device checksum flash once everything is written and compare it with the CRC of
+    // the data, much faster than reading it back. The RP2350 reads it back, see `verify_crc32`
+    DeviceCrc,
+}
+
+pub struct PicobootConnection<T: Transport> {
+    transport: T,
     cmd_token: u32,
-    has_kernel_driver: bool,
     target_id: Option<TargetID>,
+    sequencing: CommandSequencing,
+    skip_unchanged: bool,
+    reboot_arch: Option<RebootArch>,
+    diagnostics: Diagnostics,
+    trace: Option<Trace>,
+    cancel: Option<Arc<AtomicBool>>,
+    retry: RetryPolicy,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    control_timeout: Duration,
+    progress: Option<ProgressHandler>,
 }
 
-impl<T: UsbContext> Drop for PicobootConnection<T> {
-    fn drop(&mut self) {
-        self.handle
-            .release_interface(self.iface)
-            .expect("could not release interface");
+// Connection to a device opened through rusb, which is what `new` and the builder produce
+pub type UsbConnection = PicobootConnection<RusbTransport<rusb::Context>>;
 
-        if self.has_kernel_driver {
-            self.handle
-                .attach_kernel_driver(self.iface)
-                .expect("could not retach kernel driver")
-        }
+// Connections are handed between threads, see `shared::SharedPicoboot`, so this fails to build
+// if a field ever stops them being Send
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<UsbConnection>();
+    assert_send::<PicobootConnection<crate::mock::MockPicoboot>>();
+};
+
+// Options for opening a connection, for when the defaults used by `PicobootConnection::new`
+// don't fit, e.g. slow hubs needing longer timeouts or boards with a custom VID/PID
+#[derive(Debug, Clone)]
+pub struct PicobootConnectionBuilder {
+    pub(crate) ids: Vec<(u16, u16, TargetID)>,
+    pub(crate) serial: Option<String>,
+    pub(crate) location: Option<DeviceLocation>,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    control_timeout: Duration,
+    pub(crate) detach_kernel_driver: bool,
+    wait: Duration,
+    force: bool,
+    cancel: Option<Arc<AtomicBool>>,
+    retry: RetryPolicy,
+}
+impl Default for PicobootConnectionBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
-impl<T: UsbContext> PicobootConnection<T> {
-    pub fn new(mut ctx: T) -> Self {
-        let mut d = open_device(&mut ctx, PICOBOOT_VID, PICOBOOT_PID_RP2040);
-        let target_id = if d.is_some() {
-            println!("found rp2040");
-            Some(TargetID::Rp2040)
-        } else {
-            d = open_device(&mut ctx, PICOBOOT_VID, PICOBOOT_PID_RP2350);
-            if d.is_some() {
-                println!("found rp2350");
-                Some(TargetID::Rp2350)
-            } else {
-                None
-            }
-        };
-        match d {
-            Some((device, desc, handle)) => {
-                let (_cfg, _iface, _setting, in_addr) =
-                    Self::get_endpoint(&device, 0xFF, 0, 0, Direction::In, TransferType::Bulk)
-                        .unwrap();
-                let (cfg, iface, setting, out_addr) =
-                    Self::get_endpoint(&device, 0xFF, 0, 0, Direction::Out, TransferType::Bulk)
-                        .unwrap();
-
-                if _cfg != cfg || _iface != iface || _setting != setting {
-                    panic!("something doesnt match with the endpoints! {} != {} || {} != {} || {} != {}", _cfg, cfg, _iface, iface, _setting, setting)
-                }
+impl PicobootConnectionBuilder {
+    pub fn new() -> Self {
+        PicobootConnectionBuilder {
+            ids: vec![
+                (PICOBOOT_VID, PICOBOOT_PID_RP2040, TargetID::Rp2040),
+                (PICOBOOT_VID, PICOBOOT_PID_RP2350, TargetID::Rp2350),
+            ],
+            serial: None,
+            location: None,
+            read_timeout: Duration::from_secs(3),
+            write_timeout: Duration::from_secs(5),
+            control_timeout: Duration::from_secs(1),
+            detach_kernel_driver: true,
+            wait: Duration::ZERO,
+            force: false,
+            cancel: None,
+            retry: RetryPolicy::default(),
+        }
+    }
 
-                let has_kernel_driver = match handle.kernel_driver_active(iface) {
-                    Ok(true) => {
-                        handle
-                            .detach_kernel_driver(iface)
-                            .expect("could not detach kernel driver");
-                        true
-                    }
-                    _ => false,
-                };
+    // The VID/PIDs to look for, in order, along with the chip each one is
+    pub fn ids(mut self, ids: Vec<(u16, u16, TargetID)>) -> Self {
+        self.ids = ids;
+        self
+    }
 
-                if !handle.set_active_configuration(cfg).is_ok() {
-                    println!("Warning: could not set USB active configuration");
-                }
-                handle
-                    .claim_interface(iface)
-                    .expect("could not claim interface");
-                handle
-                    .set_alternate_setting(iface, setting)
-                    .expect("could not set alt setting");
-
-                return PicobootConnection {
-                    context: ctx,
-                    device: device,
-                    desc: desc,
-                    handle: handle,
-
-                    cfg: cfg,
-                    iface: iface,
-                    setting: setting,
-                    in_addr: in_addr,
-                    out_addr: out_addr,
-
-                    cmd_token: 1,
-                    has_kernel_driver: has_kernel_driver,
-                    target_id: target_id,
-                };
-            }
-            None => panic!("Could not find picoboot device."),
+    // Also looks for `vid`:`pid` as the chip `target`, after the VID/PIDs already set. An RP2350
+    // white-labelled in OTP (see `white_label`) shows up in BOOTSEL with whatever it was given
+    pub fn extra_id(mut self, vid: u16, pid: u16, target: TargetID) -> Self {
+        if !self.ids.iter().any(|&(v, p, _)| (v, p) == (vid, pid)) {
+            self.ids.push((vid, pid, target));
         }
+        self
     }
 
-    fn get_endpoint(
-        device: &Device<T>,
-        class: u8,
-        subclass: u8,
-        protocol: u8,
-        direction: Direction,
-        transfer_type: TransferType,
-    ) -> Option<(u8, u8, u8, u8)> {
-        let desc = device.device_descriptor().unwrap();
-        for n in 0..desc.num_configurations() {
-            let config_desc = match device.config_descriptor(n) {
-                Ok(c) => c,
-                Err(_) => continue,
-            };
+    // Lists the connected devices with any of the VID/PIDs to look for, as `list_devices` does
+    // for the standard ones
+    pub fn list_devices<T: UsbContext>(&self, ctx: &T) -> Result<Vec<DeviceInfo>> {
+        list_matching(ctx, &self.ids)
+    }
 
-            for iface in config_desc.interfaces() {
-                for iface_desc in iface.descriptors() {
-                    let iface_class = iface_desc.class_code();
-                    let iface_subclass = iface_desc.sub_class_code();
-                    let iface_protocol = iface_desc.protocol_code();
-                    if !(iface_class == class
-                        && iface_subclass == subclass
-                        && iface_protocol == protocol)
-                    {
-                        continue;
-                    }
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.serial = Some(serial.to_string());
+        self
+    }
+
+    // Only open the device plugged in at `location`
+    pub fn location(mut self, location: DeviceLocation) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    // Timeout for each chunk of a bulk read, 3 seconds by default. Waiting for the ack of a flash
+    // erase gets extra time on top for every sector erased
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    // Timeout for each chunk of a bulk write, 5 seconds by default
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    // Timeout for command status and interface reset requests, 1 second by default
+    pub fn control_timeout(mut self, timeout: Duration) -> Self {
+        self.control_timeout = timeout;
+        self
+    }
+
+    // Whether to detach a kernel driver bound to the interface (and reattach it when the
+    // connection is dropped). On by default, without it claiming the interface fails
+    pub fn detach_kernel_driver(mut self, detach: bool) -> Self {
+        self.detach_kernel_driver = detach;
+        self
+    }
 
-                    for endpoint_desc in iface_desc.endpoint_descriptors() {
-                        if endpoint_desc.direction() == direction
-                            && endpoint_desc.transfer_type() == transfer_type
-                        {
-                            return Some((
-                                config_desc.number(),
-                                iface_desc.interface_number(),
-                                iface_desc.setting_number(),
-                                endpoint_desc.address(),
-                            ));
-                        }
+    // How long to keep looking for a device that isn't connected yet, e.g. while someone plugs
+    // a board in with BOOTSEL held. `Duration::MAX` waits until one turns up or the cancel
+    // token is set. Not at all by default
+    pub fn wait(mut self, timeout: Duration) -> Self {
+        self.wait = timeout;
+        self
+    }
+
+    // If no device is in BOOTSEL, reboot one running application firmware into it and wait for
+    // it to come back (see `bootsel`). Off by default
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    // Gives up waiting for a device once `token` is set, and hands it on to the connection, see
+    // `PicobootConnection::set_cancel_token`
+    pub fn cancel_token(mut self, token: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    // How commands failing with a transient USB error are retried, 3 attempts 100ms apart (then
+    // 200ms) by default
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    pub(crate) fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::SeqCst))
+    }
+
+    pub fn build<C: UsbContext>(&self, ctx: C) -> Result<PicobootConnection<RusbTransport<C>>> {
+        let start = std::time::Instant::now();
+        let mut wait = self.wait;
+        let mut forced = false;
+        let (transport, target) = loop {
+            match RusbTransport::open(ctx.clone(), self) {
+                Err(PicobootError::DeviceNotFound) if self.force && !forced => {
+                    forced = true;
+                    if crate::bootsel::reboot_to_bootsel(&ctx, self)? {
+                        wait = wait.max(start.elapsed() + FORCE_REBOOT_WAIT);
                     }
                 }
+                Err(PicobootError::DeviceNotFound) if start.elapsed() < wait => {
+                    if self.cancelled() {
+                        return Err(PicobootError::Interrupted);
+                    }
+                    std::thread::sleep(WAIT_POLL_INTERVAL);
+                }
+                res => break res?,
+            }
+        };
+        let mut conn = PicobootConnection::with_transport(transport, Some(target));
+        conn.read_timeout = self.read_timeout;
+        conn.write_timeout = self.write_timeout;
+        conn.control_timeout = self.control_timeout;
+        conn.cancel = self.cancel.clone();
+        conn.retry = self.retry;
+        Ok(conn)
+    }
+}
+
+// Counts of what happened on a connection, to help tell a bad cable or hub (stalls, timeouts,
+// short transfers) apart from a bad firmware image (command status errors)
+#[derive(Debug, Default, Clone)]
+pub struct Diagnostics {
+    pub commands: u32,
+    pub bulk_reads: u32,
+    pub bulk_writes: u32,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub short_transfers: u32,
+    pub stalls: u32,
+    pub timeouts: u32,
+    pub other_usb_errors: u32,
+    pub clear_halts: u32,
+    pub interface_resets: u32,
+    pub retries: u32,
+    // commands sent again after a transient USB error, see `RetryPolicy`
+    pub usb_retries: u32,
+    pub status_errors: u32,
+    // sectors `flash_program` left alone as they already held the data
+    pub sectors_skipped: u32,
+}
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "commands:         {}", self.commands)?;
+        writeln!(
+            f,
+            "bulk reads:       {} ({} bytes)",
+            self.bulk_reads, self.bytes_read
+        )?;
+        writeln!(
+            f,
+            "bulk writes:      {} ({} bytes)",
+            self.bulk_writes, self.bytes_written
+        )?;
+        writeln!(f, "short transfers:  {}", self.short_transfers)?;
+        writeln!(f, "stalls:           {}", self.stalls)?;
+        writeln!(f, "timeouts:         {}", self.timeouts)?;
+        writeln!(f, "other USB errors: {}", self.other_usb_errors)?;
+        writeln!(f, "clear halts:      {}", self.clear_halts)?;
+        writeln!(f, "interface resets: {}", self.interface_resets)?;
+        writeln!(f, "retries:          {}", self.retries)?;
+        writeln!(f, "USB retries:      {}", self.usb_retries)?;
+        writeln!(f, "status errors:    {}", self.status_errors)?;
+        write!(f, "sectors skipped:  {}", self.sectors_skipped)
+    }
+}
+impl Diagnostics {
+    fn note_usb_error(&mut self, e: &rusb::Error) {
+        match e {
+            rusb::Error::Pipe => self.stalls += 1,
+            rusb::Error::Timeout => self.timeouts += 1,
+            _ => self.other_usb_errors += 1,
+        }
+    }
+}
+
+// Holds exclusive access to the device for as long as it lives, and gives it back when dropped,
+// so an aborted operation doesn't leave the bootrom claimed until the device is replugged.
+// Cleanup is skipped when dropped while unwinding from a panic, as the connection may be unusable
+pub struct ExclusiveAccessGuard<'a, T: Transport> {
+    conn: &'a mut PicobootConnection<T>,
+    reset_on_drop: bool,
+    armed: bool,
+}
+impl<'a, T: Transport> ExclusiveAccessGuard<'a, T> {
+    // Also reset the interface when dropped, clearing any half-finished command
+    pub fn reset_on_drop(mut self, reset: bool) -> Self {
+        self.reset_on_drop = reset;
+        self
+    }
+
+    // Drops the guard without giving back access, e.g. when the device is about to reboot
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+impl<T: Transport> std::ops::Deref for ExclusiveAccessGuard<'_, T> {
+    type Target = PicobootConnection<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn
+    }
+}
+impl<T: Transport> std::ops::DerefMut for ExclusiveAccessGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn
+    }
+}
+impl<T: Transport> Drop for ExclusiveAccessGuard<'_, T> {
+    fn drop(&mut self) {
+        if !self.armed || std::thread::panicking() {
+            return;
+        }
+
+        if self.reset_on_drop {
+            if let Err(e) = self.conn.reset_interface() {
+                log::warn!("could not reset interface: {}", e);
             }
         }
+        if let Err(e) = self.conn.access_not_exclusive() {
+            log::warn!("could not give back exclusive access: {}", e);
+        }
+    }
+}
+impl<C: UsbContext> PicobootConnection<RusbTransport<C>> {
+    // Opens and claims the first PICOBOOT device found
+    pub fn new(ctx: C) -> Result<Self> {
+        PicobootConnectionBuilder::new().build(ctx)
+    }
+
+    // Opens and claims the PICOBOOT device with the given serial number, for when several are
+    // connected at once. See `list_devices` for the serial numbers of connected devices
+    pub fn open_serial(ctx: C, serial: &str) -> Result<Self> {
+        PicobootConnectionBuilder::new().serial(serial).build(ctx)
+    }
+
+    // Like `new`, but waits up to `timeout` for a device to be connected, see
+    // `PicobootConnectionBuilder::wait`
+    pub fn wait_for_device(ctx: C, timeout: Duration) -> Result<Self> {
+        PicobootConnectionBuilder::new().wait(timeout).build(ctx)
+    }
+}
+impl<T: Transport> PicobootConnection<T> {
+    // Wraps an already opened transport, e.g. another USB backend or a test double. `target`
+    // is the chip on the other end, if known
+    pub fn with_transport(transport: T, target: Option<TargetID>) -> Self {
+        PicobootConnection {
+            transport,
+            cmd_token: 1,
+            target_id: target,
+            sequencing: CommandSequencing::Default,
+            skip_unchanged: false,
+            reboot_arch: None,
+            diagnostics: Diagnostics::default(),
+            trace: None,
+            cancel: None,
+            retry: RetryPolicy::default(),
+            read_timeout: Duration::from_secs(3),
+            write_timeout: Duration::from_secs(5),
+            control_timeout: Duration::from_secs(1),
+            progress: None,
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
 
-        return None;
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
     }
 
-    fn bulk_read(&mut self, buf_size: usize, check: bool) -> rusb::Result<Vec<u8>> {
-        let mut buf: Vec<u8> = vec![0; buf_size]; // [0; SECTOR_SIZE];
-        let timeout = std::time::Duration::from_secs(3);
-        let len = self
-            .handle
-            .read_bulk(self.in_addr, &mut buf, timeout)
-            .expect("read_bulk failed");
+    // Reads up to `buf_size` bytes a chunk at a time. A chunk coming back short means the device
+    // ended the transfer there, so it stops
+    fn bulk_read(&mut self, buf_size: usize, check: bool, timeout: Duration) -> Result<Vec<u8>> {
+        let mut buf: Vec<u8> = vec![0; buf_size];
+        let mut len = 0;
+        let chunk = chunk_size(self.transport.max_packet_sizes().0);
+        loop {
+            let end = std::cmp::min(len + chunk, buf_size);
+            let started = Instant::now();
+            let res = self.transport.bulk_read(&mut buf[len..end], timeout);
+            if let Some(trace) = &self.trace {
+                let read = &buf[len..len + *res.as_ref().unwrap_or(&0)];
+                let ep = self.transport.endpoints().0;
+                trace.record(TraceOp::BulkIn, ep, end - len, read, started, res.err());
+            }
+            self.diagnostics.bulk_reads += 1;
+            if let Err(e) = &res {
+                self.diagnostics.note_usb_error(e);
+            }
+            let n = res?;
+            self.diagnostics.bytes_read += n as u64;
+            len += n;
+            if len == buf_size || len < end {
+                break;
+            }
+        }
 
+        if len != buf_size {
+            self.diagnostics.short_transfers += 1;
+        }
         if check && len != buf_size {
-            panic!("read mismatch {} != {}", len, buf_size)
+            return Err(PicobootError::ShortTransfer {
+                expected: buf_size,
+                actual: len,
+            });
         }
 
         buf.resize(len, 0);
         Ok(buf)
     }
 
-    fn bulk_write(&mut self, mut buf: Vec<u8>, check: bool) -> rusb::Result<()> {
-        let timeout = std::time::Duration::from_secs(5);
-        let len = self
-            .handle
-            .write_bulk(self.out_addr, &mut buf, timeout)
-            .expect("write_bulk failed");
+    // Writes `buf` a chunk at a time, carrying on from wherever a partly completed write left
+    // off. Stops if a write makes no progress at all
+    fn bulk_write(&mut self, buf: Vec<u8>, check: bool) -> Result<()> {
+        let mut len = 0;
+        let chunk = chunk_size(self.transport.max_packet_sizes().1);
+        loop {
+            let end = std::cmp::min(len + chunk, buf.len());
+            let started = Instant::now();
+            let res = self
+                .transport
+                .bulk_write(&buf[len..end], self.write_timeout);
+            if let Some(trace) = &self.trace {
+                let written = &buf[len..len + *res.as_ref().unwrap_or(&0)];
+                let ep = self.transport.endpoints().1;
+                trace.record(TraceOp::BulkOut, ep, end - len, written, started, res.err());
+            }
+            self.diagnostics.bulk_writes += 1;
+            if let Err(e) = &res {
+                self.diagnostics.note_usb_error(e);
+            }
+            let n = res?;
+            self.diagnostics.bytes_written += n as u64;
+            len += n;
+            if len == buf.len() || n == 0 {
+                break;
+            }
+        }
 
+        if len != buf.len() {
+            self.diagnostics.short_transfers += 1;
+        }
         if check && len != buf.len() {
-            panic!("write mismatch {} != {}", len, buf.len())
+            return Err(PicobootError::ShortTransfer {
+                expected: buf.len(),
+                actual: len,
+            });
         }
 
         Ok(())
     }
 
-    fn cmd(&mut self, mut cmd: PicobootCmd, buf: Vec<u8>) -> rusb::Result<Vec<u8>> {
+    // Sends `cmd` with `buf` as its data, trying again as the retry policy allows
+    fn cmd(&mut self, cmd: PicobootCmd, buf: Vec<u8>) -> Result<Vec<u8>> {
+        let once = [
+            PicobootCmdId::Reboot,
+            PicobootCmdId::Reboot2,
+            PicobootCmdId::Exec,
+        ]
+        .iter()
+        .any(|&id| id as u8 == cmd.cmd_id);
+        let mut attempt = 1;
+        loop {
+            match self.cmd_once(cmd, buf.clone()) {
+                Err(PicobootError::Usb(e))
+                    if RetryPolicy::is_transient(&e)
+                        && !once
+                        && attempt < self.retry.max_attempts
+                        && !self.cancelled() =>
+                {
+                    log::warn!("command {:#X} failed ({}), retrying", cmd.cmd_id, e);
+                    self.diagnostics.usb_retries += 1;
+                    std::thread::sleep(self.retry.backoff(attempt));
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn cmd_once(&mut self, mut cmd: PicobootCmd, buf: Vec<u8>) -> Result<Vec<u8>> {
         cmd.token = self.cmd_token;
-        self.cmd_token = self.cmd_token + 1;
+        self.cmd_token += 1;
+        self.diagnostics.commands += 1;
         let cmd = cmd;
+        let id = PicobootCmdId::try_from(cmd.cmd_id).unwrap_or(PicobootCmdId::Unknown);
+        log::debug!(
+            "command {:?} (token {}, args {:02x?}, {} bytes)",
+            id,
+            cmd.token,
+            cmd.args,
+            cmd.transfer_len
+        );
+
+        let res = match self.cmd_transfers(&cmd, buf) {
+            // the bootrom stalls the endpoints when it rejects a command, so a failed transfer
+            // is reported as the command's status if there's one to give
+            Err(PicobootError::Usb(e)) => match self.check_command_status(&cmd) {
+                Err(status @ PicobootError::Command { .. }) => Err(status),
+                _ => Err(PicobootError::Usb(e)),
+            },
+            res => res,
+        };
+
+        // after a failure the endpoints may be stalled and the bootrom part way through the
+        // command, so clear the halts and reset the interface as the datasheet asks, leaving the
+        // connection ready for the next command. If that fails too the device is likely gone,
+        // and the original error says more
+        if let Err(
+            PicobootError::Usb(_)
+            | PicobootError::Command { .. }
+            | PicobootError::ShortTransfer { .. },
+        ) = res
+        {
+            let _ = self.reset_interface();
+        }
+        res
+    }
 
+    // How long to wait for a command's ack, which for erases scales with the size erased
+    fn ack_timeout(&self, cmd: &PicobootCmd) -> Duration {
+        if cmd.cmd_id != PicobootCmdId::FlashErase as u8 {
+            return self.read_timeout;
+        }
+        let size = PicobootRangeCmd::parse(&cmd.args).size;
+        self.read_timeout + ERASE_TIMEOUT_PER_SECTOR * size.div_ceil(PICO_SECTOR_SIZE)
+    }
+
+    fn cmd_transfers(&mut self, cmd: &PicobootCmd, buf: Vec<u8>) -> Result<Vec<u8>> {
         // write command
-        let cmdu8 = bincode::serialize(&cmd).expect("failed to serialize cmd");
-        self.bulk_write(cmdu8, true).expect("failed to write cmd");
-        let _stat = self.get_command_status();
+        let cmdu8 = cmd.pack().to_vec();
+        self.bulk_write(cmdu8, true)?;
+        if self.sequencing == CommandSequencing::Default {
+            self.check_command_status(cmd)?;
+        }
 
         // if we're reading or writing a buffer
-        let l = cmd.transfer_len.try_into().unwrap();
-        let mut res: Option<Vec<_>> = Some(vec![]);
+        let l = cmd.transfer_len as usize;
+        let mut res = vec![];
         if l != 0 {
-            if (cmd.cmd_id & 0x80) != 0 {
-                res = Some(self.bulk_read(l, true).unwrap());
+            if cmd.is_data_in() {
+                res = self.bulk_read(l, true, self.read_timeout)?;
             } else {
-                self.bulk_write(buf, true).unwrap()
+                self.bulk_write(buf, true)?;
+            }
+            if self.sequencing == CommandSequencing::Default {
+                self.check_command_status(cmd)?;
             }
-            let _stat = self.get_command_status();
         }
 
         // do ack
-        if (cmd.cmd_id & 0x80) != 0 {
-            self.bulk_write(vec![0], false).unwrap();
+        if cmd.is_data_in() {
+            self.bulk_write(vec![0], false)?;
         } else {
-            self.bulk_read(1, false).unwrap();
+            let timeout = self.ack_timeout(cmd);
+            self.bulk_read(1, false, timeout)?;
         }
 
-        Ok(res.unwrap())
+        Ok(res)
     }
 
-    pub fn access_not_exclusive(&mut self) -> rusb::Result<()> {
+    pub fn access_not_exclusive(&mut self) -> Result<()> {
         self.set_exclusive_access(0)
     }
 
-    pub fn access_exclusive(&mut self) -> rusb::Result<()> {
+    pub fn access_exclusive(&mut self) -> Result<()> {
         self.set_exclusive_access(1)
     }
 
-    pub fn access_exclusive_eject(&mut self) -> rusb::Result<()> {
+    pub fn access_exclusive_eject(&mut self) -> Result<()> {
         self.set_exclusive_access(2)
     }
 
-    fn set_exclusive_access(&mut self, exclusive: u8) -> rusb::Result<()> {
+    // Claims exclusive access (ejecting the mass storage drive if `eject`), which is given back
+    // once the returned guard is dropped
+    pub fn exclusive_access_guard(&mut self, eject: bool) -> Result<ExclusiveAccessGuard<'_, T>> {
+        self.set_exclusive_access(if eject { 2 } else { 1 })?;
+        Ok(ExclusiveAccessGuard {
+            conn: self,
+            reset_on_drop: false,
+            armed: true,
+        })
+    }
+
+    fn set_exclusive_access(&mut self, exclusive: u8) -> Result<()> {
         let mut args = [0; 16];
         args[0] = exclusive;
         let cmd = PicobootCmd::new(PicobootCmdId::ExclusiveAccess, 1, 0, args);
-        Ok(self.cmd(cmd, vec![]).map(|_| ())?)
+        self.cmd(cmd, vec![]).map(|_| ())
     }
 
-    pub fn reboot(&mut self, pc: u32, sp: u32, delay: u32) -> rusb::Result<()> {
+    pub fn reboot(&mut self, pc: u32, sp: u32, delay: u32) -> Result<()> {
         let args = PicobootRebootCmd::ser(pc, sp, delay);
         let cmd = PicobootCmd::new(PicobootCmdId::Reboot, 12, 0, args);
-        Ok(self.cmd(cmd, vec![]).map(|_| ())?)
+        self.cmd(cmd, vec![]).map(|_| ())
     }
 
-    pub fn reboot2_normal(&mut self, delay: u32) -> rusb::Result<()> {
-        let flags: u32 = 0x0; // Normal boot
-        let args = PicobootReboot2Cmd::ser(flags, delay, 0, 0);
+    // RP2350 only. Reboots into `kind` after `delay` milliseconds, switching architecture if
+    // one was set with `set_reboot_arch`
+    pub fn reboot2(&mut self, kind: Reboot2Kind, delay: u32) -> Result<()> {
+        if !matches!(self.target_id, Some(TargetID::Rp2350)) {
+            return Err(PicobootError::NotSupported);
+        }
+        let (mut flags, p0, p1) = kind.params();
+        if let Some(arch) = self.reboot_arch {
+            flags |= arch.flag();
+        }
+        let args = PicobootReboot2Cmd::ser(flags, delay, p0, p1);
         let cmd = PicobootCmd::new(PicobootCmdId::Reboot2, 0x10, 0, args);
-        Ok(self.cmd(cmd, vec![]).map(|_| ())?)
+        self.cmd(cmd, vec![]).map(|_| ())
+    }
+
+    pub fn reboot2_normal(&mut self, delay: u32) -> Result<()> {
+        self.reboot2(Reboot2Kind::Normal, delay)
+    }
+
+    // BOOTSEL, with both the mass storage and PICOBOOT interfaces enabled
+    pub fn reboot2_bootsel(&mut self, delay: u32) -> Result<()> {
+        let kind = Reboot2Kind::Bootsel {
+            disable_msc: false,
+            disable_picoboot: false,
+            led_gpio: None,
+            led_active_low: false,
+        };
+        self.reboot2(kind, delay)
+    }
+
+    pub fn reboot2_pc_sp(&mut self, pc: u32, sp: u32, delay: u32) -> Result<()> {
+        self.reboot2(Reboot2Kind::PcSp { pc, sp }, delay)
+    }
+
+    // Reboots into `kind` on either chip. An RP2350 uses REBOOT2, while an RP2040 can only boot
+    // normally, at a PC and SP, or into BOOTSEL by running its bootrom's reset_usb_boot (see
+    // `exec::reset_usb_boot`), which can't make the LED active low and ignores the delay, so
+    // it's waited out here instead
+    pub fn reboot_as(&mut self, kind: Reboot2Kind, delay: u32) -> Result<()> {
+        match (self.target_id, kind) {
+            (Some(TargetID::Rp2350), _) => self.reboot2(kind, delay),
+            (Some(TargetID::Rp2040), Reboot2Kind::Normal) => {
+                self.reboot(0x0, PICO_STACK_POINTER, delay)
+            }
+            (Some(TargetID::Rp2040), Reboot2Kind::PcSp { pc, sp }) => self.reboot(pc, sp, delay),
+            (
+                Some(TargetID::Rp2040),
+                Reboot2Kind::Bootsel {
+                    disable_msc,
+                    disable_picoboot,
+                    led_gpio,
+                    led_active_low: false,
+                },
+            ) => {
+                std::thread::sleep(Duration::from_millis(delay.into()));
+                crate::exec::reset_usb_boot(self, led_gpio, disable_msc, disable_picoboot)
+            }
+            _ => Err(PicobootError::NotSupported),
+        }
     }
 
-    pub fn flash_erase(&mut self, addr: u32, size: u32) -> rusb::Result<()> {
+    // Writes segments straight into SRAM, leaving flash alone. Every segment must be in SRAM
+    pub fn load_ram(&mut self, segments: &[crate::image::Segment]) -> Result<()> {
+        let addrs = segments
+            .iter()
+            .map(|s| SramAddr::new(s.addr))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(PicobootError::InvalidArgument("segment is not in SRAM"))?;
+        for (seg, addr) in segments.iter().zip(addrs) {
+            self.flash_write(addr, seg.data.clone())?;
+        }
+        Ok(())
+    }
+
+    // Programs the flash segments of an image and loads its SRAM ones, as `flash_program` and
+    // `load_ram` do. Contiguous pages go in one `flash_program`, so each sector is only erased
+    // once. Returns the bytes of flash programmed. Needs exclusive access and XIP exited first
+    pub fn program_segments(
+        &mut self,
+        segments: &[crate::image::Segment],
+        verify: VerifyMode,
+    ) -> Result<usize> {
+        let (flash, ram) = crate::image::split_flash_ram(segments.to_vec());
+        let mut programmed = 0;
+        let pages = crate::image::pages(&flash).map_err(|_| {
+            PicobootError::InvalidArgument("segment runs past the end of the address space")
+        })?;
+        for (addr, data) in crate::image::page_runs(&pages) {
+            let addr = FlashAddr::new(addr)
+                .ok_or(PicobootError::InvalidArgument("segment is not in flash"))?;
+            programmed += self.flash_program(addr, &data, verify)?;
+        }
+        if !ram.is_empty() {
+            self.load_ram(&ram)?;
+        }
+        Ok(programmed)
+    }
+
+    // Starts an image loaded with `load_ram` after `delay` milliseconds. An RP2040 jumps
+    // straight to `entry`, while an RP2350 has the bootrom boot the span of the segments as a
+    // RAM image, which goes by the image's own IMAGE_DEF and so ignores `entry`
+    pub fn run_ram(
+        &mut self,
+        segments: &[crate::image::Segment],
+        entry: u32,
+        delay: u32,
+    ) -> Result<()> {
+        match self.target_id {
+            Some(TargetID::Rp2040) => self.reboot(entry, PICO_STACK_POINTER, delay),
+            Some(TargetID::Rp2350) => {
+                let start = segments.iter().map(|s| s.addr).min();
+                let end = segments.iter().map(|s| s.end()).max();
+                let (Some(start), Some(end)) = (start, end) else {
+                    return Err(PicobootError::InvalidArgument("no segments to run"));
+                };
+                let size = (end - start as u64) as u32;
+                self.reboot2(Reboot2Kind::RamImage { start, size }, delay)
+            }
+            None => Err(PicobootError::NotSupported),
+        }
+    }
+
+    // Reboots using whichever command the connected chip needs, after `delay` milliseconds
+    pub fn reboot_into(&mut self, mode: RebootMode, delay: u32) -> Result<()> {
+        match (self.target_id, mode) {
+            // sp is SRAM_END_RP2040
+            (Some(TargetID::Rp2040), RebootMode::Normal) => {
+                self.reboot(0x0, PICO_STACK_POINTER, delay)
+            }
+            (Some(TargetID::Rp2350), RebootMode::Normal) => self.reboot2_normal(delay),
+            (Some(TargetID::Rp2350), RebootMode::Bootsel) => self.reboot2_bootsel(delay),
+            (Some(TargetID::Rp2040), RebootMode::Bootsel) => self.reboot_as(
+                Reboot2Kind::Bootsel {
+                    disable_msc: false,
+                    disable_picoboot: false,
+                    led_gpio: None,
+                    led_active_low: false,
+                },
+                delay,
+            ),
+            (Some(TargetID::Rp2040), RebootMode::Run { pc, sp }) => self.reboot(pc, sp, delay),
+            (Some(TargetID::Rp2350), RebootMode::Run { pc, sp }) => {
+                self.reboot2_pc_sp(pc, sp, delay)
+            }
+            _ => Err(PicobootError::NotSupported),
+        }
+    }
+
+    pub fn flash_erase(&mut self, addr: FlashAddr, size: u32) -> Result<()> {
+        let addr = addr.get();
+        self.check_address(addr, size, Access::Erase)?;
+        if self.sequencing == CommandSequencing::Picotool {
+            self.exit_xip()?;
+        }
         let args = PicobootRangeCmd::ser(addr, size);
         let cmd = PicobootCmd::new(PicobootCmdId::FlashErase, 8, 0, args);
-        Ok(self.cmd(cmd, vec![]).map(|_| ())?)
+        self.cmd(cmd, vec![]).map(|_| ())
+    }
+
+    // Erases a range of whole sectors of any size, 64K at a time so no single erase runs into
+    // the timeouts. Reports progress, and stops between chunks if cancelled (see
+    // `set_cancel_token`)
+    pub fn flash_erase_range(&mut self, addr: FlashAddr, size: u32) -> Result<()> {
+        if !addr.is_aligned(PICO_SECTOR_SIZE) || !size.is_multiple_of(PICO_SECTOR_SIZE) {
+            return Err(PicobootError::InvalidArgument(
+                "erase range is not sector aligned",
+            ));
+        }
+        const CHUNK: u32 = 16 * PICO_SECTOR_SIZE;
+        let mut done = 0;
+        while done < size {
+            if self.cancelled() {
+                return Err(PicobootError::Interrupted);
+            }
+            let len = std::cmp::min(CHUNK, size - done);
+            let at = addr
+                .checked_add(done)
+                .ok_or(PicobootError::InvalidArgument(
+                    "erase runs past end of flash",
+                ))?;
+            self.flash_erase(at, len)?;
+            done += len;
+            self.report_progress(ProgressOp::Erase, done as u64, size as u64);
+        }
+        Ok(())
+    }
+
+    // Erases the whole flash, of `flash_size` or else the size `flash_size()` finds
+    pub fn flash_erase_all(&mut self, flash_size: Option<u32>) -> Result<()> {
+        let size = match flash_size {
+            Some(size) => size,
+            None => self.flash_size()?,
+        };
+        self.flash_erase_range(FlashAddr::START, size)
     }
 
-    pub fn flash_write(&mut self, addr: u32, buf: Vec<u8>) -> rusb::Result<()> {
+    // The size of the flash. An RP2350's bootrom reports the total on both chip selects, while
+    // an RP2040's flash is asked for its JEDEC ID, which leaves XIP exited
+    pub fn flash_size(&mut self) -> Result<u32> {
+        match self.target_id {
+            Some(TargetID::Rp2040) => {
+                let id = crate::exec::flash_jedec_id(self)?;
+                crate::exec::jedec_flash_size(id).ok_or(PicobootError::BadResponse)
+            }
+            Some(TargetID::Rp2350) => {
+                let info = self.sys_info()?;
+                match (info.flash_size(0), info.flash_size(1)) {
+                    (Some(cs0), Some(cs1)) if cs0 + cs1 > 0 => Ok(cs0 + cs1),
+                    _ => Err(PicobootError::BadResponse),
+                }
+            }
+            None => Err(PicobootError::NotSupported),
+        }
+    }
+
+    // Fails with `PastFlashEnd` if `size` bytes at `addr` run past the end of the flash. A flash
+    // whose size can't be found out isn't checked, leaving it to the write or erase to fail
+    pub fn check_flash_range(&mut self, addr: FlashAddr, size: u32) -> Result<()> {
+        let Ok(flash_size) = self.flash_size() else {
+            return Ok(());
+        };
+        let end = addr.get() as u64 + size as u64;
+        if end > PICO_FLASH_START as u64 + flash_size as u64 {
+            return Err(PicobootError::PastFlashEnd { end, flash_size });
+        }
+        Ok(())
+    }
+
+    // The end of the program at the start of flash, from the BINARY_END entry of its binary
+    // info as `picotool save` uses. None if there's no program with one
+    pub fn program_end(&mut self) -> Result<Option<u32>> {
+        crate::binary_info::binary_end(|addr, size| self.read_at(addr, size), PICO_FLASH_START)
+    }
+
+    // What the binary info of the program at the start of flash says about it, e.g. its name,
+    // version and pins. None if there's no program with binary info
+    pub fn program_info(&mut self) -> Result<Option<crate::binary_info::ProgramInfo>> {
+        let target = self.target_id.unwrap_or(TargetID::Rp2040);
+        let entries = crate::binary_info::read_entries(
+            |addr, size| self.read_at(addr, size),
+            PICO_FLASH_START,
+        )?;
+        Ok(entries.map(|e| crate::binary_info::ProgramInfo::from_entries(&e, target)))
+    }
+
+    // Reads back just the program at the start of flash, rather than all of it, see
+    // `program_end`. Reads are retried as in `flash_read_retry`
+    pub fn save_program(&mut self, retries: u32) -> Result<Option<Vec<u8>>> {
+        match self.program_end()? {
+            Some(end) => self
+                .flash_read_all(FlashAddr::START, end - PICO_FLASH_START, retries)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Binary info holds bare addresses, which are read wherever they point
+    fn read_at(&mut self, addr: u32, size: u32) -> Result<Vec<u8>> {
+        let addr = MemAddr::new(addr).ok_or(PicobootError::InvalidArgument(
+            "binary info points outside memory",
+        ))?;
+        self.flash_read(addr, size)
+    }
+
+    pub fn flash_write(&mut self, addr: impl Into<MemAddr>, buf: Vec<u8>) -> Result<()> {
+        let addr = addr.into().get();
+        self.check_address(addr, buf.len() as u32, Access::Write)?;
+        if self.sequencing == CommandSequencing::Picotool {
+            self.exit_xip()?;
+        }
         let args = PicobootRangeCmd::ser(addr, buf.len() as u32);
         let cmd = PicobootCmd::new(PicobootCmdId::Write, 8, buf.len() as u32, args);
-        Ok(self.cmd(cmd, buf).map(|_| ())?)
+        self.cmd(cmd, buf).map(|_| ())
     }
 
-    pub fn flash_read(&mut self, addr: u32, size: u32) -> rusb::Result<Vec<u8>> {
+    pub fn flash_read(&mut self, addr: impl Into<MemAddr>, size: u32) -> Result<Vec<u8>> {
+        let addr = addr.into().get();
+        self.check_address(addr, size, Access::Read)?;
         let args = PicobootRangeCmd::ser(addr, size);
         let cmd = PicobootCmd::new(PicobootCmdId::Read, 8, size, args);
         self.cmd(cmd, vec![])
     }
 
-    pub fn enter_xip(&mut self) -> rusb::Result<()> {
+    // Reads like `flash_read`, but tries again up to `retries` times when a read fails or comes
+    // back short. The failed command has already reset the interface, so it can go again as is
+    pub fn flash_read_retry(
+        &mut self,
+        addr: impl Into<MemAddr>,
+        size: u32,
+        retries: u32,
+    ) -> Result<Vec<u8>> {
+        let addr = addr.into();
+        let mut attempt = 0;
+        loop {
+            match self.flash_read(addr, size) {
+                Ok(buf) => return Ok(buf),
+                Err(e) if attempt < retries => {
+                    log::warn!("read of {} failed ({}), retrying", addr, e);
+                    attempt += 1;
+                    self.diagnostics.retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Programs `data` into flash at `addr`, which must be page aligned: erases each sector it
+    // touches, writes it and verifies it as asked. Parts of a sector not covered by `data` keep
+    // their contents. Stops between sectors if cancelled (see `set_cancel_token`). Returns the
+    // bytes of `data` actually programmed, leaving out sectors skipped as unchanged (see
+    // `set_skip_unchanged`)
+    pub fn flash_program(
+        &mut self,
+        flash_addr: FlashAddr,
+        data: &[u8],
+        verify: VerifyMode,
+    ) -> Result<usize> {
+        if !flash_addr.is_aligned(PICO_PAGE_SIZE as u32) {
+            return Err(PicobootError::InvalidArgument(
+                "address is not page aligned",
+            ));
+        }
+        let addr = flash_addr.get();
+        let retries = match verify {
+            VerifyMode::ReadBack { retries } => retries,
+            VerifyMode::None | VerifyMode::DeviceCrc => 0,
+        };
+        let read_back = matches!(verify, VerifyMode::ReadBack { .. });
+        let end = addr as u64 + data.len() as u64;
+        let mut programmed = 0;
+        let mut sector = addr - (addr % PICO_SECTOR_SIZE);
+        while (sector as u64) < end {
+            if self.cancelled() {
+                return Err(PicobootError::Interrupted);
+            }
+            let sector_end = sector as u64 + PICO_SECTOR_SIZE as u64;
+            let from = std::cmp::max(addr, sector);
+            let to = std::cmp::min(end, sector_end);
+
+            // a partly covered sector is read first, so the erase doesn't lose the rest of it.
+            // When skipping unchanged sectors every sector is read, to compare against
+            let partial = from != sector || to != sector_end;
+            let old = if partial || self.skip_unchanged {
+                Some(self.flash_read_retry(FlashAddr(sector), PICO_SECTOR_SIZE, retries)?)
+            } else {
+                None
+            };
+            let part = &data[(from - addr) as usize..(to - addr as u64) as usize];
+            let buf = match &old {
+                Some(old) => {
+                    let mut buf = old.clone();
+                    let offset = (from - sector) as usize;
+                    buf[offset..offset + part.len()].copy_from_slice(part);
+                    buf
+                }
+                None => part.to_vec(),
+            };
+
+            let total = data.len() as u64;
+            if self.skip_unchanged && old.as_ref() == Some(&buf) {
+                self.diagnostics.sectors_skipped += 1;
+                self.report_progress(ProgressOp::Erase, to - addr as u64, total);
+                self.report_progress(ProgressOp::Write, to - addr as u64, total);
+                if read_back {
+                    self.report_progress(ProgressOp::Verify, to - addr as u64, total);
+                }
+                sector += PICO_SECTOR_SIZE;
+                continue;
+            }
+
+            self.flash_erase(FlashAddr(sector), PICO_SECTOR_SIZE)?;
+            programmed += part.len();
+            self.report_progress(ProgressOp::Erase, to - addr as u64, total);
+            for (i, page) in buf.chunks(PICO_PAGE_SIZE).enumerate() {
+                let page_addr = sector + (i * PICO_PAGE_SIZE) as u32;
+                // erased flash reads as 0xFF already, no need to write it
+                if !page.iter().all(|&b| b == 0xFF) {
+                    self.flash_write(FlashAddr(page_addr), page.to_vec())?;
+                    if read_back {
+                        self.verify_page(FlashAddr(page_addr), page, retries)?;
+                    }
+                }
+                // pages before `addr` in a partly covered sector don't count towards progress
+                let page_end = std::cmp::min(page_addr as u64 + PICO_PAGE_SIZE as u64, end);
+                if page_end > addr as u64 {
+                    let done = page_end - addr as u64;
+                    self.report_progress(ProgressOp::Write, done, total);
+                    if read_back {
+                        self.report_progress(ProgressOp::Verify, done, total);
+                    }
+                }
+            }
+            sector += PICO_SECTOR_SIZE;
+        }
+        if verify == VerifyMode::DeviceCrc {
+            self.verify_crc32(flash_addr, data)?;
+        }
+        Ok(programmed)
+    }
+
+    // Compares flash with `data` by having the device compute the CRC of each 64K chunk, instead
+    // of reading it all back. Fails on the first chunk that differs. Leaves XIP exited. The
+    // RP2350 can't run the stub, so there each chunk is read back and its CRC taken here instead
+    pub fn verify_crc32(&mut self, addr: FlashAddr, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if FlashAddr::new((addr.get() as u64 + data.len() as u64 - 1) as u32).is_none() {
+            return Err(PicobootError::InvalidArgument("range is not in flash"));
+        }
+        let addr = addr.get();
+        let mut done = 0;
+        for chunk in data.chunks(0x10000) {
+            if self.cancelled() {
+                return Err(PicobootError::Interrupted);
+            }
+            let chunk_addr = addr + done as u32;
+            let mut crc = crate::crc32::Crc32::new();
+            crc.update(chunk);
+            let device = match self.target_id {
+                Some(TargetID::Rp2040) => {
+                    crate::exec::device_crc32(self, chunk_addr, chunk.len() as u32)?
+                }
+                _ => {
+                    let mut read = crate::crc32::Crc32::new();
+                    read.update(&self.flash_read(FlashAddr(chunk_addr), chunk.len() as u32)?);
+                    read.finish()
+                }
+            };
+            if device != crc.finish() {
+                return Err(PicobootError::CrcMismatch {
+                    addr: chunk_addr,
+                    size: chunk.len() as u32,
+                });
+            }
+            done += chunk.len();
+            self.report_progress(ProgressOp::Verify, done as u64, data.len() as u64);
+        }
+        Ok(())
+    }
+
+    // Like `verify_program`, but carries on past differing pages to list the first `max` bytes
+    // that differ, for reporting where an image and flash disagree. Pages are checked as in
+    // `verify_program`, so only differences that read back the same every time are listed
+    pub fn find_mismatches(
+        &mut self,
+        addr: FlashAddr,
+        data: &[u8],
+        retries: u32,
+        max: usize,
+    ) -> Result<Vec<Mismatch>> {
+        let addr = addr.get();
+        let mut found = vec![];
+        let end = addr as u64 + data.len() as u64;
+        let mut from = addr as u64;
+        while from < end && found.len() < max {
+            if self.cancelled() {
+                return Err(PicobootError::Interrupted);
+            }
+            let page_end = std::cmp::min(
+                from - from % PICO_PAGE_SIZE as u64 + PICO_PAGE_SIZE as u64,
+                end,
+            );
+            let part = &data[(from - addr as u64) as usize..(page_end - addr as u64) as usize];
+            match self.verify_page(FlashAddr(from as u32), part, retries) {
+                Ok(()) => {}
+                Err(PicobootError::VerifyFailed { .. }) => {
+                    let at = FlashAddr(from as u32);
+                    let read = self.flash_read_retry(at, part.len() as u32, retries)?;
+                    let diffs = part
+                        .iter()
+                        .zip(&read)
+                        .enumerate()
+                        .filter(|(_, (a, b))| a != b);
+                    for (i, (&expected, &actual)) in diffs.take(max - found.len()) {
+                        found.push(Mismatch {
+                            addr: from as u32 + i as u32,
+                            expected,
+                            actual,
+                        });
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            self.report_progress(
+                ProgressOp::Verify,
+                page_end - addr as u64,
+                data.len() as u64,
+            );
+            from = page_end;
+        }
+        Ok(found)
+    }
+
+    // Reads flash back and compares it with `data` without erasing or writing anything, e.g. to
+    // audit what a device holds. `addr` needn't be aligned. Fails on the first page that differs
+    pub fn verify_program(&mut self, addr: FlashAddr, data: &[u8], retries: u32) -> Result<()> {
+        let addr = addr.get();
+        let end = addr as u64 + data.len() as u64;
+        let mut from = addr as u64;
+        while from < end {
+            if self.cancelled() {
+                return Err(PicobootError::Interrupted);
+            }
+            let page_end = std::cmp::min(
+                from - from % PICO_PAGE_SIZE as u64 + PICO_PAGE_SIZE as u64,
+                end,
+            );
+            let part = &data[(from - addr as u64) as usize..(page_end - addr as u64) as usize];
+            self.verify_page(FlashAddr(from as u32), part, retries)?;
+            self.report_progress(
+                ProgressOp::Verify,
+                page_end - addr as u64,
+                data.len() as u64,
+            );
+            from = page_end;
+        }
+        Ok(())
+    }
+
+    // Reads a page back and compares it with what was written. A mismatching page is re-read up
+    // to `retries` times: a read matching the page means the earlier one was corrupted in
+    // transfer, while two identical mismatching reads mean flash really holds something else
+    fn verify_page(&mut self, addr: FlashAddr, page: &[u8], retries: u32) -> Result<()> {
+        let size = page.len() as u32;
+        let mut attempts: Vec<Vec<u8>> = vec![];
+        while attempts.len() <= retries as usize {
+            let read = self.flash_read_retry(addr, size, retries)?;
+            if read == page {
+                if !attempts.is_empty() {
+                    log::warn!(
+                        "page at {} matched after {} re-reads, earlier reads were corrupted",
+                        addr,
+                        attempts.len()
+                    );
+                }
+                return Ok(());
+            }
+            if attempts.contains(&read) {
+                let mismatched = page.iter().zip(&read).filter(|&(a, b)| a != b).count();
+                return Err(PicobootError::VerifyFailed {
+                    addr: addr.get(),
+                    mismatched,
+                });
+            }
+            self.diagnostics.retries += 1;
+            attempts.push(read);
+        }
+        Err(PicobootError::UnstableRead { addr: addr.get() })
+    }
+
+    // Reads `size` bytes 64K at a time, retrying each read as `flash_read_retry` does and
+    // reporting progress along the way. Stops between reads if cancelled (see `set_cancel_token`)
+    pub fn flash_read_all(
+        &mut self,
+        addr: impl Into<MemAddr>,
+        size: u32,
+        retries: u32,
+    ) -> Result<Vec<u8>> {
+        let addr = addr.into();
+        let mut buf = Vec::with_capacity(size as usize);
+        while buf.len() < size as usize {
+            if self.cancelled() {
+                return Err(PicobootError::Interrupted);
+            }
+            let chunk = std::cmp::min(READ_CHUNK_SIZE, size - buf.len() as u32);
+            let at = addr
+                .checked_add(buf.len() as u32)
+                .ok_or(PicobootError::InvalidArgument(
+                    "read runs past end of memory",
+                ))?;
+            let read = self.flash_read_retry(at, chunk, retries)?;
+            buf.extend_from_slice(&read);
+            self.report_progress(ProgressOp::Read, buf.len() as u64, size as u64);
+        }
+        Ok(buf)
+    }
+
+    // Calls `handler` as erases, writes, verifies and reads in `flash_program` and
+    // `flash_read_all` go, so frontends can show progress
+    pub fn set_progress_handler(&mut self, handler: impl FnMut(ProgressEvent) + Send + 'static) {
+        self.progress = Some(Box::new(handler));
+    }
+
+    pub fn clear_progress_handler(&mut self) {
+        self.progress = None;
+    }
+
+    fn report_progress(&mut self, op: ProgressOp, done: u64, total: u64) {
+        if let Some(handler) = &mut self.progress {
+            handler(ProgressEvent { op, done, total });
+        }
+    }
+
+    // RP2040 only. Calls the function at `addr` (in RAM) and returns once it does. The Thumb bit
+    // is set here, so `addr` can be where the code was loaded
+    pub fn exec(&mut self, addr: SramAddr) -> Result<()> {
+        if !matches!(self.target_id, Some(TargetID::Rp2040)) {
+            return Err(PicobootError::NotSupported);
+        }
+        let args = PicobootRangeCmd::ser(addr.get() | 1, 0);
+        let cmd = PicobootCmd::new(PicobootCmdId::Exec, 4, 0, args);
+        self.cmd(cmd, vec![]).map(|_| ())
+    }
+
+    // RP2040 only. Has the bootrom copy the table of flash access functions used by its mass
+    // storage and PICOBOOT interfaces to `addr` in SRAM and use them from there, so they can be
+    // replaced (e.g. by custom second stage flash routines) before erasing or writing
+    pub fn vectorize_flash(&mut self, addr: SramAddr) -> Result<()> {
+        if !matches!(self.target_id, Some(TargetID::Rp2040)) {
+            return Err(PicobootError::NotSupported);
+        }
+        let args = PicobootRangeCmd::ser(addr.get(), 0);
+        let cmd = PicobootCmd::new(PicobootCmdId::VectorizeFlash, 4, 0, args);
+        self.cmd(cmd, vec![]).map(|_| ())
+    }
+
+    // Puts flash back into XIP mode, using the slow but always working 03h read command, so it
+    // can be read through the XIP address space again (e.g. by code started with `exec`)
+    pub fn enter_cmd_xip(&mut self) -> Result<()> {
         let args = [0; 16];
         let cmd = PicobootCmd::new(PicobootCmdId::EnterCmdXip, 0, 0, args);
-        Ok(self.cmd(cmd, vec![]).map(|_| ())?)
+        self.cmd(cmd, vec![]).map(|_| ())
     }
 
-    pub fn exit_xip(&mut self) -> rusb::Result<()> {
+    // Takes flash out of XIP mode, which the bootrom needs before it erases or writes flash
+    pub fn exit_xip(&mut self) -> Result<()> {
         let args = [0; 16];
         let cmd = PicobootCmd::new(PicobootCmdId::ExitXip, 0, 0, args);
-        Ok(self.cmd(cmd, vec![]).map(|_| ())?)
-    }
-
-    pub fn reset_interface(&mut self) {
-        self.handle
-            .clear_halt(self.in_addr)
-            .expect("failed to clear in addr halt");
-        self.handle
-            .clear_halt(self.out_addr)
-            .expect("failed to clear out addr halt");
-
-        let timeout = std::time::Duration::from_secs(1);
-        let mut buf = [0u8; 0];
-        let _res = self
-            .handle
-            .write_control(
-                0b01000001,
-                0b01000001,
-                0,
-                self.iface.into(),
-                &mut buf,
-                timeout,
-            )
-            .expect("failed to reset interface");
-    }
-
-    fn get_command_status(&mut self) -> PicobootStatusCmd {
-        let timeout = std::time::Duration::from_secs(1);
-        let mut buf = [0u8; 16];
-        let _res = self
-            .handle
-            .read_control(
-                0b11000001,
-                0b01000010,
-                0,
-                self.iface.into(),
-                &mut buf,
-                timeout,
-            )
-            .expect("failed to get command status");
-        let buf: PicobootStatusCmd =
-            bincode::deserialize(&buf).expect("failed to parse command status buffer");
-
-        let tkn = buf.token;
-        let stat = buf.status_code;
-        let cmdid = buf.cmd_id;
-        let wip = buf.in_progress;
-        println!(
-            "\t\tcmdstat => tkn={}, stat={:?}, cmdid={:?}, wip={}",
-            tkn,
-            PicobootStatus::try_from(stat).unwrap(),
-            PicobootCmdId::try_from(cmdid).unwrap(),
-            wip == 1
-        );
+        self.cmd(cmd, vec![]).map(|_| ())
+    }
+
+    // RP2350 only. Asks the bootrom for information of the given type, returning the words of
+    // its response. See `sys_info` for the parsed system information
+    pub fn get_info(&mut self, info_type: InfoType, params: [u32; 3]) -> Result<Vec<u32>> {
+        if !matches!(self.target_id, Some(TargetID::Rp2350)) {
+            return Err(PicobootError::NotSupported);
+        }
+        let size = 256;
+        let args = PicobootGetInfoCmd::ser(info_type, params);
+        let cmd = PicobootCmd::new(PicobootCmdId::GetInfo, 0x10, size, args);
+        let buf = self.cmd(cmd, vec![])?;
+        Ok(buf
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect())
+    }
+
+    // RP2350 only. Reads the chip ID, critical OTP flags, CPU architecture, flash device info
+    // and boot info, for checking a device before flashing it
+    pub fn sys_info(&mut self) -> Result<SysInfo> {
+        let flags = SYS_INFO_CHIP_INFO
+            | SYS_INFO_CRITICAL
+            | SYS_INFO_CPU_INFO
+            | SYS_INFO_FLASH_DEV_INFO
+            | SYS_INFO_BOOT_INFO;
+        let words = self.get_info(InfoType::Sys, [flags, 0, 0])?;
+        SysInfo::parse(&words)
+    }
+
+    // Works out what the connected chip is, the USB PID only telling the family. The revision
+    // comes from the bootrom version in ROM, and on an RP2350 the package, secure boot state and
+    // running architecture from GET_INFO
+    pub fn identify(&mut self) -> Result<ChipIdentity> {
+        let target = self.target_id.ok_or(PicobootError::NotSupported)?;
+        // a bootrom that won't have its ROM read still gets identified by the rest
+        let rom_version = self
+            .flash_read(MemAddr::Rom(ROM_VERSION_ADDR & !3), 4)
+            .ok()
+            .map(|w| w[(ROM_VERSION_ADDR & 3) as usize]);
+        let mut identity = ChipIdentity {
+            target,
+            rom_version,
+            chip: None,
+            secure_boot: None,
+            cpu_riscv: None,
+        };
+        if let TargetID::Rp2350 = target {
+            let info = self.sys_info()?;
+            identity.chip = info.chip;
+            identity.secure_boot = info.critical.map(|c| c & CRIT1_SECURE_BOOT_ENABLE != 0);
+            identity.cpu_riscv = info.cpu_riscv;
+        }
+        Ok(identity)
+    }
+
+    // RP2350 only. Reads the partition table the bootrom is using: each partition's flash
+    // range, permissions, ID and the UF2 families it accepts
+    pub fn get_partition_table(&mut self) -> Result<PartitionTable> {
+        let flags = crate::partition::PT_INFO_PT_INFO
+            | crate::partition::PT_INFO_PARTITION_LOCATION_AND_FLAGS
+            | crate::partition::PT_INFO_PARTITION_ID
+            | crate::partition::PT_INFO_PARTITION_FAMILY_IDS;
+        let words = self.get_info(InfoType::PartitionTable, [flags, 0, 0])?;
+        PartitionTable::parse(&words)
+    }
+
+    // RP2350 only. Writes `table` as a hashed block over the first sector of flash and reads it
+    // back. It takes effect once the device reboots. Needs exclusive access and XIP exited first
+    pub fn write_partition_table(&mut self, table: &PartitionTable) -> Result<()> {
+        if !matches!(self.target_id, Some(TargetID::Rp2350)) {
+            return Err(PicobootError::NotSupported);
+        }
+        self.flash_program(
+            FlashAddr::START,
+            &table.to_block(),
+            VerifyMode::ReadBack { retries: 3 },
+        )
+        .map(|_| ())
+    }
+
+    // RP2350 only. Reads `row_count` OTP rows starting at `row`. With `ecc` each row is read as
+    // 2 bytes of ECC corrected data, otherwise as 4 bytes holding the 24 raw bits of the row
+    pub fn otp_read(&mut self, row: u16, row_count: u16, ecc: bool) -> Result<Vec<u8>> {
+        let row_size = if ecc { 2 } else { 4 };
+        let args = PicobootOtpCmd::ser(row, row_count, ecc);
+        let cmd = PicobootCmd::new(PicobootCmdId::OtpRead, 5, row_count as u32 * row_size, args);
+        self.cmd(cmd, vec![])
+    }
+
+    // RP2350 only. Programs OTP rows starting at `row`, laid out as `otp_read` returns them: 2
+    // bytes per row with `ecc`, which the bootrom encodes, or 4 bytes holding 24 raw bits. Bits
+    // can only ever be set, never cleared
+    pub fn otp_write(&mut self, row: u16, ecc: bool, data: Vec<u8>) -> Result<()> {
+        let row_size = if ecc { 2 } else { 4 };
+        if data.is_empty() || !data.len().is_multiple_of(row_size) {
+            return Err(PicobootError::InvalidArgument(
+                "OTP data is not a whole number of rows",
+            ));
+        }
+        let row_count = (data.len() / row_size) as u16;
+        let args = PicobootOtpCmd::ser(row, row_count, ecc);
+        let cmd = PicobootCmd::new(PicobootCmdId::OtpWrite, 5, data.len() as u32, args);
+        self.cmd(cmd, data).map(|_| ())
+    }
+
+    pub fn reset_interface(&mut self) -> Result<()> {
+        self.diagnostics.interface_resets += 1;
+        let started = Instant::now();
+        let (in_ep, out_ep) = self.transport.endpoints();
+        let mut res = Ok(());
+        for ep in [in_ep, out_ep] {
+            res = self.transport.clear_halt(ep);
+            if res.is_err() {
+                break;
+            }
+            self.diagnostics.clear_halts += 1;
+        }
+        let res = res.and_then(|()| self.transport.reset_interface(self.control_timeout));
+        if let Some(trace) = &self.trace {
+            let op = TraceOp::ResetInterface;
+            trace.record(op, 0, 0, &[], started, res.err());
+        }
+        res?;
+        Ok(())
+    }
+
+    // Fails with the bootrom's status if it rejected `cmd`
+    fn check_command_status(&mut self, cmd: &PicobootCmd) -> Result<()> {
+        let stat = self.get_command_status()?;
+        let (token, status) = (stat.token, stat.status_code);
+        if token == cmd.token && status != PicobootStatus::Ok as u32 {
+            return Err(PicobootError::Command {
+                cmd_id: cmd.cmd_id,
+                token,
+                status: PicobootStatus::try_from(status).unwrap_or(PicobootStatus::UnknownError),
+            });
+        }
+        Ok(())
+    }
 
-        buf
+    fn get_command_status(&mut self) -> Result<PicobootStatusCmd> {
+        let mut buf = [0u8; STATUS_SIZE];
+        let mut attempt = 1;
+        loop {
+            let started = Instant::now();
+            let res = self
+                .transport
+                .command_status(&mut buf, self.control_timeout);
+            if let Some(trace) = &self.trace {
+                let read: &[u8] = if res.is_ok() { &buf } else { &[] };
+                let op = TraceOp::CommandStatus;
+                trace.record(op, 0x80, STATUS_SIZE, read, started, res.err());
+            }
+            match res {
+                Ok(()) => break,
+                // reading the status changes nothing, so it can simply be asked for again
+                Err(e) if RetryPolicy::is_transient(&e) && attempt < self.retry.max_attempts => {
+                    self.diagnostics.note_usb_error(&e);
+                    self.diagnostics.usb_retries += 1;
+                    std::thread::sleep(self.retry.backoff(attempt));
+                    attempt += 1;
+                }
+                Err(e) => {
+                    self.diagnostics.note_usb_error(&e);
+                    return Err(e.into());
+                }
+            }
+        }
+        let buf = PicobootStatusCmd::parse(&buf);
+        log::trace!("{:?}", buf);
+        if buf.status_code != PicobootStatus::Ok as u32 {
+            self.diagnostics.status_errors += 1;
+        }
+        Ok(buf)
     }
 
     pub fn get_device_type(&self) -> Option<TargetID> {
         self.target_id
     }
+
+    // The memory map of the connected chip, None if it isn't known
+    pub fn memory_map(&self) -> Option<MemoryMap> {
+        self.target_id.map(MemoryMap::for_target)
+    }
+
+    // Refuses an access outside the chip's memory before it goes to the device, see `memmap`
+    fn check_address(&self, addr: u32, size: u32, access: Access) -> Result<()> {
+        match self.memory_map() {
+            Some(map) => map
+                .check(addr, size, access)
+                .map(|_| ())
+                .map_err(Into::into),
+            None => Ok(()),
+        }
+    }
+
+    pub fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+
+    // Records every transfer from now on into `trace`, which the caller keeps a clone of to
+    // export, see `trace::Trace`. None stops recording
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        if let Some(trace) = &trace {
+            trace.set_target(self.target_id);
+        }
+        self.trace = trace;
+    }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    // Stops the long running operations (`flash_program`, `flash_erase_range`, `flash_read_all`
+    // and the verifies) with `PicobootError::Interrupted` once `token` is set, e.g. from a GUI's
+    // cancel button. They only check it between commands, so the device is left ready for the
+    // next one and the operation can be redone. None never stops them
+    pub fn set_cancel_token(&mut self, token: Option<Arc<AtomicBool>>) {
+        self.cancel = token;
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::SeqCst))
+    }
+
+    // How commands failing with a transient USB error are retried. See
+    // `PicobootConnectionBuilder::retry_policy`
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    pub fn get_sequencing(&self) -> CommandSequencing {
+        self.sequencing
+    }
+
+    pub fn set_sequencing(&mut self, sequencing: CommandSequencing) {
+        self.sequencing = sequencing;
+    }
+
+    // Timeout for each chunk of a bulk read, and the base for waiting on a command's ack. See
+    // `PicobootConnectionBuilder::read_timeout`
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+
+    // Timeout for each chunk of a bulk write
+    pub fn set_write_timeout(&mut self, timeout: Duration) {
+        self.write_timeout = timeout;
+    }
+
+    // Timeout for command status and interface reset requests
+    pub fn set_control_timeout(&mut self, timeout: Duration) {
+        self.control_timeout = timeout;
+    }
+
+    // With `skip` set, `flash_program` reads each sector first and leaves it alone if it
+    // already holds the data, so re-flashing a mostly unchanged image only rewrites what changed
+    pub fn set_skip_unchanged(&mut self, skip: bool) {
+        self.skip_unchanged = skip;
+    }
+
+    // RP2350 only. Has every following reboot switch the cores to `arch`, e.g. to boot a RISC-V
+    // image on a chip last running ARM code. None leaves the architecture to the bootrom
+    pub fn set_reboot_arch(&mut self, arch: Option<RebootArch>) -> Result<()> {
+        if arch.is_some() && !matches!(self.target_id, Some(TargetID::Rp2350)) {
+            return Err(PicobootError::NotSupported);
+        }
+        self.reboot_arch = arch;
+        Ok(())
+    }
 }
@@ -0,0 +1,165 @@
+use crate::memmap::{FlashAddr, MemAddr};
+use crate::picousb::{
+    ChipIdentity, PicobootConnection, RebootMode, Result, SysInfo, TargetID, VerifyMode,
+};
+use crate::shared::SharedPicoboot;
+use crate::transport::Transport;
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+struct Slot<R> {
+    result: Option<thread::Result<R>>,
+    waker: Option<Waker>,
+}
+
+// Future for a blocking call running on its own thread, which resolves once the call returns.
+// It works with any executor, as it only relies on the waker it's polled with
+pub struct Blocking<R> {
+    slot: Arc<Mutex<Slot<R>>>,
+}
+
+impl<R> Future for Blocking<R> {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        let mut slot = lock(&self.slot);
+        match slot.result.take() {
+            Some(Ok(r)) => Poll::Ready(r),
+            // a panic in the call is passed on to whoever awaits it
+            Some(Err(e)) => panic::resume_unwind(e),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn spawn_blocking<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> Blocking<R> {
+    let slot = Arc::new(Mutex::new(Slot {
+        result: None,
+        waker: None,
+    }));
+    let theirs = slot.clone();
+    thread::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let mut slot = lock(&theirs);
+        slot.result = Some(result);
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    });
+    Blocking { slot }
+}
+
+// A panicking call only poisons the lock, the data behind it is still usable
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Async wrapper around a connection, for flashing from async services without blocking the
+// executor. Each call runs the blocking transfers on a short lived thread, and calls made
+// while another is still running wait their turn. Cloning gives another handle to the same
+// device
+pub struct PicobootConnectionAsync<T: Transport + Send + 'static> {
+    pub(crate) conn: Arc<Mutex<PicobootConnection<T>>>,
+}
+
+impl<T: Transport + Send + 'static> Clone for PicobootConnectionAsync<T> {
+    fn clone(&self) -> Self {
+        PicobootConnectionAsync {
+            conn: self.conn.clone(),
+        }
+    }
+}
+
+impl<T: Transport + Send + 'static> PicobootConnectionAsync<T> {
+    pub fn new(conn: PicobootConnection<T>) -> Self {
+        PicobootConnectionAsync {
+            conn: Arc::new(Mutex::new(conn)),
+        }
+    }
+
+    // Gives back the blocking connection, or the wrapper itself if other handles to it are
+    // still around
+    pub fn into_inner(self) -> std::result::Result<PicobootConnection<T>, Self> {
+        match Arc::try_unwrap(self.conn) {
+            Ok(m) => Ok(m.into_inner().unwrap_or_else(|e| e.into_inner())),
+            Err(conn) => Err(PicobootConnectionAsync { conn }),
+        }
+    }
+
+    // Runs `f` with exclusive use of the connection on a blocking thread, for anything the
+    // methods below don't cover
+    pub fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut PicobootConnection<T>) -> R + Send + 'static,
+    ) -> Blocking<R> {
+        let conn = self.conn.clone();
+        spawn_blocking(move || f(&mut lock(&conn)))
+    }
+
+    // A blocking handle to the same connection, for calls made from plain threads
+    pub fn shared(&self) -> SharedPicoboot<T> {
+        SharedPicoboot {
+            conn: self.conn.clone(),
+        }
+    }
+
+    pub fn get_device_type(&self) -> Option<TargetID> {
+        lock(&self.conn).get_device_type()
+    }
+
+    pub async fn access_exclusive(&self) -> Result<()> {
+        self.run(|c| c.access_exclusive()).await
+    }
+
+    pub async fn access_not_exclusive(&self) -> Result<()> {
+        self.run(|c| c.access_not_exclusive()).await
+    }
+
+    pub async fn flash_erase(&self, addr: FlashAddr, size: u32) -> Result<()> {
+        self.run(move |c| c.flash_erase(addr, size)).await
+    }
+
+    pub async fn flash_write(&self, addr: impl Into<MemAddr>, buf: Vec<u8>) -> Result<()> {
+        let addr = addr.into();
+        self.run(move |c| c.flash_write(addr, buf)).await
+    }
+
+    pub async fn flash_read(&self, addr: impl Into<MemAddr>, size: u32) -> Result<Vec<u8>> {
+        let addr = addr.into();
+        self.run(move |c| c.flash_read(addr, size)).await
+    }
+
+    pub async fn flash_program(
+        &self,
+        addr: FlashAddr,
+        data: Vec<u8>,
+        verify: VerifyMode,
+    ) -> Result<usize> {
+        self.run(move |c| c.flash_program(addr, &data, verify))
+            .await
+    }
+
+    pub async fn reboot(&self, pc: u32, sp: u32, delay: u32) -> Result<()> {
+        self.run(move |c| c.reboot(pc, sp, delay)).await
+    }
+
+    pub async fn reboot_into(&self, mode: RebootMode, delay: u32) -> Result<()> {
+        self.run(move |c| c.reboot_into(mode, delay)).await
+    }
+
+    pub async fn sys_info(&self) -> Result<SysInfo> {
+        self.run(|c| c.sys_info()).await
+    }
+
+    pub async fn identify(&self) -> Result<ChipIdentity> {
+        self.run(|c| c.identify()).await
+    }
+}
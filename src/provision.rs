@@ -0,0 +1,507 @@
+// Giving each device a serial number of its own from a manifest, for production lines. The
+// serial goes into user OTP as ECC rows: its length in characters, then two ASCII characters to
+// a row, first in the low byte. The manifest lists the serials to hand out, and as each is used
+// it's marked with the chip ID of the device that got it, so none is given out twice
+
+use std::fmt;
+
+use crate::json::{self, Value};
+use crate::otp::{self, OtpWrite};
+use crate::picousb::{self, PicobootConnection};
+use crate::transport::Transport;
+
+// Where the serial goes unless told otherwise, clear of a white-label struct at its default row
+pub const DEFAULT_SERIAL_ROW: u16 = 0x200;
+pub const MAX_SERIAL_LEN: usize = 64;
+
+const CSV_HEADER: &str = "serial,chip_id";
+
+#[derive(Debug)]
+pub enum ProvisionError {
+    Json(json::ParseError),
+    // the manifest entry (counting from 1, or the line of a CSV manifest) doesn't parse
+    BadEntry(usize),
+    // a serial that's empty, too long or not printable ASCII
+    BadSerial(String),
+    // the same serial is in the manifest twice
+    Duplicate(String),
+    // the manifest gave the chip a serial its OTP doesn't hold
+    NotInOtp { chip_id: u64, serial: String },
+    // the chip holds a serial the manifest doesn't have free for it
+    Taken { chip_id: u64, serial: String },
+    NoneLeft,
+}
+impl fmt::Display for ProvisionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProvisionError::Json(e) => write!(f, "{}", e),
+            ProvisionError::BadEntry(n) => write!(f, "manifest entry {} is invalid", n),
+            ProvisionError::BadSerial(s) => write!(
+                f,
+                "bad serial {:?}, expected 1 to {} printable ASCII characters",
+                s, MAX_SERIAL_LEN
+            ),
+            ProvisionError::Duplicate(s) => write!(f, "serial {:?} is in the manifest twice", s),
+            ProvisionError::NotInOtp { chip_id, serial } => write!(
+                f,
+                "the manifest gave chip {} serial {}, but its OTP doesn't hold it",
+                format_chip_id(*chip_id),
+                serial
+            ),
+            ProvisionError::Taken { chip_id, serial } => write!(
+                f,
+                "chip {} already holds serial {:?}, which the manifest doesn't have free",
+                format_chip_id(*chip_id),
+                serial
+            ),
+            ProvisionError::NoneLeft => write!(f, "the manifest has no unused serials left"),
+        }
+    }
+}
+impl std::error::Error for ProvisionError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub serial: String,
+    // the chip the serial was given to, None while it's unused
+    pub chip_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Json,
+}
+
+// The serials to hand out, read from and written back as either CSV, one `serial,chip_id` line
+// per entry with the chip ID left empty while unused:
+//   serial,chip_id
+//   SN-0001,E4A1C9D2F0B35A17
+//   SN-0002,
+// or a JSON array of the same:
+//   [{"serial": "SN-0001", "chip_id": "E4A1C9D2F0B35A17"}, {"serial": "SN-0002"}]
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+    format: Format,
+}
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Self, ProvisionError> {
+        let manifest = if text.trim_start().starts_with('[') {
+            Manifest {
+                entries: parse_json(text)?,
+                format: Format::Json,
+            }
+        } else {
+            Manifest {
+                entries: parse_csv(text)?,
+                format: Format::Csv,
+            }
+        };
+        for (i, e) in manifest.entries.iter().enumerate() {
+            check_serial(&e.serial)?;
+            if manifest.entries[..i].iter().any(|o| o.serial == e.serial) {
+                return Err(ProvisionError::Duplicate(e.serial.clone()));
+            }
+        }
+        Ok(manifest)
+    }
+
+    // The manifest in the format it was read in, for writing back once an entry is used
+    pub fn to_text(&self) -> String {
+        match self.format {
+            Format::Csv => {
+                let mut text = format!("{}\n", CSV_HEADER);
+                for e in &self.entries {
+                    let id = e.chip_id.map(format_chip_id).unwrap_or_default();
+                    text += &format!("{},{}\n", e.serial, id);
+                }
+                text
+            }
+            Format::Json => {
+                let lines: Vec<String> = self
+                    .entries
+                    .iter()
+                    .map(|e| {
+                        let mut fields = vec![("serial".to_string(), e.serial.as_str().into())];
+                        if let Some(id) = e.chip_id {
+                            fields.push(("chip_id".to_string(), format_chip_id(id).into()));
+                        }
+                        format!("  {}", Value::Object(fields))
+                    })
+                    .collect();
+                format!("[\n{}\n]\n", lines.join(",\n"))
+            }
+        }
+    }
+
+    // The first serial not given out yet
+    pub fn next_unused(&self) -> Option<usize> {
+        self.entries.iter().position(|e| e.chip_id.is_none())
+    }
+
+    pub fn find_serial(&self, serial: &str) -> Option<usize> {
+        self.entries.iter().position(|e| e.serial == serial)
+    }
+
+    pub fn find_chip(&self, chip_id: u64) -> Option<usize> {
+        self.entries.iter().position(|e| e.chip_id == Some(chip_id))
+    }
+
+    pub fn unused(&self) -> usize {
+        self.entries.iter().filter(|e| e.chip_id.is_none()).count()
+    }
+}
+
+// What to do for a chip, given the serial its OTP holds now, as the index of its manifest entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    // the chip already has the serial the manifest gave it
+    Provisioned(usize),
+    // the chip holds a free serial, written last time without the manifest being updated, e.g.
+    // as the run was cut short
+    Unrecorded(usize),
+    // the serial to write
+    Assign(usize),
+}
+
+// Picks the serial for the chip `chip_id` holding `current` in OTP
+pub fn allocate(
+    manifest: &Manifest,
+    chip_id: u64,
+    current: Option<&str>,
+) -> Result<Allocation, ProvisionError> {
+    match (current, manifest.find_chip(chip_id)) {
+        (Some(serial), Some(i)) if manifest.entries[i].serial == serial => {
+            Ok(Allocation::Provisioned(i))
+        }
+        (_, Some(i)) => Err(ProvisionError::NotInOtp {
+            chip_id,
+            serial: manifest.entries[i].serial.clone(),
+        }),
+        (Some(serial), None) => match manifest.find_serial(serial) {
+            Some(i) if manifest.entries[i].chip_id.is_none() => Ok(Allocation::Unrecorded(i)),
+            _ => Err(ProvisionError::Taken {
+                chip_id,
+                serial: serial.to_string(),
+            }),
+        },
+        (None, None) => manifest
+            .next_unused()
+            .map(Allocation::Assign)
+            .ok_or(ProvisionError::NoneLeft),
+    }
+}
+
+fn parse_csv(text: &str) -> Result<Vec<ManifestEntry>, ProvisionError> {
+    let mut entries = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (serial, id) = line.split_once(',').unwrap_or((line, ""));
+        let (serial, id) = (serial.trim(), id.trim());
+        if serial.eq_ignore_ascii_case("serial") && entries.is_empty() {
+            continue;
+        }
+        let chip_id = match id {
+            "" => None,
+            id => Some(parse_chip_id(id).ok_or(ProvisionError::BadEntry(i + 1))?),
+        };
+        entries.push(ManifestEntry {
+            serial: serial.to_string(),
+            chip_id,
+        });
+    }
+    Ok(entries)
+}
+
+fn parse_json(text: &str) -> Result<Vec<ManifestEntry>, ProvisionError> {
+    let Value::Array(items) = json::parse(text).map_err(ProvisionError::Json)? else {
+        return Err(ProvisionError::BadEntry(1));
+    };
+    let entry = |item: &Value| {
+        let Some(Value::String(serial)) = item.get("serial") else {
+            return None;
+        };
+        let chip_id = match item.get("chip_id") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(id)) => Some(parse_chip_id(id)?),
+            Some(_) => return None,
+        };
+        Some(ManifestEntry {
+            serial: serial.clone(),
+            chip_id,
+        })
+    };
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| entry(item).ok_or(ProvisionError::BadEntry(i + 1)))
+        .collect()
+}
+
+// Chip IDs are written as 16 hex digits, most significant first
+pub fn format_chip_id(id: u64) -> String {
+    format!("{:016X}", id)
+}
+
+fn parse_chip_id(s: &str) -> Option<u64> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn check_serial(serial: &str) -> Result<(), ProvisionError> {
+    let printable = serial.bytes().all(|b| (0x20..0x7F).contains(&b));
+    if serial.is_empty() || serial.len() > MAX_SERIAL_LEN || !printable {
+        return Err(ProvisionError::BadSerial(serial.to_string()));
+    }
+    Ok(())
+}
+
+// The chip's unique ID, from the CHIPID0 to CHIPID3 rows with CHIPID3 the most significant
+pub fn read_chip_id<T: Transport>(conn: &mut PicobootConnection<T>) -> picousb::Result<u64> {
+    let rows = otp::read_rows(conn, 0, 4, true)?;
+    Ok(rows
+        .iter()
+        .rev()
+        .fold(0, |id, &row| (id << 16) | (row & 0xFFFF) as u64))
+}
+
+// The OTP writes storing `serial` at `row`. The length row comes last, so a write cut short
+// leaves no serial rather than a truncated one
+pub fn serial_writes(serial: &str, row: u16) -> Result<Vec<OtpWrite>, ProvisionError> {
+    check_serial(serial)?;
+    let rows = 1 + serial.len().div_ceil(2) as u16;
+    if row as u32 + rows as u32 > otp::OTP_ROW_COUNT as u32 {
+        return Err(ProvisionError::BadSerial(serial.to_string()));
+    }
+    let write = |row: u16, value: u32| OtpWrite {
+        row,
+        value,
+        ecc: true,
+        info: otp::row_info(row),
+    };
+    let mut writes: Vec<OtpWrite> = serial
+        .as_bytes()
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            let value = pair[0] as u32 | (*pair.get(1).unwrap_or(&0) as u32) << 8;
+            write(row + 1 + i as u16, value)
+        })
+        .collect();
+    writes.push(write(row, serial.len() as u32));
+    Ok(writes)
+}
+
+// Reads back a serial stored at `row` by `serial_writes`, None if there's none. Rows holding
+// something else come back as whatever characters they make
+pub fn read_serial<T: Transport>(
+    conn: &mut PicobootConnection<T>,
+    row: u16,
+) -> picousb::Result<Option<String>> {
+    let len = (otp::read_rows(conn, row, 1, true)?[0] as usize).min(MAX_SERIAL_LEN);
+    if len == 0 {
+        return Ok(None);
+    }
+    let rows = otp::read_rows(conn, row + 1, len.div_ceil(2) as u16, true)?;
+    let bytes: Vec<u8> = rows
+        .iter()
+        .flat_map(|&r| [r as u8, (r >> 8) as u8])
+        .take(len)
+        .collect();
+    Ok(Some(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPicoboot;
+    use crate::picousb::TargetID;
+
+    type Conn = PicobootConnection<MockPicoboot>;
+
+    // A device with the chip ID 0x0123456789ABCDEF in CHIPID0 to CHIPID3
+    fn connect() -> Conn {
+        let mut mock = MockPicoboot::new(TargetID::Rp2350);
+        for (row, data) in [0xCDEF, 0x89AB, 0x4567, 0x0123].into_iter().enumerate() {
+            mock.otp_mut()[row] = otp::ecc_encode(data);
+        }
+        PicobootConnection::with_transport(mock, Some(TargetID::Rp2350))
+    }
+
+    const CHIP_ID: u64 = 0x0123456789ABCDEF;
+
+    fn write_serial(conn: &mut Conn, serial: &str) {
+        for w in serial_writes(serial, DEFAULT_SERIAL_ROW).unwrap() {
+            otp::write_rows(conn, w.row, &[w.value], w.ecc).unwrap();
+        }
+    }
+
+    #[test]
+    fn manifests_parse_as_csv_or_json() {
+        let csv =
+            "serial,chip_id\n# spares at the end\nSN-0001, E4A1C9D2F0B35A17\n\nSN-0002,\nSN-0003\n";
+        let manifest = Manifest::parse(csv).unwrap();
+        let entries: Vec<(&str, Option<u64>)> = manifest
+            .entries
+            .iter()
+            .map(|e| (e.serial.as_str(), e.chip_id))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("SN-0001", Some(0xE4A1C9D2F0B35A17)),
+                ("SN-0002", None),
+                ("SN-0003", None)
+            ]
+        );
+        assert_eq!(manifest.unused(), 2);
+        assert_eq!(
+            manifest.to_text(),
+            "serial,chip_id\nSN-0001,E4A1C9D2F0B35A17\nSN-0002,\nSN-0003,\n"
+        );
+
+        let json = r#"[{"serial": "SN-0001", "chip_id": "0xE4A1C9D2F0B35A17"},
+                       {"serial": "SN-0002", "chip_id": null}, {"serial": "SN-0003"}]"#;
+        let from_json = Manifest::parse(json).unwrap();
+        assert_eq!(from_json.entries, manifest.entries);
+        // written back as JSON, and read back the same
+        let text = from_json.to_text();
+        assert!(text.starts_with('['));
+        assert_eq!(Manifest::parse(&text).unwrap().entries, manifest.entries);
+    }
+
+    #[test]
+    fn bad_manifests_are_rejected() {
+        let err = |text: &str| Manifest::parse(text).unwrap_err().to_string();
+        assert_eq!(err("SN-1,xyz"), "manifest entry 1 is invalid");
+        assert_eq!(
+            err("serial,chip_id\nSN-1,\nSN-2,12G"),
+            "manifest entry 3 is invalid"
+        );
+        assert_eq!(
+            err(r#"[{"serial": "SN-1"}, {"chip_id": "12"}]"#),
+            "manifest entry 2 is invalid"
+        );
+        assert_eq!(err(r#"[{"serial": 1}]"#), "manifest entry 1 is invalid");
+        assert!(matches!(
+            Manifest::parse("[1,"),
+            Err(ProvisionError::Json(_))
+        ));
+        assert!(matches!(
+            Manifest::parse("SN 1\u{e9}"),
+            Err(ProvisionError::BadSerial(_))
+        ));
+        assert!(matches!(
+            Manifest::parse(&"x".repeat(MAX_SERIAL_LEN + 1)),
+            Err(ProvisionError::BadSerial(_))
+        ));
+        assert!(matches!(
+            Manifest::parse("SN-1\nSN-2\nSN-1"),
+            Err(ProvisionError::Duplicate(s)) if s == "SN-1"
+        ));
+    }
+
+    #[test]
+    fn serials_are_handed_out_in_order() {
+        let mut manifest = Manifest::parse("SN-1,0000000000000001\nSN-2\nSN-3").unwrap();
+        assert_eq!(
+            allocate(&manifest, CHIP_ID, None).unwrap(),
+            Allocation::Assign(1)
+        );
+        manifest.entries[1].chip_id = Some(CHIP_ID);
+        assert_eq!(
+            allocate(&manifest, CHIP_ID, Some("SN-2")).unwrap(),
+            Allocation::Provisioned(1)
+        );
+        assert_eq!(allocate(&manifest, 2, None).unwrap(), Allocation::Assign(2));
+        manifest.entries[2].chip_id = Some(2);
+        assert!(matches!(
+            allocate(&manifest, 3, None),
+            Err(ProvisionError::NoneLeft)
+        ));
+    }
+
+    #[test]
+    fn serials_in_otp_are_matched_to_the_manifest() {
+        let manifest = Manifest::parse("SN-1,0000000000000001\nSN-2\nSN-3").unwrap();
+        // written last time, but the manifest wasn't updated
+        assert_eq!(
+            allocate(&manifest, CHIP_ID, Some("SN-3")).unwrap(),
+            Allocation::Unrecorded(2)
+        );
+        // given to another chip, or not in the manifest at all
+        for serial in ["SN-1", "SN-9"] {
+            assert!(matches!(
+                allocate(&manifest, CHIP_ID, Some(serial)),
+                Err(ProvisionError::Taken {
+                    chip_id: CHIP_ID,
+                    ..
+                })
+            ));
+        }
+        // the manifest says this chip has a serial, but it holds another or none
+        for current in [None, Some("SN-2")] {
+            assert!(matches!(
+                allocate(&manifest, 1, current),
+                Err(ProvisionError::NotInOtp { chip_id: 1, serial }) if serial == "SN-1"
+            ));
+        }
+    }
+
+    #[test]
+    fn serials_are_stored_length_first_and_read_back() {
+        let writes = serial_writes("SN-001", DEFAULT_SERIAL_ROW).unwrap();
+        let rows: Vec<(u16, u32)> = writes.iter().map(|w| (w.row, w.value)).collect();
+        assert_eq!(
+            rows,
+            [
+                (0x201, 0x4E53),
+                (0x202, 0x302D),
+                (0x203, 0x3130),
+                (0x200, 6)
+            ]
+        );
+        assert!(writes.iter().all(|w| w.ecc));
+        assert!(serial_writes("SN-1", 0xFFE).is_err());
+        assert!(serial_writes("", DEFAULT_SERIAL_ROW).is_err());
+
+        let mut conn = connect();
+        assert_eq!(read_chip_id(&mut conn).unwrap(), CHIP_ID);
+        assert_eq!(read_serial(&mut conn, DEFAULT_SERIAL_ROW).unwrap(), None);
+        write_serial(&mut conn, "SN-00001");
+        assert_eq!(
+            read_serial(&mut conn, DEFAULT_SERIAL_ROW)
+                .unwrap()
+                .as_deref(),
+            Some("SN-00001")
+        );
+    }
+
+    #[test]
+    fn provisioned_devices_are_left_alone() {
+        let mut conn = connect();
+        let mut manifest = Manifest::parse("SN-1\nSN-2").unwrap();
+
+        // the first run writes the serial and marks it used
+        let chip_id = read_chip_id(&mut conn).unwrap();
+        let current = read_serial(&mut conn, DEFAULT_SERIAL_ROW).unwrap();
+        let Allocation::Assign(i) = allocate(&manifest, chip_id, current.as_deref()).unwrap()
+        else {
+            panic!("expected a serial to write");
+        };
+        write_serial(&mut conn, &manifest.entries[i].serial);
+        manifest.entries[i].chip_id = Some(chip_id);
+
+        // the second finds it already there, so nothing is written
+        let current = read_serial(&mut conn, DEFAULT_SERIAL_ROW).unwrap();
+        assert_eq!(
+            allocate(&manifest, chip_id, current.as_deref()).unwrap(),
+            Allocation::Provisioned(0)
+        );
+        assert_eq!(manifest.unused(), 1);
+    }
+}
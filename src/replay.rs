@@ -0,0 +1,449 @@
+// Replaying a traced session (see `trace`) to lock in protocol behaviour, e.g. in CI. A session
+// recorded from a real device can be replayed two ways:
+// - `replay_against_mock` sends the host's side of it to `MockPicoboot` and checks the mock
+//   answers as the device did, so the mock keeps behaving like real bootroms
+// - `ReplayTransport` plays the device's side back to host code, and checks the host sends the
+//   same transfers in the same order, catching regressions in command sequencing. Only the tests
+//   drive the host code, so it's only built for them
+
+use std::fmt;
+#[cfg(test)]
+use std::time::Duration;
+
+use crate::mock::MockPicoboot;
+use crate::picousb::{TargetID, PICO_FLASH_START};
+use crate::protocol::{PicobootCmd, PicobootCmdId, PicobootRangeCmd, STATUS_SIZE};
+use crate::trace::{TraceEntry, TraceOp};
+use crate::transport::Transport;
+
+// How a replayed transfer differs from the recorded one. Out of order transfers are only noted
+// by `ReplayTransport`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub enum Divergence {
+    // the host did something else than the recording, e.g. a bulk read instead of a write
+    Op {
+        expected: TraceOp,
+        actual: TraceOp,
+    },
+    // the host wrote other bytes, or the device sent other bytes back
+    Data {
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    Length {
+        expected: usize,
+        actual: usize,
+    },
+    Error {
+        expected: Option<rusb::Error>,
+        actual: Option<rusb::Error>,
+    },
+    // the host went on past the end of the recording
+    PastEnd,
+    // the host stopped before the end of the recording
+    Unfinished {
+        remaining: usize,
+    },
+}
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex = |d: &[u8]| d.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        match self {
+            Divergence::Op { expected, actual } => {
+                write!(f, "expected {:?} but got {:?}", expected, actual)
+            }
+            Divergence::Data { expected, actual } => {
+                // only where they first differ, as the data can be a whole chunk of flash
+                let at = expected
+                    .iter()
+                    .zip(actual)
+                    .position(|(e, a)| e != a)
+                    .unwrap_or(expected.len().min(actual.len()));
+                let show = |d: &[u8]| hex(&d[at.min(d.len())..(at + 8).min(d.len())]);
+                write!(
+                    f,
+                    "data differs from byte {}: expected {} but got {}",
+                    at,
+                    show(expected),
+                    show(actual)
+                )
+            }
+            Divergence::Length { expected, actual } => {
+                write!(f, "expected {} bytes but got {}", expected, actual)
+            }
+            Divergence::Error { expected, actual } => {
+                let name = |e: &Option<rusb::Error>| match e {
+                    Some(e) => format!("{:?}", e),
+                    None => "success".to_string(),
+                };
+                write!(f, "expected {} but got {}", name(expected), name(actual))
+            }
+            Divergence::PastEnd => write!(f, "host went on past the end of the recording"),
+            Divergence::Unfinished { remaining } => write!(
+                f,
+                "host stopped with {} transfers of the recording left",
+                remaining
+            ),
+        }
+    }
+}
+
+// A divergence and the index of the recorded transfer it happened at
+pub type Mismatch = (usize, Divergence);
+
+// The flash read by a READ command, or None for other commands and other memory
+fn flash_read(cmd: &PicobootCmd) -> Option<u32> {
+    let args = PicobootRangeCmd::parse(&cmd.args);
+    (cmd.cmd_id == PicobootCmdId::Read as u8 && args.addr >= PICO_FLASH_START).then_some(args.addr)
+}
+
+// Commands sent in the recording, each with the index of the transfer carrying it
+fn commands(entries: &[TraceEntry]) -> Vec<(usize, PicobootCmd)> {
+    entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e.op == TraceOp::BulkOut && e.error.is_none())
+        .filter_map(|(i, e)| Some((i, PicobootCmd::parse(&e.data)?)))
+        .collect()
+}
+
+// Puts what the recording read from flash before changing it into the mock's flash, as the mock
+// starts out erased while the device held whatever it held
+fn seed_flash(entries: &[TraceEntry], mock: &mut MockPicoboot) {
+    let cmds = commands(entries);
+    let mut changed: Vec<(u32, u32)> = vec![];
+    let mut seeded = vec![false; mock.flash().len()];
+    for (n, (i, cmd)) in cmds.iter().enumerate() {
+        let args = PicobootRangeCmd::parse(&cmd.args);
+        if cmd.cmd_id == PicobootCmdId::FlashErase as u8 || cmd.cmd_id == PicobootCmdId::Write as u8
+        {
+            changed.push((args.addr, args.addr.saturating_add(args.size)));
+            continue;
+        }
+        let Some(mut addr) = flash_read(cmd) else {
+            continue;
+        };
+        let end = cmds.get(n + 1).map_or(entries.len(), |(next, _)| *next);
+        for e in entries[i + 1..end]
+            .iter()
+            .filter(|e| e.op == TraceOp::BulkIn)
+        {
+            for b in &e.data {
+                let offset = (addr - PICO_FLASH_START) as usize;
+                let untouched = !changed.iter().any(|(s, e)| (*s..*e).contains(&addr));
+                if offset < seeded.len() && untouched && !seeded[offset] {
+                    mock.flash_mut()[offset] = *b;
+                    seeded[offset] = true;
+                }
+                addr += 1;
+            }
+        }
+    }
+}
+
+// A mock of `target`, the chip the recording was made with, with its flash holding what the
+// recording read from the device before changing it
+pub fn mock_for(entries: &[TraceEntry], target: TargetID) -> MockPicoboot {
+    let mut mock = MockPicoboot::new(target);
+    seed_flash(entries, &mut mock);
+    mock
+}
+
+// Sends the host's side of the recording to `mock`, returning wherever the mock answered
+// differently than the device. Statuses, errors and lengths are compared, and the data of flash
+// reads, but not other data the mock can't know, like system info or SRAM a stub wrote
+pub fn replay_against_mock(entries: &[TraceEntry], mock: &mut MockPicoboot) -> Vec<Mismatch> {
+    let mut mismatches = vec![];
+    let mut cmd: Option<PicobootCmd> = None;
+    for (i, e) in entries.iter().enumerate() {
+        let mut diverge = |d| mismatches.push((i, d));
+        match e.op {
+            TraceOp::BulkOut => {
+                // data going out is hardly ever exactly a command, so anything that parses as one
+                // is taken as one
+                if let Some(c) = PicobootCmd::parse(&e.data) {
+                    cmd = Some(c);
+                }
+                let res = mock.bulk_write(&e.data, e.duration);
+                compare_result(&res, e, &mut diverge);
+            }
+            TraceOp::BulkIn => {
+                let mut buf = vec![0; e.requested];
+                let res = mock.bulk_read(&mut buf, e.duration);
+                compare_result(&res, e, &mut diverge);
+                if let Ok(n) = res {
+                    buf.truncate(n);
+                    if cmd.as_ref().and_then(flash_read).is_some() && buf != e.data {
+                        diverge(Divergence::Data {
+                            expected: e.data.clone(),
+                            actual: buf,
+                        });
+                    }
+                }
+            }
+            TraceOp::CommandStatus => {
+                let mut buf = [0u8; STATUS_SIZE];
+                let res = mock.command_status(&mut buf, e.duration);
+                if res.err() != e.error {
+                    diverge(Divergence::Error {
+                        expected: e.error,
+                        actual: res.err(),
+                    });
+                } else if res.is_ok() && e.data.get(..8) != Some(&buf[..8]) {
+                    // the token and status code, the rest being left to the bootrom
+                    diverge(Divergence::Data {
+                        expected: e.data.clone(),
+                        actual: buf.to_vec(),
+                    });
+                }
+            }
+            TraceOp::ResetInterface => {
+                cmd = None;
+                let res = mock.reset_interface(e.duration).map(|()| 0);
+                compare_result(&res, e, &mut diverge);
+            }
+        }
+    }
+    mismatches
+}
+
+fn compare_result(res: &rusb::Result<usize>, e: &TraceEntry, diverge: &mut impl FnMut(Divergence)) {
+    match res {
+        Err(err) if Some(*err) != e.error => diverge(Divergence::Error {
+            expected: e.error,
+            actual: Some(*err),
+        }),
+        Ok(_) if e.error.is_some() => diverge(Divergence::Error {
+            expected: e.error,
+            actual: None,
+        }),
+        Ok(n) if *n != e.data.len() => diverge(Divergence::Length {
+            expected: e.data.len(),
+            actual: *n,
+        }),
+        _ => {}
+    }
+}
+
+// Stands in for the device with the recording, for running host code against a session recorded
+// from a real one. Each call has to be the next recorded transfer: writes must carry the same
+// bytes, and reads get back what the device sent. Anything else is noted as a divergence and
+// fails with `rusb::Error::Other`, which the host sees as a USB error
+#[cfg(test)]
+pub struct ReplayTransport {
+    entries: Vec<TraceEntry>,
+    next: usize,
+    mismatches: Vec<Mismatch>,
+}
+#[cfg(test)]
+impl ReplayTransport {
+    pub fn new(entries: Vec<TraceEntry>) -> Self {
+        ReplayTransport {
+            entries,
+            next: 0,
+            mismatches: vec![],
+        }
+    }
+
+    // Every divergence so far, plus the recording being unfinished if the host stopped early
+    pub fn mismatches(&self) -> Vec<Mismatch> {
+        let mut m = self.mismatches.clone();
+        let remaining = self.entries.len().saturating_sub(self.next);
+        if remaining != 0 {
+            m.push((self.next, Divergence::Unfinished { remaining }));
+        }
+        m
+    }
+
+    // The next recorded transfer, which has to be `op`
+    fn take(&mut self, op: TraceOp) -> rusb::Result<TraceEntry> {
+        let i = self.next;
+        let Some(e) = self.entries.get(i).cloned() else {
+            self.mismatches.push((i, Divergence::PastEnd));
+            return Err(rusb::Error::Other);
+        };
+        if e.op != op {
+            self.mismatches.push((
+                i,
+                Divergence::Op {
+                    expected: e.op,
+                    actual: op,
+                },
+            ));
+            return Err(rusb::Error::Other);
+        }
+        self.next += 1;
+        Ok(e)
+    }
+}
+
+#[cfg(test)]
+impl Transport for ReplayTransport {
+    fn bulk_read(&mut self, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        let i = self.next;
+        let e = self.take(TraceOp::BulkIn)?;
+        if e.requested != buf.len() {
+            let d = Divergence::Length {
+                expected: e.requested,
+                actual: buf.len(),
+            };
+            self.mismatches.push((i, d));
+        }
+        if let Some(err) = e.error {
+            return Err(err);
+        }
+        let n = e.data.len().min(buf.len());
+        buf[..n].copy_from_slice(&e.data[..n]);
+        Ok(n)
+    }
+
+    fn bulk_write(&mut self, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        let i = self.next;
+        let e = self.take(TraceOp::BulkOut)?;
+        // the recording holds what went out, which on a failed write may be less than was asked
+        let sent = &buf[..e.data.len().min(buf.len())];
+        if e.requested != buf.len() || sent != e.data {
+            let d = Divergence::Data {
+                expected: e.data.clone(),
+                actual: buf.to_vec(),
+            };
+            self.mismatches.push((i, d));
+            return Err(rusb::Error::Other);
+        }
+        match e.error {
+            Some(err) => Err(err),
+            None => Ok(e.data.len()),
+        }
+    }
+
+    fn command_status(&mut self, buf: &mut [u8; 16], _timeout: Duration) -> rusb::Result<()> {
+        let e = self.take(TraceOp::CommandStatus)?;
+        if let Some(err) = e.error {
+            return Err(err);
+        }
+        buf.fill(0);
+        let n = e.data.len().min(buf.len());
+        buf[..n].copy_from_slice(&e.data[..n]);
+        Ok(())
+    }
+
+    // halts are cleared as part of the reset, which is traced as a whole
+    fn clear_halt(&mut self, _endpoint: u8) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn reset_interface(&mut self, _timeout: Duration) -> rusb::Result<()> {
+        let e = self.take(TraceOp::ResetInterface)?;
+        match e.error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memmap::FlashAddr;
+    use crate::picousb::{PicobootConnection, PicobootError, PicobootStatus};
+    use crate::trace::Trace;
+
+    // An RP2040 session: claiming exclusive access, reading a page that held data already,
+    // erasing the first sector, writing and reading back a page, then a misaligned erase the
+    // bootrom stalls, before giving access back
+    const PROGRAM_PAGE: &str = include_str!("../tests/fixtures/rp2040_program_page.json");
+
+    fn recording() -> (Vec<TraceEntry>, TargetID) {
+        let trace = Trace::from_json(PROGRAM_PAGE).unwrap();
+        (trace.entries(), trace.target().unwrap())
+    }
+
+    fn flash_addr(addr: u32) -> FlashAddr {
+        FlashAddr::new(addr).unwrap()
+    }
+
+    fn page() -> Vec<u8> {
+        (0..=255u8).rev().collect()
+    }
+
+    // What the host did in the recording
+    fn session<T: Transport>(conn: &mut PicobootConnection<T>) -> Result<(), PicobootError> {
+        let mut conn = conn.exclusive_access_guard(false)?;
+        conn.flash_read(flash_addr(0x10001000), 256)?;
+        conn.flash_erase(flash_addr(0x10000000), 4096)?;
+        conn.flash_write(flash_addr(0x10000000), page())?;
+        assert_eq!(conn.flash_read(flash_addr(0x10000000), 256)?, page());
+        match conn.flash_erase(flash_addr(0x10000100), 4096) {
+            Err(PicobootError::Command { status, .. }) => {
+                assert_eq!(status, PicobootStatus::BadAlignment)
+            }
+            res => panic!("misaligned erase gave {:?}", res),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mock_answers_as_recorded() {
+        let (entries, target) = recording();
+        assert_eq!(target, TargetID::Rp2040);
+        let mut mock = mock_for(&entries, target);
+        // the page read before anything was changed is seeded, the rest is left erased
+        let seeded: Vec<u8> = (0..=255u8).collect();
+        assert_eq!(&mock.flash()[0x1000..0x1100], &seeded[..]);
+        assert!(mock.flash()[0x1100..0x2000].iter().all(|&b| b == 0xFF));
+
+        assert_eq!(replay_against_mock(&entries, &mut mock), vec![]);
+        assert_eq!(&mock.flash()[..0x100], &page()[..]);
+        assert!(mock.flash()[0x100..0x1000].iter().all(|&b| b == 0xFF));
+        assert_eq!(mock.exclusive(), 0);
+    }
+
+    #[test]
+    fn mock_diverging_is_reported() {
+        let (entries, target) = recording();
+        let mut mock = mock_for(&entries, target);
+        mock.flash_mut()[0x1001] = 0x55;
+        let mismatches = replay_against_mock(&entries, &mut mock);
+        assert_eq!(mismatches.len(), 1);
+        let (i, divergence) = &mismatches[0];
+        assert_eq!(entries[*i].op, TraceOp::BulkIn);
+        match divergence {
+            Divergence::Data { expected, actual } => {
+                assert_eq!(expected[1], 0x01);
+                assert_eq!(actual[1], 0x55);
+            }
+            d => panic!("unexpected divergence {:?}", d),
+        }
+    }
+
+    #[test]
+    fn host_sends_what_was_recorded() {
+        let (entries, target) = recording();
+        let replay = ReplayTransport::new(entries);
+        let mut conn = PicobootConnection::with_transport(replay, Some(target));
+        session(&mut conn).unwrap();
+        assert_eq!(conn.transport().mismatches(), vec![]);
+    }
+
+    #[test]
+    fn host_diverging_is_reported() {
+        let (entries, target) = recording();
+        let replay = ReplayTransport::new(entries.clone());
+        let mut conn = PicobootConnection::with_transport(replay, Some(target));
+        {
+            let mut conn = conn.exclusive_access_guard(false).unwrap();
+            conn.flash_read(flash_addr(0x10001000), 256).unwrap();
+            // writing without the erase first
+            assert!(conn.flash_write(flash_addr(0x10000000), page()).is_err());
+        }
+        let mismatches = conn.transport().mismatches();
+        let (i, divergence) = &mismatches[0];
+        assert_eq!(entries[*i].op, TraceOp::BulkOut);
+        assert!(matches!(divergence, Divergence::Data { .. }));
+        assert!(matches!(
+            mismatches.last(),
+            Some((_, Divergence::Unfinished { .. }))
+        ));
+    }
+}
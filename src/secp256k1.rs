@@ -0,0 +1,167 @@
+// secp256k1 ECDSA for signing and checking images for RP2350 secure boot, on top of the k256
+// crate's constant time implementation. Keys and signatures are kept as the bootrom lays them
+// out: big-endian X and Y for a public key, and big-endian R and S for a signature
+
+use k256::ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
+use k256::ecdsa::{Signature, SigningKey, VerifyingKey};
+
+// Whether a private key is in range, i.e. from 1 to the group order - 1
+pub(crate) fn is_valid_private_key(d: &[u8; 32]) -> bool {
+    SigningKey::from_bytes(d.into()).is_ok()
+}
+
+// The public key of a valid private key, as big-endian X and Y
+pub(crate) fn public_key(d: &[u8; 32]) -> [u8; 64] {
+    let mut out = [0; 64];
+    if let Ok(key) = SigningKey::from_bytes(d.into()) {
+        let point = key.verifying_key().to_encoded_point(false);
+        out.copy_from_slice(&point.as_bytes()[1..]);
+    }
+    out
+}
+
+// Signs a SHA-256 digest with a valid private key, returning big-endian R and S. The nonce is
+// derived from the key and digest as in RFC 6979, so no source of randomness is needed, and S
+// is kept in the lower half of the order. An invalid key gives an all zero signature, which
+// never verifies
+pub(crate) fn sign(d: &[u8; 32], digest: &[u8; 32]) -> [u8; 64] {
+    let mut out = [0; 64];
+    let Ok(key) = SigningKey::from_bytes(d.into()) else {
+        return out;
+    };
+    let Ok(signature): Result<Signature, _> = key.sign_prehash(digest) else {
+        return out;
+    };
+    let signature = signature.normalize_s().unwrap_or(signature);
+    out.copy_from_slice(&signature.to_bytes());
+    out
+}
+
+// Checks a signature (big-endian R and S) of a SHA-256 digest against a public key (big-endian
+// X and Y). Both coordinates have to be below the field prime and on the curve, and R and S
+// from 1 to the group order - 1
+pub(crate) fn verify(public_key: &[u8; 64], digest: &[u8; 32], signature: &[u8; 64]) -> bool {
+    let mut sec1 = [0; 65];
+    sec1[0] = 0x04;
+    sec1[1..].copy_from_slice(public_key);
+    let Ok(key) = VerifyingKey::from_sec1_bytes(&sec1) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    key.verify_prehash(digest, &signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn digest(message: &str) -> [u8; 32] {
+        Sha256::digest(message.as_bytes()).into()
+    }
+
+    fn key(hex: &str) -> [u8; 32] {
+        unhex(hex).try_into().unwrap()
+    }
+
+    const ONE: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+    // the group order - 1, the largest private key
+    const N_MINUS_1: &str = "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364140";
+    const N: &str = "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141";
+    // the field prime
+    const P: &str = "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f";
+    const G: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+                     483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+    #[test]
+    fn private_key_range() {
+        assert!(is_valid_private_key(&key(ONE)));
+        assert!(is_valid_private_key(&key(N_MINUS_1)));
+        assert!(!is_valid_private_key(&[0; 32]));
+        assert!(!is_valid_private_key(&key(N)));
+        assert!(!is_valid_private_key(&[0xFF; 32]));
+    }
+
+    #[test]
+    fn public_keys() {
+        assert_eq!(public_key(&key(ONE))[..], unhex(G)[..]);
+        // -G, with Y mirrored
+        let minus_g = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+                       b7c52588d95c3b9aa25b0403f1eef75702e84bb7597aabe663b82f6f04ef2777";
+        assert_eq!(public_key(&key(N_MINUS_1))[..], unhex(minus_g)[..]);
+    }
+
+    // Deterministic signatures (RFC 6979 nonces with SHA-256, S in the lower half) as published
+    // for secp256k1, e.g. by python-ecdsa and Trezor
+    #[test]
+    fn rfc6979_vectors() {
+        let vectors = [
+            (
+                ONE,
+                "Satoshi Nakamoto",
+                "934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d8\
+                 2442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5",
+            ),
+            (
+                ONE,
+                "All those moments will be lost in time, like tears in rain. Time to die...",
+                "8600dbd41e348fe5c9465ab92d23e3db8b98b873beecd930736488696438cb6b\
+                 547fe64427496db33bf66019dacbf0039c04199abb0122918601db38a72cfc21",
+            ),
+            (
+                N_MINUS_1,
+                "Satoshi Nakamoto",
+                "fd567d121db66e382991534ada77a6bd3106f0a1098c231e47993447cd6af2d0\
+                 6b39cd0eb1bc8603e159ef5c20a5c8ad685a45b06ce9bebed3f153d10d93bed5",
+            ),
+        ];
+        for (d, message, expected) in vectors {
+            let d = key(d);
+            let signature = sign(&d, &digest(message));
+            assert_eq!(signature[..], unhex(expected)[..], "{}", message);
+            assert!(verify(&public_key(&d), &digest(message), &signature));
+            assert!(!verify(
+                &public_key(&d),
+                &digest("something else"),
+                &signature
+            ));
+        }
+    }
+
+    #[test]
+    fn verify_rejects_bad_keys_and_signatures() {
+        let d = key(ONE);
+        let digest = digest("Satoshi Nakamoto");
+        let signature = sign(&d, &digest);
+        let public = public_key(&d);
+
+        // coordinates at or past the field prime, and a point off the curve
+        let mut big_x = public;
+        big_x[..32].copy_from_slice(&unhex(P));
+        assert!(!verify(&big_x, &digest, &signature));
+        let mut big_y = public;
+        big_y[32..].copy_from_slice(&unhex(P));
+        assert!(!verify(&big_y, &digest, &signature));
+        let mut off_curve = public;
+        off_curve[63] ^= 1;
+        assert!(!verify(&off_curve, &digest, &signature));
+        assert!(!verify(&[0; 64], &digest, &signature));
+
+        // R and S of zero, or of the order or more
+        let mut zero_r = signature;
+        zero_r[..32].fill(0);
+        assert!(!verify(&public, &digest, &zero_r));
+        let mut big_s = signature;
+        big_s[32..].copy_from_slice(&unhex(N));
+        assert!(!verify(&public, &digest, &big_s));
+    }
+}
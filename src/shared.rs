@@ -0,0 +1,67 @@
+// Sharing one connection between threads, e.g. a provisioning service handling requests on a
+// thread pool. `PicobootConnection` is `Send` whenever its transport is (`RusbTransport` and
+// `MockPicoboot` both are), but not `Sync`, as every call needs `&mut`. `SharedPicoboot` puts it
+// behind a mutex so each thread takes its turn, with a whole operation like `flash_program` done
+// under one lock so commands from different threads never interleave on the wire
+
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+
+use crate::picousb::PicobootConnection;
+use crate::picousb_async::PicobootConnectionAsync;
+use crate::transport::Transport;
+
+pub struct SharedPicoboot<T: Transport + Send> {
+    pub(crate) conn: Arc<Mutex<PicobootConnection<T>>>,
+}
+
+impl<T: Transport + Send> Clone for SharedPicoboot<T> {
+    fn clone(&self) -> Self {
+        SharedPicoboot {
+            conn: self.conn.clone(),
+        }
+    }
+}
+
+impl<T: Transport + Send> SharedPicoboot<T> {
+    pub fn new(conn: PicobootConnection<T>) -> Self {
+        SharedPicoboot {
+            conn: Arc::new(Mutex::new(conn)),
+        }
+    }
+
+    // Exclusive use of the connection until the guard is dropped, waiting for any other thread
+    // using it. A thread that panicked while holding it only poisons the lock: every command
+    // cleans up after itself on failure, so the connection is still usable
+    pub fn lock(&self) -> MutexGuard<'_, PicobootConnection<T>> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Like `lock`, but None straight away if another thread is using the connection
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, PicobootConnection<T>>> {
+        match self.conn.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    // Runs `f` with exclusive use of the connection
+    pub fn with<R>(&self, f: impl FnOnce(&mut PicobootConnection<T>) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    // Gives back the connection, or the wrapper itself if other handles to it are still around
+    pub fn into_inner(self) -> Result<PicobootConnection<T>, Self> {
+        match Arc::try_unwrap(self.conn) {
+            Ok(m) => Ok(m.into_inner().unwrap_or_else(|e| e.into_inner())),
+            Err(conn) => Err(SharedPicoboot { conn }),
+        }
+    }
+}
+
+// An async handle to the same connection, for services mixing threads and async code
+impl<T: Transport + Send + 'static> From<SharedPicoboot<T>> for PicobootConnectionAsync<T> {
+    fn from(shared: SharedPicoboot<T>) -> Self {
+        PicobootConnectionAsync { conn: shared.conn }
+    }
+}
@@ -0,0 +1,299 @@
+// Recording every USB transfer of a connection, for diagnosing failures in the field such as a
+// write that reads back wrong. See `PicobootConnection::set_trace`. A trace can be exported as
+// JSON, or as a pcapng of usbmon events that Wireshark and `capture` read
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::capture::{self, Transfer, TransferKind};
+use crate::json::{self, Value};
+use crate::picousb::TargetID;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOp {
+    BulkIn,
+    BulkOut,
+    // the GET_COMMAND_STATUS control request
+    CommandStatus,
+    // clearing both bulk endpoints' halts and the INTERFACE_RESET control request
+    ResetInterface,
+}
+impl TraceOp {
+    const ALL: [TraceOp; 4] = [
+        TraceOp::BulkIn,
+        TraceOp::BulkOut,
+        TraceOp::CommandStatus,
+        TraceOp::ResetInterface,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            TraceOp::BulkIn => "bulk_in",
+            TraceOp::BulkOut => "bulk_out",
+            TraceOp::CommandStatus => "command_status",
+            TraceOp::ResetInterface => "reset_interface",
+        }
+    }
+}
+
+// rusb errors by the names the JSON export gives them
+const USB_ERRORS: [rusb::Error; 14] = [
+    rusb::Error::Io,
+    rusb::Error::InvalidParam,
+    rusb::Error::Access,
+    rusb::Error::NoDevice,
+    rusb::Error::NotFound,
+    rusb::Error::Busy,
+    rusb::Error::Timeout,
+    rusb::Error::Overflow,
+    rusb::Error::Pipe,
+    rusb::Error::Interrupted,
+    rusb::Error::NoMem,
+    rusb::Error::NotSupported,
+    rusb::Error::BadDescriptor,
+    rusb::Error::Other,
+];
+
+#[derive(Debug)]
+pub enum TraceError {
+    Json(json::ParseError),
+    // the transfer at this index is missing a field or has a bad value
+    BadTransfer(usize),
+    BadChip,
+}
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TraceError::Json(e) => write!(f, "{}", e),
+            TraceError::BadTransfer(i) => write!(f, "transfer {} of the trace is invalid", i),
+            TraceError::BadChip => write!(f, "trace has an unknown chip"),
+        }
+    }
+}
+impl std::error::Error for TraceError {}
+
+#[derive(Debug, Clone)]
+pub struct TraceEntry {
+    // when the transfer started, counted from when tracing did
+    pub start: Duration,
+    pub duration: Duration,
+    pub op: TraceOp,
+    // the endpoint address, with 0x80 set for IN, or 0 for the control requests
+    pub endpoint: u8,
+    // bytes asked to read or write
+    pub requested: usize,
+    // what was written, or what was read back
+    pub data: Vec<u8>,
+    pub error: Option<rusb::Error>,
+}
+
+struct TraceLog {
+    started: Instant,
+    started_at: SystemTime,
+    // the chip on the other end, if the connection knew
+    target: Option<TargetID>,
+    entries: Vec<TraceEntry>,
+}
+
+// A trace being recorded. Clones share the same log, so one can be kept to export while the
+// connection adds to another
+#[derive(Clone)]
+pub struct Trace {
+    log: Arc<Mutex<TraceLog>>,
+}
+impl Default for Trace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Trace {
+    pub fn new() -> Self {
+        Trace {
+            log: Arc::new(Mutex::new(TraceLog {
+                started: Instant::now(),
+                started_at: SystemTime::now(),
+                target: None,
+                entries: vec![],
+            })),
+        }
+    }
+
+    // Reads back a trace exported with `to_json`, e.g. to replay it, see `replay`
+    pub fn from_json(text: &str) -> Result<Self, TraceError> {
+        let v = json::parse(text).map_err(TraceError::Json)?;
+        let target = match v.get("chip") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(s)) if s == "rp2040" => Some(TargetID::Rp2040),
+            Some(Value::String(s)) if s == "rp2350" => Some(TargetID::Rp2350),
+            Some(_) => return Err(TraceError::BadChip),
+        };
+        let transfers = match v.get("transfers") {
+            Some(Value::Array(t)) => t,
+            _ => return Err(TraceError::BadTransfer(0)),
+        };
+        let entries = transfers
+            .iter()
+            .enumerate()
+            .map(|(i, t)| entry_from_json(t).ok_or(TraceError::BadTransfer(i)))
+            .collect::<Result<_, _>>()?;
+        let trace = Trace::new();
+        trace.lock().target = target;
+        trace.lock().entries = entries;
+        Ok(trace)
+    }
+
+    pub fn target(&self) -> Option<TargetID> {
+        self.lock().target
+    }
+
+    pub(crate) fn set_target(&self, target: Option<TargetID>) {
+        self.lock().target = target;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TraceLog> {
+        self.log.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn record(
+        &self,
+        op: TraceOp,
+        endpoint: u8,
+        requested: usize,
+        data: &[u8],
+        started: Instant,
+        error: Option<rusb::Error>,
+    ) {
+        let mut log = self.lock();
+        let entry = TraceEntry {
+            start: started.saturating_duration_since(log.started),
+            duration: started.elapsed(),
+            op,
+            endpoint,
+            requested,
+            data: data.to_vec(),
+            error,
+        };
+        log.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.lock().entries.clone()
+    }
+
+    // Every entry with its times in microseconds, its data as hex and its error, if any, as
+    // rusb names it
+    pub fn to_json(&self) -> Value {
+        let entries = self
+            .lock()
+            .entries
+            .iter()
+            .map(|e| {
+                let data: String = e.data.iter().map(|b| format!("{:02x}", b)).collect();
+                Value::Object(vec![
+                    ("time_us".into(), (e.start.as_micros() as u64).into()),
+                    ("duration_us".into(), (e.duration.as_micros() as u64).into()),
+                    ("op".into(), e.op.name().into()),
+                    ("endpoint".into(), format!("{:#04X}", e.endpoint).into()),
+                    ("requested".into(), e.requested.into()),
+                    ("length".into(), e.data.len().into()),
+                    ("data".into(), data.into()),
+                    ("error".into(), e.error.map(|e| format!("{:?}", e)).into()),
+                ])
+            })
+            .collect::<Vec<_>>();
+        let chip = self.target().map(|t| match t {
+            TargetID::Rp2040 => "rp2040",
+            TargetID::Rp2350 => "rp2350",
+        });
+        Value::Object(vec![
+            ("chip".into(), chip.into()),
+            ("transfers".into(), Value::Array(entries)),
+        ])
+    }
+
+    // The trace as a usbmon capture of the device at `bus` and `device`, with wall clock times
+    pub fn to_pcapng(&self, bus: u16, device: u8) -> Vec<u8> {
+        let log = self.lock();
+        let epoch = log
+            .started_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let transfers: Vec<Transfer> = log
+            .entries
+            .iter()
+            .map(|e| {
+                let submit_us = (epoch + e.start).as_micros() as u64;
+                let time_us = submit_us + e.duration.as_micros() as u64;
+                let status = e.error.map_or(0, errno);
+                let transfer = |kind, endpoint, len, data: &[u8]| Transfer {
+                    submit_us,
+                    time_us,
+                    bus,
+                    device,
+                    endpoint,
+                    kind,
+                    len,
+                    data: data.to_vec(),
+                    status,
+                };
+                match e.op {
+                    TraceOp::BulkIn | TraceOp::BulkOut => {
+                        transfer(TransferKind::Bulk, e.endpoint, e.data.len(), &e.data)
+                    }
+                    TraceOp::CommandStatus => {
+                        let setup = [0xC1, 0x42, 0, 0, 0, 0, 16, 0];
+                        let kind = TransferKind::Control { setup };
+                        transfer(kind, 0x80, e.data.len(), &e.data)
+                    }
+                    TraceOp::ResetInterface => {
+                        let setup = [0x41, 0x41, 0, 0, 0, 0, 0, 0];
+                        transfer(TransferKind::Control { setup }, 0x00, 0, &[])
+                    }
+                }
+            })
+            .collect();
+        capture::to_pcapng(&transfers)
+    }
+}
+
+fn entry_from_json(t: &Value) -> Option<TraceEntry> {
+    let micros = |key| t.get(key)?.as_u64().map(Duration::from_micros);
+    let op = match t.get("op")? {
+        Value::String(s) => *TraceOp::ALL.iter().find(|op| op.name() == s)?,
+        _ => return None,
+    };
+    let data = match t.get("data")? {
+        Value::String(s) if s.len() % 2 == 0 => (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?,
+        _ => return None,
+    };
+    let error = match t.get("error") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(s)) => Some(*USB_ERRORS.iter().find(|e| format!("{:?}", e) == *s)?),
+        Some(_) => return None,
+    };
+    Some(TraceEntry {
+        start: micros("time_us")?,
+        duration: micros("duration_us")?,
+        op,
+        endpoint: t.get("endpoint")?.as_u64()? as u8,
+        requested: t.get("requested")?.as_u64()? as usize,
+        data,
+        error,
+    })
+}
+
+// The status usbmon shows for a transfer failing with `e`
+fn errno(e: rusb::Error) -> i32 {
+    match e {
+        rusb::Error::Pipe => -32,
+        // a timed out transfer is cancelled, which usbmon shows as ENOENT
+        rusb::Error::Timeout => -2,
+        rusb::Error::NoDevice => -19,
+        rusb::Error::Overflow => -75,
+        rusb::Error::Io => -5,
+        _ => -71,
+    }
+}
@@ -0,0 +1,323 @@
+use crate::picousb::{
+    DeviceLocation, PicobootConnectionBuilder, PicobootError, Result, TargetID, UsbStrings,
+};
+
+use rusb::{Device, DeviceDescriptor, DeviceHandle, Direction, TransferType, UsbContext};
+use std::time::Duration;
+
+// The USB operations PICOBOOT needs from a backend. The protocol code in `PicobootConnection`
+// only talks to the device through this, so another USB library (or a test double) can stand in
+// for rusb
+pub trait Transport {
+    // Reads from the bulk IN endpoint, returning the number of bytes received
+    fn bulk_read(&mut self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+
+    // Writes to the bulk OUT endpoint, returning the number of bytes sent
+    fn bulk_write(&mut self, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+
+    // Reads the 16 byte status of the last command with the GET_COMMAND_STATUS control request
+    fn command_status(&mut self, buf: &mut [u8; 16], timeout: Duration) -> rusb::Result<()>;
+
+    // Clears a halt on one of the bulk endpoints given by `endpoints`
+    fn clear_halt(&mut self, endpoint: u8) -> rusb::Result<()>;
+
+    // Sends the INTERFACE_RESET control request
+    fn reset_interface(&mut self, timeout: Duration) -> rusb::Result<()>;
+
+    // The bulk IN and OUT endpoint addresses, only used to label traces. Backends that don't
+    // know them can leave the default
+    fn endpoints(&self) -> (u8, u8) {
+        (0x81, 0x01)
+    }
+
+    // wMaxPacketSize of the bulk IN and OUT endpoints, which transfers are chunked in multiples
+    // of. The default is what RP2040 and RP2350 use, as full-speed devices
+    fn max_packet_sizes(&self) -> (u16, u16) {
+        (64, 64)
+    }
+}
+
+type OpenedDevice<T> = (Device<T>, DeviceDescriptor, DeviceHandle<T>);
+
+// Opens the first device with the given VID/PID, and serial number and location if given
+fn open_device<T: UsbContext>(
+    ctx: &mut T,
+    vid: u16,
+    pid: u16,
+    serial: Option<&str>,
+    location: Option<&DeviceLocation>,
+) -> Result<Option<OpenedDevice<T>>> {
+    let devices = match ctx.devices() {
+        Ok(d) => d,
+        Err(_) => return Ok(None),
+    };
+
+    for device in devices.iter() {
+        let device_desc = match device.device_descriptor() {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        if location.is_some_and(|l| !l.matches(&device)) {
+            continue;
+        }
+        if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
+            let handle = match device.open() {
+                Ok(handle) => handle,
+                // on Windows this means no WinUSB driver is bound, try installing one and reopen
+                #[cfg(all(windows, feature = "windows-driver"))]
+                Err(rusb::Error::NotSupported) => {
+                    log::warn!("device found but has no WinUSB driver bound");
+                    if let Err(e) = crate::windriver::install_winusb(vid, pid) {
+                        log::error!("could not install WinUSB driver: {}", e);
+                        return Err(PicobootError::NoDriver { vid, pid });
+                    }
+                    match device.open() {
+                        Ok(handle) => handle,
+                        Err(rusb::Error::NotSupported) => {
+                            return Err(PicobootError::NoDriver { vid, pid })
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                // libusb's WinUSB backend can't open a device without a driver it knows
+                #[cfg(all(windows, not(feature = "windows-driver")))]
+                Err(rusb::Error::NotSupported) => {
+                    return Err(PicobootError::NoDriver { vid, pid });
+                }
+                Err(rusb::Error::Access) => {
+                    return Err(PicobootError::PermissionDenied {
+                        vid,
+                        pid,
+                        bus: device.bus_number(),
+                        address: device.address(),
+                    });
+                }
+                Err(e) => {
+                    log::error!("device found but failed to open: {}", e);
+                    return Err(e.into());
+                }
+            };
+
+            if let Some(serial) = serial {
+                let found = handle.read_serial_number_string_ascii(&device_desc).ok();
+                if found.as_deref() != Some(serial) {
+                    continue;
+                }
+            }
+            return Ok(Some((device, device_desc, handle)));
+        }
+    }
+
+    Ok(None)
+}
+
+// PICOBOOT interface of a device opened and claimed through rusb
+#[allow(dead_code)]
+pub struct RusbTransport<T: UsbContext> {
+    context: T,
+    device: Device<T>,
+    desc: DeviceDescriptor,
+    handle: DeviceHandle<T>,
+
+    cfg: u8,
+    iface: u8,
+    setting: u8,
+    in_addr: u8,
+    out_addr: u8,
+    in_packet: u16,
+    out_packet: u16,
+
+    has_kernel_driver: bool,
+}
+
+impl<T: UsbContext> RusbTransport<T> {
+    // Opens and claims the first device matching the builder's options, along with which chip
+    // it turned out to be
+    pub fn open(mut ctx: T, opts: &PicobootConnectionBuilder) -> Result<(Self, TargetID)> {
+        let mut d = None;
+        let mut target_id = None;
+        for &(vid, pid, target) in &opts.ids {
+            d = open_device(
+                &mut ctx,
+                vid,
+                pid,
+                opts.serial.as_deref(),
+                opts.location.as_ref(),
+            )?;
+            if d.is_some() {
+                log::info!("found {:?}", target);
+                target_id = Some(target);
+                break;
+            }
+        }
+        match (d, target_id) {
+            (Some((device, desc, handle)), Some(target_id)) => {
+                let (_cfg, _iface, _setting, in_addr, in_packet) =
+                    Self::get_endpoint(&device, 0xFF, 0, 0, Direction::In, TransferType::Bulk)
+                        .ok_or(PicobootError::InterfaceNotFound)?;
+                let (cfg, iface, setting, out_addr, out_packet) =
+                    Self::get_endpoint(&device, 0xFF, 0, 0, Direction::Out, TransferType::Bulk)
+                        .ok_or(PicobootError::InterfaceNotFound)?;
+
+                // both endpoints have to be on the same interface
+                if _cfg != cfg || _iface != iface || _setting != setting {
+                    return Err(PicobootError::InterfaceNotFound);
+                }
+
+                log::debug!(
+                    "bulk endpoints {:#04X} and {:#04X}, {} and {} byte packets",
+                    in_addr,
+                    out_addr,
+                    in_packet,
+                    out_packet
+                );
+
+                let has_kernel_driver = match handle.kernel_driver_active(iface) {
+                    Ok(true) if opts.detach_kernel_driver => {
+                        handle.detach_kernel_driver(iface)?;
+                        true
+                    }
+                    _ => false,
+                };
+
+                if handle.set_active_configuration(cfg).is_err() {
+                    log::warn!("could not set USB active configuration");
+                }
+                handle.claim_interface(iface)?;
+                handle.set_alternate_setting(iface, setting)?;
+
+                let transport = RusbTransport {
+                    context: ctx,
+                    device,
+                    desc,
+                    handle,
+
+                    cfg,
+                    iface,
+                    setting,
+                    in_addr,
+                    out_addr,
+                    in_packet,
+                    out_packet,
+
+                    has_kernel_driver,
+                };
+                Ok((transport, target_id))
+            }
+            _ => Err(PicobootError::DeviceNotFound),
+        }
+    }
+
+    fn get_endpoint(
+        device: &Device<T>,
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+        direction: Direction,
+        transfer_type: TransferType,
+    ) -> Option<(u8, u8, u8, u8, u16)> {
+        let desc = device.device_descriptor().ok()?;
+        for n in 0..desc.num_configurations() {
+            let config_desc = match device.config_descriptor(n) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+
+            for iface in config_desc.interfaces() {
+                for iface_desc in iface.descriptors() {
+                    let iface_class = iface_desc.class_code();
+                    let iface_subclass = iface_desc.sub_class_code();
+                    let iface_protocol = iface_desc.protocol_code();
+                    if !(iface_class == class
+                        && iface_subclass == subclass
+                        && iface_protocol == protocol)
+                    {
+                        continue;
+                    }
+
+                    for endpoint_desc in iface_desc.endpoint_descriptors() {
+                        if endpoint_desc.direction() == direction
+                            && endpoint_desc.transfer_type() == transfer_type
+                        {
+                            return Some((
+                                config_desc.number(),
+                                iface_desc.interface_number(),
+                                iface_desc.setting_number(),
+                                endpoint_desc.address(),
+                                // the low 11 bits, the rest count extra transactions per
+                                // microframe for high-bandwidth endpoints
+                                endpoint_desc.max_packet_size() & 0x7FF,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn device(&self) -> &Device<T> {
+        &self.device
+    }
+
+    pub fn handle(&self) -> &DeviceHandle<T> {
+        &self.handle
+    }
+
+    // The device's manufacturer, product and serial number strings
+    pub fn strings(&self) -> UsbStrings {
+        UsbStrings::read(&self.handle, &self.desc)
+    }
+}
+
+impl<T: UsbContext> Transport for RusbTransport<T> {
+    fn bulk_read(&mut self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        self.handle.read_bulk(self.in_addr, buf, timeout)
+    }
+
+    fn bulk_write(&mut self, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        self.handle.write_bulk(self.out_addr, buf, timeout)
+    }
+
+    fn command_status(&mut self, buf: &mut [u8; 16], timeout: Duration) -> rusb::Result<()> {
+        self.handle
+            .read_control(0b11000001, 0b01000010, 0, self.iface.into(), buf, timeout)?;
+        Ok(())
+    }
+
+    fn endpoints(&self) -> (u8, u8) {
+        (self.in_addr, self.out_addr)
+    }
+
+    fn max_packet_sizes(&self) -> (u16, u16) {
+        (self.in_packet, self.out_packet)
+    }
+
+    fn clear_halt(&mut self, endpoint: u8) -> rusb::Result<()> {
+        self.handle.clear_halt(endpoint)
+    }
+
+    fn reset_interface(&mut self, timeout: Duration) -> rusb::Result<()> {
+        self.handle
+            .write_control(0b01000001, 0b01000001, 0, self.iface.into(), &[], timeout)?;
+        Ok(())
+    }
+}
+
+// Drop can run while unwinding from a panic or after an interrupted operation, so failures here
+// are only reported rather than panicking again
+impl<T: UsbContext> Drop for RusbTransport<T> {
+    fn drop(&mut self) {
+        if let Err(e) = self.handle.release_interface(self.iface) {
+            log::warn!("could not release interface: {}", e);
+        }
+
+        if self.has_kernel_driver {
+            if let Err(e) = self.handle.attach_kernel_driver(self.iface) {
+                log::warn!("could not reattach kernel driver: {}", e);
+            }
+        }
+    }
+}
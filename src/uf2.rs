@@ -0,0 +1,210 @@
+// Decoding and encoding of UF2 files, see https://github.com/microsoft/uf2
+// Blocks are kept grouped by family ID, so multi-family files survive a round trip
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::image::Segment;
+use crate::picousb::{TargetID, PICO_PAGE_SIZE};
+
+pub const UF2_MAGIC_START0: u32 = 0x0A324655;
+pub const UF2_MAGIC_START1: u32 = 0x9E5D5157;
+pub const UF2_MAGIC_END: u32 = 0x0AB16F30;
+pub const UF2_BLOCK_SIZE: usize = 512;
+const UF2_MAX_PAYLOAD: usize = 476;
+
+pub const UF2_FLAG_NOT_MAIN_FLASH: u32 = 0x00000001;
+pub const UF2_FLAG_FAMILY_ID_PRESENT: u32 = 0x00002000;
+
+pub const FAMILY_ID_RP2040: u32 = 0xE48BFF56;
+pub const FAMILY_ID_ABSOLUTE: u32 = 0xE48BFF57;
+pub const FAMILY_ID_DATA: u32 = 0xE48BFF58;
+pub const FAMILY_ID_RP2350_ARM_S: u32 = 0xE48BFF59;
+pub const FAMILY_ID_RP2350_RISCV: u32 = 0xE48BFF5A;
+pub const FAMILY_ID_RP2350_ARM_NS: u32 = 0xE48BFF5B;
+
+#[derive(Debug)]
+pub enum Uf2Error {
+    BadLength(usize),
+    BadPayloadSize(usize),
+}
+impl fmt::Display for Uf2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Uf2Error::BadLength(len) => {
+                write!(f, "length {} is not a multiple of the UF2 block size", len)
+            }
+            Uf2Error::BadPayloadSize(block) => write!(f, "block {} has a bad payload size", block),
+        }
+    }
+}
+
+// Maps a family name like "rp2040" or "rp2350-arm-s" (or a raw number) to its family ID
+pub fn family_id_from_str(s: &str) -> Option<u32> {
+    match s.to_lowercase().as_str() {
+        "rp2040" => Some(FAMILY_ID_RP2040),
+        "absolute" => Some(FAMILY_ID_ABSOLUTE),
+        "data" => Some(FAMILY_ID_DATA),
+        "rp2350-arm-s" => Some(FAMILY_ID_RP2350_ARM_S),
+        "rp2350-riscv" => Some(FAMILY_ID_RP2350_RISCV),
+        "rp2350-arm-ns" => Some(FAMILY_ID_RP2350_ARM_NS),
+        s => match s.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+    }
+}
+
+pub fn family_id_name(id: u32) -> String {
+    match id {
+        FAMILY_ID_RP2040 => "rp2040".to_string(),
+        FAMILY_ID_ABSOLUTE => "absolute".to_string(),
+        FAMILY_ID_DATA => "data".to_string(),
+        FAMILY_ID_RP2350_ARM_S => "rp2350-arm-s".to_string(),
+        FAMILY_ID_RP2350_RISCV => "rp2350-riscv".to_string(),
+        FAMILY_ID_RP2350_ARM_NS => "rp2350-arm-ns".to_string(),
+        id => format!("{:#010x}", id),
+    }
+}
+
+// Family IDs the bootrom of the given chip will accept
+pub fn family_ids_for(target: TargetID) -> &'static [u32] {
+    match target {
+        TargetID::Rp2040 => &[FAMILY_ID_RP2040, FAMILY_ID_ABSOLUTE, FAMILY_ID_DATA],
+        TargetID::Rp2350 => &[
+            FAMILY_ID_RP2350_ARM_S,
+            FAMILY_ID_RP2350_RISCV,
+            FAMILY_ID_RP2350_ARM_NS,
+            FAMILY_ID_ABSOLUTE,
+            FAMILY_ID_DATA,
+        ],
+    }
+}
+
+pub fn is_uf2(bytes: &[u8]) -> bool {
+    bytes.len() >= 8
+        && u32::from_le_bytes(bytes[0..4].try_into().unwrap()) == UF2_MAGIC_START0
+        && u32::from_le_bytes(bytes[4..8].try_into().unwrap()) == UF2_MAGIC_START1
+}
+
+// Decodes a UF2 file into segments of contiguous data, one set per family. Blocks with bad
+// magic or that aren't meant for main flash are skipped, like the bootrom does
+pub fn decode(bytes: &[u8]) -> Result<Vec<Segment>, Uf2Error> {
+    if !bytes.len().is_multiple_of(UF2_BLOCK_SIZE) {
+        return Err(Uf2Error::BadLength(bytes.len()));
+    }
+
+    let mut segments: Vec<Segment> = vec![];
+    for (index, block) in bytes.chunks_exact(UF2_BLOCK_SIZE).enumerate() {
+        let word = |i: usize| u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        let magic_end = u32::from_le_bytes(block[508..512].try_into().unwrap());
+        let flags = word(2);
+        if word(0) != UF2_MAGIC_START0
+            || word(1) != UF2_MAGIC_START1
+            || magic_end != UF2_MAGIC_END
+            || (flags & UF2_FLAG_NOT_MAIN_FLASH) != 0
+        {
+            continue;
+        }
+
+        let addr = word(3);
+        let size = word(4) as usize;
+        if size > UF2_MAX_PAYLOAD {
+            return Err(Uf2Error::BadPayloadSize(index));
+        }
+        let family = if (flags & UF2_FLAG_FAMILY_ID_PRESENT) != 0 {
+            Some(word(7))
+        } else {
+            None
+        };
+        let data = &block[32..32 + size];
+
+        // extend the family's previous segment if this block directly follows it
+        match segments
+            .iter_mut()
+            .rev()
+            .find(|s| s.family == family && s.end() == addr as u64)
+        {
+            Some(seg) => seg.data.extend_from_slice(data),
+            None => segments.push(Segment {
+                addr,
+                data: data.to_vec(),
+                family,
+            }),
+        }
+    }
+
+    Ok(segments)
+}
+
+// Encodes segments into a UF2 file made of page-sized, page-aligned blocks. Segments without a
+// family are given `default_family`. Block numbering is kept separate for each family, the
+// same as concatenating single-family UF2 files together
+pub fn encode(
+    segments: &[Segment],
+    default_family: u32,
+) -> Result<Vec<u8>, crate::image::ImageError> {
+    let mut out = vec![];
+    for (family, segs) in split_families(segments, default_family) {
+        let pages = crate::image::pages(&segs)?;
+        let num_blocks = pages.len() as u32;
+        for (block_no, (addr, page)) in pages.into_iter().enumerate() {
+            let header = [
+                UF2_MAGIC_START0,
+                UF2_MAGIC_START1,
+                UF2_FLAG_FAMILY_ID_PRESENT,
+                addr,
+                PICO_PAGE_SIZE as u32,
+                block_no as u32,
+                num_blocks,
+                family,
+            ];
+            let mut block = Vec::with_capacity(UF2_BLOCK_SIZE);
+            header
+                .iter()
+                .for_each(|w| block.extend_from_slice(&w.to_le_bytes()));
+            block.extend_from_slice(&page);
+            block.resize(UF2_BLOCK_SIZE - 4, 0);
+            block.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
+            out.extend_from_slice(&block);
+        }
+    }
+
+    Ok(out)
+}
+
+// Groups segments by family ID, with segments without a family put under `default_family`
+pub fn split_families(segments: &[Segment], default_family: u32) -> BTreeMap<u32, Vec<Segment>> {
+    let mut families: BTreeMap<u32, Vec<Segment>> = BTreeMap::new();
+    for seg in segments {
+        families
+            .entry(seg.family.unwrap_or(default_family))
+            .or_default()
+            .push(seg.clone());
+    }
+    families
+}
+
+// Family IDs among the segments that the given chip won't accept, e.g. an RP2040 image about
+// to be written to an RP2350
+pub fn foreign_families(segments: &[Segment], target: TargetID) -> Vec<u32> {
+    let families = family_ids_for(target);
+    let mut foreign: Vec<u32> = segments
+        .iter()
+        .filter_map(|s| s.family)
+        .filter(|f| !families.contains(f))
+        .collect();
+    foreign.sort_unstable();
+    foreign.dedup();
+    foreign
+}
+
+// Keeps only the segments that are relevant to the given chip. Segments without a family
+// (from ELF or BIN inputs) are always kept
+pub fn filter_for_target(segments: Vec<Segment>, target: TargetID) -> Vec<Segment> {
+    let families = family_ids_for(target);
+    segments
+        .into_iter()
+        .filter(|s| s.family.is_none_or(|f| families.contains(&f)))
+        .collect()
+}
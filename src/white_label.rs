@@ -0,0 +1,529 @@
+// RP2350 white-label data: the USB and mass storage identity the bootrom presents in BOOTSEL
+// mode, kept in OTP as a struct of 16 ECC rows followed by its strings. USB_WHITE_LABEL_ADDR
+// points at the struct, and USB_BOOT_FLAGS says which of its entries replace the defaults. See
+// section 5.7 of the RP2350 datasheet
+
+use std::fmt;
+
+#[cfg(feature = "cli")]
+use crate::json::Value;
+use crate::otp::{self, OtpWrite};
+use crate::picousb::{self, PicobootConnection};
+use crate::transport::Transport;
+
+pub const OTP_ROW_USB_BOOT_FLAGS: u16 = 0x059;
+pub const OTP_ROW_USB_WHITE_LABEL_ADDR: u16 = 0x05C;
+const USB_BOOT_FLAGS_COPIES: u16 = 3;
+const USB_BOOT_FLAGS_VOTES: usize = 2;
+// bits 0 to 15 mark each entry of the struct valid
+const USB_BOOT_FLAGS_WHITE_LABEL_ADDR_VALID: u32 = 1 << 22;
+
+// Where the struct goes unless told otherwise, the first row free for user data
+pub const DEFAULT_WHITE_LABEL_ROW: u16 = 0x100;
+const STRUCT_ROWS: u16 = 16;
+
+const INDEX_VID: usize = 0;
+const INDEX_PID: usize = 1;
+const INDEX_BCD_DEVICE: usize = 2;
+const INDEX_LANG_ID: usize = 3;
+const INDEX_ATTRIBUTES_MAX_POWER: usize = 7;
+
+// Strings as (struct index, JSON section, JSON key, most characters allowed)
+const STRINGS: &[(usize, &str, &str, usize)] = &[
+    (4, "device", "manufacturer", 30),
+    (5, "device", "product", 30),
+    (6, "device", "serial_number", 30),
+    (8, "volume", "label", 11),
+    (9, "scsi", "vendor", 8),
+    (10, "scsi", "product", 16),
+    (11, "scsi", "version", 4),
+    (12, "volume", "redirect_url", 127),
+    (13, "volume", "redirect_name", 127),
+    (14, "volume", "model", 127),
+    (15, "volume", "board_id", 127),
+];
+
+// A STRDEF entry holds the string's row offset from the struct in the top byte, a flag for
+// UTF-16 (one character per row rather than two ASCII ones) and the character count
+const STRDEF_UTF16: u16 = 0x80;
+const STRDEF_LEN_MASK: u16 = 0x7F;
+
+// Default configuration attributes (bus powered) and max power (100mA), for when only one of
+// the two is given
+const DEFAULT_ATTRIBUTES: u8 = 0x80;
+const DEFAULT_MAX_POWER: u8 = 0x32;
+
+#[derive(Debug)]
+pub enum WhiteLabelError {
+    NotAnObject,
+    // the field, and what's wrong with it
+    BadField(String, &'static str),
+    // the struct and its strings don't fit in the rows a STRDEF can point at
+    TooLong,
+}
+impl fmt::Display for WhiteLabelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WhiteLabelError::NotAnObject => write!(f, "expected a JSON object"),
+            WhiteLabelError::BadField(field, why) => write!(f, "bad {}: {}", field, why),
+            WhiteLabelError::TooLong => write!(f, "strings are too long to fit in 256 rows"),
+        }
+    }
+}
+
+// White-label settings, None where the bootrom's default is kept. Strings are indexed as in
+// the struct, see `STRINGS`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WhiteLabel {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub bcd_device: Option<u16>,
+    pub lang_id: Option<u16>,
+    pub attributes: Option<u8>,
+    pub max_power: Option<u8>,
+    pub strings: [Option<String>; 16],
+}
+impl WhiteLabel {
+    // Reads settings from JSON in the layout picotool uses, every field being optional:
+    // { "device": { "vid": "0x2E8A", "pid": "0x000F", "bcd": "0x0100", "lang_id": "0x0409",
+    //               "manufacturer": "Acme", "product": "Widget", "serial_number": "1234",
+    //               "max_power": "0x32", "attributes": "0x80" },
+    //   "scsi": { "vendor": "Acme", "product": "Widget Boot", "version": "1.00" },
+    //   "volume": { "label": "WIDGET", "redirect_url": "https://example.com",
+    //               "redirect_name": "Example", "model": "Widget", "board_id": "WIDGET-1" } }
+    #[cfg(feature = "cli")]
+    pub(crate) fn from_json(v: &Value) -> Result<Self, WhiteLabelError> {
+        let Value::Object(sections) = v else {
+            return Err(WhiteLabelError::NotAnObject);
+        };
+        for (name, section) in sections {
+            if !["device", "scsi", "volume"].contains(&name.as_str()) {
+                return Err(WhiteLabelError::BadField(name.clone(), "unknown section"));
+            }
+            let Value::Object(fields) = section else {
+                return Err(WhiteLabelError::BadField(
+                    name.clone(),
+                    "expected an object",
+                ));
+            };
+            for (key, _) in fields {
+                let known = STRINGS.iter().any(|&(_, s, k, _)| s == name && k == key)
+                    || (name == "device"
+                        && ["vid", "pid", "bcd", "lang_id", "max_power", "attributes"]
+                            .contains(&key.as_str()));
+                if !known {
+                    return Err(WhiteLabelError::BadField(
+                        format!("{}.{}", name, key),
+                        "unknown field",
+                    ));
+                }
+            }
+        }
+
+        let field = |section: &str, key: &str| v.get(section).and_then(|s| s.get(key));
+        let number = |key: &str, max: u64| -> Result<Option<u64>, WhiteLabelError> {
+            match field("device", key) {
+                None => Ok(None),
+                Some(n) => n.as_u64().filter(|&n| n <= max).map(Some).ok_or_else(|| {
+                    WhiteLabelError::BadField(format!("device.{}", key), "number out of range")
+                }),
+            }
+        };
+
+        let mut wl = WhiteLabel {
+            vid: number("vid", 0xFFFF)?.map(|n| n as u16),
+            pid: number("pid", 0xFFFF)?.map(|n| n as u16),
+            bcd_device: number("bcd", 0xFFFF)?.map(|n| n as u16),
+            lang_id: number("lang_id", 0xFFFF)?.map(|n| n as u16),
+            attributes: number("attributes", 0xFF)?.map(|n| n as u8),
+            max_power: number("max_power", 0xFF)?.map(|n| n as u8),
+            ..Default::default()
+        };
+        if wl.attributes.is_some_and(|a| a & 0x9F != 0x80) {
+            return Err(WhiteLabelError::BadField(
+                "device.attributes".into(),
+                "bit 7 must be set, and only bits 6 (self powered) and 5 (remote wakeup) may be",
+            ));
+        }
+        for &(index, section, key, max) in STRINGS {
+            let Some(s) = field(section, key) else {
+                continue;
+            };
+            let name = || format!("{}.{}", section, key);
+            let Value::String(s) = s else {
+                return Err(WhiteLabelError::BadField(name(), "expected a string"));
+            };
+            if s.encode_utf16().count() > max {
+                return Err(WhiteLabelError::BadField(name(), "string is too long"));
+            }
+            if !s.is_ascii() && section != "device" {
+                return Err(WhiteLabelError::BadField(
+                    name(),
+                    "only USB strings may be UTF-16",
+                ));
+            }
+            wl.strings[index] = Some(s.clone());
+        }
+        Ok(wl)
+    }
+
+    // Bits of USB_BOOT_FLAGS marking which struct entries are set
+    pub fn valid_mask(&self) -> u32 {
+        let mut mask = 0;
+        for (index, set) in [
+            (INDEX_VID, self.vid.is_some()),
+            (INDEX_PID, self.pid.is_some()),
+            (INDEX_BCD_DEVICE, self.bcd_device.is_some()),
+            (INDEX_LANG_ID, self.lang_id.is_some()),
+            (
+                INDEX_ATTRIBUTES_MAX_POWER,
+                self.attributes.is_some() || self.max_power.is_some(),
+            ),
+        ] {
+            mask |= (set as u32) << index;
+        }
+        for (index, s) in self.strings.iter().enumerate() {
+            mask |= (s.is_some() as u32) << index;
+        }
+        mask
+    }
+
+    // The 16 bits of data of each row, the struct followed by the strings it points at. ASCII
+    // strings are packed two characters to a row, anything else is stored as UTF-16
+    pub fn to_rows(&self) -> Result<Vec<u16>, WhiteLabelError> {
+        let mut rows = vec![0u16; STRUCT_ROWS as usize];
+        rows[INDEX_VID] = self.vid.unwrap_or(0);
+        rows[INDEX_PID] = self.pid.unwrap_or(0);
+        rows[INDEX_BCD_DEVICE] = self.bcd_device.unwrap_or(0);
+        rows[INDEX_LANG_ID] = self.lang_id.unwrap_or(0);
+        if self.attributes.is_some() || self.max_power.is_some() {
+            rows[INDEX_ATTRIBUTES_MAX_POWER] =
+                (self.attributes.unwrap_or(DEFAULT_ATTRIBUTES) as u16) << 8
+                    | self.max_power.unwrap_or(DEFAULT_MAX_POWER) as u16;
+        }
+        for (index, s) in self.strings.iter().enumerate() {
+            let Some(s) = s else {
+                continue;
+            };
+            let offset = rows.len();
+            let (utf16, len) = if s.is_ascii() {
+                for pair in s.as_bytes().chunks(2) {
+                    rows.push(pair[0] as u16 | (*pair.get(1).unwrap_or(&0) as u16) << 8);
+                }
+                (0, s.len())
+            } else {
+                let chars: Vec<u16> = s.encode_utf16().collect();
+                rows.extend(&chars);
+                (STRDEF_UTF16, chars.len())
+            };
+            if offset > 0xFF {
+                return Err(WhiteLabelError::TooLong);
+            }
+            rows[index] = (offset as u16) << 8 | utf16 | len as u16;
+        }
+        Ok(rows)
+    }
+
+    // The OTP writes setting up white-labelling with the struct at `row`, given what
+    // USB_BOOT_FLAGS holds now. Its other bits are kept, as OTP bits can't be cleared anyway
+    pub fn to_writes(
+        &self,
+        row: u16,
+        usb_boot_flags: u32,
+    ) -> Result<Vec<OtpWrite>, WhiteLabelError> {
+        let rows = self.to_rows()?;
+        if row as usize + rows.len() > otp::OTP_ROW_COUNT as usize {
+            return Err(WhiteLabelError::TooLong);
+        }
+        let ecc = |row: u16, value: u32| OtpWrite {
+            row,
+            value,
+            ecc: true,
+            info: otp::row_info(row),
+        };
+        let mut writes: Vec<OtpWrite> =
+            (row..).zip(&rows).map(|(r, &v)| ecc(r, v as u32)).collect();
+        writes.push(ecc(OTP_ROW_USB_WHITE_LABEL_ADDR, row as u32));
+        let flags = usb_boot_flags | self.valid_mask() | USB_BOOT_FLAGS_WHITE_LABEL_ADDR_VALID;
+        for copy in 0..USB_BOOT_FLAGS_COPIES {
+            let row = OTP_ROW_USB_BOOT_FLAGS + copy;
+            writes.push(OtpWrite {
+                row,
+                value: flags,
+                ecc: false,
+                info: otp::row_info(row),
+            });
+        }
+        Ok(writes)
+    }
+
+    // Reads the white-label settings the bootrom uses, None if none are set up
+    pub fn read<T: Transport>(conn: &mut PicobootConnection<T>) -> picousb::Result<Option<Self>> {
+        let flags = read_usb_boot_flags(conn)?;
+        if flags & USB_BOOT_FLAGS_WHITE_LABEL_ADDR_VALID == 0 {
+            return Ok(None);
+        }
+        let addr = otp::read_rows(conn, OTP_ROW_USB_WHITE_LABEL_ADDR, 1, true)?[0] as u16;
+        let entries = otp::read_rows(conn, addr, STRUCT_ROWS, true)?;
+        let valid = |index: usize| flags & (1 << index) != 0;
+        let value = |index: usize| valid(index).then_some(entries[index] as u16);
+
+        let mut wl = WhiteLabel {
+            vid: value(INDEX_VID),
+            pid: value(INDEX_PID),
+            bcd_device: value(INDEX_BCD_DEVICE),
+            lang_id: value(INDEX_LANG_ID),
+            attributes: value(INDEX_ATTRIBUTES_MAX_POWER).map(|v| (v >> 8) as u8),
+            max_power: value(INDEX_ATTRIBUTES_MAX_POWER).map(|v| v as u8),
+            ..Default::default()
+        };
+        for &(index, ..) in STRINGS {
+            if !valid(index) {
+                continue;
+            }
+            let strdef = entries[index] as u16;
+            let len = strdef & STRDEF_LEN_MASK;
+            let utf16 = strdef & STRDEF_UTF16 != 0;
+            let count = if utf16 { len } else { len.div_ceil(2) };
+            let row = addr + (strdef >> 8);
+            let data: Vec<u16> = if count == 0 {
+                vec![]
+            } else {
+                otp::read_rows(conn, row, count, true)?
+                    .into_iter()
+                    .map(|v| v as u16)
+                    .collect()
+            };
+            wl.strings[index] = Some(if utf16 {
+                String::from_utf16_lossy(&data)
+            } else {
+                let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_le_bytes()).collect();
+                String::from_utf8_lossy(&bytes[..len as usize]).into_owned()
+            });
+        }
+        Ok(Some(wl))
+    }
+
+    // The settings in the JSON layout `from_json` reads, leaving out those not set
+    #[cfg(feature = "cli")]
+    pub(crate) fn to_json(&self) -> Value {
+        let hex = |v: Option<u32>| v.map(|v| Value::String(format!("{:#06X}", v)));
+        let mut sections: Vec<(String, Value)> = vec![];
+        let mut add = |section: &str, key: &str, value: Option<Value>| {
+            let Some(value) = value else {
+                return;
+            };
+            if !sections.iter().any(|(s, _)| s == section) {
+                sections.push((section.to_string(), Value::Object(vec![])));
+            }
+            let (_, Value::Object(fields)) =
+                sections.iter_mut().find(|(s, _)| s == section).unwrap()
+            else {
+                unreachable!()
+            };
+            fields.push((key.to_string(), value));
+        };
+        add("device", "vid", hex(self.vid.map(Into::into)));
+        add("device", "pid", hex(self.pid.map(Into::into)));
+        add("device", "bcd", hex(self.bcd_device.map(Into::into)));
+        add("device", "lang_id", hex(self.lang_id.map(Into::into)));
+        add("device", "attributes", hex(self.attributes.map(Into::into)));
+        add("device", "max_power", hex(self.max_power.map(Into::into)));
+        for &(index, section, key, _) in STRINGS {
+            add(section, key, self.strings[index].clone().map(Value::String));
+        }
+        Value::Object(sections)
+    }
+}
+
+// Reads USB_BOOT_FLAGS, a bit counting as set when it's set in 2 of its 3 copies
+pub fn read_usb_boot_flags<T: Transport>(conn: &mut PicobootConnection<T>) -> picousb::Result<u32> {
+    otp::read_redundant(
+        conn,
+        OTP_ROW_USB_BOOT_FLAGS,
+        USB_BOOT_FLAGS_COPIES,
+        USB_BOOT_FLAGS_VOTES,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPicoboot;
+    use crate::picousb::TargetID;
+
+    fn example() -> WhiteLabel {
+        let mut wl = WhiteLabel {
+            vid: Some(0x2E8A),
+            pid: Some(0x000F),
+            max_power: Some(0xFA),
+            ..Default::default()
+        };
+        wl.strings[4] = Some("Acme".into());
+        wl.strings[5] = Some("Wïdget".into());
+        wl.strings[8] = Some("WIDGET".into());
+        wl
+    }
+
+    #[test]
+    fn struct_and_strings_follow_the_datasheet_layout() {
+        let rows = example().to_rows().unwrap();
+        assert_eq!(&rows[..4], [0x2E8A, 0x000F, 0, 0]);
+        // attributes in the top byte, defaulting to bus powered
+        assert_eq!(rows[7], 0x80FA);
+        // STRDEFs: row offset from the struct, UTF-16 flag and character count
+        assert_eq!(rows[4], 16 << 8 | 4);
+        assert_eq!(rows[5], 18 << 8 | 0x80 | 6);
+        assert_eq!(rows[8], 24 << 8 | 6);
+        assert_eq!(
+            &rows[16..18],
+            [u16::from_le_bytes(*b"Ac"), u16::from_le_bytes(*b"me")]
+        );
+        assert_eq!(
+            rows[18..24],
+            "Wïdget".encode_utf16().collect::<Vec<_>>()[..]
+        );
+        // an odd length leaves the top byte of the last row clear
+        let mut wl = WhiteLabel::default();
+        wl.strings[11] = Some("1.0".into());
+        assert_eq!(&wl.to_rows().unwrap()[16..], [0x2E31, 0x0030]);
+
+        assert_eq!(
+            example().valid_mask(),
+            1 << 0 | 1 << 1 | 1 << 4 | 1 << 5 | 1 << 7 | 1 << 8
+        );
+    }
+
+    #[test]
+    fn writes_point_the_bootrom_at_the_struct() {
+        let wl = example();
+        let rows = wl.to_rows().unwrap();
+        let writes = wl.to_writes(0x200, 0x010000).unwrap();
+        assert_eq!(writes.len(), rows.len() + 1 + 3);
+        for (i, w) in writes[..rows.len()].iter().enumerate() {
+            assert_eq!(
+                (w.row, w.value, w.ecc),
+                (0x200 + i as u16, rows[i] as u32, true)
+            );
+        }
+        let addr = &writes[rows.len()];
+        assert_eq!((addr.row, addr.value, addr.ecc), (0x05C, 0x200, true));
+        assert_eq!(addr.info.as_ref().unwrap().name, "USB_WHITE_LABEL_ADDR");
+        for (copy, w) in writes[rows.len() + 1..].iter().enumerate() {
+            assert_eq!(w.row, 0x059 + copy as u16);
+            assert!(!w.ecc);
+            assert_eq!(w.value, 0x010000 | 1 << 22 | wl.valid_mask());
+        }
+
+        // the struct has to fit in the OTP, and the strings in reach of a STRDEF
+        assert!(matches!(
+            wl.to_writes(0xFF0, 0),
+            Err(WhiteLabelError::TooLong)
+        ));
+        let mut long = WhiteLabel::default();
+        for index in [4, 5, 6] {
+            long.strings[index] = Some("ï".repeat(30));
+        }
+        for index in [12, 13, 14] {
+            long.strings[index] = Some("x".repeat(127));
+        }
+        // the last string starts at row 234, still in reach, but one more is not
+        assert!(long.to_rows().is_ok());
+        long.strings[15] = Some("x".into());
+        assert!(matches!(long.to_rows(), Err(WhiteLabelError::TooLong)));
+    }
+
+    #[test]
+    fn written_settings_read_back_the_same() {
+        let target = TargetID::Rp2350;
+        let mut conn = PicobootConnection::with_transport(MockPicoboot::new(target), Some(target));
+        assert_eq!(WhiteLabel::read(&mut conn).unwrap(), None);
+
+        let wl = example();
+        for w in wl.to_writes(DEFAULT_WHITE_LABEL_ROW, 0).unwrap() {
+            otp::write_rows(&mut conn, w.row, &[w.value], w.ecc).unwrap();
+        }
+        let read = WhiteLabel::read(&mut conn).unwrap().unwrap();
+        assert_eq!(
+            read,
+            WhiteLabel {
+                attributes: Some(DEFAULT_ATTRIBUTES),
+                ..wl
+            }
+        );
+    }
+
+    #[cfg(feature = "cli")]
+    fn from_json(json: &str) -> Result<WhiteLabel, WhiteLabelError> {
+        WhiteLabel::from_json(&crate::json::parse(json).unwrap())
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn strings_are_limited_to_what_the_bootrom_takes() {
+        for &(index, section, key, max) in STRINGS {
+            let json = |len: usize| {
+                format!(
+                    r#"{{ "{}": {{ "{}": "{}" }} }}"#,
+                    section,
+                    key,
+                    "x".repeat(len)
+                )
+            };
+            let wl = from_json(&json(max)).unwrap();
+            assert_eq!(wl.strings[index].as_ref().map(String::len), Some(max));
+            assert!(
+                matches!(
+                    from_json(&json(max + 1)),
+                    Err(WhiteLabelError::BadField(..))
+                ),
+                "{}.{} takes {} characters",
+                section,
+                key,
+                max + 1
+            );
+        }
+        // the limits from the datasheet
+        let limit = |key: &str| STRINGS.iter().find(|s| s.2 == key).unwrap().3;
+        assert_eq!(limit("manufacturer"), 30);
+        assert_eq!(limit("label"), 11);
+        assert_eq!(limit("vendor"), 8);
+        assert_eq!(limit("version"), 4);
+        assert_eq!(limit("board_id"), 127);
+
+        // UTF-16 counts characters as the bootrom does, and is only for the USB strings
+        assert!(from_json(&format!(
+            r#"{{ "device": {{ "product": "{}" }} }}"#,
+            "ï".repeat(30)
+        ))
+        .is_ok());
+        assert!(from_json(r#"{ "volume": { "label": "WÏDGET" } }"#).is_err());
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn json_fields_are_checked() {
+        let wl = from_json(r#"{ "device": { "vid": "0x2E8A", "attributes": "0xC0" } }"#).unwrap();
+        assert_eq!((wl.vid, wl.attributes), (Some(0x2E8A), Some(0xC0)));
+        for bad in [
+            r#"{ "device": { "vid": "0x10000" } }"#,
+            r#"{ "device": { "max_power": 256 } }"#,
+            r#"{ "device": { "attributes": "0x40" } }"#,
+            r#"{ "device": { "attributes": "0x81" } }"#,
+            r#"{ "device": { "colour": "red" } }"#,
+            r#"{ "scsi": { "vendor": 5 } }"#,
+            r#"{ "usb": {} }"#,
+            r#"{ "device": [] }"#,
+        ] {
+            assert!(
+                matches!(from_json(bad), Err(WhiteLabelError::BadField(..))),
+                "{}",
+                bad
+            );
+        }
+        assert!(matches!(from_json("[]"), Err(WhiteLabelError::NotAnObject)));
+
+        let wl = example();
+        assert_eq!(WhiteLabel::from_json(&wl.to_json()).unwrap(), wl);
+    }
+}
@@ -0,0 +1,86 @@
+// Windows only: when the PICOBOOT interface has no WinUSB driver bound, libusb can see the device
+// but not open it. With the `windows-driver` feature this writes a WinUSB INF for the interface
+// and installs it with pnputil, the same thing Zadig does by hand. This needs an elevated
+// prompt, and on systems enforcing driver signing the install can still be refused, in which
+// case Zadig is the fallback (see the README)
+
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+// The PICOBOOT interface comes after the mass storage interface
+const PICOBOOT_INTERFACE: u8 = 1;
+
+// Random GUID for the PICOBOOT interface, so it shows up as a WinUSB device interface
+const PICOBOOT_INTERFACE_GUID: &str = "{C2A48D4B-9A3B-4E6A-9C9B-2E8A0003B007}";
+
+fn inf_contents(vid: u16, pid: u16) -> String {
+    let hwid = format!(
+        "USB\\VID_{:04X}&PID_{:04X}&MI_{:02X}",
+        vid, pid, PICOBOOT_INTERFACE
+    );
+    format!(
+        r#"[Version]
+Signature = "$Windows NT$"
+Class = USBDevice
+ClassGUID = {{88BAE032-5A81-49f0-BC3D-A4FF138216D6}}
+Provider = %ManufacturerName%
+DriverVer = 01/01/2024,1.0.0.0
+
+[Manufacturer]
+%ManufacturerName% = Standard,NTamd64,NTx86,NTarm64
+
+[Standard.NTamd64]
+%DeviceName% = USB_Install, {hwid}
+
+[Standard.NTx86]
+%DeviceName% = USB_Install, {hwid}
+
+[Standard.NTarm64]
+%DeviceName% = USB_Install, {hwid}
+
+[USB_Install]
+Include = winusb.inf
+Needs = WINUSB.NT
+
+[USB_Install.Services]
+Include = winusb.inf
+Needs = WINUSB.NT.Services
+
+[USB_Install.HW]
+AddReg = Dev_AddReg
+
+[Dev_AddReg]
+HKR,,DeviceInterfaceGUIDs,0x10000,"{guid}"
+
+[Strings]
+ManufacturerName = "Raspberry Pi"
+DeviceName = "RP2 Boot (PICOBOOT)"
+"#,
+        hwid = hwid,
+        guid = PICOBOOT_INTERFACE_GUID
+    )
+}
+
+// Writes the INF to a temporary directory and asks pnputil to install it for the device
+pub fn install_winusb(vid: u16, pid: u16) -> io::Result<()> {
+    let dir: PathBuf = std::env::temp_dir().join("usb_picoboot_rs_driver");
+    std::fs::create_dir_all(&dir)?;
+    let inf = dir.join(format!("picoboot_{:04x}_{:04x}.inf", vid, pid));
+    std::fs::write(&inf, inf_contents(vid, pid))?;
+
+    log::info!("installing WinUSB driver from {}", inf.display());
+    let status = Command::new("pnputil")
+        .arg("/add-driver")
+        .arg(&inf)
+        .arg("/install")
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "pnputil failed ({}), try again from an administrator prompt or use Zadig",
+            status
+        )));
+    }
+
+    Ok(())
+}